@@ -3,9 +3,10 @@ use std::{collections::HashMap, error::Error};
 
 use chrono::{DateTime, Utc};
 
-use aw_models::{Bucket, Event};
+use aw_models::{Bucket, Event, EventChanges, EventOrder, EventPatch, OriginChanges};
 
 use super::AwClient as AsyncAwClient;
+use super::EventsPage;
 
 pub struct AwClient {
     client: AsyncAwClient,
@@ -61,6 +62,16 @@ impl AwClient {
         stop: Option<DateTime<Utc>>,
         limit: Option<u64>
     );
+    proxy_method!(
+        get_events_page,
+        EventsPage,
+        bucketname: &str,
+        start: Option<DateTime<Utc>>,
+        stop: Option<DateTime<Utc>>,
+        limit: u64,
+        order: EventOrder,
+        cursor: Option<&str>
+    );
     proxy_method!(
         query,
         Vec<serde_json::Value>,
@@ -77,7 +88,34 @@ impl AwClient {
         pulsetime: f64
     );
     proxy_method!(delete_event, (), bucketname: &str, event_id: i64);
+    proxy_method!(
+        update_event,
+        Event,
+        bucketname: &str,
+        event_id: i64,
+        event: &Event
+    );
+    proxy_method!(
+        patch_event,
+        Event,
+        bucketname: &str,
+        event_id: i64,
+        patch: &EventPatch
+    );
     proxy_method!(get_event_count, i64, bucketname: &str);
+    proxy_method!(
+        get_event_changes,
+        EventChanges,
+        bucketname: &str,
+        token: Option<&str>,
+        limit: u64
+    );
+    proxy_method!(
+        apply_event_changes,
+        (),
+        bucketname: &str,
+        changes: &OriginChanges
+    );
     proxy_method!(get_info, aw_models::Info,);
 
     pub fn wait_for_start(&self) -> Result<(), Box<dyn Error>> {
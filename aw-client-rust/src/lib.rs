@@ -14,7 +14,9 @@ use serde_json::{json, Map};
 use std::net::TcpStream;
 use std::time::Duration;
 
-pub use aw_models::{Bucket, BucketMetadata, Event};
+pub use aw_models::{
+    Bucket, BucketMetadata, Event, EventChanges, EventOrder, EventPatch, OriginChanges,
+};
 
 pub struct AwClient {
     client: reqwest::Client,
@@ -29,6 +31,14 @@ impl std::fmt::Debug for AwClient {
     }
 }
 
+/// A page of events from get_events_page
+#[derive(Debug, Clone)]
+pub struct EventsPage {
+    pub events: Vec<Event>,
+    /// Pass this to get_events_page to get the next page, None if this is the last page
+    pub next_cursor: Option<String>,
+}
+
 fn get_hostname() -> String {
     return gethostname::gethostname().to_string_lossy().to_string();
 }
@@ -154,6 +164,48 @@ impl AwClient {
         self.client.get(url).send().await?.json().await
     }
 
+    /// Gets up to limit events in the given order, starting after the cursor if there is one
+    pub async fn get_events_page(
+        &self,
+        bucketname: &str,
+        start: Option<DateTime<Utc>>,
+        stop: Option<DateTime<Utc>>,
+        limit: u64,
+        order: EventOrder,
+        cursor: Option<&str>,
+    ) -> Result<EventsPage, reqwest::Error> {
+        let mut url = reqwest::Url::parse(
+            format!("{}/api/0/buckets/{}/events", self.baseurl, bucketname).as_str(),
+        )
+        .unwrap();
+
+        if let Some(s) = start {
+            url.query_pairs_mut()
+                .append_pair("start", s.to_rfc3339().as_str());
+        };
+        if let Some(s) = stop {
+            url.query_pairs_mut()
+                .append_pair("end", s.to_rfc3339().as_str());
+        };
+        url.query_pairs_mut()
+            .append_pair("limit", limit.to_string().as_str())
+            .append_pair("order", order.to_string().as_str());
+        if let Some(s) = cursor {
+            url.query_pairs_mut().append_pair("cursor", s);
+        };
+        let response = self.client.get(url).send().await?.error_for_status()?;
+        let next_cursor = response
+            .headers()
+            .get("X-Next-Cursor")
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string());
+        let events = response.json().await?;
+        Ok(EventsPage {
+            events,
+            next_cursor,
+        })
+    }
+
     pub async fn insert_event(
         &self,
         bucketname: &str,
@@ -202,6 +254,48 @@ impl AwClient {
         Ok(())
     }
 
+    /// Replaces the event with the given id, keeping the id
+    pub async fn update_event(
+        &self,
+        bucketname: &str,
+        event_id: i64,
+        event: &Event,
+    ) -> Result<Event, reqwest::Error> {
+        let url = format!(
+            "{}/api/0/buckets/{}/events/{}",
+            self.baseurl, bucketname, event_id
+        );
+        self.client
+            .put(url)
+            .json(event)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+
+    /// Changes only the fields of the event which are set in the patch
+    pub async fn patch_event(
+        &self,
+        bucketname: &str,
+        event_id: i64,
+        patch: &EventPatch,
+    ) -> Result<Event, reqwest::Error> {
+        let url = format!(
+            "{}/api/0/buckets/{}/events/{}",
+            self.baseurl, bucketname, event_id
+        );
+        self.client
+            .patch(url)
+            .json(patch)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+
     pub async fn get_event_count(&self, bucketname: &str) -> Result<i64, reqwest::Error> {
         let url = format!("{}/api/0/buckets/{}/events/count", self.baseurl, bucketname);
         let res = self
@@ -219,6 +313,48 @@ impl AwClient {
         Ok(count)
     }
 
+    /// Gets up to limit changes to the events of a bucket after the resume token, pass the token
+    /// of the returned changes to get the ones after them
+    pub async fn get_event_changes(
+        &self,
+        bucketname: &str,
+        token: Option<&str>,
+        limit: u64,
+    ) -> Result<EventChanges, reqwest::Error> {
+        let mut url = reqwest::Url::parse(
+            format!("{}/api/0/buckets/{}/changes", self.baseurl, bucketname).as_str(),
+        )
+        .unwrap();
+        url.query_pairs_mut()
+            .append_pair("limit", limit.to_string().as_str());
+        if let Some(s) = token {
+            url.query_pairs_mut().append_pair("token", s);
+        };
+        self.client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+
+    /// Inserts or replaces events by their origin and deletes the events with the deleted origins
+    pub async fn apply_event_changes(
+        &self,
+        bucketname: &str,
+        changes: &OriginChanges,
+    ) -> Result<(), reqwest::Error> {
+        let url = format!("{}/api/0/buckets/{}/changes", self.baseurl, bucketname);
+        self.client
+            .post(url)
+            .json(changes)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
     pub async fn get_info(&self) -> Result<aw_models::Info, reqwest::Error> {
         let url = format!("{}/api/0/info", self.baseurl);
         self.client.get(url).send().await?.json().await
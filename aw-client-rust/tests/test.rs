@@ -9,7 +9,7 @@ extern crate tokio_test;
 #[cfg(test)]
 mod test {
     use aw_client_rust::blocking::AwClient;
-    use aw_client_rust::Event;
+    use aw_client_rust::{Event, EventOrder, EventPatch};
     use chrono::{DateTime, Duration, Utc};
     use serde_json::Map;
     use std::sync::Mutex;
@@ -94,6 +94,7 @@ mod test {
             ),
             duration: Duration::seconds(0),
             data: Map::new(),
+            origin: None,
         };
         println!("{event:?}");
         client.insert_event(&bucketname, &event).unwrap();
@@ -126,6 +127,23 @@ RETURN = events;",
         let query_result = client.query(&query, vec![timeperiods]).unwrap();
         println!("Query result: {query_result:?}");
 
+        // Update
+        let patch = EventPatch {
+            data: Some(serde_json::from_str(r#"{"label": "test"}"#).unwrap()),
+            ..EventPatch::default()
+        };
+        let patched = client
+            .patch_event(&bucketname, events[0].id.unwrap(), &patch)
+            .unwrap();
+        assert_eq!(patched.id, events[0].id);
+        assert_eq!(patched.data["label"], "test");
+        let mut replaced = patched.clone();
+        replaced.duration = Duration::seconds(2);
+        let replaced = client
+            .update_event(&bucketname, events[0].id.unwrap(), &replaced)
+            .unwrap();
+        assert_eq!(replaced.duration, Duration::seconds(2));
+
         client
             .delete_event(&bucketname, events[0].id.unwrap())
             .unwrap();
@@ -133,6 +151,37 @@ RETURN = events;",
         let count = client.get_event_count(&bucketname).unwrap();
         assert_eq!(count, 0);
 
+        // Pagination
+        let events: Vec<Event> = (0..5)
+            .map(|i| Event {
+                timestamp: event.timestamp + Duration::seconds(i),
+                ..event.clone()
+            })
+            .collect();
+        client.insert_events(&bucketname, events.clone()).unwrap();
+        let mut paged = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let page = client
+                .get_events_page(
+                    &bucketname,
+                    None,
+                    None,
+                    2,
+                    EventOrder::Ascending,
+                    cursor.as_deref(),
+                )
+                .unwrap();
+            paged.extend(page.events);
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        let timestamps: Vec<_> = paged.iter().map(|e| e.timestamp).collect();
+        let expected: Vec<_> = events.iter().map(|e| e.timestamp).collect();
+        assert_eq!(timestamps, expected);
+
         client.delete_bucket(&bucketname).unwrap();
 
         shutdown_handler.notify();
@@ -0,0 +1,194 @@
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::Utc;
+use rusqlite::Connection;
+use serde_json::value::Value;
+
+use aw_models::DeletedEvent;
+use aw_models::Event;
+use aw_models::EventChanges;
+
+use super::DatastoreError;
+
+enum Change {
+    Event(Event),
+    Deleted(DeletedEvent),
+}
+
+/// Resume tokens are the sequence number of the last change they include
+fn parse_token(token: &str) -> Result<i64, DatastoreError> {
+    match token.parse::<i64>() {
+        Ok(seq) if seq >= 0 => Ok(seq),
+        _ => Err(DatastoreError::InvalidToken(format!(
+            "Invalid resume token '{token}'"
+        ))),
+    }
+}
+
+/// Gets up to limit changes to the events of a bucket after the resume token, the first changes
+/// if there is no token
+pub(crate) fn query_event_changes(
+    conn: &Connection,
+    bucketrow: i64,
+    token: Option<&str>,
+    limit: u64,
+) -> Result<EventChanges, DatastoreError> {
+    let since = match token {
+        Some(token) => parse_token(token)?,
+        None => 0,
+    };
+    // Sequence numbers are never reused, unless the database was replaced, by restoring a backup
+    // for example, in which case tokens given out before could skip changes
+    let last_seq: i64 = match conn.query_row(
+        "SELECT coalesce(max(seq), 0) FROM sqlite_sequence WHERE name = 'event_changes'",
+        [],
+        |row| row.get(0),
+    ) {
+        Ok(seq) => seq,
+        Err(err) => {
+            return Err(DatastoreError::InternalError(format!(
+                "Failed to query last event change: {err}"
+            )))
+        }
+    };
+    if since > last_seq {
+        return Err(DatastoreError::InvalidToken(format!(
+            "Resume token '{since}' is newer than the last change, it is from another database"
+        )));
+    }
+    // Events deleted after the token might have been pruned, the changes would be incomplete
+    if token.is_some() {
+        let pruned_seq: i64 = match conn.query_row(
+            "SELECT coalesce(max(seq), 0) FROM event_changes_pruned WHERE bucketrow = ?1",
+            [bucketrow],
+            |row| row.get(0),
+        ) {
+            Ok(seq) => seq,
+            Err(err) => {
+                return Err(DatastoreError::InternalError(format!(
+                    "Failed to query pruned event changes: {err}"
+                )))
+            }
+        };
+        if since < pruned_seq {
+            return Err(DatastoreError::InvalidToken(format!(
+                "Resume token '{since}' is older than the deleted events which are kept track of"
+            )));
+        }
+    }
+
+    let mut stmt = match conn.prepare(
+        "
+            SELECT event_changes.seq, event_changes.event_id, event_changes.deleted,
+                event_changes.origin, events.starttime, events.endtime, events.data,
+                events.origin
+            FROM event_changes
+            LEFT OUTER JOIN events ON events.id = event_changes.event_id
+            WHERE event_changes.bucketrow = ?1 AND event_changes.seq > ?2
+            ORDER BY event_changes.seq ASC
+            LIMIT ?3
+        ;",
+    ) {
+        Ok(stmt) => stmt,
+        Err(err) => {
+            return Err(DatastoreError::InternalError(format!(
+                "Failed to prepare get_event_changes SQL statement: {err}"
+            )))
+        }
+    };
+    let rows = match stmt.query_map([bucketrow, since, limit as i64], |row| {
+        let seq: i64 = row.get(0)?;
+        let id: i64 = row.get(1)?;
+        let deleted: bool = row.get(2)?;
+        if deleted {
+            let deleted_event = DeletedEvent {
+                id,
+                origin: row.get(3)?,
+            };
+            return Ok((seq, Change::Deleted(deleted_event)));
+        }
+        let starttime_ns: i64 = row.get(4)?;
+        let endtime_ns: i64 = row.get(5)?;
+        let data_str: String = row.get(6)?;
+        let data: serde_json::map::Map<String, Value> = serde_json::from_str(&data_str).unwrap();
+        let event = Event {
+            id: Some(id),
+            timestamp: DateTime::from_timestamp_nanos(starttime_ns),
+            duration: Duration::nanoseconds(endtime_ns - starttime_ns),
+            data,
+            origin: row.get(7)?,
+        };
+        Ok((seq, Change::Event(event)))
+    }) {
+        Ok(rows) => rows,
+        Err(err) => {
+            return Err(DatastoreError::InternalError(format!(
+                "Failed to map get_event_changes SQL statement: {err}"
+            )))
+        }
+    };
+
+    let mut changes = EventChanges::default();
+    let mut seq = since;
+    let mut count = 0;
+    for row in rows {
+        match row {
+            Ok((row_seq, change)) => {
+                seq = row_seq;
+                count += 1;
+                match change {
+                    Change::Event(event) => changes.events.push(event),
+                    Change::Deleted(deleted_event) => changes.deleted.push(deleted_event),
+                }
+            }
+            Err(err) => {
+                return Err(DatastoreError::InternalError(format!(
+                    "Failed to parse event change from SQLite: {err}"
+                )))
+            }
+        }
+    }
+    changes.token = seq.to_string();
+    changes.more = limit > 0 && count == limit;
+    Ok(changes)
+}
+
+/// Deletes the changes of the events deleted before the given time, and keeps track of the last
+/// of them in every bucket so that the tokens from before can be refused
+pub(crate) fn prune_deleted_changes(
+    conn: &Connection,
+    before: DateTime<Utc>,
+) -> Result<usize, DatastoreError> {
+    match conn.execute(
+        "
+            INSERT INTO event_changes_pruned(bucketrow, seq)
+            SELECT bucketrow, max(seq) FROM event_changes
+            WHERE deleted = 1 AND deleted_at <= ?1
+            GROUP BY bucketrow
+            ON CONFLICT(bucketrow) DO UPDATE SET seq = max(seq, excluded.seq)
+        ",
+        [before.timestamp()],
+    ) {
+        Ok(_) => (),
+        Err(err) => {
+            return Err(DatastoreError::InternalError(format!(
+                "Failed to update pruned event changes: {err}"
+            )))
+        }
+    };
+    match conn.execute(
+        "
+            DELETE FROM event_changes
+            WHERE deleted = 1 AND seq <= (
+                SELECT seq FROM event_changes_pruned
+                WHERE event_changes_pruned.bucketrow = event_changes.bucketrow
+            )
+        ",
+        [],
+    ) {
+        Ok(count) => Ok(count),
+        Err(err) => Err(DatastoreError::InternalError(format!(
+            "Failed to prune event changes: {err}"
+        ))),
+    }
+}
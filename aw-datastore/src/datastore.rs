@@ -11,13 +11,27 @@ use serde_json::value::Value;
 use aw_models::Bucket;
 use aw_models::BucketMetadata;
 use aw_models::Event;
+use aw_models::EventChanges;
+use aw_models::EventOrder;
+use aw_models::StoredQuery;
 
 use rusqlite::params;
+use rusqlite::params_from_iter;
 use rusqlite::types::ToSql;
-
+use rusqlite::types::Value as SqlValue;
+
+use super::changes::{prune_deleted_changes, query_event_changes};
+use super::eventquery::{EventCursor, EventPage, EventQuery};
+use super::filter::{
+    data_field_expr, data_field_path, data_index_name, validate_data_key, DataFilter,
+};
+use super::maintenance::MetadataRepair;
+use super::retention::{bucket_policies, merge_events};
+use super::revisions::BucketRevisions;
 use super::DatastoreError;
+use super::{RetentionAction, RetentionPolicy, RetentionResult};
 
-fn _get_db_version(conn: &Connection) -> i32 {
+pub(crate) fn _get_db_version(conn: &Connection) -> i32 {
     conn.pragma_query_value(None, "user_version", |row| row.get(0))
         .unwrap()
 }
@@ -29,8 +43,12 @@ fn _get_db_version(conn: &Connection) -> i32 {
  * 2: Added 'data' field to 'buckets' table
  * 3: see: https://github.com/ActivityWatch/aw-server-rust/pull/52
  * 4: Added 'key_value' table for storing key - value pairs
+ * 5: Added 'queries' table for storing named queries
+ * 6: Added 'data_indexes' table for the event data fields each bucket has indexed
+ * 7: Added 'origin' column to 'events' table for identifying synced events
+ * 8: Added 'event_changes' table with the last change of every event, kept up to date by triggers
  */
-static NEWEST_DB_VERSION: i32 = 4;
+pub(crate) static NEWEST_DB_VERSION: i32 = 8;
 
 fn _create_tables(conn: &Connection, version: i32) -> bool {
     let mut first_init = false;
@@ -52,6 +70,22 @@ fn _create_tables(conn: &Connection, version: i32) -> bool {
         _migrate_v3_to_v4(conn);
     }
 
+    if version < 5 {
+        _migrate_v4_to_v5(conn);
+    }
+
+    if version < 6 {
+        _migrate_v5_to_v6(conn);
+    }
+
+    if version < 7 {
+        _migrate_v6_to_v7(conn);
+    }
+
+    if version < 8 {
+        _migrate_v7_to_v8(conn);
+    }
+
     first_init
 }
 
@@ -169,8 +203,114 @@ fn _migrate_v3_to_v4(conn: &Connection) {
         .expect("Failed to update database version!");
 }
 
+fn _migrate_v4_to_v5(conn: &Connection) {
+    info!("Upgrading database to v5, adding table for stored queries");
+    conn.execute(
+        "CREATE TABLE queries (
+        name TEXT PRIMARY KEY,
+        query TEXT NOT NULL,
+        description TEXT NOT NULL DEFAULT '',
+        last_modified INTEGER NOT NULL
+    );",
+        [],
+    )
+    .expect("Failed to upgrade db and add stored queries table");
+
+    conn.pragma_update(None, "user_version", 5)
+        .expect("Failed to update database version!");
+}
+
+fn _migrate_v5_to_v6(conn: &Connection) {
+    info!("Upgrading database to v6, adding table for event data indexes");
+    conn.execute(
+        "CREATE TABLE data_indexes (
+        bucketrow INTEGER NOT NULL,
+        key TEXT NOT NULL,
+        PRIMARY KEY (bucketrow, key),
+        FOREIGN KEY (bucketrow) REFERENCES buckets(id)
+    );",
+        [],
+    )
+    .expect("Failed to upgrade db and add data indexes table");
+
+    conn.pragma_update(None, "user_version", 6)
+        .expect("Failed to update database version!");
+}
+
+fn _migrate_v6_to_v7(conn: &Connection) {
+    info!("Upgrading database to v7, adding origin column to events");
+    conn.execute("ALTER TABLE events ADD COLUMN origin TEXT", [])
+        .expect("Failed to upgrade db and add origin column to events");
+    // NULLs are distinct in unique indexes, so only events with an origin are constrained
+    conn.execute(
+        "CREATE UNIQUE INDEX events_origin_index ON events(bucketrow, origin)",
+        [],
+    )
+    .expect("Failed to create events_origin index");
+
+    conn.pragma_update(None, "user_version", 7)
+        .expect("Failed to update database version!");
+}
+
+fn _migrate_v7_to_v8(conn: &Connection) {
+    info!("Upgrading database to v8, adding table for tracking changes to events");
+    // The table only has a row per event and one per deleted event, which triggers keep up to
+    // date however the events are written. Inserts and deletes replace the row of the event,
+    // updates (like heartbeats) give it the next sequence number in place. The old row is deleted
+    // rather than replaced, as the conflict clause of the statement writing the event would
+    // override the one of the trigger.
+    //
+    // Deleted events are pruned after a while, event_changes_pruned has the last sequence number
+    // pruned from every bucket so that older resume tokens can be refused.
+    conn.execute_batch(
+        "
+        CREATE TABLE event_changes (
+            seq INTEGER PRIMARY KEY AUTOINCREMENT,
+            bucketrow INTEGER NOT NULL,
+            event_id INTEGER NOT NULL UNIQUE,
+            origin TEXT,
+            deleted INTEGER NOT NULL DEFAULT 0,
+            deleted_at INTEGER
+        );
+        CREATE INDEX event_changes_bucketrow_index ON event_changes(bucketrow, seq);
+        CREATE TABLE event_changes_pruned (
+            bucketrow INTEGER PRIMARY KEY,
+            seq INTEGER NOT NULL
+        );
+        CREATE TRIGGER events_insert_change AFTER INSERT ON events BEGIN
+            DELETE FROM event_changes WHERE event_id = NEW.id;
+            INSERT INTO event_changes(bucketrow, event_id) VALUES (NEW.bucketrow, NEW.id);
+        END;
+        CREATE TRIGGER events_update_change AFTER UPDATE ON events BEGIN
+            UPDATE sqlite_sequence SET seq = seq + 1 WHERE name = 'event_changes';
+            UPDATE event_changes
+            SET seq = (SELECT seq FROM sqlite_sequence WHERE name = 'event_changes')
+            WHERE event_id = NEW.id;
+        END;
+        CREATE TRIGGER events_delete_change AFTER DELETE ON events BEGIN
+            DELETE FROM event_changes WHERE event_id = OLD.id;
+            INSERT INTO event_changes(bucketrow, event_id, origin, deleted, deleted_at)
+            VALUES (OLD.bucketrow, OLD.id, OLD.origin, 1, CAST(strftime('%s', 'now') AS INTEGER));
+        END;
+        INSERT INTO event_changes(bucketrow, event_id) SELECT bucketrow, id FROM events ORDER BY id;
+        ",
+    )
+    .expect("Failed to upgrade db and add event changes table");
+
+    conn.pragma_update(None, "user_version", 8)
+        .expect("Failed to update database version!");
+}
+
+/// Bucket ID, keys and minutes of a merge retention policy
+type MergePolicy = (String, Vec<String>, u32);
+
 pub struct DatastoreInstance {
     buckets_cache: HashMap<String, Bucket>,
+    bucket_revisions: BucketRevisions,
+    // Time until which each merge retention policy has merged the events of a bucket, so the
+    // next merge only walks the events after it. Only kept in memory, so the first merge after
+    // the datastore is opened walks the whole bucket.
+    merged_until: HashMap<MergePolicy, DateTime<Utc>>,
     first_init: bool,
     pub db_version: i32,
 }
@@ -199,6 +339,8 @@ impl DatastoreInstance {
 
         let mut ds = DatastoreInstance {
             buckets_cache: HashMap::new(),
+            bucket_revisions: BucketRevisions::default(),
+            merged_until: HashMap::new(),
             first_init,
             db_version,
         };
@@ -357,6 +499,7 @@ impl DatastoreInstance {
                 bucket.events = None;
                 // Cache bucket
                 self.buckets_cache.insert(bucket.id.clone(), bucket.clone());
+                self.bucket_revisions.bump_all(&bucket.id);
                 // Insert events
                 if let Some(events) = events {
                     self.insert_events(conn, &bucket.id, events.take_inner())?;
@@ -392,10 +535,25 @@ impl DatastoreInstance {
             Ok(_) => (),
             Err(err) => return Err(DatastoreError::InternalError(err.to_string())),
         }
+        // The bucket is gone, so nothing needs to know which of its events were deleted
+        for table in ["event_changes", "event_changes_pruned"] {
+            match conn.execute(
+                &format!("DELETE FROM {table} WHERE bucketrow = ?1"),
+                [&bucket.bid],
+            ) {
+                Ok(_) => (),
+                Err(err) => return Err(DatastoreError::InternalError(err.to_string())),
+            }
+        }
+        for key in self.get_data_indexes(conn, bucket_id)? {
+            self.delete_data_index(conn, bucket_id, &key)?;
+        }
         // Delete bucket itself
         match conn.execute("DELETE FROM buckets WHERE id = ?1", [&bucket.bid]) {
             Ok(_) => {
                 self.buckets_cache.remove(bucket_id);
+                self.bucket_revisions.remove(bucket_id);
+                self.merged_until.retain(|(id, _, _), _| id != bucket_id);
                 Ok(())
             }
             Err(err) => match err {
@@ -422,6 +580,33 @@ impl DatastoreInstance {
         self.buckets_cache.clone()
     }
 
+    /// Current revision of every bucket, see BucketRevisions
+    pub fn get_bucket_revisions(&self) -> HashMap<String, u64> {
+        self.buckets_cache
+            .keys()
+            .map(|bucket_id| (bucket_id.clone(), self.bucket_revisions.revision(bucket_id)))
+            .collect()
+    }
+
+    /// Returns true if buckets have been created or deleted since the revisions snapshot was
+    /// taken, or if any of the buckets has had events modified within starttime..endtime
+    pub fn buckets_modified_since(
+        &self,
+        revisions: &HashMap<String, u64>,
+        starttime: DateTime<Utc>,
+        endtime: DateTime<Utc>,
+    ) -> bool {
+        if revisions.len() != self.buckets_cache.len()
+            || revisions
+                .keys()
+                .any(|bucket_id| !self.buckets_cache.contains_key(bucket_id))
+        {
+            return true;
+        }
+        self.bucket_revisions
+            .modified_since(revisions, starttime, endtime)
+    }
+
     pub fn insert_events(
         &mut self,
         conn: &Connection,
@@ -430,10 +615,15 @@ impl DatastoreInstance {
     ) -> Result<Vec<Event>, DatastoreError> {
         let mut bucket = self.get_bucket(bucket_id)?;
 
+        // Events with an origin which is already in the bucket update the existing event, which
+        // keeps its id
         let mut stmt = match conn.prepare(
             "
-                INSERT OR REPLACE INTO events(bucketrow, id, starttime, endtime, data)
-                VALUES (?1, ?2, ?3, ?4, ?5)",
+                INSERT OR REPLACE INTO events(bucketrow, id, starttime, endtime, data, origin)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                ON CONFLICT(bucketrow, origin) DO UPDATE
+                    SET starttime = excluded.starttime, endtime = excluded.endtime, data = excluded.data
+                RETURNING id",
         ) {
             Ok(stmt) => stmt,
             Err(err) => {
@@ -454,17 +644,28 @@ impl DatastoreInstance {
             };
             let endtime_nanos = starttime_nanos + duration_nanos;
             let data = serde_json::to_string(&event.data).unwrap();
-            let res = stmt.execute([
-                &bucket.bid.unwrap(),
-                &event.id as &dyn ToSql,
-                &starttime_nanos,
-                &endtime_nanos,
-                &data as &dyn ToSql,
-            ]);
+            let res = stmt.query_row(
+                [
+                    &bucket.bid.unwrap(),
+                    &event.id as &dyn ToSql,
+                    &starttime_nanos,
+                    &endtime_nanos,
+                    &data as &dyn ToSql,
+                    &event.origin,
+                ],
+                |row| row.get(0),
+            );
             match res {
-                Ok(_) => {
+                Ok(rowid) => {
                     self.update_endtime(&mut bucket, event);
-                    let rowid = conn.last_insert_rowid();
+                    self.unmerge(bucket_id, event.timestamp);
+                    if event.origin.is_some() {
+                        // The event might have replaced one in a different time range
+                        self.bucket_revisions.bump_all(bucket_id);
+                    } else {
+                        self.bucket_revisions
+                            .bump(bucket_id, starttime_nanos, endtime_nanos);
+                    }
                     event.id = Some(rowid);
                 }
                 Err(err) => {
@@ -478,7 +679,7 @@ impl DatastoreInstance {
     }
 
     pub fn delete_events_by_id(
-        &self,
+        &mut self,
         conn: &Connection,
         bucket_id: &str,
         event_ids: Vec<i64>,
@@ -507,10 +708,500 @@ impl DatastoreInstance {
                 }
             };
         }
+        // The time range of the deleted events is unknown without fetching them first
+        self.bucket_revisions.bump_all(bucket_id);
+        Ok(())
+    }
+
+    /// Deletes the events with the given origins, returning how many were deleted
+    pub fn delete_events_by_origin(
+        &mut self,
+        conn: &Connection,
+        bucket_id: &str,
+        origins: Vec<String>,
+    ) -> Result<i64, DatastoreError> {
+        let bucket = self.get_bucket(bucket_id)?;
+        let mut stmt = match conn.prepare(
+            "
+                DELETE FROM events
+                WHERE bucketrow = ?1 AND origin = ?2",
+        ) {
+            Ok(stmt) => stmt,
+            Err(err) => {
+                return Err(DatastoreError::InternalError(format!(
+                    "Failed to prepare delete_events_by_origin SQL statement: {err}"
+                )))
+            }
+        };
+        let mut deleted = 0;
+        for origin in origins {
+            match stmt.execute([&bucket.bid.unwrap() as &dyn ToSql, &origin]) {
+                Ok(n) => deleted += n as i64,
+                Err(err) => {
+                    return Err(DatastoreError::InternalError(format!(
+                        "Failed to delete event with origin {origin} in bucket {bucket_id}: {err:?}"
+                    )));
+                }
+            };
+        }
+        self.bucket_revisions.bump_all(bucket_id);
+        Ok(deleted)
+    }
+
+    /// Deletes the events which overlap the time range and match all of the filters, returning
+    /// how many were deleted
+    ///
+    /// With clip, the events which only partly overlap the range are cut off at it instead, and
+    /// counted as well.
+    pub fn delete_events(
+        &mut self,
+        conn: &Connection,
+        bucket_id: &str,
+        starttime_opt: Option<DateTime<Utc>>,
+        endtime_opt: Option<DateTime<Utc>>,
+        filters: &[DataFilter],
+        clip: bool,
+    ) -> Result<i64, DatastoreError> {
+        let mut bucket = self.get_bucket(bucket_id)?;
+        let starttime_filter_ns: i64 = match starttime_opt {
+            Some(dt) => dt.timestamp_nanos_opt().unwrap(),
+            None => 0,
+        };
+        let endtime_filter_ns: i64 = match endtime_opt {
+            Some(dt) => dt.timestamp_nanos_opt().unwrap(),
+            None => i64::MAX,
+        };
+        // Same as get_event_count, so that it tells how many events would be deleted
+        if starttime_filter_ns >= endtime_filter_ns {
+            return Ok(0);
+        }
+
+        let mut params = vec![
+            SqlValue::Integer(bucket.bid.unwrap()),
+            SqlValue::Integer(starttime_filter_ns),
+            SqlValue::Integer(endtime_filter_ns),
+        ];
+        let mut filter_conditions = String::new();
+        push_filter_conditions(&mut filter_conditions, filters, &mut params);
+        let where_clause = format!(
+            "
+                WHERE bucketrow = ?1
+                    AND endtime >= ?2
+                    AND starttime <= ?3{filter_conditions}"
+        );
+
+        // The time range of the events is needed for the bucket revisions
+        let res = conn.query_row(
+            &format!("SELECT count(*), min(starttime), max(endtime) FROM events {where_clause}"),
+            params_from_iter(params.iter()),
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, Option<i64>>(1)?,
+                    row.get::<_, Option<i64>>(2)?,
+                ))
+            },
+        );
+        let (count, start_ns, end_ns) = match res {
+            Ok((count, Some(start_ns), Some(end_ns))) => (count, start_ns, end_ns),
+            Ok(_) => return Ok(0),
+            Err(err) => {
+                return Err(DatastoreError::InternalError(format!(
+                    "Failed to find events to delete in bucket {bucket_id}: {err}"
+                )))
+            }
+        };
+        let statements = match clip {
+            false => vec![format!("DELETE FROM events {where_clause}")],
+            true => vec![
+                // The part after the range of the events which cover all of it is kept as a new
+                // event, the events are then cut off at the range before the rest is deleted
+                format!(
+                    "INSERT INTO events(bucketrow, starttime, endtime, data)
+                    SELECT bucketrow, ?3, endtime, data FROM events {where_clause}
+                        AND starttime < ?2 AND endtime > ?3"
+                ),
+                format!(
+                    "UPDATE events SET endtime = ?2 {where_clause}
+                        AND starttime < ?2 AND endtime > ?2"
+                ),
+                format!(
+                    "UPDATE events SET starttime = ?3 {where_clause}
+                        AND starttime < ?3 AND endtime > ?3"
+                ),
+                format!(
+                    "DELETE FROM events {where_clause}
+                        AND starttime >= ?2 AND endtime <= ?3"
+                ),
+            ],
+        };
+        for statement in statements {
+            if let Err(err) = conn.execute(&statement, params_from_iter(params.iter())) {
+                return Err(DatastoreError::InternalError(format!(
+                    "Failed to delete events in bucket {bucket_id}: {err}"
+                )));
+            }
+        }
+        if clip {
+            // The events split in two have a new part which starts at the end of the range
+            if let Some(endtime) = endtime_opt {
+                self.unmerge(bucket_id, endtime);
+            }
+        }
+        self.bucket_revisions.bump(bucket_id, start_ns, end_ns);
+        self.refresh_bucket_timerange(conn, &mut bucket)?;
+        Ok(count)
+    }
+
+    /// Enforces the retention policies on all buckets, or only reports what they would do if
+    /// dry_run is set
+    pub fn apply_retention(
+        &mut self,
+        conn: &Connection,
+        policies: &[RetentionPolicy],
+        now: DateTime<Utc>,
+        dry_run: bool,
+    ) -> Result<Vec<RetentionResult>, DatastoreError> {
+        let mut buckets: Vec<Bucket> = self.get_buckets().into_values().collect();
+        buckets.sort_by(|a, b| a.id.cmp(&b.id));
+        let mut results = Vec::new();
+        for bucket in buckets {
+            for policy in bucket_policies(&bucket, policies) {
+                let cutoff = policy.cutoff(now);
+                let events = match &policy.action {
+                    RetentionAction::Delete => match dry_run {
+                        true => {
+                            query_event_count(conn, bucket.bid.unwrap(), None, Some(cutoff), &[])?
+                        }
+                        false => {
+                            self.delete_events(conn, &bucket.id, None, Some(cutoff), &[], false)?
+                        }
+                    },
+                    RetentionAction::StripKeys { keys } => {
+                        self.strip_data_keys(conn, &bucket.id, cutoff, keys, dry_run)?
+                    }
+                    RetentionAction::Merge { keys, minutes } => {
+                        self.merge_old_events(conn, &bucket.id, cutoff, keys, *minutes, dry_run)?
+                    }
+                };
+                results.push(RetentionResult {
+                    bucket_id: bucket.id.clone(),
+                    policy,
+                    cutoff,
+                    events,
+                });
+            }
+        }
+        Ok(results)
+    }
+
+    /// Removes the keys from the data of the events which started before the cutoff
+    fn strip_data_keys(
+        &mut self,
+        conn: &Connection,
+        bucket_id: &str,
+        cutoff: DateTime<Utc>,
+        keys: &[String],
+        dry_run: bool,
+    ) -> Result<i64, DatastoreError> {
+        let bucket = self.get_bucket(bucket_id)?;
+        let paths: Vec<String> = keys.iter().map(|key| data_field_path(key)).collect();
+        let has_keys: Vec<String> = paths
+            .iter()
+            .map(|path| format!("json_type(data, {path}) IS NOT NULL"))
+            .collect();
+        let where_clause = format!(
+            "WHERE bucketrow = ?1 AND starttime <= ?2 AND ({})",
+            has_keys.join(" OR ")
+        );
+        let params = [bucket.bid.unwrap(), cutoff.timestamp_nanos_opt().unwrap()];
+
+        let res = conn.query_row(
+            &format!("SELECT count(*), min(starttime), max(endtime) FROM events {where_clause}"),
+            params,
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, Option<i64>>(1)?,
+                    row.get::<_, Option<i64>>(2)?,
+                ))
+            },
+        );
+        let (count, start_ns, end_ns) = match res {
+            Ok((count, Some(start_ns), Some(end_ns))) => (count, start_ns, end_ns),
+            Ok(_) => return Ok(0),
+            Err(err) => {
+                return Err(DatastoreError::InternalError(format!(
+                    "Failed to find events to strip in bucket {bucket_id}: {err}"
+                )))
+            }
+        };
+        if dry_run {
+            return Ok(count);
+        }
+        if let Err(err) = conn.execute(
+            &format!(
+                "UPDATE events SET data = json_remove(data, {}) {where_clause}",
+                paths.join(", ")
+            ),
+            params,
+        ) {
+            return Err(DatastoreError::InternalError(format!(
+                "Failed to strip events in bucket {bucket_id}: {err}"
+            )));
+        }
+        self.bucket_revisions.bump(bucket_id, start_ns, end_ns);
+        Ok(count)
+    }
+
+    /// Merges the events which started before the cutoff within each period, returning how
+    /// many events were replaced by merged ones
+    fn merge_old_events(
+        &mut self,
+        conn: &Connection,
+        bucket_id: &str,
+        cutoff: DateTime<Utc>,
+        keys: &[String],
+        minutes: u32,
+        dry_run: bool,
+    ) -> Result<i64, DatastoreError> {
+        let mut bucket = self.get_bucket(bucket_id)?;
+        let period_ns = minutes as i64 * 60 * 1_000_000_000;
+        let period_of = |event: &Event| {
+            event
+                .timestamp
+                .timestamp_nanos_opt()
+                .unwrap()
+                .div_euclid(period_ns)
+        };
+        // The periods before the one the last merge stopped in are merged already
+        let policy: MergePolicy = (bucket_id.to_string(), keys.to_vec(), minutes);
+        let from = self.merged_until.get(&policy).map(|until| {
+            let until_ns = until.timestamp_nanos_opt().unwrap();
+            DateTime::from_timestamp_nanos(until_ns.div_euclid(period_ns) * period_ns)
+        });
+        // Walk the events oldest first, a period is merged once all of its events have been
+        // seen. Merged events start at the first event of their period, which is always before
+        // the cursor, so they are never walked over again. The walk starts from a cursor rather
+        // than a start time, which would cut off the events of an earlier period that only end
+        // after it starts, instead of skipping them.
+        let mut query = EventQuery {
+            limit: Some(1000),
+            order: EventOrder::Ascending,
+            cursor: from.map(|from| EventCursor {
+                starttime_ns: from.timestamp_nanos_opt().unwrap(),
+                id: i64::MIN,
+            }),
+            ..EventQuery::default()
+        };
+        let mut period: Vec<Event> = Vec::new();
+        let mut merged_count = 0;
+        'pages: loop {
+            let page = query_events(conn, bucket.bid.unwrap(), bucket_id, &query)?;
+            for event in page.events {
+                if event.timestamp > cutoff {
+                    break 'pages;
+                }
+                if period.first().map(period_of) != Some(period_of(&event)) {
+                    merged_count += self.merge_period(conn, &bucket, &period, keys, dry_run)?;
+                    period.clear();
+                }
+                period.push(event);
+            }
+            match page.next_cursor {
+                Some(cursor) => query.cursor = Some(cursor),
+                None => break,
+            }
+        }
+        merged_count += self.merge_period(conn, &bucket, &period, keys, dry_run)?;
+        if !dry_run {
+            if merged_count > 0 {
+                self.refresh_bucket_timerange(conn, &mut bucket)?;
+            }
+            self.merged_until.insert(policy, cutoff);
+        }
+        Ok(merged_count)
+    }
+
+    fn merge_period(
+        &mut self,
+        conn: &Connection,
+        bucket: &Bucket,
+        events: &[Event],
+        keys: &[String],
+        dry_run: bool,
+    ) -> Result<i64, DatastoreError> {
+        let mergeable: Vec<Event> = events
+            .iter()
+            .filter(|e| keys.iter().all(|key| e.data.contains_key(key)))
+            .cloned()
+            .collect();
+        let merged = match merge_events(&mergeable, keys) {
+            Some(merged) => merged,
+            None => return Ok(0),
+        };
+        if dry_run {
+            return Ok(mergeable.len() as i64);
+        }
+        let mut stmt = match conn.prepare("DELETE FROM events WHERE bucketrow = ?1 AND id = ?2") {
+            Ok(stmt) => stmt,
+            Err(err) => {
+                return Err(DatastoreError::InternalError(format!(
+                    "Failed to prepare merge_period SQL statement: {err}"
+                )))
+            }
+        };
+        for event in &mergeable {
+            if let Err(err) = stmt.execute([bucket.bid.unwrap(), event.id.unwrap()]) {
+                return Err(DatastoreError::InternalError(format!(
+                    "Failed to delete merged event in bucket {}: {err}",
+                    bucket.id
+                )));
+            }
+        }
+        let start_ns = mergeable[0].timestamp.timestamp_nanos_opt().unwrap();
+        let end_ns = mergeable
+            .iter()
+            .map(|e| e.calculate_endtime().timestamp_nanos_opt().unwrap())
+            .max()
+            .unwrap();
+        self.bucket_revisions.bump(&bucket.id, start_ns, end_ns);
+        self.insert_events(conn, &bucket.id, merged)?;
+        Ok(mergeable.len() as i64)
+    }
+
+    /// Replaces the timestamp, duration and data of an existing event, keeping its id
+    pub fn update_event(
+        &mut self,
+        conn: &Connection,
+        bucket_id: &str,
+        event_id: i64,
+        event: &Event,
+    ) -> Result<Event, DatastoreError> {
+        let mut bucket = self.get_bucket(bucket_id)?;
+        let old_event = query_event(conn, bucket.bid.unwrap(), event_id)?;
+
+        let mut stmt = match conn.prepare(
+            "
+                UPDATE events
+                SET starttime = ?3, endtime = ?4, data = ?5
+                WHERE bucketrow = ?1 AND id = ?2",
+        ) {
+            Ok(stmt) => stmt,
+            Err(err) => {
+                return Err(DatastoreError::InternalError(format!(
+                    "Failed to prepare update_event SQL statement: {err}"
+                )))
+            }
+        };
+        let starttime_nanos = event.timestamp.timestamp_nanos_opt().unwrap();
+        let duration_nanos = match event.duration.num_nanoseconds() {
+            Some(nanos) => nanos,
+            None => {
+                return Err(DatastoreError::InternalError(
+                    "Failed to convert duration to nanoseconds".to_string(),
+                ))
+            }
+        };
+        let endtime_nanos = starttime_nanos + duration_nanos;
+        let data = serde_json::to_string(&event.data).unwrap();
+        if let Err(err) = stmt.execute([
+            &bucket.bid.unwrap(),
+            &event_id,
+            &starttime_nanos,
+            &endtime_nanos,
+            &data as &dyn ToSql,
+        ]) {
+            return Err(DatastoreError::InternalError(format!(
+                "Failed to update event with id {event_id} in bucket {bucket_id}: {err}"
+            )));
+        }
+
+        // Both where the event was and where it is now have changed
+        self.bucket_revisions.bump(
+            bucket_id,
+            old_event.timestamp.timestamp_nanos_opt().unwrap(),
+            old_event.calculate_endtime().timestamp_nanos_opt().unwrap(),
+        );
+        self.bucket_revisions
+            .bump(bucket_id, starttime_nanos, endtime_nanos);
+        self.unmerge(bucket_id, event.timestamp);
+        // update_endtime can only widen the range of the bucket, so if the event was at the
+        // start or end of the bucket the range has to be looked up again
+        if bucket.metadata.start == Some(old_event.timestamp)
+            || bucket.metadata.end == Some(old_event.calculate_endtime())
+        {
+            self.refresh_bucket_timerange(conn, &mut bucket)?;
+        }
+        self.update_endtime(&mut bucket, event);
+
+        let mut updated_event = event.clone();
+        updated_event.id = Some(event_id);
+        Ok(updated_event)
+    }
+
+    /// Recomputes the start and end of every bucket from its events, returns the buckets whose
+    /// start or end was wrong
+    pub fn repair_bucket_metadata(
+        &mut self,
+        conn: &Connection,
+    ) -> Result<Vec<MetadataRepair>, DatastoreError> {
+        let mut buckets: Vec<Bucket> = self.buckets_cache.values().cloned().collect();
+        buckets.sort_by(|a, b| a.id.cmp(&b.id));
+        let mut repairs = Vec::new();
+        for mut bucket in buckets {
+            let old = bucket.metadata.clone();
+            self.refresh_bucket_timerange(conn, &mut bucket)?;
+            if bucket.metadata.start != old.start || bucket.metadata.end != old.end {
+                repairs.push(MetadataRepair {
+                    bucket_id: bucket.id.clone(),
+                    old_start: old.start,
+                    old_end: old.end,
+                    new_start: bucket.metadata.start,
+                    new_end: bucket.metadata.end,
+                });
+            }
+        }
+        Ok(repairs)
+    }
+
+    fn refresh_bucket_timerange(
+        &mut self,
+        conn: &Connection,
+        bucket: &mut Bucket,
+    ) -> Result<(), DatastoreError> {
+        let res = conn.query_row(
+            "SELECT min(starttime), max(endtime) FROM events WHERE bucketrow = ?1",
+            [&bucket.bid.unwrap()],
+            |row| Ok((row.get::<_, Option<i64>>(0)?, row.get::<_, Option<i64>>(1)?)),
+        );
+        let (start_ns, end_ns) = match res {
+            Ok(range) => range,
+            Err(err) => {
+                return Err(DatastoreError::InternalError(format!(
+                    "Failed to get time range of bucket {}: {err}",
+                    bucket.id
+                )))
+            }
+        };
+        let to_datetime = |ns: i64| {
+            DateTime::from_timestamp(ns / 1_000_000_000, (ns % 1_000_000_000) as u32).unwrap()
+        };
+        bucket.metadata.start = start_ns.map(to_datetime);
+        bucket.metadata.end = end_ns.map(to_datetime);
+        self.buckets_cache.insert(bucket.id.clone(), bucket.clone());
         Ok(())
     }
 
-    // TODO: Function for deleting events by timerange with limit
+    /// Makes the merge retention policies of the bucket walk the events from the timestamp
+    /// again, as an event was written there after they were merged
+    fn unmerge(&mut self, bucket_id: &str, timestamp: DateTime<Utc>) {
+        for ((id, _, _), until) in self.merged_until.iter_mut() {
+            if id == bucket_id && timestamp < *until {
+                *until = timestamp;
+            }
+        }
+    }
 
     fn update_endtime(&mut self, bucket: &mut Bucket, event: &Event) {
         let mut update = false;
@@ -587,7 +1278,12 @@ impl DatastoreInstance {
             &endtime_nanos,
             &data as &dyn ToSql,
         ]) {
-            Ok(_) => self.update_endtime(&mut bucket, event),
+            Ok(_) => {
+                self.update_endtime(&mut bucket, event);
+                self.unmerge(bucket_id, event.timestamp);
+                self.bucket_revisions
+                    .bump(bucket_id, starttime_nanos, endtime_nanos);
+            }
             Err(err) => {
                 return Err(DatastoreError::InternalError(format!(
                     "Failed to execute replace_last_event SQL statement: {err}"
@@ -641,60 +1337,123 @@ impl DatastoreInstance {
         Ok(inserted_heartbeat)
     }
 
-    pub fn get_event(
-        &mut self,
+    /// Returns the event data fields which are indexed for the bucket
+    pub fn get_data_indexes(
+        &self,
         conn: &Connection,
         bucket_id: &str,
-        event_id: i64,
-    ) -> Result<Event, DatastoreError> {
+    ) -> Result<Vec<String>, DatastoreError> {
         let bucket = self.get_bucket(bucket_id)?;
+        let mut stmt =
+            match conn.prepare("SELECT key FROM data_indexes WHERE bucketrow = ?1 ORDER BY key") {
+                Ok(stmt) => stmt,
+                Err(err) => {
+                    return Err(DatastoreError::InternalError(format!(
+                        "Failed to prepare get_data_indexes SQL statement: {err}"
+                    )))
+                }
+            };
+        let keys = match stmt.query_map([&bucket.bid.unwrap()], |row| row.get(0)) {
+            Ok(rows) => rows.collect::<Result<Vec<String>, rusqlite::Error>>(),
+            Err(err) => Err(err),
+        };
+        match keys {
+            Ok(keys) => Ok(keys),
+            Err(err) => Err(DatastoreError::InternalError(format!(
+                "Failed to query get_data_indexes SQL statement: {err}"
+            ))),
+        }
+    }
 
-        let mut stmt = match conn.prepare(
-            "
-                SELECT id, starttime, endtime, data
-                FROM events
-                WHERE bucketrow = ?1
-                    AND id = ?2
-                LIMIT 1
-            ;",
+    /// Indexes an event data field for the bucket, which speeds up filtering its events on
+    /// the field
+    ///
+    /// The index itself is shared by all buckets indexing the same field.
+    pub fn create_data_index(
+        &self,
+        conn: &Connection,
+        bucket_id: &str,
+        key: &str,
+    ) -> Result<(), DatastoreError> {
+        validate_data_key(key)?;
+        let bucket = self.get_bucket(bucket_id)?;
+        if let Err(err) = conn.execute(
+            "INSERT OR IGNORE INTO data_indexes(bucketrow, key) VALUES (?1, ?2)",
+            params![bucket.bid.unwrap(), key],
         ) {
-            Ok(stmt) => stmt,
+            return Err(DatastoreError::InternalError(format!(
+                "Failed to insert data index: {err}"
+            )));
+        }
+        match conn.execute(
+            &format!(
+                "CREATE INDEX IF NOT EXISTS {} ON events(bucketrow, {})",
+                data_index_name(key),
+                data_field_expr(key)
+            ),
+            [],
+        ) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(DatastoreError::InternalError(format!(
+                "Failed to create index on data field '{key}': {err}"
+            ))),
+        }
+    }
+
+    /// Removes an event data field index for the bucket, the index itself is dropped once no
+    /// bucket uses it
+    pub fn delete_data_index(
+        &self,
+        conn: &Connection,
+        bucket_id: &str,
+        key: &str,
+    ) -> Result<(), DatastoreError> {
+        let bucket = self.get_bucket(bucket_id)?;
+        match conn.execute(
+            "DELETE FROM data_indexes WHERE bucketrow = ?1 AND key = ?2",
+            params![bucket.bid.unwrap(), key],
+        ) {
+            Ok(0) => return Err(DatastoreError::NoSuchKey(key.to_string())),
+            Ok(_) => (),
             Err(err) => {
                 return Err(DatastoreError::InternalError(format!(
-                    "Failed to prepare get_event SQL statement: {err}"
+                    "Failed to delete data index: {err}"
                 )))
             }
-        };
-
-        // TODO: Refactor to share row-parsing logic with get_events
-        let row = match stmt.query_row([&bucket.bid.unwrap(), &event_id], |row| {
-            let id = row.get(0)?;
-            let starttime_ns: i64 = row.get(1)?;
-            let endtime_ns: i64 = row.get(2)?;
-            let data_str: String = row.get(3)?;
-
-            let time_seconds: i64 = starttime_ns / 1_000_000_000;
-            let time_subnanos: u32 = (starttime_ns % 1_000_000_000) as u32;
-            let duration_ns = endtime_ns - starttime_ns;
-            let data: serde_json::map::Map<String, Value> =
-                serde_json::from_str(&data_str).unwrap();
-
-            Ok(Event {
-                id: Some(id),
-                timestamp: DateTime::from_timestamp(time_seconds, time_subnanos).unwrap(),
-                duration: Duration::nanoseconds(duration_ns),
-                data,
-            })
-        }) {
-            Ok(rows) => rows,
+        }
+        let still_used: bool = match conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM data_indexes WHERE key = ?1)",
+            [key],
+            |row| row.get(0),
+        ) {
+            Ok(still_used) => still_used,
             Err(err) => {
                 return Err(DatastoreError::InternalError(format!(
-                    "Failed to map get_event SQL statement: {err}"
+                    "Failed to query data indexes: {err}"
                 )))
             }
         };
+        if !still_used {
+            if let Err(err) = conn.execute(
+                &format!("DROP INDEX IF EXISTS {}", data_index_name(key)),
+                [],
+            ) {
+                return Err(DatastoreError::InternalError(format!(
+                    "Failed to drop index on data field '{key}': {err}"
+                )));
+            }
+        }
+        Ok(())
+    }
 
-        Ok(row)
+    pub fn get_event(
+        &mut self,
+        conn: &Connection,
+        bucket_id: &str,
+        event_id: i64,
+    ) -> Result<Event, DatastoreError> {
+        let bucket = self.get_bucket(bucket_id)?;
+        query_event(conn, bucket.bid.unwrap(), event_id)
     }
 
     pub fn get_events(
@@ -705,95 +1464,46 @@ impl DatastoreInstance {
         endtime_opt: Option<DateTime<Utc>>,
         limit_opt: Option<u64>,
     ) -> Result<Vec<Event>, DatastoreError> {
-        let bucket = self.get_bucket(bucket_id)?;
-
-        let mut list = Vec::new();
-
-        let starttime_filter_ns: i64 = match starttime_opt {
-            Some(dt) => dt.timestamp_nanos_opt().unwrap(),
-            None => 0,
-        };
-        let endtime_filter_ns: i64 = match endtime_opt {
-            Some(dt) => dt.timestamp_nanos_opt().unwrap(),
-            None => std::i64::MAX,
-        };
-        if starttime_filter_ns > endtime_filter_ns {
-            warn!("Starttime in event query was lower than endtime!");
-            return Ok(list);
-        }
-        let limit = match limit_opt {
-            Some(l) => l as i64,
-            None => -1,
-        };
-
-        let mut stmt = match conn.prepare(
-            "
-                SELECT id, starttime, endtime, data
-                FROM events
-                WHERE bucketrow = ?1
-                    AND endtime >= ?2
-                    AND starttime <= ?3
-                ORDER BY starttime DESC
-                LIMIT ?4
-            ;",
-        ) {
-            Ok(stmt) => stmt,
-            Err(err) => {
-                return Err(DatastoreError::InternalError(format!(
-                    "Failed to prepare get_events SQL statement: {err}"
-                )))
-            }
+        let query = EventQuery {
+            starttime: starttime_opt,
+            endtime: endtime_opt,
+            limit: limit_opt,
+            ..EventQuery::default()
         };
+        Ok(self.get_events_page(conn, bucket_id, &query)?.events)
+    }
 
-        let rows = match stmt.query_map(
-            [
-                &bucket.bid.unwrap(),
-                &starttime_filter_ns,
-                &endtime_filter_ns,
-                &limit,
-            ],
-            |row| {
-                let id = row.get(0)?;
-                let mut starttime_ns: i64 = row.get(1)?;
-                let mut endtime_ns: i64 = row.get(2)?;
-                let data_str: String = row.get(3)?;
+    pub fn get_events_page(
+        &mut self,
+        conn: &Connection,
+        bucket_id: &str,
+        query: &EventQuery,
+    ) -> Result<EventPage, DatastoreError> {
+        let bucket = self.get_bucket(bucket_id)?;
+        query_events(conn, bucket.bid.unwrap(), bucket_id, query)
+    }
 
-                if starttime_ns < starttime_filter_ns {
-                    starttime_ns = starttime_filter_ns
-                }
-                if endtime_ns > endtime_filter_ns {
-                    endtime_ns = endtime_filter_ns
-                }
-                let duration_ns = endtime_ns - starttime_ns;
-
-                let time_seconds: i64 = starttime_ns / 1_000_000_000;
-                let time_subnanos: u32 = (starttime_ns % 1_000_000_000) as u32;
-                let data: serde_json::map::Map<String, Value> =
-                    serde_json::from_str(&data_str).unwrap();
-
-                Ok(Event {
-                    id: Some(id),
-                    timestamp: DateTime::from_timestamp(time_seconds, time_subnanos).unwrap(),
-                    duration: Duration::nanoseconds(duration_ns),
-                    data,
-                })
-            },
-        ) {
-            Ok(rows) => rows,
-            Err(err) => {
-                return Err(DatastoreError::InternalError(format!(
-                    "Failed to map get_events SQL statement: {err}"
-                )))
-            }
-        };
-        for row in rows {
-            match row {
-                Ok(event) => list.push(event),
-                Err(err) => warn!("Corrupt event in bucket {}: {}", bucket_id, err),
-            };
-        }
+    pub fn get_event_changes(
+        &self,
+        conn: &Connection,
+        bucket_id: &str,
+        token: Option<&str>,
+        limit: u64,
+    ) -> Result<EventChanges, DatastoreError> {
+        let bucket = self.get_bucket(bucket_id)?;
+        query_event_changes(conn, bucket.bid.unwrap(), token, limit)
+    }
 
-        Ok(list)
+    /// Forgets the events deleted before the given time, returns how many there were
+    ///
+    /// Resume tokens given out before the last of them was deleted are refused from then on, as
+    /// the changes after them would be incomplete.
+    pub fn prune_event_changes(
+        &self,
+        conn: &Connection,
+        before: DateTime<Utc>,
+    ) -> Result<usize, DatastoreError> {
+        prune_deleted_changes(conn, before)
     }
 
     pub fn get_event_count(
@@ -802,54 +1512,16 @@ impl DatastoreInstance {
         bucket_id: &str,
         starttime_opt: Option<DateTime<Utc>>,
         endtime_opt: Option<DateTime<Utc>>,
+        filters: &[DataFilter],
     ) -> Result<i64, DatastoreError> {
         let bucket = self.get_bucket(bucket_id)?;
-
-        let starttime_filter_ns: i64 = match starttime_opt {
-            Some(dt) => dt.timestamp_nanos_opt().unwrap(),
-            None => 0,
-        };
-        let endtime_filter_ns: i64 = match endtime_opt {
-            Some(dt) => dt.timestamp_nanos_opt().unwrap(),
-            None => std::i64::MAX,
-        };
-        if starttime_filter_ns >= endtime_filter_ns {
-            warn!("Endtime in event query was same or lower than starttime!");
-            return Ok(0);
-        }
-
-        let mut stmt = match conn.prepare(
-            "
-            SELECT count(*) FROM events
-            WHERE bucketrow = ?1
-                AND endtime >= ?2
-                AND starttime <= ?3",
-        ) {
-            Ok(stmt) => stmt,
-            Err(err) => {
-                return Err(DatastoreError::InternalError(format!(
-                    "Failed to prepare get_event_count SQL statement: {err}",
-                )))
-            }
-        };
-
-        let count = match stmt.query_row(
-            [
-                &bucket.bid.unwrap(),
-                &starttime_filter_ns,
-                &endtime_filter_ns,
-            ],
-            |row| row.get(0),
-        ) {
-            Ok(count) => count,
-            Err(err) => {
-                return Err(DatastoreError::InternalError(format!(
-                    "Failed to query get_event_count SQL statement: {err}"
-                )))
-            }
-        };
-
-        Ok(count)
+        query_event_count(
+            conn,
+            bucket.bid.unwrap(),
+            starttime_opt,
+            endtime_opt,
+            filters,
+        )
     }
 
     pub fn insert_key_value(
@@ -884,70 +1556,469 @@ impl DatastoreInstance {
     }
 
     pub fn get_key_value(&self, conn: &Connection, key: &str) -> Result<String, DatastoreError> {
+        query_key_value(conn, key)
+    }
+
+    pub fn get_key_values(
+        &self,
+        conn: &Connection,
+        pattern: &str,
+    ) -> Result<HashMap<String, String>, DatastoreError> {
+        query_key_values(conn, pattern)
+    }
+
+    pub fn insert_stored_query(
+        &self,
+        conn: &Connection,
+        query: &StoredQuery,
+    ) -> Result<(), DatastoreError> {
         let mut stmt = match conn.prepare(
             "
-                SELECT * FROM key_value WHERE KEY = ?1",
+                INSERT OR REPLACE INTO queries(name, query, description, last_modified)
+                VALUES (?1, ?2, ?3, ?4)",
         ) {
             Ok(stmt) => stmt,
             Err(err) => {
                 return Err(DatastoreError::InternalError(format!(
-                    "Failed to prepare get_value SQL statement: {err}"
+                    "Failed to prepare insert_stored_query SQL statement: {err}"
                 )))
             }
         };
+        let code = serde_json::to_string(&query.query).unwrap();
+        let timestamp_nanos = Utc::now().timestamp_nanos_opt().unwrap();
+        match stmt.execute(params![
+            query.name,
+            code,
+            query.description,
+            timestamp_nanos
+        ]) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(DatastoreError::InternalError(format!(
+                "Failed to insert stored query '{}': {err}",
+                query.name
+            ))),
+        }
+    }
 
-        match stmt.query_row([key], |row| row.get(1)) {
-            Ok(result) => Ok(result),
-            Err(err) => match err {
-                rusqlite::Error::QueryReturnedNoRows => {
-                    Err(DatastoreError::NoSuchKey(key.to_string()))
-                }
-                _ => Err(DatastoreError::InternalError(format!(
-                    "Get value query failed for key {key}"
-                ))),
-            },
+    pub fn delete_stored_query(&self, conn: &Connection, name: &str) -> Result<(), DatastoreError> {
+        match conn.execute("DELETE FROM queries WHERE name = ?1", [name]) {
+            Ok(0) => Err(DatastoreError::NoSuchQuery(name.to_string())),
+            Ok(_) => Ok(()),
+            Err(err) => Err(DatastoreError::InternalError(format!(
+                "Failed to delete stored query '{name}': {err}"
+            ))),
         }
     }
 
-    pub fn get_key_values(
+    pub fn get_stored_query(
         &self,
         conn: &Connection,
-        pattern: &str,
-    ) -> Result<HashMap<String, String>, DatastoreError> {
-        let mut stmt = match conn.prepare("SELECT key, value FROM key_value WHERE key LIKE ?") {
+        name: &str,
+    ) -> Result<StoredQuery, DatastoreError> {
+        match self
+            .get_stored_queries_where(conn, "WHERE name = ?1", [name])?
+            .pop()
+        {
+            Some(query) => Ok(query),
+            None => Err(DatastoreError::NoSuchQuery(name.to_string())),
+        }
+    }
+
+    pub fn get_stored_queries(
+        &self,
+        conn: &Connection,
+    ) -> Result<HashMap<String, StoredQuery>, DatastoreError> {
+        let queries = self.get_stored_queries_where(conn, "", [])?;
+        Ok(queries
+            .into_iter()
+            .map(|query| (query.name.clone(), query))
+            .collect())
+    }
+
+    fn get_stored_queries_where<P: rusqlite::Params>(
+        &self,
+        conn: &Connection,
+        where_clause: &str,
+        params: P,
+    ) -> Result<Vec<StoredQuery>, DatastoreError> {
+        let mut stmt = match conn.prepare(&format!(
+            "SELECT name, query, description, last_modified FROM queries {where_clause}"
+        )) {
             Ok(stmt) => stmt,
             Err(err) => {
                 return Err(DatastoreError::InternalError(format!(
-                    "Failed to prepare get_value SQL statement: {err}"
+                    "Failed to prepare get_stored_queries SQL statement: {err}"
                 )))
             }
         };
+        let rows = match stmt.query_map(params, |row| {
+            let code: String = row.get(1)?;
+            let query: Vec<String> = match serde_json::from_str(&code) {
+                Ok(query) => query,
+                Err(e) => {
+                    return Err(rusqlite::Error::InvalidColumnName(format!(
+                        "Failed to parse stored query to JSON: {e:?}"
+                    )))
+                }
+            };
+            let last_modified_ns: i64 = row.get(3)?;
+            Ok(StoredQuery {
+                name: row.get(0)?,
+                query,
+                description: row.get(2)?,
+                last_modified: Some(DateTime::from_timestamp_nanos(last_modified_ns)),
+            })
+        }) {
+            Ok(rows) => rows,
+            Err(err) => {
+                return Err(DatastoreError::InternalError(format!(
+                    "Failed to query get_stored_queries SQL statement: {err}"
+                )))
+            }
+        };
+        let mut queries = Vec::new();
+        for row in rows {
+            match row {
+                Ok(query) => queries.push(query),
+                Err(err) => {
+                    return Err(DatastoreError::InternalError(format!(
+                        "Failed to parse stored query from SQLite: {err}"
+                    )))
+                }
+            }
+        }
+        Ok(queries)
+    }
+}
 
-        let mut output = HashMap::<String, String>::new();
-        // Rusqlite's get wants index and item type as parameters.
-        let result = stmt.query_map([pattern], |row| {
-            Ok((row.get::<usize, String>(0)?, row.get::<usize, String>(1)?))
-        });
-        match result {
-            Ok(settings) => {
-                for row in settings {
-                    // Unwrap to String or panic on SQL row if type is invalid. Can't happen with a
-                    // properly initialized table.
-                    let (key, value) = row.unwrap();
-                    // Only return keys starting with "settings.".
-                    if !key.starts_with("settings.") {
-                        continue;
-                    }
-                    output.insert(key, value);
+/// Looks up the row id of a bucket, for connections which are not owned by a
+/// DatastoreInstance and therefore have no bucket cache
+pub(crate) fn query_bucket_row(conn: &Connection, bucket_id: &str) -> Result<i64, DatastoreError> {
+    match conn.query_row(
+        "SELECT id FROM buckets WHERE name = ?1",
+        [bucket_id],
+        |row| row.get(0),
+    ) {
+        Ok(bucketrow) => Ok(bucketrow),
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            Err(DatastoreError::NoSuchBucket(bucket_id.to_string()))
+        }
+        Err(err) => Err(DatastoreError::InternalError(format!(
+            "Failed to query bucket row: {err}"
+        ))),
+    }
+}
+
+pub(crate) fn query_event(
+    conn: &Connection,
+    bucketrow: i64,
+    event_id: i64,
+) -> Result<Event, DatastoreError> {
+    let mut stmt = match conn.prepare(
+        "
+            SELECT id, starttime, endtime, data, origin
+            FROM events
+            WHERE bucketrow = ?1
+                AND id = ?2
+            LIMIT 1
+        ;",
+    ) {
+        Ok(stmt) => stmt,
+        Err(err) => {
+            return Err(DatastoreError::InternalError(format!(
+                "Failed to prepare get_event SQL statement: {err}"
+            )))
+        }
+    };
+
+    // TODO: Refactor to share row-parsing logic with get_events
+    let row = match stmt.query_row([&bucketrow, &event_id], |row| {
+        let id = row.get(0)?;
+        let starttime_ns: i64 = row.get(1)?;
+        let endtime_ns: i64 = row.get(2)?;
+        let data_str: String = row.get(3)?;
+
+        let time_seconds: i64 = starttime_ns / 1_000_000_000;
+        let time_subnanos: u32 = (starttime_ns % 1_000_000_000) as u32;
+        let duration_ns = endtime_ns - starttime_ns;
+        let data: serde_json::map::Map<String, Value> = serde_json::from_str(&data_str).unwrap();
+
+        Ok(Event {
+            id: Some(id),
+            timestamp: DateTime::from_timestamp(time_seconds, time_subnanos).unwrap(),
+            duration: Duration::nanoseconds(duration_ns),
+            data,
+            origin: row.get(4)?,
+        })
+    }) {
+        Ok(rows) => rows,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(DatastoreError::NoSuchEvent(event_id))
+        }
+        Err(err) => {
+            return Err(DatastoreError::InternalError(format!(
+                "Failed to map get_event SQL statement: {err}"
+            )))
+        }
+    };
+
+    Ok(row)
+}
+
+pub(crate) fn query_events(
+    conn: &Connection,
+    bucketrow: i64,
+    bucket_id: &str,
+    query: &EventQuery,
+) -> Result<EventPage, DatastoreError> {
+    let mut page = EventPage {
+        events: Vec::new(),
+        next_cursor: None,
+    };
+
+    let starttime_filter_ns: i64 = match query.starttime {
+        Some(dt) => dt.timestamp_nanos_opt().unwrap(),
+        None => 0,
+    };
+    let endtime_filter_ns: i64 = match query.endtime {
+        Some(dt) => dt.timestamp_nanos_opt().unwrap(),
+        None => i64::MAX,
+    };
+    if starttime_filter_ns > endtime_filter_ns {
+        warn!("Starttime in event query was lower than endtime!");
+        return Ok(page);
+    }
+    let limit = match query.limit {
+        Some(l) => l as i64,
+        None => -1,
+    };
+
+    let mut params = vec![
+        SqlValue::Integer(bucketrow),
+        SqlValue::Integer(starttime_filter_ns),
+        SqlValue::Integer(endtime_filter_ns),
+        SqlValue::Integer(limit),
+    ];
+    let mut filter_conditions = String::new();
+    push_filter_conditions(&mut filter_conditions, &query.filters, &mut params);
+    // Events with the same starttime are always in the order they were inserted
+    let (order, starttime_op) = match query.order {
+        EventOrder::Ascending => ("ASC", ">"),
+        EventOrder::Descending => ("DESC", "<"),
+    };
+    if let Some(cursor) = query.cursor {
+        let (starttime_param, id_param) = (params.len() + 1, params.len() + 2);
+        filter_conditions.push_str(&format!(
+            "\n                AND (starttime {starttime_op} ?{starttime_param} OR (starttime = ?{starttime_param} AND id > ?{id_param}))"
+        ));
+        params.push(SqlValue::Integer(cursor.starttime_ns));
+        params.push(SqlValue::Integer(cursor.id));
+    }
+
+    let mut stmt = match conn.prepare(&format!(
+        "
+            SELECT id, starttime, endtime, data, origin
+            FROM events
+            WHERE bucketrow = ?1
+                AND endtime >= ?2
+                AND starttime <= ?3{filter_conditions}
+            ORDER BY starttime {order}, id ASC
+            LIMIT ?4
+        ;"
+    )) {
+        Ok(stmt) => stmt,
+        Err(err) => {
+            return Err(DatastoreError::InternalError(format!(
+                "Failed to prepare get_events SQL statement: {err}"
+            )))
+        }
+    };
+
+    let rows = match stmt.query_map(params_from_iter(params), |row| {
+        let id = row.get(0)?;
+        let mut starttime_ns: i64 = row.get(1)?;
+        // The cursor has to point at where the event is stored, not where it is cut off
+        let cursor = EventCursor { starttime_ns, id };
+        let mut endtime_ns: i64 = row.get(2)?;
+        let data_str: String = row.get(3)?;
+
+        if starttime_ns < starttime_filter_ns {
+            starttime_ns = starttime_filter_ns
+        }
+        if endtime_ns > endtime_filter_ns {
+            endtime_ns = endtime_filter_ns
+        }
+        let duration_ns = endtime_ns - starttime_ns;
+
+        let time_seconds: i64 = starttime_ns / 1_000_000_000;
+        let time_subnanos: u32 = (starttime_ns % 1_000_000_000) as u32;
+        let data: serde_json::map::Map<String, Value> = serde_json::from_str(&data_str).unwrap();
+
+        let event = Event {
+            id: Some(id),
+            timestamp: DateTime::from_timestamp(time_seconds, time_subnanos).unwrap(),
+            duration: Duration::nanoseconds(duration_ns),
+            data,
+            origin: row.get(4)?,
+        };
+        Ok((event, cursor))
+    }) {
+        Ok(rows) => rows,
+        Err(err) => {
+            return Err(DatastoreError::InternalError(format!(
+                "Failed to map get_events SQL statement: {err}"
+            )))
+        }
+    };
+    let mut row_count = 0;
+    let mut last_cursor = None;
+    for row in rows {
+        row_count += 1;
+        match row {
+            Ok((event, cursor)) => {
+                page.events.push(event);
+                last_cursor = Some(cursor);
+            }
+            Err(err) => warn!("Corrupt event in bucket {}: {}", bucket_id, err),
+        };
+    }
+    if row_count == limit {
+        page.next_cursor = last_cursor;
+    }
+
+    Ok(page)
+}
+
+/// Appends a condition for each of the filters to a WHERE clause, binding their values after the
+/// parameters which are already in params
+fn push_filter_conditions(
+    conditions: &mut String,
+    filters: &[DataFilter],
+    params: &mut Vec<SqlValue>,
+) {
+    for filter in filters {
+        let (condition, filter_params) = filter.to_sql(params.len() + 1);
+        conditions.push_str(&format!("\n                AND {condition}"));
+        params.extend(filter_params);
+    }
+}
+
+pub(crate) fn query_event_count(
+    conn: &Connection,
+    bucketrow: i64,
+    starttime_opt: Option<DateTime<Utc>>,
+    endtime_opt: Option<DateTime<Utc>>,
+    filters: &[DataFilter],
+) -> Result<i64, DatastoreError> {
+    let starttime_filter_ns: i64 = match starttime_opt {
+        Some(dt) => dt.timestamp_nanos_opt().unwrap(),
+        None => 0,
+    };
+    let endtime_filter_ns: i64 = match endtime_opt {
+        Some(dt) => dt.timestamp_nanos_opt().unwrap(),
+        None => i64::MAX,
+    };
+    if starttime_filter_ns >= endtime_filter_ns {
+        warn!("Endtime in event query was same or lower than starttime!");
+        return Ok(0);
+    }
+
+    let mut params = vec![
+        SqlValue::Integer(bucketrow),
+        SqlValue::Integer(starttime_filter_ns),
+        SqlValue::Integer(endtime_filter_ns),
+    ];
+    let mut filter_conditions = String::new();
+    push_filter_conditions(&mut filter_conditions, filters, &mut params);
+
+    let mut stmt = match conn.prepare(&format!(
+        "
+        SELECT count(*) FROM events
+        WHERE bucketrow = ?1
+            AND endtime >= ?2
+            AND starttime <= ?3{filter_conditions}"
+    )) {
+        Ok(stmt) => stmt,
+        Err(err) => {
+            return Err(DatastoreError::InternalError(format!(
+                "Failed to prepare get_event_count SQL statement: {err}",
+            )))
+        }
+    };
+
+    let count = match stmt.query_row(params_from_iter(params), |row| row.get(0)) {
+        Ok(count) => count,
+        Err(err) => {
+            return Err(DatastoreError::InternalError(format!(
+                "Failed to query get_event_count SQL statement: {err}"
+            )))
+        }
+    };
+
+    Ok(count)
+}
+
+pub(crate) fn query_key_value(conn: &Connection, key: &str) -> Result<String, DatastoreError> {
+    let mut stmt = match conn.prepare(
+        "
+            SELECT * FROM key_value WHERE KEY = ?1",
+    ) {
+        Ok(stmt) => stmt,
+        Err(err) => {
+            return Err(DatastoreError::InternalError(format!(
+                "Failed to prepare get_value SQL statement: {err}"
+            )))
+        }
+    };
+
+    match stmt.query_row([key], |row| row.get(1)) {
+        Ok(result) => Ok(result),
+        Err(err) => match err {
+            rusqlite::Error::QueryReturnedNoRows => Err(DatastoreError::NoSuchKey(key.to_string())),
+            _ => Err(DatastoreError::InternalError(format!(
+                "Get value query failed for key {key}"
+            ))),
+        },
+    }
+}
+
+pub(crate) fn query_key_values(
+    conn: &Connection,
+    pattern: &str,
+) -> Result<HashMap<String, String>, DatastoreError> {
+    let mut stmt = match conn.prepare("SELECT key, value FROM key_value WHERE key LIKE ?") {
+        Ok(stmt) => stmt,
+        Err(err) => {
+            return Err(DatastoreError::InternalError(format!(
+                "Failed to prepare get_value SQL statement: {err}"
+            )))
+        }
+    };
+
+    let mut output = HashMap::<String, String>::new();
+    // Rusqlite's get wants index and item type as parameters.
+    let result = stmt.query_map([pattern], |row| {
+        Ok((row.get::<usize, String>(0)?, row.get::<usize, String>(1)?))
+    });
+    match result {
+        Ok(settings) => {
+            for row in settings {
+                // Unwrap to String or panic on SQL row if type is invalid. Can't happen with a
+                // properly initialized table.
+                let (key, value) = row.unwrap();
+                // Only return keys starting with "settings.".
+                if !key.starts_with("settings.") {
+                    continue;
                 }
-                Ok(output)
+                output.insert(key, value);
             }
-            Err(err) => match err {
-                rusqlite::Error::QueryReturnedNoRows => Ok(output),
-                _ => Err(DatastoreError::InternalError(
-                    "Failed to get settings".to_string(),
-                )),
-            },
+            Ok(output)
         }
+        Err(err) => match err {
+            rusqlite::Error::QueryReturnedNoRows => Ok(output),
+            _ => Err(DatastoreError::InternalError(
+                "Failed to get settings".to_string(),
+            )),
+        },
     }
 }
@@ -0,0 +1,154 @@
+use std::fmt;
+#[cfg(feature = "encryption")]
+use std::path::Path;
+
+use rusqlite::Connection;
+#[cfg(feature = "encryption")]
+use rusqlite::{DatabaseName, ErrorCode, OpenFlags};
+
+use super::DatastoreError;
+
+/// Key to encrypt a datastore with using SQLCipher, which needs the `encryption` feature
+#[derive(Clone, PartialEq, Eq)]
+pub enum DatastoreKey {
+    /// Turned into a key by SQLCipher, with a salt stored in the datastore
+    Passphrase(String),
+    /// Used as the key as it is
+    Raw([u8; 32]),
+}
+
+impl DatastoreKey {
+    fn pragma_value(&self) -> String {
+        match self {
+            DatastoreKey::Passphrase(passphrase) => passphrase.clone(),
+            DatastoreKey::Raw(key) => {
+                let hex: String = key.iter().map(|b| format!("{b:02x}")).collect();
+                format!("x'{hex}'")
+            }
+        }
+    }
+}
+
+// Keys are kept out of logs
+impl fmt::Debug for DatastoreKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DatastoreKey::Passphrase(_) => write!(f, "DatastoreKey::Passphrase(..)"),
+            DatastoreKey::Raw(_) => write!(f, "DatastoreKey::Raw(..)"),
+        }
+    }
+}
+
+fn check_supported() -> Result<(), DatastoreError> {
+    if cfg!(feature = "encryption") {
+        Ok(())
+    } else {
+        Err(DatastoreError::InvalidKey(
+            "Datastore encryption is not supported, aw-datastore was built without the \
+             encryption feature"
+                .to_string(),
+        ))
+    }
+}
+
+/// Makes the connection use the key, this has to be done before anything is read
+pub(crate) fn apply_key(
+    conn: &Connection,
+    key: Option<&DatastoreKey>,
+) -> Result<(), DatastoreError> {
+    let key = match key {
+        Some(key) => key,
+        None => return Ok(()),
+    };
+    check_supported()?;
+    match conn.pragma_update(None, "key", key.pragma_value()) {
+        Ok(()) => Ok(()),
+        Err(err) => Err(DatastoreError::InternalError(format!(
+            "Failed to set datastore key: {err}"
+        ))),
+    }
+}
+
+/// Checks that the datastore at the path can be read with the key, or without a key if there is
+/// none. A datastore which doesn't exist yet passes, as it will be created with the key.
+#[cfg(feature = "encryption")]
+pub fn check_key(path: &str, key: Option<&DatastoreKey>) -> Result<(), DatastoreError> {
+    if !Path::new(path).exists() {
+        return Ok(());
+    }
+    let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+    let conn = match Connection::open_with_flags(path, flags) {
+        Ok(conn) => conn,
+        Err(err) => {
+            return Err(DatastoreError::InternalError(format!(
+                "Failed to open datastore {path}: {err}"
+            )))
+        }
+    };
+    apply_key(&conn, key)?;
+    // SQLCipher can't tell a wrong key from a file which isn't a database
+    match conn.query_row("SELECT count(*) FROM sqlite_master", [], |row| {
+        row.get::<_, i64>(0)
+    }) {
+        Ok(_) => Ok(()),
+        Err(rusqlite::Error::SqliteFailure(err, _)) if err.code == ErrorCode::NotADatabase => {
+            Err(DatastoreError::InvalidKey(match key {
+                Some(_) => format!("Wrong key for datastore {path}, or it isn't encrypted"),
+                None => format!("Datastore {path} is encrypted, or it isn't a datastore"),
+            }))
+        }
+        Err(err) => Err(DatastoreError::InternalError(format!(
+            "Failed to read datastore {path}: {err}"
+        ))),
+    }
+}
+
+/// Encrypts an unencrypted datastore in place, it can't be open while this is done
+#[cfg(feature = "encryption")]
+pub fn encrypt_datastore(path: &str, key: &DatastoreKey) -> Result<(), DatastoreError> {
+    let conn = match Connection::open(path) {
+        Ok(conn) => conn,
+        Err(err) => {
+            return Err(DatastoreError::InternalError(format!(
+                "Failed to open datastore {path}: {err}"
+            )))
+        }
+    };
+    let tmp_path = format!("{path}.tmp");
+    let _ = std::fs::remove_file(&tmp_path);
+    if let Err(err) = export_encrypted(&conn, &tmp_path, key) {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(DatastoreError::InternalError(format!(
+            "Failed to encrypt datastore {path}: {err}"
+        )));
+    }
+    drop(conn);
+    match std::fs::rename(&tmp_path, path) {
+        Ok(()) => Ok(()),
+        Err(err) => Err(DatastoreError::InternalError(format!(
+            "Failed to replace datastore {path} with the encrypted copy: {err}"
+        ))),
+    }
+}
+
+#[cfg(feature = "encryption")]
+fn export_encrypted(conn: &Connection, tmp_path: &str, key: &DatastoreKey) -> rusqlite::Result<()> {
+    // Leave WAL mode, so the unencrypted write-ahead log is merged and removed and not applied to
+    // the encrypted copy once it replaces the datastore
+    conn.pragma_update_and_check(None, "journal_mode", "DELETE", |row| {
+        row.get::<_, String>(0)
+    })?;
+    let version: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    conn.execute(
+        "ATTACH DATABASE ?1 AS encrypted KEY ?2",
+        [tmp_path, key.pragma_value().as_str()],
+    )?;
+    conn.query_row("SELECT sqlcipher_export('encrypted')", [], |_| Ok(()))?;
+    conn.pragma_update(
+        Some(DatabaseName::Attached("encrypted")),
+        "user_version",
+        version,
+    )?;
+    conn.execute("DETACH DATABASE encrypted", [])?;
+    Ok(())
+}
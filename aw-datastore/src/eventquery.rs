@@ -0,0 +1,61 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::DateTime;
+use chrono::Utc;
+
+use aw_models::Event;
+use aw_models::EventOrder;
+
+use super::DataFilter;
+
+/// The position of an event in a listing of events, used to continue the listing after it
+///
+/// Events are listed by start time and then by id in ascending order, so a cursor stays valid while events are
+/// inserted or deleted, and a listing continued from it neither repeats nor skips events as
+/// long as their start times don't change. The string form is meant to be passed around as is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventCursor {
+    pub(crate) starttime_ns: i64,
+    pub(crate) id: i64,
+}
+
+impl fmt::Display for EventCursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:x}.{:x}", self.starttime_ns as u64, self.id as u64)
+    }
+}
+
+impl FromStr for EventCursor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<EventCursor, String> {
+        let parse = |part: &str| u64::from_str_radix(part, 16).map(|n| n as i64);
+        match s.split_once('.') {
+            Some((starttime_ns, id)) => match (parse(starttime_ns), parse(id)) {
+                (Ok(starttime_ns), Ok(id)) => Ok(EventCursor { starttime_ns, id }),
+                _ => Err(format!("Invalid cursor '{s}'")),
+            },
+            None => Err(format!("Invalid cursor '{s}'")),
+        }
+    }
+}
+
+/// Which events to get from a bucket, see Datastore::get_events_page
+#[derive(Debug, Clone, Default)]
+pub struct EventQuery {
+    pub starttime: Option<DateTime<Utc>>,
+    pub endtime: Option<DateTime<Utc>>,
+    pub limit: Option<u64>,
+    pub filters: Vec<DataFilter>,
+    pub order: EventOrder,
+    /// Only get the events after the cursor in the order
+    pub cursor: Option<EventCursor>,
+}
+
+#[derive(Debug, Clone)]
+pub struct EventPage {
+    pub events: Vec<Event>,
+    /// Set if the page is full, in which case there may be more events after it
+    pub next_cursor: Option<EventCursor>,
+}
@@ -0,0 +1,144 @@
+use std::sync::Arc;
+
+use regex::Regex;
+use rusqlite::functions::FunctionFlags;
+use rusqlite::types::Value as SqlValue;
+use rusqlite::types::ValueRef;
+use rusqlite::Connection;
+use serde_json::Value;
+
+use super::DatastoreError;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// A filter on a top level field in the data of events, evaluated by SQLite so that events
+/// which don't match are never loaded
+#[derive(Debug, Clone, PartialEq)]
+pub enum DataFilter {
+    /// The field is equal to the value, which has to be a string, number, bool or null
+    Equals(String, Value),
+    /// The field is a string which matches the regex
+    Regex(String, String),
+}
+
+impl DataFilter {
+    pub fn key(&self) -> &str {
+        match self {
+            DataFilter::Equals(key, _) => key,
+            DataFilter::Regex(key, _) => key,
+        }
+    }
+
+    /// Checks that the filter can be evaluated, so that errors are reported before the query
+    /// is made
+    pub fn validate(&self) -> Result<(), DatastoreError> {
+        validate_data_key(self.key())?;
+        match self {
+            DataFilter::Equals(_, Value::Array(_)) | DataFilter::Equals(_, Value::Object(_)) => {
+                Err(DatastoreError::InvalidFilter(format!(
+                    "Can only filter '{}' on strings, numbers, bools and null",
+                    self.key()
+                )))
+            }
+            DataFilter::Equals(_, _) => Ok(()),
+            DataFilter::Regex(_, pattern) => match Regex::new(pattern) {
+                Ok(_) => Ok(()),
+                Err(err) => Err(DatastoreError::InvalidFilter(format!(
+                    "Invalid regex '{pattern}': {err}"
+                ))),
+            },
+        }
+    }
+
+    /// Returns an SQL condition for the filter together with the parameters it binds, which are
+    /// numbered from param
+    pub(crate) fn to_sql(&self, param: usize) -> (String, Vec<SqlValue>) {
+        let field = data_field_expr(self.key());
+        let path = data_field_path(self.key());
+        match self {
+            // json_extract turns JSON strings into TEXT, which is never equal to a number
+            DataFilter::Equals(_, Value::String(s)) => (
+                format!("{field} = ?{param}"),
+                vec![SqlValue::Text(s.clone())],
+            ),
+            // Bools are extracted as 0 and 1 so the JSON type has to be checked as well
+            DataFilter::Equals(_, Value::Number(n)) => {
+                let value = match n.as_i64() {
+                    Some(i) => SqlValue::Integer(i),
+                    None => SqlValue::Real(n.as_f64().unwrap_or(f64::NAN)),
+                };
+                (
+                    format!(
+                        "{field} = ?{param} AND json_type(data, {path}) IN ('integer', 'real')"
+                    ),
+                    vec![value],
+                )
+            }
+            DataFilter::Equals(_, Value::Bool(b)) => (
+                format!("json_type(data, {path}) = ?{param}"),
+                vec![SqlValue::Text(b.to_string())],
+            ),
+            DataFilter::Equals(_, _) => (format!("json_type(data, {path}) = 'null'"), vec![]),
+            DataFilter::Regex(_, pattern) => (
+                format!("{field} REGEXP ?{param}"),
+                vec![SqlValue::Text(pattern.clone())],
+            ),
+        }
+    }
+}
+
+/// Keys are put into SQL as literals rather than bound, as SQLite can only use an index on an
+/// expression if the query contains the exact same expression
+pub(crate) fn validate_data_key(key: &str) -> Result<(), DatastoreError> {
+    if key.is_empty() || key.contains(['"', '\\']) || key.chars().any(char::is_control) {
+        return Err(DatastoreError::InvalidFilter(format!(
+            "Invalid data key '{key}', keys can't be empty or contain quotes, backslashes or control characters"
+        )));
+    }
+    Ok(())
+}
+
+pub(crate) fn data_field_path(key: &str) -> String {
+    format!("'$.\"{}\"'", key.replace('\'', "''"))
+}
+
+/// SQL expression for the value of a top level field in the data of an event
+pub(crate) fn data_field_expr(key: &str) -> String {
+    format!("json_extract(data, {})", data_field_path(key))
+}
+
+/// Name of the index on a data field, the key is hex encoded to keep the name a valid
+/// identifier whatever characters the key contains
+pub(crate) fn data_index_name(key: &str) -> String {
+    let hex: String = key.bytes().map(|b| format!("{b:02x}")).collect();
+    format!("events_data_{hex}_index")
+}
+
+/// Registers the regexp function, which SQLite calls for the REGEXP operator
+pub(crate) fn register_functions(conn: &Connection) -> Result<(), DatastoreError> {
+    let res = conn.create_scalar_function(
+        "regexp",
+        2,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            // Compiled once per statement rather than once per row
+            let regex: Arc<Regex> = ctx.get_or_create_aux(0, |pattern| -> Result<_, BoxError> {
+                Ok(Regex::new(pattern.as_str()?)?)
+            })?;
+            let is_match = match ctx.get_raw(1) {
+                ValueRef::Text(text) => match std::str::from_utf8(text) {
+                    Ok(text) => regex.is_match(text),
+                    Err(_) => false,
+                },
+                _ => false,
+            };
+            Ok(is_match)
+        },
+    );
+    match res {
+        Ok(()) => Ok(()),
+        Err(err) => Err(DatastoreError::InternalError(format!(
+            "Failed to register SQL functions: {err}"
+        ))),
+    }
+}
@@ -141,6 +141,7 @@ mod import {
                         timestamp,
                         duration: Duration::nanoseconds(duration_ns),
                         data,
+                        origin: None,
                     };
                     list.push(event)
                 }
@@ -15,12 +15,37 @@ macro_rules! json_map {
     }};
 }
 
+mod changes;
 mod datastore;
+mod encryption;
+mod eventquery;
+mod filter;
 mod legacy_import;
+mod maintenance;
+mod readpool;
+mod requests;
+mod redaction;
+mod retention;
+mod revisions;
 mod worker;
 
 pub use self::datastore::DatastoreInstance;
-pub use self::worker::Datastore;
+#[cfg(feature = "encryption")]
+pub use self::encryption::{check_key, encrypt_datastore};
+pub use self::encryption::DatastoreKey;
+pub use self::eventquery::{EventCursor, EventPage, EventQuery};
+pub use self::filter::DataFilter;
+pub use self::maintenance::{
+    restore_backup, BucketStats, DatabaseSize, DatabaseStats, IntegrityReport, MetadataRepair,
+    OrphanReport, OrphanedEvents, RestoreReport, VacuumReport,
+};
+pub use self::redaction::{RedactionAction, RedactionRule};
+pub use self::retention::{RetentionAction, RetentionPolicy, RetentionResult};
+pub use self::worker::{BulkLoad, Datastore};
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
 
 #[derive(Debug, Clone)]
 pub enum DatastoreMethod {
@@ -28,12 +53,100 @@ pub enum DatastoreMethod {
     File(String),
 }
 
+/// How hard SQLite tries to make sure that commits survive a crash, see the SQLite docs for
+/// PRAGMA synchronous
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncMode {
+    /// Commits are durable even if the OS crashes or loses power
+    Full,
+    /// Commits are durable if the process crashes, but the last ones may be lost if the OS does
+    Normal,
+    /// Leaves flushing to the OS, a power loss may corrupt the database
+    Off,
+}
+
+impl SyncMode {
+    pub(crate) fn pragma_value(&self) -> &'static str {
+        match self {
+            SyncMode::Full => "FULL",
+            SyncMode::Normal => "NORMAL",
+            SyncMode::Off => "OFF",
+        }
+    }
+}
+
+impl FromStr for SyncMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SyncMode, String> {
+        match s {
+            "full" => Ok(SyncMode::Full),
+            "normal" => Ok(SyncMode::Normal),
+            "off" => Ok(SyncMode::Off),
+            _ => Err(format!(
+                "Invalid sync mode '{s}', expected one of full, normal or off"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SyncMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.pragma_value().to_lowercase())
+    }
+}
+
+/// Controls how often the datastore worker commits and how durable the commits are, and which
+/// retention policies and redaction rules it enforces
+#[derive(Debug, Clone)]
+pub struct DatastoreOptions {
+    /// Max time writes are left uncommitted
+    pub commit_interval: Duration,
+    /// Number of inserted or updated events which triggers a commit
+    pub commit_events: usize,
+    pub sync: SyncMode,
+    pub retention: Vec<RetentionPolicy>,
+    /// How often the retention policies are enforced, they are also enforced on startup. Has to
+    /// be more than zero, the worker would otherwise do nothing else.
+    pub retention_interval: Duration,
+    /// How long deleted events are kept track of for syncing the changes to buckets, resume
+    /// tokens from before are refused. They are forgotten when the retention policies are enforced.
+    pub change_horizon: Duration,
+    /// Applied in order to inserted events and heartbeats before they are stored
+    pub redaction: Vec<RedactionRule>,
+    /// Key the datastore file is encrypted with, not used for datastores in memory
+    pub key: Option<DatastoreKey>,
+}
+
+impl Default for DatastoreOptions {
+    fn default() -> DatastoreOptions {
+        DatastoreOptions {
+            commit_interval: Duration::from_secs(15),
+            commit_events: 100,
+            sync: SyncMode::Full,
+            retention: Vec::new(),
+            retention_interval: Duration::from_secs(60 * 60),
+            change_horizon: Duration::from_secs(30 * 24 * 60 * 60),
+            redaction: Vec::new(),
+            key: None,
+        }
+    }
+}
+
 /* TODO: Implement this as a proper error */
 #[derive(Debug, Clone)]
 pub enum DatastoreError {
     NoSuchBucket(String),
     BucketAlreadyExists(String),
+    NoSuchEvent(i64),
+    // The event was deleted rather than updated, as the update matched a redaction rule which
+    // drops events
+    EventDropped(i64),
     NoSuchKey(String),
+    NoSuchQuery(String),
+    InvalidFilter(String),
+    InvalidToken(String),
+    InvalidKey(String),
     MpscError,
     InternalError(String),
     // Errors specific to when migrate is disabled
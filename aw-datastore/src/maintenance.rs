@@ -0,0 +1,322 @@
+use chrono::DateTime;
+use chrono::Utc;
+use rusqlite::backup::{Backup, StepResult};
+use rusqlite::Connection;
+use rusqlite::OpenFlags;
+use serde::{Deserialize, Serialize};
+
+use super::datastore::{_get_db_version, NEWEST_DB_VERSION};
+use super::encryption::apply_key;
+use super::DatastoreError;
+use super::DatastoreInstance;
+use super::DatastoreKey;
+
+/// Size of the database file, excluding the write-ahead log
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DatabaseSize {
+    pub page_size: i64,
+    pub page_count: i64,
+    /// Pages which are unused and would be released by a vacuum
+    pub freelist_count: i64,
+    pub bytes: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BucketStats {
+    pub bucket_id: String,
+    pub events: i64,
+    /// Bytes of the event data, not counting the fixed size columns or indexes
+    pub data_bytes: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DatabaseStats {
+    pub version: i32,
+    pub size: DatabaseSize,
+    pub buckets: Vec<BucketStats>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VacuumReport {
+    pub before: DatabaseSize,
+    pub after: DatabaseSize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IntegrityReport {
+    pub ok: bool,
+    /// The problems found by SQLite, empty if the database is ok
+    pub errors: Vec<String>,
+}
+
+/// Events whose bucket no longer exists, grouped by the row id of the missing bucket
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OrphanedEvents {
+    pub bucketrow: i64,
+    pub events: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OrphanReport {
+    pub orphans: Vec<OrphanedEvents>,
+    pub deleted: bool,
+}
+
+/// The schema versions of a restored backup, it is migrated to the newest version if older
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RestoreReport {
+    pub backup_version: i32,
+    pub version: i32,
+}
+
+/// The start and end of a bucket before and after they were recomputed from its events
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MetadataRepair {
+    pub bucket_id: String,
+    pub old_start: Option<DateTime<Utc>>,
+    pub old_end: Option<DateTime<Utc>>,
+    pub new_start: Option<DateTime<Utc>>,
+    pub new_end: Option<DateTime<Utc>>,
+}
+
+pub(crate) fn database_size(conn: &Connection) -> Result<DatabaseSize, DatastoreError> {
+    let pragma = |name: &str| -> Result<i64, DatastoreError> {
+        match conn.pragma_query_value(None, name, |row| row.get(0)) {
+            Ok(value) => Ok(value),
+            Err(err) => Err(DatastoreError::InternalError(format!(
+                "Failed to get {name} of database: {err}"
+            ))),
+        }
+    };
+    let page_size = pragma("page_size")?;
+    let page_count = pragma("page_count")?;
+    Ok(DatabaseSize {
+        page_size,
+        page_count,
+        freelist_count: pragma("freelist_count")?,
+        bytes: page_size * page_count,
+    })
+}
+
+/// Runs a query and collects its rows, what is used in error messages
+fn query_rows<T, F>(
+    conn: &Connection,
+    sql: &str,
+    what: &str,
+    f: F,
+) -> Result<Vec<T>, DatastoreError>
+where
+    F: FnMut(&rusqlite::Row) -> Result<T, rusqlite::Error>,
+{
+    let mut stmt = match conn.prepare(sql) {
+        Ok(stmt) => stmt,
+        Err(err) => {
+            return Err(DatastoreError::InternalError(format!(
+                "Failed to prepare {what} SQL statement: {err}"
+            )))
+        }
+    };
+    let rows = match stmt.query_map([], f) {
+        Ok(rows) => rows,
+        Err(err) => {
+            return Err(DatastoreError::InternalError(format!(
+                "Failed to query {what}: {err}"
+            )))
+        }
+    };
+    match rows.collect::<Result<Vec<T>, _>>() {
+        Ok(rows) => Ok(rows),
+        Err(err) => Err(DatastoreError::InternalError(format!(
+            "Failed to read {what}: {err}"
+        ))),
+    }
+}
+
+pub(crate) fn bucket_stats(conn: &Connection) -> Result<Vec<BucketStats>, DatastoreError> {
+    query_rows(
+        conn,
+        "
+        SELECT buckets.name, count(events.id),
+               coalesce(sum(length(CAST(events.data AS BLOB))), 0)
+        FROM buckets
+        LEFT OUTER JOIN events ON buckets.id = events.bucketrow
+        GROUP BY buckets.id
+        ORDER BY buckets.name",
+        "bucket stats",
+        |row| {
+            Ok(BucketStats {
+                bucket_id: row.get(0)?,
+                events: row.get(1)?,
+                data_bytes: row.get(2)?,
+            })
+        },
+    )
+}
+
+pub(crate) fn integrity_check(conn: &Connection) -> Result<IntegrityReport, DatastoreError> {
+    let messages = query_rows(conn, "PRAGMA integrity_check", "integrity check", |row| {
+        row.get::<_, String>(0)
+    })?;
+    // A database without problems results in a single "ok" row
+    let errors: Vec<String> = messages.into_iter().filter(|m| m != "ok").collect();
+    Ok(IntegrityReport {
+        ok: errors.is_empty(),
+        errors,
+    })
+}
+
+pub(crate) fn orphaned_events(
+    conn: &Connection,
+    delete: bool,
+) -> Result<OrphanReport, DatastoreError> {
+    let orphans = query_rows(
+        conn,
+        "
+        SELECT bucketrow, count(*) FROM events
+        WHERE bucketrow NOT IN (SELECT id FROM buckets)
+        GROUP BY bucketrow
+        ORDER BY bucketrow",
+        "orphaned events",
+        |row| {
+            Ok(OrphanedEvents {
+                bucketrow: row.get(0)?,
+                events: row.get(1)?,
+            })
+        },
+    )?;
+    if delete && !orphans.is_empty() {
+        if let Err(err) = conn.execute(
+            "DELETE FROM events WHERE bucketrow NOT IN (SELECT id FROM buckets)",
+            [],
+        ) {
+            return Err(DatastoreError::InternalError(format!(
+                "Failed to delete orphaned events: {err}"
+            )));
+        }
+    }
+    Ok(OrphanReport {
+        orphans,
+        deleted: delete,
+    })
+}
+
+/// Rebuilds the database file, which can't be done within a transaction, and then updates the
+/// statistics the query planner uses
+pub(crate) fn vacuum(conn: &Connection) -> Result<VacuumReport, DatastoreError> {
+    let before = database_size(conn)?;
+    if let Err(err) = conn.execute_batch("VACUUM; ANALYZE;") {
+        return Err(DatastoreError::InternalError(format!(
+            "Failed to vacuum database: {err}"
+        )));
+    }
+    Ok(VacuumReport {
+        before,
+        after: database_size(conn)?,
+    })
+}
+
+/// Copies all pages of the source database into the destination in one step, so that no other
+/// connection can modify the source in between
+fn copy_database(from: &Connection, to: &mut Connection) -> Result<(), DatastoreError> {
+    let backup = match Backup::new(from, to) {
+        Ok(backup) => backup,
+        Err(err) => {
+            return Err(DatastoreError::InternalError(format!(
+                "Failed to start copying database: {err}"
+            )))
+        }
+    };
+    match backup.step(-1) {
+        Ok(StepResult::Done) => Ok(()),
+        Ok(StepResult::Busy) | Ok(StepResult::Locked) => Err(DatastoreError::InternalError(
+            "Failed to copy database, it is locked by another connection".to_string(),
+        )),
+        Ok(_) => Err(DatastoreError::InternalError(
+            "Failed to copy database, the copy is incomplete".to_string(),
+        )),
+        Err(err) => Err(DatastoreError::InternalError(format!(
+            "Failed to copy database: {err}"
+        ))),
+    }
+}
+
+/// Writes a copy of the database to a temporary file next to the path which is then renamed,
+/// the copy is encrypted with the same key as the database
+pub(crate) fn backup(
+    conn: &Connection,
+    path: &str,
+    key: Option<&DatastoreKey>,
+) -> Result<(), DatastoreError> {
+    let tmp_path = format!("{path}.tmp");
+    let _ = std::fs::remove_file(&tmp_path);
+    let res = match Connection::open(&tmp_path) {
+        Ok(mut dst) => match apply_key(&dst, key) {
+            Ok(()) => copy_database(conn, &mut dst),
+            Err(err) => Err(err),
+        },
+        Err(err) => Err(DatastoreError::InternalError(format!(
+            "Failed to create backup at {tmp_path}: {err}"
+        ))),
+    };
+    if let Err(err) = res {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(err);
+    }
+    match std::fs::rename(&tmp_path, path) {
+        Ok(()) => Ok(()),
+        Err(err) => Err(DatastoreError::InternalError(format!(
+            "Failed to move backup to {path}: {err}"
+        ))),
+    }
+}
+
+/// Replaces the datastore at db_path with a backup and migrates it to the newest version
+///
+/// The backup is checked before anything is replaced. The datastore can't be open while it is
+/// restored, if it is the restore fails as the datastore worker keeps it locked.
+pub fn restore_backup(backup_path: &str, db_path: &str) -> Result<RestoreReport, DatastoreError> {
+    let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+    let src = match Connection::open_with_flags(backup_path, flags) {
+        Ok(conn) => conn,
+        Err(err) => {
+            return Err(DatastoreError::InternalError(format!(
+                "Failed to open backup {backup_path}: {err}"
+            )))
+        }
+    };
+    // Also fails if the file isn't an SQLite database at all
+    let integrity = integrity_check(&src)?;
+    if !integrity.ok {
+        return Err(DatastoreError::InternalError(format!(
+            "Backup {backup_path} is corrupt: {}",
+            integrity.errors.join(", ")
+        )));
+    }
+    let backup_version = _get_db_version(&src);
+    if backup_version < 1 {
+        return Err(DatastoreError::InternalError(format!(
+            "Backup {backup_path} is not an aw-server datastore"
+        )));
+    }
+    if backup_version > NEWEST_DB_VERSION {
+        return Err(DatastoreError::InternalError(format!(
+            "Backup {backup_path} has version {backup_version}, which is newer than the newest \
+             supported version {NEWEST_DB_VERSION}"
+        )));
+    }
+
+    let mut dst = match Connection::open(db_path) {
+        Ok(conn) => conn,
+        Err(err) => {
+            return Err(DatastoreError::InternalError(format!(
+                "Failed to open datastore {db_path}: {err}"
+            )))
+        }
+    };
+    copy_database(&src, &mut dst)?;
+    DatastoreInstance::new(&dst, true)?;
+    Ok(RestoreReport {
+        backup_version,
+        version: _get_db_version(&dst),
+    })
+}
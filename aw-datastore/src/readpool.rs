@@ -0,0 +1,134 @@
+use std::sync::{Condvar, Mutex};
+
+use rusqlite::Connection;
+use rusqlite::OpenFlags;
+use rusqlite::Transaction;
+
+use super::encryption::apply_key;
+use super::filter::register_functions;
+use super::DatastoreError;
+use super::DatastoreKey;
+
+struct PoolState {
+    idle: Vec<Connection>,
+    // Connections which have been opened, idle or in use
+    open: usize,
+    closed: bool,
+}
+
+/// A pool of read-only connections to a datastore file in WAL mode
+///
+/// Connections are opened lazily, so a pool only holds as many connections as it has had
+/// concurrent readers. Every read runs in its own transaction, so it sees a consistent snapshot
+/// of the database as of the last commit before the read started.
+pub struct ReadPool {
+    path: String,
+    size: usize,
+    key: Option<DatastoreKey>,
+    state: Mutex<PoolState>,
+    available: Condvar,
+}
+
+impl ReadPool {
+    pub fn new(path: String, size: usize, key: Option<DatastoreKey>) -> Self {
+        ReadPool {
+            path,
+            size,
+            key,
+            state: Mutex::new(PoolState {
+                idle: Vec::new(),
+                open: 0,
+                closed: false,
+            }),
+            available: Condvar::new(),
+        }
+    }
+
+    /// Runs f on a read-only connection, waiting for one to become available if all are in use
+    pub fn read<T, F>(&self, f: F) -> Result<T, DatastoreError>
+    where
+        F: FnOnce(&Transaction) -> Result<T, DatastoreError>,
+    {
+        let mut conn = self.acquire()?;
+        let res = match conn.transaction() {
+            Ok(tx) => f(&tx),
+            Err(err) => Err(DatastoreError::InternalError(format!(
+                "Failed to start read transaction: {err}"
+            ))),
+        };
+        self.release(conn);
+        res
+    }
+
+    /// Closes all idle connections, connections in use are closed as soon as they are released
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.open -= state.idle.len();
+        state.idle.clear();
+        self.available.notify_all();
+    }
+
+    fn acquire(&self) -> Result<Connection, DatastoreError> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.closed {
+                return Err(DatastoreError::InternalError(
+                    "Datastore has been closed".to_string(),
+                ));
+            }
+            if let Some(conn) = state.idle.pop() {
+                return Ok(conn);
+            }
+            if state.open < self.size {
+                state.open += 1;
+                break;
+            }
+            state = self.available.wait(state).unwrap();
+        }
+        // Open the new connection without holding the lock
+        drop(state);
+        match self.open_connection() {
+            Ok(conn) => Ok(conn),
+            Err(err) => {
+                let mut state = self.state.lock().unwrap();
+                state.open -= 1;
+                self.available.notify_one();
+                Err(err)
+            }
+        }
+    }
+
+    fn release(&self, conn: Connection) {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            state.open -= 1;
+            return;
+        }
+        state.idle.push(conn);
+        self.available.notify_one();
+    }
+
+    fn open_connection(&self) -> Result<Connection, DatastoreError> {
+        let flags = OpenFlags::SQLITE_OPEN_READ_ONLY
+            | OpenFlags::SQLITE_OPEN_NO_MUTEX
+            | OpenFlags::SQLITE_OPEN_URI;
+        let conn = match Connection::open_with_flags(&self.path, flags) {
+            Ok(conn) => conn,
+            Err(err) => {
+                return Err(DatastoreError::InternalError(format!(
+                    "Failed to open read-only connection to datastore: {err}"
+                )))
+            }
+        };
+        apply_key(&conn, self.key.as_ref())?;
+        register_functions(&conn)?;
+        // Readers in WAL mode are only blocked briefly during recovery or checkpoint restarts
+        if let Err(err) = conn.busy_timeout(std::time::Duration::from_secs(5)) {
+            return Err(DatastoreError::InternalError(format!(
+                "Failed to set busy timeout on read-only connection: {err}"
+            )));
+        }
+        Ok(conn)
+    }
+}
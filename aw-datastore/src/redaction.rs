@@ -0,0 +1,147 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use aw_models::Bucket;
+use aw_models::Event;
+use aw_transform::classify::RegexRule;
+
+use super::filter::validate_data_key;
+use super::retention::glob_match;
+use super::DatastoreError;
+
+/// What happens to an event which matches a redaction rule
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum RedactionAction {
+    /// Replaces the matching values
+    Replace {
+        #[serde(default = "default_replacement")]
+        replacement: String,
+    },
+    /// Replaces the matching values with their SHA-256 hash, so that events with the same
+    /// value can still be told apart from others and merged by heartbeats
+    Hash,
+    /// The event is not stored at all
+    Drop,
+}
+
+fn default_replacement() -> String {
+    "[redacted]".to_string()
+}
+
+/// A rule for scrubbing events before they are stored
+///
+/// The regex is matched against the string value of the key in the data of events, or
+/// against every string value if no key is set. Rules apply to the buckets which match both
+/// the bucket and the type glob, where a missing glob matches every bucket.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RedactionRule {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bucket: Option<String>,
+    #[serde(default, rename = "type", skip_serializing_if = "Option::is_none")]
+    pub bucket_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    pub regex: String,
+    #[serde(default)]
+    pub ignore_case: bool,
+    #[serde(flatten)]
+    pub action: RedactionAction,
+}
+
+impl RedactionRule {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(key) = &self.key {
+            match validate_data_key(key) {
+                Ok(()) => (),
+                Err(DatastoreError::InvalidFilter(msg)) => return Err(msg),
+                Err(err) => return Err(format!("{err:?}")),
+            }
+        }
+        self.compile().map(|_| ())
+    }
+
+    fn compile(&self) -> Result<RegexRule, String> {
+        RegexRule::new(&self.regex, self.ignore_case)
+            .map_err(|err| format!("Invalid redaction regex '{}': {err}", self.regex))
+    }
+
+    fn applies_to(&self, bucket: &Bucket) -> bool {
+        let matches = |glob: &Option<String>, s: &str| match glob {
+            Some(glob) => glob_match(glob, s),
+            None => true,
+        };
+        matches(&self.bucket, &bucket.id) && matches(&self.bucket_type, &bucket._type)
+    }
+}
+
+/// The redaction rules compiled once for all events
+pub(crate) struct Redactor {
+    rules: Vec<(RedactionRule, RegexRule)>,
+}
+
+impl Redactor {
+    /// Invalid rules are left out, they are expected to have been validated beforehand
+    pub fn new(rules: &[RedactionRule]) -> Redactor {
+        let mut compiled = Vec::new();
+        for rule in rules {
+            match rule.validate().and_then(|()| rule.compile()) {
+                Ok(regex_rule) => compiled.push((rule.clone(), regex_rule)),
+                Err(err) => error!("Ignoring redaction rule {:?}: {}", rule, err),
+            }
+        }
+        Redactor { rules: compiled }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Applies the rules in order, returns None if the event should be dropped
+    pub fn redact(&self, bucket: &Bucket, mut event: Event) -> Option<Event> {
+        for (rule, regex_rule) in &self.rules {
+            if !rule.applies_to(bucket) {
+                continue;
+            }
+            let matched: Vec<String> = match &rule.key {
+                Some(key) => match event.data.get(key) {
+                    Some(Value::String(s)) if value_matches(regex_rule, s) => vec![key.clone()],
+                    _ => vec![],
+                },
+                None => event
+                    .data
+                    .iter()
+                    .filter(|(_, v)| v.as_str().is_some_and(|s| value_matches(regex_rule, s)))
+                    .map(|(k, _)| k.clone())
+                    .collect(),
+            };
+            for key in matched {
+                let value = match &rule.action {
+                    RedactionAction::Replace { replacement } => replacement.clone(),
+                    RedactionAction::Hash => hash_value(event.data[&key].as_str().unwrap()),
+                    RedactionAction::Drop => return None,
+                };
+                event.data.insert(key, Value::String(value));
+            }
+        }
+        Some(event)
+    }
+}
+
+fn value_matches(rule: &RegexRule, value: &str) -> bool {
+    match rule.is_match(value) {
+        Ok(is_match) => is_match,
+        // Better to redact too much than to store what should have been redacted
+        Err(err) => {
+            warn!("Failed to match redaction regex, redacting anyway: {}", err);
+            true
+        }
+    }
+}
+
+fn hash_value(value: &str) -> String {
+    let digest = Sha256::digest(value.as_bytes());
+    let hex: String = digest.iter().map(|b| format!("{b:02x}")).collect();
+    format!("sha256:{hex}")
+}
@@ -0,0 +1,102 @@
+/// Request/response channels between the datastore handles and the worker thread
+///
+/// The same as the mpsc_requests crate, but the worker can also wait for a request with a
+/// timeout, so that it can commit when the commit interval runs out even if nothing else arrives.
+use std::time::Duration;
+
+use crossbeam_channel as cc;
+
+pub fn channel<Req, Res>() -> (RequestSender<Req, Res>, RequestReceiver<Req, Res>) {
+    let (request_sender, request_receiver) = cc::unbounded::<(Req, ResponseSender<Res>)>();
+    (
+        RequestSender { request_sender },
+        RequestReceiver { request_receiver },
+    )
+}
+
+#[derive(Debug)]
+pub enum RequestError {
+    RecvError,
+    SendError,
+}
+
+pub struct ResponseSender<Res> {
+    response_sender: cc::Sender<Res>,
+}
+
+impl<Res> ResponseSender<Res> {
+    pub fn respond(&self, response: Res) {
+        match self.response_sender.send(response) {
+            Ok(_) => (),
+            Err(_) => panic!("Response failed, send pipe was broken during request!"),
+        }
+    }
+}
+
+pub struct RequestReceiver<Req, Res> {
+    request_receiver: cc::Receiver<(Req, ResponseSender<Res>)>,
+}
+
+impl<Req, Res> RequestReceiver<Req, Res> {
+    /// Waits for the next request, fails once all senders are gone
+    pub fn poll(&self) -> Result<(Req, ResponseSender<Res>), RequestError> {
+        match self.request_receiver.recv() {
+            Ok(request) => Ok(request),
+            Err(_) => Err(RequestError::RecvError),
+        }
+    }
+
+    /// Waits for the next request for at most the timeout, or as long as it takes without one
+    ///
+    /// Returns None if the timeout ran out before a request arrived.
+    pub fn poll_timeout(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<Option<(Req, ResponseSender<Res>)>, RequestError> {
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => return self.poll().map(Some),
+        };
+        match self.request_receiver.recv_timeout(timeout) {
+            Ok(request) => Ok(Some(request)),
+            Err(cc::RecvTimeoutError::Timeout) => Ok(None),
+            Err(cc::RecvTimeoutError::Disconnected) => Err(RequestError::RecvError),
+        }
+    }
+}
+
+pub struct ResponseReceiver<Res> {
+    response_receiver: cc::Receiver<Res>,
+}
+
+impl<Res> ResponseReceiver<Res> {
+    pub fn collect(&self) -> Result<Res, RequestError> {
+        match self.response_receiver.recv() {
+            Ok(response) => Ok(response),
+            Err(_) => Err(RequestError::RecvError),
+        }
+    }
+}
+
+pub struct RequestSender<Req, Res> {
+    request_sender: cc::Sender<(Req, ResponseSender<Res>)>,
+}
+
+impl<Req, Res> Clone for RequestSender<Req, Res> {
+    fn clone(&self) -> Self {
+        RequestSender {
+            request_sender: self.request_sender.clone(),
+        }
+    }
+}
+
+impl<Req, Res> RequestSender<Req, Res> {
+    pub fn request(&self, request: Req) -> Result<ResponseReceiver<Res>, RequestError> {
+        let (response_sender, response_receiver) = cc::unbounded::<Res>();
+        let response_sender = ResponseSender { response_sender };
+        match self.request_sender.send((request, response_sender)) {
+            Ok(_) => Ok(ResponseReceiver { response_receiver }),
+            Err(_) => Err(RequestError::SendError),
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use aw_models::Bucket;
+use aw_models::Event;
+
+use super::filter::validate_data_key;
+use super::DatastoreError;
+
+/// What happens to events once they are older than a retention policy allows
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum RetentionAction {
+    Delete,
+    /// Removes the keys from the data of the events
+    StripKeys {
+        keys: Vec<String>,
+    },
+    /// Merges the events within each period of the given number of minutes which have the same
+    /// values for all of the keys, merged events only keep the keys in their data. Events which
+    /// don't have all of the keys are left as they are.
+    Merge {
+        keys: Vec<String>,
+        #[serde(default = "default_merge_minutes")]
+        minutes: u32,
+    },
+}
+
+fn default_merge_minutes() -> u32 {
+    60
+}
+
+/// Limits for how long events are kept as they are
+///
+/// A policy applies to the buckets which match both the bucket and the type glob, where a
+/// missing glob matches every bucket. Policies can also be set on a single bucket as a list
+/// under the "retention" key of the bucket data, in which case they replace the policies which
+/// would otherwise apply to it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RetentionPolicy {
+    /// Glob for the ids of the buckets, * matches any characters and ? a single character
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bucket: Option<String>,
+    /// Glob for the types of the buckets
+    #[serde(default, rename = "type", skip_serializing_if = "Option::is_none")]
+    pub bucket_type: Option<String>,
+    /// Events which started more than this many days ago are affected
+    pub days: u32,
+    #[serde(flatten)]
+    pub action: RetentionAction,
+}
+
+impl RetentionPolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        let keys = match &self.action {
+            RetentionAction::Delete => return Ok(()),
+            RetentionAction::StripKeys { keys } => keys,
+            RetentionAction::Merge { keys, minutes } => {
+                if *minutes == 0 {
+                    return Err("Retention merge period has to be at least 1 minute".to_string());
+                }
+                keys
+            }
+        };
+        if keys.is_empty() {
+            return Err("Retention policy needs at least one key".to_string());
+        }
+        for key in keys {
+            match validate_data_key(key) {
+                Ok(()) => (),
+                Err(DatastoreError::InvalidFilter(msg)) => return Err(msg),
+                Err(err) => return Err(format!("{err:?}")),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn applies_to(&self, bucket: &Bucket) -> bool {
+        let matches = |glob: &Option<String>, s: &str| match glob {
+            Some(glob) => glob_match(glob, s),
+            None => true,
+        };
+        matches(&self.bucket, &bucket.id) && matches(&self.bucket_type, &bucket._type)
+    }
+
+    /// Events which started before the cutoff are affected by the policy
+    pub fn cutoff(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        now - Duration::days(self.days as i64)
+    }
+}
+
+/// What a retention policy did, or would do in a dry run, to a bucket
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RetentionResult {
+    pub bucket_id: String,
+    pub policy: RetentionPolicy,
+    pub cutoff: DateTime<Utc>,
+    /// Number of events which were deleted, stripped or merged into others
+    pub events: i64,
+}
+
+/// Returns the policies which apply to the bucket, in the order they are applied
+pub(crate) fn bucket_policies(
+    bucket: &Bucket,
+    policies: &[RetentionPolicy],
+) -> Vec<RetentionPolicy> {
+    if let Some(value) = bucket.data.get("retention") {
+        match serde_json::from_value::<Vec<RetentionPolicy>>(value.clone()) {
+            Ok(bucket_policies) => {
+                return match bucket_policies.iter().try_for_each(|p| p.validate()) {
+                    Ok(()) => bucket_policies,
+                    Err(err) => {
+                        warn!(
+                            "Ignoring retention policies of bucket {}: {}",
+                            bucket.id, err
+                        );
+                        vec![]
+                    }
+                }
+            }
+            Err(err) => {
+                warn!(
+                    "Ignoring retention policies of bucket {}: {}",
+                    bucket.id, err
+                );
+                return vec![];
+            }
+        }
+    }
+    policies
+        .iter()
+        .filter(|p| p.applies_to(bucket))
+        .cloned()
+        .collect()
+}
+
+/// Merges events which all have the keys, returns None if they are already merged
+pub(crate) fn merge_events(events: &[Event], keys: &[String]) -> Option<Vec<Event>> {
+    let mut merged = aw_transform::merge_events_by_keys(events.to_vec(), keys.to_vec());
+    for event in &mut merged {
+        event.data.retain(|key, _| keys.contains(key));
+    }
+    if merged.len() == events.len() && events.iter().all(|e| e.data.len() == keys.len()) {
+        return None;
+    }
+    merged.sort_by_key(|e| e.timestamp);
+    Some(merged)
+}
+
+pub(crate) fn glob_match(glob: &str, s: &str) -> bool {
+    let glob: Vec<char> = glob.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+    let (mut g, mut i) = (0, 0);
+    // Where to continue from if the last * has to match more characters
+    let mut backtrack: Option<(usize, usize)> = None;
+    while i < s.len() {
+        match glob.get(g) {
+            Some('*') => {
+                backtrack = Some((g, i));
+                g += 1;
+            }
+            Some(c) if *c == '?' || *c == s[i] => {
+                g += 1;
+                i += 1;
+            }
+            _ => match backtrack {
+                Some((star_g, star_i)) => {
+                    backtrack = Some((star_g, star_i + 1));
+                    g = star_g + 1;
+                    i = star_i + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[g..].iter().all(|c| *c == '*')
+}
@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+
+use chrono::DateTime;
+use chrono::Utc;
+
+/* Max number of modified time ranges remembered per bucket, when exceeded the oldest ranges
+ * are merged together which can only make change detection more conservative */
+const MAX_CHANGES_PER_BUCKET: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+struct Change {
+    // All modifications with a revision in (previous change revision, revision] are
+    // contained within start_ns..end_ns
+    revision: u64,
+    start_ns: i64,
+    end_ns: i64,
+}
+
+impl Change {
+    fn overlaps(&self, start_ns: i64, end_ns: i64) -> bool {
+        self.start_ns <= end_ns && start_ns <= self.end_ns
+    }
+
+    fn covers(&self, other: &Change) -> bool {
+        self.start_ns <= other.start_ns && other.end_ns <= self.end_ns
+    }
+
+    fn merge(&mut self, other: &Change) {
+        self.revision = self.revision.max(other.revision);
+        self.start_ns = self.start_ns.min(other.start_ns);
+        self.end_ns = self.end_ns.max(other.end_ns);
+    }
+}
+
+/// Keeps a change counter for every bucket together with the time ranges that the changes
+/// touched, so that anyone caching data derived from events can tell if the cache is stale.
+///
+/// Revisions come from a single counter shared between all buckets, so a bucket which is
+/// deleted and created again never gets a revision it has had before.
+/// Revisions are only kept in memory and start over whenever the datastore is opened.
+#[derive(Debug, Default)]
+pub struct BucketRevisions {
+    counter: u64,
+    changes: HashMap<String, Vec<Change>>,
+}
+
+impl BucketRevisions {
+    /// Register that events in bucket_id between start_ns and end_ns have been modified
+    pub fn bump(&mut self, bucket_id: &str, start_ns: i64, end_ns: i64) {
+        self.counter += 1;
+        let change = Change {
+            revision: self.counter,
+            start_ns,
+            end_ns,
+        };
+        let changes = self.changes.entry(bucket_id.to_string()).or_default();
+        match changes.last_mut() {
+            // Heartbeats keep extending the same event, so the new change usually covers the
+            // previous one which can then be replaced to keep the list short
+            Some(last) if change.covers(last) => *last = change,
+            _ => changes.push(change),
+        }
+        if changes.len() > MAX_CHANGES_PER_BUCKET {
+            let oldest = changes.remove(0);
+            changes[0].merge(&oldest);
+        }
+    }
+
+    /// Register that anything in the bucket might have been modified
+    pub fn bump_all(&mut self, bucket_id: &str) {
+        self.bump(bucket_id, i64::MIN, i64::MAX);
+    }
+
+    pub fn remove(&mut self, bucket_id: &str) {
+        self.changes.remove(bucket_id);
+    }
+
+    pub fn revision(&self, bucket_id: &str) -> u64 {
+        match self
+            .changes
+            .get(bucket_id)
+            .and_then(|changes| changes.last())
+        {
+            Some(change) => change.revision,
+            None => 0,
+        }
+    }
+
+    /// Returns true if any of the buckets in the revisions snapshot has been modified within
+    /// starttime..endtime since the snapshot was taken.
+    pub fn modified_since(
+        &self,
+        revisions: &HashMap<String, u64>,
+        starttime: DateTime<Utc>,
+        endtime: DateTime<Utc>,
+    ) -> bool {
+        let start_ns = starttime.timestamp_nanos_opt().unwrap_or(i64::MIN);
+        let end_ns = endtime.timestamp_nanos_opt().unwrap_or(i64::MAX);
+        revisions
+            .iter()
+            .any(|(bucket_id, revision)| match self.changes.get(bucket_id) {
+                Some(changes) => changes
+                    .iter()
+                    .rev()
+                    .take_while(|change| change.revision > *revision)
+                    .any(|change| change.overlaps(start_ns, end_ns)),
+                None => false,
+            })
+    }
+}
+
+#[test]
+fn test_bucket_revisions() {
+    use std::str::FromStr;
+
+    let ts = |s: &str| DateTime::<Utc>::from_str(s).unwrap();
+    let ns = |s: &str| ts(s).timestamp_nanos_opt().unwrap();
+
+    let mut revs = BucketRevisions::default();
+    assert_eq!(revs.revision("a"), 0);
+    revs.bump("a", ns("2000-01-01T00:00:00Z"), ns("2000-01-01T00:30:00Z"));
+    let snapshot: HashMap<String, u64> = [("a".to_string(), revs.revision("a"))].into();
+    assert_eq!(snapshot["a"], 1);
+
+    // Heartbeat-like modifications of the same range are merged
+    revs.bump("a", ns("2000-01-01T01:00:00Z"), ns("2000-01-01T01:00:10Z"));
+    revs.bump("a", ns("2000-01-01T01:00:00Z"), ns("2000-01-01T01:00:20Z"));
+    assert_eq!(revs.revision("a"), 3);
+    assert_eq!(revs.changes["a"].len(), 2);
+
+    // Only intervals overlapping the modifications since the snapshot are modified
+    assert!(!revs.modified_since(
+        &snapshot,
+        ts("1999-01-01T00:00:00Z"),
+        ts("2000-01-01T00:59:00Z")
+    ));
+    assert!(revs.modified_since(
+        &snapshot,
+        ts("2000-01-01T00:59:00Z"),
+        ts("2000-01-02T00:00:00Z")
+    ));
+
+    // Other buckets don't affect the snapshot
+    revs.bump_all("b");
+    assert!(!revs.modified_since(
+        &snapshot,
+        ts("1999-01-01T00:00:00Z"),
+        ts("2000-01-01T00:59:00Z")
+    ));
+
+    // Old changes are merged when there are too many
+    for i in 0..(MAX_CHANGES_PER_BUCKET as i64 * 2) {
+        revs.bump("c", i * 10, i * 10 + 1);
+    }
+    assert_eq!(revs.changes["c"].len(), MAX_CHANGES_PER_BUCKET);
+    let snapshot: HashMap<String, u64> = [("c".to_string(), 0)].into();
+    assert!(revs.modified_since(
+        &snapshot,
+        DateTime::from_timestamp_nanos(5),
+        DateTime::from_timestamp_nanos(6)
+    ));
+}
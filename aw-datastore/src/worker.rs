@@ -1,9 +1,12 @@
 use std::collections::HashMap;
 use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, RwLock};
 use std::thread;
+use std::time::Instant;
 
 use chrono::DateTime;
-use chrono::Duration;
 use chrono::Utc;
 
 use rusqlite::Connection;
@@ -13,19 +16,75 @@ use rusqlite::TransactionBehavior;
 
 use aw_models::Bucket;
 use aw_models::Event;
+use aw_models::EventChanges;
+use aw_models::EventPatch;
+use aw_models::StoredQuery;
+use aw_models::TryVec;
 
+use crate::changes::query_event_changes;
+use crate::datastore::{
+    _get_db_version, query_bucket_row, query_event, query_event_count, query_events,
+    query_key_value, query_key_values,
+};
+use crate::encryption::apply_key;
+use crate::filter::register_functions;
+use crate::maintenance;
+use crate::readpool::ReadPool;
+use crate::redaction::Redactor;
+use crate::DataFilter;
+use crate::DatabaseStats;
 use crate::DatastoreError;
 use crate::DatastoreInstance;
 use crate::DatastoreMethod;
+use crate::DatastoreOptions;
+use crate::EventPage;
+use crate::EventQuery;
+use crate::IntegrityReport;
+use crate::MetadataRepair;
+use crate::OrphanReport;
+use crate::RetentionResult;
+use crate::VacuumReport;
 
-use mpsc_requests::ResponseReceiver;
+use crate::requests::ResponseReceiver;
 
-type RequestSender = mpsc_requests::RequestSender<Command, Result<Response, DatastoreError>>;
-type RequestReceiver = mpsc_requests::RequestReceiver<Command, Result<Response, DatastoreError>>;
+type RequestSender = crate::requests::RequestSender<Command, Result<Response, DatastoreError>>;
+type RequestReceiver = crate::requests::RequestReceiver<Command, Result<Response, DatastoreError>>;
 
+/// Max number of read-only connections for datastores stored in a file
+const READ_POOL_SIZE: usize = 4;
+
+/// A handle to a datastore, which can be cloned and shared between threads
+///
+/// All writes go through a single worker thread which batches them into transactions, which
+/// are committed according to the DatastoreOptions commit interval and event threshold, or right
+/// away for writes such as creating a bucket. Uncommitted writes are committed once the commit
+/// interval runs out, whether or not any more requests arrive.
+///
+/// Datastores stored in a file are put in WAL mode, and reads of buckets, events and key-values
+/// are served from a pool of read-only connections so they are never queued behind the worker.
+/// The pool reads the last committed snapshot, so while the worker holds uncommitted writes the
+/// reads go through the worker instead, which makes every write visible to the reads made after
+/// it. In-memory datastores can't be shared between connections, so all their reads go through
+/// the worker.
 #[derive(Clone)]
 pub struct Datastore {
     requester: RequestSender,
+    shared: Arc<SharedState>,
+    read_pool: Option<Arc<ReadPool>>,
+}
+
+/// State published by the worker for reads which don't go through it
+#[derive(Default)]
+struct SharedState {
+    // The buckets as of the last commit, buckets are cached by the worker as their metadata is
+    // expensive to compute
+    buckets: RwLock<HashMap<String, Bucket>>,
+    // The bucket revisions as of the last commit, so that data read from the last commit is
+    // never cached under a revision which includes writes it doesn't see
+    revisions: RwLock<HashMap<String, u64>>,
+    // Set from before a write is handled until it has been committed, the read pool doesn't see
+    // the writes until then
+    uncommitted: AtomicBool,
 }
 
 impl fmt::Debug for Datastore {
@@ -35,9 +94,6 @@ impl fmt::Debug for Datastore {
 }
 
 /*
- * TODO:
- * - Allow read requests to go straight through a read-only db connection instead of requesting the
- * worker thread for better performance?
  * TODO: Add an separate "Import" request which does an import with an transaction
  */
 
@@ -49,9 +105,21 @@ pub enum Response {
     BucketMap(HashMap<String, Bucket>),
     Event(Event),
     EventList(Vec<Event>),
+    EventPage(EventPage),
+    EventChanges(EventChanges),
     Count(i64),
     KeyValue(String),
     KeyValues(HashMap<String, String>),
+    StringList(Vec<String>),
+    Bool(bool),
+    StoredQuery(StoredQuery),
+    StoredQueries(HashMap<String, StoredQuery>),
+    RetentionResults(Vec<RetentionResult>),
+    Vacuum(VacuumReport),
+    Integrity(IntegrityReport),
+    DatabaseStats(DatabaseStats),
+    Orphans(OrphanReport),
+    MetadataRepairs(Vec<MetadataRepair>),
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -64,22 +132,96 @@ pub enum Command {
     InsertEvents(String, Vec<Event>),
     Heartbeat(String, Event, f64),
     GetEvent(String, i64),
-    GetEvents(
+    GetEvents(String, EventQuery),
+    GetEventCount(
         String,
         Option<DateTime<Utc>>,
         Option<DateTime<Utc>>,
-        Option<u64>,
+        Vec<DataFilter>,
     ),
-    GetEventCount(String, Option<DateTime<Utc>>, Option<DateTime<Utc>>),
     DeleteEventsById(String, Vec<i64>),
+    DeleteEventsByOrigin(String, Vec<String>),
+    GetEventChanges(String, Option<String>, u64),
+    UpdateEvent(String, i64, Event),
+    PatchEvent(String, i64, EventPatch),
+    DeleteEvents(
+        String,
+        Option<DateTime<Utc>>,
+        Option<DateTime<Utc>>,
+        Vec<DataFilter>,
+        bool,
+    ),
+    GetDataIndexes(String),
+    CreateDataIndex(String, String),
+    DeleteDataIndex(String, String),
     ForceCommit(),
+    BeginBulkLoad(),
+    EndBulkLoad(),
     GetKeyValues(String),
     GetKeyValue(String),
     SetKeyValue(String, String),
     DeleteKeyValue(String),
+    BucketsModifiedSince(HashMap<String, u64>, DateTime<Utc>, DateTime<Utc>),
+    GetStoredQueries(),
+    GetStoredQuery(String),
+    SetStoredQuery(StoredQuery),
+    DeleteStoredQuery(String),
+    ApplyRetention(bool),
+    Vacuum(),
+    Backup(String),
+    IntegrityCheck(),
+    GetDatabaseStats(),
+    FindOrphanedEvents(bool),
+    RepairBucketMetadata(),
     Close(),
 }
 
+impl Command {
+    fn is_write(&self) -> bool {
+        match self {
+            Command::CreateBucket(_)
+            | Command::DeleteBucket(_)
+            | Command::InsertEvents(_, _)
+            | Command::Heartbeat(_, _, _)
+            | Command::DeleteEventsById(_, _)
+            | Command::DeleteEventsByOrigin(_, _)
+            | Command::UpdateEvent(_, _, _)
+            | Command::PatchEvent(_, _, _)
+            | Command::DeleteEvents(_, _, _, _, _)
+            | Command::CreateDataIndex(_, _)
+            | Command::DeleteDataIndex(_, _)
+            | Command::SetKeyValue(_, _)
+            | Command::DeleteKeyValue(_)
+            | Command::SetStoredQuery(_)
+            | Command::DeleteStoredQuery(_)
+            | Command::RepairBucketMetadata() => true,
+            // A dry run only reads
+            Command::ApplyRetention(dry_run) => !dry_run,
+            Command::FindOrphanedEvents(delete) => *delete,
+            Command::GetBucket(_)
+            | Command::GetBuckets()
+            | Command::GetEvent(_, _)
+            | Command::GetEvents(_, _)
+            | Command::GetEventChanges(_, _, _)
+            | Command::GetDataIndexes(_)
+            | Command::GetEventCount(_, _, _, _)
+            | Command::ForceCommit()
+            | Command::BeginBulkLoad()
+            | Command::EndBulkLoad()
+            | Command::GetKeyValues(_)
+            | Command::GetKeyValue(_)
+            | Command::BucketsModifiedSince(_, _, _)
+            | Command::GetStoredQueries()
+            | Command::GetStoredQuery(_)
+            | Command::Vacuum()
+            | Command::Backup(_)
+            | Command::IntegrityCheck()
+            | Command::GetDatabaseStats()
+            | Command::Close() => false,
+        }
+    }
+}
+
 fn _unwrap_response(
     receiver: ResponseReceiver<Result<Response, DatastoreError>>,
 ) -> Result<(), DatastoreError> {
@@ -92,40 +234,93 @@ fn _unwrap_response(
     }
 }
 
+enum AfterCommit {
+    Vacuum,
+    Backup(String),
+}
+
+impl AfterCommit {
+    fn name(&self) -> &'static str {
+        match self {
+            AfterCommit::Vacuum => "vacuum",
+            AfterCommit::Backup(_) => "back up",
+        }
+    }
+}
+
 struct DatastoreWorker {
     responder: RequestReceiver,
+    shared: Arc<SharedState>,
     legacy_import: bool,
+    options: DatastoreOptions,
     quit: bool,
+    // Set from before a write is handled until it has been committed, and published to
+    // SharedState::uncommitted
+    uncommitted: bool,
     uncommitted_events: usize,
+    // Set by writes which should be committed right away unless a bulk load is in progress
     commit: bool,
+    // Set when a commit is requested explicitly
+    force_commit: bool,
+    // Number of bulk loads in progress, nothing is committed until all of them have ended
+    bulk_loads: usize,
     last_heartbeat: HashMap<String, Option<Event>>,
+    last_retention: Option<Instant>,
+    // Set by requests which have to be done outside of a transaction, which is done after the
+    // commit they force
+    after_commit: Option<AfterCommit>,
+    redactor: Redactor,
 }
 
 impl DatastoreWorker {
     pub fn new(
-        responder: mpsc_requests::RequestReceiver<Command, Result<Response, DatastoreError>>,
+        responder: RequestReceiver,
+        shared: Arc<SharedState>,
         legacy_import: bool,
+        options: DatastoreOptions,
     ) -> Self {
         DatastoreWorker {
             responder,
+            shared,
             legacy_import,
+            redactor: Redactor::new(&options.redaction),
+            options,
             quit: false,
+            uncommitted: false,
             uncommitted_events: 0,
             commit: false,
+            force_commit: false,
+            bulk_loads: 0,
             last_heartbeat: HashMap::new(),
+            last_retention: None,
+            after_commit: None,
         }
     }
 
-    fn work_loop(&mut self, method: DatastoreMethod) {
+    fn work_loop(&mut self, method: DatastoreMethod, ready: mpsc::Sender<()>) {
         // Open SQLite connection
         let mut conn = match &method {
             DatastoreMethod::Memory() => {
                 Connection::open_in_memory().expect("Failed to create in-memory datastore")
             }
             DatastoreMethod::File(path) => {
-                Connection::open(path).expect("Failed to create datastore")
+                let conn = Connection::open(path).expect("Failed to create datastore");
+                apply_key(&conn, self.options.key.as_ref()).expect("Failed to set datastore key");
+                // WAL lets the read pool read while the worker has a transaction open
+                let journal_mode: String = conn
+                    .pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get(0))
+                    .expect("Failed to set journal mode of datastore");
+                if !journal_mode.eq_ignore_ascii_case("wal") {
+                    panic!(
+                        "Failed to enable WAL mode on datastore, journal mode is {journal_mode}"
+                    );
+                }
+                conn
             }
         };
+        conn.pragma_update(None, "synchronous", self.options.sync.pragma_value())
+            .expect("Failed to set synchronous mode of datastore");
+        register_functions(&conn).unwrap();
         let mut ds = DatastoreInstance::new(&conn, true).unwrap();
 
         // Ensure legacy import
@@ -145,10 +340,16 @@ impl DatastoreWorker {
                 Err(err) => panic!("Failed to commit datastore transaction! {err}"),
             }
         }
+        self.publish_buckets(&ds);
+        // The database is now created and migrated, so the read pool can connect to it
+        let _ = ready.send(());
 
         // Start handling and respond to requests
         loop {
-            let last_commit_time: DateTime<Utc> = Utc::now();
+            if self.retention_due() {
+                self.enforce_retention(&mut conn, &mut ds);
+            }
+            let last_commit_time = Instant::now();
             let mut tx: Transaction =
                 match conn.transaction_with_behavior(TransactionBehavior::Immediate) {
                     Ok(tx) => tx,
@@ -163,9 +364,17 @@ impl DatastoreWorker {
 
             self.uncommitted_events = 0;
             self.commit = false;
+            self.force_commit = false;
+            // The response to the request which triggers the commit is held back until the
+            // commit is done, so that the requester can rely on the read pool seeing the changes
+            let mut committed_response = None;
             loop {
-                let (request, response_sender) = match self.responder.poll() {
-                    Ok((req, res_sender)) => (req, res_sender),
+                let timeout = self
+                    .wakeup(last_commit_time)
+                    .map(|wakeup| wakeup.saturating_duration_since(Instant::now()));
+                let (request, response_sender) = match self.responder.poll_timeout(timeout) {
+                    Ok(Some((req, res_sender))) => (req, res_sender),
+                    Ok(None) => break,
                     Err(err) => {
                         // All references to responder is gone, quit
                         error!("DB worker quitting, error: {err:?}");
@@ -173,27 +382,48 @@ impl DatastoreWorker {
                         break;
                     }
                 };
+                if request.is_write() {
+                    self.set_uncommitted(true);
+                }
                 let response = self.handle_request(request, &mut ds, &tx);
-                response_sender.respond(response);
 
-                let now: DateTime<Utc> = Utc::now();
-                let commit_interval_passed: bool = (now - last_commit_time) > Duration::seconds(15);
-                if self.commit
-                    || commit_interval_passed
-                    || self.uncommitted_events > 100
-                    || self.quit
-                {
+                let commit_due = self.commit
+                    || last_commit_time.elapsed() >= self.options.commit_interval
+                    || self.uncommitted_events >= self.options.commit_events;
+                // A bulk load is committed as a whole unless a commit is explicitly requested
+                if self.force_commit || self.quit || (commit_due && self.bulk_loads == 0) {
+                    committed_response = Some((response_sender, response));
                     break;
-                };
+                }
+                response_sender.respond(response);
             }
             debug!(
                 "Committing DB! Force commit {}, {} uncommitted events",
-                self.commit, self.uncommitted_events
+                self.commit || self.force_commit,
+                self.uncommitted_events
             );
             match tx.commit() {
                 Ok(_) => (),
                 Err(err) => panic!("Failed to commit datastore transaction! {err}"),
             }
+            self.publish_buckets(&ds);
+            self.set_uncommitted(false);
+            if let Some((response_sender, mut response)) = committed_response {
+                response = match self.after_commit.take() {
+                    Some(AfterCommit::Vacuum) => match maintenance::vacuum(&conn) {
+                        Ok(report) => Ok(Response::Vacuum(report)),
+                        Err(e) => Err(e),
+                    },
+                    Some(AfterCommit::Backup(path)) => {
+                        match maintenance::backup(&conn, &path, self.options.key.as_ref()) {
+                            Ok(()) => Ok(Response::Empty()),
+                            Err(e) => Err(e),
+                        }
+                    }
+                    None => response,
+                };
+                response_sender.respond(response);
+            }
             if self.quit {
                 break;
             };
@@ -201,6 +431,162 @@ impl DatastoreWorker {
         info!("DB Worker thread finished");
     }
 
+    fn prune_event_changes(&self, tx: &Transaction, ds: &DatastoreInstance) {
+        let horizon = match chrono::Duration::from_std(self.options.change_horizon) {
+            Ok(horizon) => horizon,
+            Err(_) => return,
+        };
+        let before = match Utc::now().checked_sub_signed(horizon) {
+            Some(before) => before,
+            None => return,
+        };
+        match ds.prune_event_changes(tx, before) {
+            Ok(0) => (),
+            Ok(count) => info!("Forgot {} events deleted before {}", count, before),
+            Err(err) => error!("Failed to prune event changes: {:?}", err),
+        }
+    }
+
+    /// When the worker has to stop waiting for requests, to commit the uncommitted writes once
+    /// the commit interval runs out or to enforce the retention policies, even if no other
+    /// request arrives by then
+    fn wakeup(&self, last_commit_time: Instant) -> Option<Instant> {
+        // Neither happens until all bulk loads have ended
+        if self.bulk_loads > 0 {
+            return None;
+        }
+        let commit = match self.uncommitted {
+            true => last_commit_time.checked_add(self.options.commit_interval),
+            false => None,
+        };
+        let retention = self
+            .last_retention
+            .and_then(|last| last.checked_add(self.options.retention_interval));
+        match (commit, retention) {
+            (Some(commit), Some(retention)) => Some(commit.min(retention)),
+            (commit, retention) => commit.or(retention),
+        }
+    }
+
+    fn retention_due(&self) -> bool {
+        // Retention could otherwise delete events a bulk load has only just inserted
+        self.bulk_loads == 0
+            && match self.last_retention {
+                Some(last) => last.elapsed() >= self.options.retention_interval,
+                None => true,
+            }
+    }
+
+    fn enforce_retention(&mut self, conn: &mut Connection, ds: &mut DatastoreInstance) {
+        self.last_retention = Some(Instant::now());
+        let mut tx = match conn.transaction_with_behavior(TransactionBehavior::Immediate) {
+            Ok(tx) => tx,
+            Err(err) => {
+                error!("Unable to start transaction for retention! {:?}", err);
+                return;
+            }
+        };
+        // Whatever was done before an error is still consistent, so keep it
+        tx.set_drop_behavior(DropBehavior::Commit);
+        match ds.apply_retention(&tx, &self.options.retention, Utc::now(), false) {
+            Ok(results) => {
+                for result in results.iter().filter(|r| r.events > 0) {
+                    info!(
+                        "Retention policy {:?} affected {} events in bucket {}",
+                        result.policy.action, result.events, result.bucket_id
+                    );
+                }
+            }
+            Err(err) => error!("Failed to enforce retention policies: {:?}", err),
+        }
+        self.prune_event_changes(&tx, ds);
+        match tx.commit() {
+            Ok(_) => (),
+            Err(err) => panic!("Failed to commit datastore transaction! {err}"),
+        }
+        self.last_heartbeat.clear();
+        self.publish_buckets(ds);
+    }
+
+    /// The response is replaced once the transaction has been committed
+    fn request_after_commit(&mut self, action: AfterCommit) -> Result<Response, DatastoreError> {
+        // A bulk load is meant to be committed as a whole
+        if self.bulk_loads > 0 {
+            return Err(DatastoreError::InternalError(format!(
+                "Can't {} during a bulk load",
+                action.name()
+            )));
+        }
+        self.after_commit = Some(action);
+        self.force_commit = true;
+        Ok(Response::Empty())
+    }
+
+    fn redact_events(
+        &self,
+        ds: &DatastoreInstance,
+        bucket_id: &str,
+        events: Vec<Event>,
+    ) -> Result<Vec<Event>, DatastoreError> {
+        if self.redactor.is_empty() {
+            return Ok(events);
+        }
+        let bucket = ds.get_bucket(bucket_id)?;
+        Ok(events
+            .into_iter()
+            .filter_map(|event| self.redactor.redact(&bucket, event))
+            .collect())
+    }
+
+    /// Publishes the buckets and their revisions after a commit
+    fn update_event(
+        &mut self,
+        ds: &mut DatastoreInstance,
+        tx: &Transaction,
+        bucketname: &str,
+        event_id: i64,
+        event: Event,
+    ) -> Result<Response, DatastoreError> {
+        let event = match self.redact_events(ds, bucketname, vec![event]) {
+            Ok(mut events) => match events.pop() {
+                Some(event) => event,
+                // The event would have been dropped if it was inserted like this, so the stored
+                // event is deleted rather than kept as it was
+                None => {
+                    return match ds.delete_events_by_id(tx, bucketname, vec![event_id]) {
+                        Ok(()) => {
+                            self.uncommitted_events += 1;
+                            self.last_heartbeat.insert(bucketname.to_string(), None);
+                            Err(DatastoreError::EventDropped(event_id))
+                        }
+                        Err(e) => Err(e),
+                    }
+                }
+            },
+            Err(e) => return Err(e),
+        };
+        match ds.update_event(tx, bucketname, event_id, &event) {
+            Ok(e) => {
+                self.uncommitted_events += 1;
+                // The updated event might be the last heartbeat
+                self.last_heartbeat.insert(bucketname.to_string(), None);
+                Ok(Response::Event(e))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Publishes the buckets and their revisions after a commit
+    fn publish_buckets(&self, ds: &DatastoreInstance) {
+        *self.shared.buckets.write().unwrap() = ds.get_buckets();
+        *self.shared.revisions.write().unwrap() = ds.get_bucket_revisions();
+    }
+
+    fn set_uncommitted(&mut self, uncommitted: bool) {
+        self.uncommitted = uncommitted;
+        self.shared.uncommitted.store(uncommitted, Ordering::SeqCst);
+    }
+
     fn handle_request(
         &mut self,
         request: Command,
@@ -208,13 +594,26 @@ impl DatastoreWorker {
         tx: &Transaction,
     ) -> Result<Response, DatastoreError> {
         match request {
-            Command::CreateBucket(bucket) => match ds.create_bucket(tx, bucket) {
-                Ok(_) => {
-                    self.commit = true;
-                    Ok(Response::Empty())
+            Command::CreateBucket(mut bucket) => {
+                // Events of imported buckets are redacted like inserted ones
+                if !self.redactor.is_empty() {
+                    if let Some(events) = bucket.events.take() {
+                        let events = events
+                            .take_inner()
+                            .into_iter()
+                            .filter_map(|event| self.redactor.redact(&bucket, event))
+                            .collect();
+                        bucket.events = Some(TryVec::new(events));
+                    }
                 }
-                Err(e) => Err(e),
-            },
+                match ds.create_bucket(tx, bucket) {
+                    Ok(_) => {
+                        self.commit = true;
+                        Ok(Response::Empty())
+                    }
+                    Err(e) => Err(e),
+                }
+            }
             Command::DeleteBucket(bucketname) => match ds.delete_bucket(tx, &bucketname) {
                 Ok(_) => {
                     self.commit = true;
@@ -228,7 +627,8 @@ impl DatastoreWorker {
             },
             Command::GetBuckets() => Ok(Response::BucketMap(ds.get_buckets())),
             Command::InsertEvents(bucketname, events) => {
-                match ds.insert_events(tx, &bucketname, events) {
+                let redacted = self.redact_events(ds, &bucketname, events);
+                match redacted.and_then(|events| ds.insert_events(tx, &bucketname, events)) {
                     Ok(events) => {
                         self.uncommitted_events += events.len();
                         self.last_heartbeat.insert(bucketname.to_string(), None); // invalidate last_heartbeat cache
@@ -238,6 +638,14 @@ impl DatastoreWorker {
                 }
             }
             Command::Heartbeat(bucketname, event, pulsetime) => {
+                let event = match self.redact_events(ds, &bucketname, vec![event.clone()]) {
+                    Ok(mut events) => match events.pop() {
+                        Some(event) => event,
+                        // Dropped heartbeats are answered as if they had been stored
+                        None => return Ok(Response::Event(event)),
+                    },
+                    Err(e) => return Err(e),
+                };
                 match ds.heartbeat(tx, &bucketname, event, pulsetime, &mut self.last_heartbeat) {
                     Ok(e) => {
                         self.uncommitted_events += 1;
@@ -252,14 +660,20 @@ impl DatastoreWorker {
                     Err(e) => Err(e),
                 }
             }
-            Command::GetEvents(bucketname, starttime_opt, endtime_opt, limit_opt) => {
-                match ds.get_events(tx, &bucketname, starttime_opt, endtime_opt, limit_opt) {
-                    Ok(el) => Ok(Response::EventList(el)),
+            Command::GetEvents(bucketname, query) => {
+                match ds.get_events_page(tx, &bucketname, &query) {
+                    Ok(page) => Ok(Response::EventPage(page)),
                     Err(e) => Err(e),
                 }
             }
-            Command::GetEventCount(bucketname, starttime_opt, endtime_opt) => {
-                match ds.get_event_count(tx, &bucketname, starttime_opt, endtime_opt) {
+            Command::GetEventChanges(bucketname, token, limit) => {
+                match ds.get_event_changes(tx, &bucketname, token.as_deref(), limit) {
+                    Ok(changes) => Ok(Response::EventChanges(changes)),
+                    Err(e) => Err(e),
+                }
+            }
+            Command::GetEventCount(bucketname, starttime_opt, endtime_opt, filters) => {
+                match ds.get_event_count(tx, &bucketname, starttime_opt, endtime_opt, &filters) {
                     Ok(n) => Ok(Response::Count(n)),
                     Err(e) => Err(e),
                 }
@@ -270,16 +684,141 @@ impl DatastoreWorker {
                     Err(e) => Err(e),
                 }
             }
+            Command::DeleteEventsByOrigin(bucketname, origins) => {
+                match ds.delete_events_by_origin(tx, &bucketname, origins) {
+                    Ok(n) => {
+                        self.uncommitted_events += n as usize;
+                        // The last heartbeat might have been deleted
+                        self.last_heartbeat.insert(bucketname.to_string(), None);
+                        Ok(Response::Count(n))
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            Command::UpdateEvent(bucketname, event_id, event) => {
+                self.update_event(ds, tx, &bucketname, event_id, event)
+            }
+            Command::PatchEvent(bucketname, event_id, patch) => {
+                match ds.get_event(tx, &bucketname, event_id) {
+                    Ok(mut event) => {
+                        patch.apply(&mut event);
+                        self.update_event(ds, tx, &bucketname, event_id, event)
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            Command::DeleteEvents(bucketname, starttime_opt, endtime_opt, filters, clip) => {
+                match ds.delete_events(tx, &bucketname, starttime_opt, endtime_opt, &filters, clip)
+                {
+                    Ok(n) => {
+                        self.uncommitted_events += n as usize;
+                        // The last heartbeat might have been deleted
+                        self.last_heartbeat.insert(bucketname.to_string(), None);
+                        Ok(Response::Count(n))
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            Command::GetDataIndexes(bucketname) => match ds.get_data_indexes(tx, &bucketname) {
+                Ok(keys) => Ok(Response::StringList(keys)),
+                Err(e) => Err(e),
+            },
+            Command::CreateDataIndex(bucketname, key) => {
+                match ds.create_data_index(tx, &bucketname, &key) {
+                    Ok(()) => {
+                        self.commit = true;
+                        Ok(Response::Empty())
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            Command::DeleteDataIndex(bucketname, key) => {
+                match ds.delete_data_index(tx, &bucketname, &key) {
+                    Ok(()) => {
+                        self.commit = true;
+                        Ok(Response::Empty())
+                    }
+                    Err(e) => Err(e),
+                }
+            }
             Command::ForceCommit() => {
-                self.commit = true;
+                self.force_commit = true;
+                Ok(Response::Empty())
+            }
+            Command::BeginBulkLoad() => {
+                self.bulk_loads += 1;
+                Ok(Response::Empty())
+            }
+            Command::EndBulkLoad() => {
+                if self.bulk_loads == 0 {
+                    return Err(DatastoreError::InternalError(
+                        "Tried to end a bulk load which was never started".to_string(),
+                    ));
+                }
+                self.bulk_loads -= 1;
+                if self.bulk_loads == 0 {
+                    self.force_commit = true;
+                }
                 Ok(Response::Empty())
             }
+            Command::ApplyRetention(dry_run) => {
+                match ds.apply_retention(tx, &self.options.retention, Utc::now(), dry_run) {
+                    Ok(results) => {
+                        if !dry_run {
+                            self.prune_event_changes(tx, ds);
+                            self.commit = true;
+                            self.last_heartbeat.clear();
+                            self.last_retention = Some(Instant::now());
+                        }
+                        Ok(Response::RetentionResults(results))
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            Command::Vacuum() => self.request_after_commit(AfterCommit::Vacuum),
+            Command::Backup(path) => self.request_after_commit(AfterCommit::Backup(path)),
+            Command::IntegrityCheck() => match maintenance::integrity_check(tx) {
+                Ok(report) => Ok(Response::Integrity(report)),
+                Err(e) => Err(e),
+            },
+            Command::GetDatabaseStats() => {
+                let size = maintenance::database_size(tx)?;
+                match maintenance::bucket_stats(tx) {
+                    Ok(buckets) => Ok(Response::DatabaseStats(DatabaseStats {
+                        version: _get_db_version(tx),
+                        size,
+                        buckets,
+                    })),
+                    Err(e) => Err(e),
+                }
+            }
+            Command::FindOrphanedEvents(delete) => match maintenance::orphaned_events(tx, delete) {
+                Ok(report) => {
+                    if delete {
+                        self.commit = true;
+                    }
+                    Ok(Response::Orphans(report))
+                }
+                Err(e) => Err(e),
+            },
+            Command::RepairBucketMetadata() => match ds.repair_bucket_metadata(tx) {
+                Ok(repairs) => {
+                    // Commit so that the repaired buckets are published to readers right away
+                    self.commit = true;
+                    Ok(Response::MetadataRepairs(repairs))
+                }
+                Err(e) => Err(e),
+            },
             Command::GetKeyValues(pattern) => match ds.get_key_values(tx, pattern.as_str()) {
                 Ok(result) => Ok(Response::KeyValues(result)),
                 Err(e) => Err(e),
             },
+            // Settings are read back right after they are changed, from the read pool
             Command::SetKeyValue(key, data) => match ds.insert_key_value(tx, &key, &data) {
-                Ok(()) => Ok(Response::Empty()),
+                Ok(()) => {
+                    self.commit = true;
+                    Ok(Response::Empty())
+                }
                 Err(e) => Err(e),
             },
             Command::GetKeyValue(key) => match ds.get_key_value(tx, &key) {
@@ -287,7 +826,35 @@ impl DatastoreWorker {
                 Err(e) => Err(e),
             },
             Command::DeleteKeyValue(key) => match ds.delete_key_value(tx, &key) {
-                Ok(()) => Ok(Response::Empty()),
+                Ok(()) => {
+                    self.commit = true;
+                    Ok(Response::Empty())
+                }
+                Err(e) => Err(e),
+            },
+            Command::BucketsModifiedSince(revisions, starttime, endtime) => Ok(Response::Bool(
+                ds.buckets_modified_since(&revisions, starttime, endtime),
+            )),
+            Command::GetStoredQueries() => match ds.get_stored_queries(tx) {
+                Ok(queries) => Ok(Response::StoredQueries(queries)),
+                Err(e) => Err(e),
+            },
+            Command::GetStoredQuery(name) => match ds.get_stored_query(tx, &name) {
+                Ok(query) => Ok(Response::StoredQuery(query)),
+                Err(e) => Err(e),
+            },
+            Command::SetStoredQuery(query) => match ds.insert_stored_query(tx, &query) {
+                Ok(()) => {
+                    self.commit = true;
+                    Ok(Response::Empty())
+                }
+                Err(e) => Err(e),
+            },
+            Command::DeleteStoredQuery(name) => match ds.delete_stored_query(tx, &name) {
+                Ok(()) => {
+                    self.commit = true;
+                    Ok(Response::Empty())
+                }
                 Err(e) => Err(e),
             },
             Command::Close() => {
@@ -300,23 +867,65 @@ impl DatastoreWorker {
 
 impl Datastore {
     pub fn new(dbpath: String, legacy_import: bool) -> Self {
+        Datastore::new_with_options(dbpath, legacy_import, DatastoreOptions::default())
+    }
+
+    pub fn new_with_options(
+        dbpath: String,
+        legacy_import: bool,
+        options: DatastoreOptions,
+    ) -> Self {
         let method = DatastoreMethod::File(dbpath);
-        Datastore::_new_internal(method, legacy_import)
+        Datastore::_new_internal(method, legacy_import, options)
     }
 
     pub fn new_in_memory(legacy_import: bool) -> Self {
+        Datastore::new_in_memory_with_options(legacy_import, DatastoreOptions::default())
+    }
+
+    pub fn new_in_memory_with_options(legacy_import: bool, options: DatastoreOptions) -> Self {
         let method = DatastoreMethod::Memory();
-        Datastore::_new_internal(method, legacy_import)
+        Datastore::_new_internal(method, legacy_import, options)
     }
 
-    fn _new_internal(method: DatastoreMethod, legacy_import: bool) -> Self {
+    fn _new_internal(
+        method: DatastoreMethod,
+        legacy_import: bool,
+        options: DatastoreOptions,
+    ) -> Self {
         let (requester, responder) =
-            mpsc_requests::channel::<Command, Result<Response, DatastoreError>>();
+            crate::requests::channel::<Command, Result<Response, DatastoreError>>();
+        let shared = Arc::new(SharedState::default());
+        let read_pool = match &method {
+            DatastoreMethod::Memory() => None,
+            DatastoreMethod::File(path) => Some(Arc::new(ReadPool::new(
+                path.to_string(),
+                READ_POOL_SIZE,
+                options.key.clone(),
+            ))),
+        };
+        let (ready_sender, ready_receiver) = mpsc::channel();
+        let worker_shared = shared.clone();
         let _thread = thread::spawn(move || {
-            let mut di = DatastoreWorker::new(responder, legacy_import);
-            di.work_loop(method);
+            let mut di = DatastoreWorker::new(responder, worker_shared, legacy_import, options);
+            di.work_loop(method, ready_sender);
         });
-        Datastore { requester }
+        // Wait for the worker to create the database before the read pool connects to it, if
+        // the worker fails the error is returned by the first request instead
+        let _ = ready_receiver.recv();
+        Datastore {
+            requester,
+            shared,
+            read_pool,
+        }
+    }
+
+    /// The read pool, unless the worker holds writes which the pool can't see yet
+    fn committed_read_pool(&self) -> Option<&ReadPool> {
+        match self.shared.uncommitted.load(Ordering::SeqCst) {
+            true => None,
+            false => self.read_pool.as_deref(),
+        }
     }
 
     pub fn create_bucket(&self, bucket: &Bucket) -> Result<(), DatastoreError> {
@@ -341,6 +950,12 @@ impl Datastore {
     }
 
     pub fn get_bucket(&self, bucket_id: &str) -> Result<Bucket, DatastoreError> {
+        if self.committed_read_pool().is_some() {
+            return match self.shared.buckets.read().unwrap().get(bucket_id) {
+                Some(bucket) => Ok(bucket.clone()),
+                None => Err(DatastoreError::NoSuchBucket(bucket_id.to_string())),
+            };
+        }
         let cmd = Command::GetBucket(bucket_id.to_string());
         let receiver = self.requester.request(cmd).unwrap();
         match receiver.collect().unwrap() {
@@ -353,6 +968,9 @@ impl Datastore {
     }
 
     pub fn get_buckets(&self) -> Result<HashMap<String, Bucket>, DatastoreError> {
+        if self.committed_read_pool().is_some() {
+            return Ok(self.shared.buckets.read().unwrap().clone());
+        }
         let cmd = Command::GetBuckets();
         let receiver = self.requester.request(cmd).unwrap();
         match receiver.collect().unwrap() {
@@ -400,6 +1018,12 @@ impl Datastore {
     }
 
     pub fn get_event(&self, bucket_id: &str, event_id: i64) -> Result<Event, DatastoreError> {
+        if let Some(read_pool) = self.committed_read_pool() {
+            return read_pool.read(|conn| {
+                let bucketrow = query_bucket_row(conn, bucket_id)?;
+                query_event(conn, bucketrow, event_id)
+            });
+        }
         let cmd = Command::GetEvent(bucket_id.to_string(), event_id);
         let receiver = self.requester.request(cmd).unwrap();
         match receiver.collect().unwrap() {
@@ -418,11 +1042,49 @@ impl Datastore {
         endtime_opt: Option<DateTime<Utc>>,
         limit_opt: Option<u64>,
     ) -> Result<Vec<Event>, DatastoreError> {
-        let cmd = Command::GetEvents(bucket_id.to_string(), starttime_opt, endtime_opt, limit_opt);
+        self.get_events_filtered(bucket_id, starttime_opt, endtime_opt, limit_opt, &[])
+    }
+
+    /// Like get_events, but only returns the events which match all of the filters
+    pub fn get_events_filtered(
+        &self,
+        bucket_id: &str,
+        starttime_opt: Option<DateTime<Utc>>,
+        endtime_opt: Option<DateTime<Utc>>,
+        limit_opt: Option<u64>,
+        filters: &[DataFilter],
+    ) -> Result<Vec<Event>, DatastoreError> {
+        let query = EventQuery {
+            starttime: starttime_opt,
+            endtime: endtime_opt,
+            limit: limit_opt,
+            filters: filters.to_vec(),
+            ..EventQuery::default()
+        };
+        Ok(self.get_events_page(bucket_id, &query)?.events)
+    }
+
+    /// Gets a page of events, the next page is gotten by repeating the query with the cursor
+    /// set to the next_cursor of the page
+    pub fn get_events_page(
+        &self,
+        bucket_id: &str,
+        query: &EventQuery,
+    ) -> Result<EventPage, DatastoreError> {
+        for filter in &query.filters {
+            filter.validate()?;
+        }
+        if let Some(read_pool) = self.committed_read_pool() {
+            return read_pool.read(|conn| {
+                let bucketrow = query_bucket_row(conn, bucket_id)?;
+                query_events(conn, bucketrow, bucket_id, query)
+            });
+        }
+        let cmd = Command::GetEvents(bucket_id.to_string(), query.clone());
         let receiver = self.requester.request(cmd).unwrap();
         match receiver.collect().unwrap() {
             Ok(r) => match r {
-                Response::EventList(el) => Ok(el),
+                Response::EventPage(page) => Ok(page),
                 _ => panic!("Invalid response"),
             },
             Err(e) => Err(e),
@@ -435,7 +1097,32 @@ impl Datastore {
         starttime_opt: Option<DateTime<Utc>>,
         endtime_opt: Option<DateTime<Utc>>,
     ) -> Result<i64, DatastoreError> {
-        let cmd = Command::GetEventCount(bucket_id.to_string(), starttime_opt, endtime_opt);
+        self.get_event_count_filtered(bucket_id, starttime_opt, endtime_opt, &[])
+    }
+
+    /// Like get_event_count, but only counts the events which match all of the filters
+    pub fn get_event_count_filtered(
+        &self,
+        bucket_id: &str,
+        starttime_opt: Option<DateTime<Utc>>,
+        endtime_opt: Option<DateTime<Utc>>,
+        filters: &[DataFilter],
+    ) -> Result<i64, DatastoreError> {
+        for filter in filters {
+            filter.validate()?;
+        }
+        if let Some(read_pool) = self.committed_read_pool() {
+            return read_pool.read(|conn| {
+                let bucketrow = query_bucket_row(conn, bucket_id)?;
+                query_event_count(conn, bucketrow, starttime_opt, endtime_opt, filters)
+            });
+        }
+        let cmd = Command::GetEventCount(
+            bucket_id.to_string(),
+            starttime_opt,
+            endtime_opt,
+            filters.to_vec(),
+        );
         let receiver = self.requester.request(cmd).unwrap();
         match receiver.collect().unwrap() {
             Ok(r) => match r {
@@ -462,6 +1149,188 @@ impl Datastore {
         }
     }
 
+    /// Deletes the events with the given origins, returning how many were deleted
+    pub fn delete_events_by_origin(
+        &self,
+        bucket_id: &str,
+        origins: Vec<String>,
+    ) -> Result<i64, DatastoreError> {
+        let cmd = Command::DeleteEventsByOrigin(bucket_id.to_string(), origins);
+        let receiver = self.requester.request(cmd).unwrap();
+        match receiver.collect().unwrap() {
+            Ok(r) => match r {
+                Response::Count(n) => Ok(n),
+                _ => panic!("Invalid response"),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Gets up to limit of the events which were inserted, modified or deleted after the resume
+    /// token in the order they were changed in, or from the first change if there is no token
+    ///
+    /// Only the last change of every event is kept, so an event which was changed again after the
+    /// token is only included once, at its last change.
+    pub fn get_event_changes(
+        &self,
+        bucket_id: &str,
+        token: Option<&str>,
+        limit: u64,
+    ) -> Result<EventChanges, DatastoreError> {
+        if let Some(read_pool) = self.committed_read_pool() {
+            return read_pool.read(|conn| {
+                let bucketrow = query_bucket_row(conn, bucket_id)?;
+                query_event_changes(conn, bucketrow, token, limit)
+            });
+        }
+        let cmd =
+            Command::GetEventChanges(bucket_id.to_string(), token.map(|t| t.to_string()), limit);
+        let receiver = self.requester.request(cmd).unwrap();
+        match receiver.collect().unwrap() {
+            Ok(r) => match r {
+                Response::EventChanges(changes) => Ok(changes),
+                _ => panic!("Invalid response"),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Deletes all events which overlap the time range, returning how many were deleted
+    pub fn delete_events_in_range(
+        &self,
+        bucket_id: &str,
+        starttime_opt: Option<DateTime<Utc>>,
+        endtime_opt: Option<DateTime<Utc>>,
+    ) -> Result<i64, DatastoreError> {
+        self.delete_events_filtered(bucket_id, starttime_opt, endtime_opt, &[])
+    }
+
+    /// Like delete_events_in_range, but only deletes the events which match all of the filters.
+    /// get_event_count_filtered with the same arguments tells how many events would be deleted.
+    pub fn delete_events_filtered(
+        &self,
+        bucket_id: &str,
+        starttime_opt: Option<DateTime<Utc>>,
+        endtime_opt: Option<DateTime<Utc>>,
+        filters: &[DataFilter],
+    ) -> Result<i64, DatastoreError> {
+        self.request_delete_events(bucket_id, starttime_opt, endtime_opt, filters, false)
+    }
+
+    /// Like delete_events_filtered, but the events which only partly overlap the time range are
+    /// cut off at it instead of deleted, so nothing outside of the range is lost. An event which
+    /// covers the whole range is split in two. The clipped events are included in the count.
+    pub fn delete_events_clipped(
+        &self,
+        bucket_id: &str,
+        starttime_opt: Option<DateTime<Utc>>,
+        endtime_opt: Option<DateTime<Utc>>,
+        filters: &[DataFilter],
+    ) -> Result<i64, DatastoreError> {
+        self.request_delete_events(bucket_id, starttime_opt, endtime_opt, filters, true)
+    }
+
+    fn request_delete_events(
+        &self,
+        bucket_id: &str,
+        starttime_opt: Option<DateTime<Utc>>,
+        endtime_opt: Option<DateTime<Utc>>,
+        filters: &[DataFilter],
+        clip: bool,
+    ) -> Result<i64, DatastoreError> {
+        for filter in filters {
+            filter.validate()?;
+        }
+        let cmd = Command::DeleteEvents(
+            bucket_id.to_string(),
+            starttime_opt,
+            endtime_opt,
+            filters.to_vec(),
+            clip,
+        );
+        let receiver = self.requester.request(cmd).unwrap();
+        match receiver.collect().unwrap() {
+            Ok(r) => match r {
+                Response::Count(n) => Ok(n),
+                _ => panic!("Invalid response"),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Replaces the timestamp, duration and data of the event with the given id, the id of the
+    /// passed event is ignored
+    ///
+    /// If the new event matches a redaction rule which drops events, the stored event is
+    /// deleted and EventDropped is returned.
+    pub fn update_event(
+        &self,
+        bucket_id: &str,
+        event_id: i64,
+        event: &Event,
+    ) -> Result<Event, DatastoreError> {
+        let cmd = Command::UpdateEvent(bucket_id.to_string(), event_id, event.clone());
+        let receiver = self.requester.request(cmd).unwrap();
+        match receiver.collect().unwrap() {
+            Ok(r) => match r {
+                Response::Event(e) => Ok(e),
+                _ => panic!("Invalid response"),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Applies the patch to the event with the given id, reading and updating it in the same
+    /// transaction so that no other write can come in between
+    ///
+    /// Like update_event, returns EventDropped if the event was deleted by a redaction rule.
+    pub fn patch_event(
+        &self,
+        bucket_id: &str,
+        event_id: i64,
+        patch: &EventPatch,
+    ) -> Result<Event, DatastoreError> {
+        let cmd = Command::PatchEvent(bucket_id.to_string(), event_id, patch.clone());
+        let receiver = self.requester.request(cmd).unwrap();
+        match receiver.collect().unwrap() {
+            Ok(r) => match r {
+                Response::Event(e) => Ok(e),
+                _ => panic!("Invalid response"),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns the event data fields which are indexed for the bucket
+    pub fn get_data_indexes(&self, bucket_id: &str) -> Result<Vec<String>, DatastoreError> {
+        let cmd = Command::GetDataIndexes(bucket_id.to_string());
+        let receiver = self.requester.request(cmd).unwrap();
+        match receiver.collect().unwrap() {
+            Ok(r) => match r {
+                Response::StringList(keys) => Ok(keys),
+                _ => panic!("Invalid response"),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Indexes a field in the data of the events in the bucket, so that filtering on it with
+    /// get_events_filtered doesn't have to scan every event in the time range
+    pub fn create_data_index(&self, bucket_id: &str, key: &str) -> Result<(), DatastoreError> {
+        let cmd = Command::CreateDataIndex(bucket_id.to_string(), key.to_string());
+        let receiver = self.requester.request(cmd).unwrap();
+
+        _unwrap_response(receiver)
+    }
+
+    pub fn delete_data_index(&self, bucket_id: &str, key: &str) -> Result<(), DatastoreError> {
+        let cmd = Command::DeleteDataIndex(bucket_id.to_string(), key.to_string());
+        let receiver = self.requester.request(cmd).unwrap();
+
+        _unwrap_response(receiver)
+    }
+
+    /// Commits all writes done so far, so that other connections to the database see them
     pub fn force_commit(&self) -> Result<(), DatastoreError> {
         let cmd = Command::ForceCommit();
         let receiver = self.requester.request(cmd).unwrap();
@@ -474,7 +1343,128 @@ impl Datastore {
         }
     }
 
+    /// Enforces the retention policies right away instead of waiting for the worker to do it
+    pub fn apply_retention(&self) -> Result<Vec<RetentionResult>, DatastoreError> {
+        self.request_retention(false)
+    }
+
+    /// Reports what enforcing the retention policies would do, without changing anything
+    pub fn retention_report(&self) -> Result<Vec<RetentionResult>, DatastoreError> {
+        self.request_retention(true)
+    }
+
+    fn request_retention(&self, dry_run: bool) -> Result<Vec<RetentionResult>, DatastoreError> {
+        let cmd = Command::ApplyRetention(dry_run);
+        let receiver = self.requester.request(cmd).unwrap();
+        match receiver.collect().unwrap() {
+            Ok(r) => match r {
+                Response::RetentionResults(results) => Ok(results),
+                _ => panic!("Invalid response"),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Rebuilds the database file to release unused space and updates the query planner
+    /// statistics, which commits any uncommitted writes first
+    pub fn vacuum(&self) -> Result<VacuumReport, DatastoreError> {
+        let cmd = Command::Vacuum();
+        let receiver = self.requester.request(cmd).unwrap();
+        match receiver.collect().unwrap() {
+            Ok(r) => match r {
+                Response::Vacuum(report) => Ok(report),
+                _ => panic!("Invalid response"),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes a consistent copy of the datastore to the path, including any uncommitted writes
+    /// which are committed first
+    ///
+    /// The copy is written next to the path and then renamed, so the path never holds a
+    /// partial backup.
+    pub fn backup(&self, path: &str) -> Result<(), DatastoreError> {
+        let cmd = Command::Backup(path.to_string());
+        let receiver = self.requester.request(cmd).unwrap();
+        _unwrap_response(receiver)
+    }
+
+    pub fn integrity_check(&self) -> Result<IntegrityReport, DatastoreError> {
+        let cmd = Command::IntegrityCheck();
+        let receiver = self.requester.request(cmd).unwrap();
+        match receiver.collect().unwrap() {
+            Ok(r) => match r {
+                Response::Integrity(report) => Ok(report),
+                _ => panic!("Invalid response"),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn get_database_stats(&self) -> Result<DatabaseStats, DatastoreError> {
+        let cmd = Command::GetDatabaseStats();
+        let receiver = self.requester.request(cmd).unwrap();
+        match receiver.collect().unwrap() {
+            Ok(r) => match r {
+                Response::DatabaseStats(stats) => Ok(stats),
+                _ => panic!("Invalid response"),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Finds events whose bucket doesn't exist, which are deleted as well if delete is set
+    pub fn find_orphaned_events(&self, delete: bool) -> Result<OrphanReport, DatastoreError> {
+        let cmd = Command::FindOrphanedEvents(delete);
+        let receiver = self.requester.request(cmd).unwrap();
+        match receiver.collect().unwrap() {
+            Ok(r) => match r {
+                Response::Orphans(report) => Ok(report),
+                _ => panic!("Invalid response"),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Recomputes the start and end of the buckets from their events
+    ///
+    /// They are computed when the datastore is opened and then kept up to date as events
+    /// change, except when events are deleted by ID, so this is only useful on a datastore
+    /// which has been running.
+    pub fn repair_bucket_metadata(&self) -> Result<Vec<MetadataRepair>, DatastoreError> {
+        let cmd = Command::RepairBucketMetadata();
+        let receiver = self.requester.request(cmd).unwrap();
+        match receiver.collect().unwrap() {
+            Ok(r) => match r {
+                Response::MetadataRepairs(repairs) => Ok(repairs),
+                _ => panic!("Invalid response"),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Starts a bulk load, during which nothing is committed unless a commit is forced
+    ///
+    /// Meant for large imports, which are a lot faster in a single transaction. Bulk loads
+    /// apply to the whole datastore and not only to this handle. The bulk load lasts until the
+    /// returned guard is ended or dropped, so it also ends if the import fails halfway or
+    /// panics. All reads go through the worker until it ends, as the read pool can't see its
+    /// writes.
+    pub fn begin_bulk_load(&self) -> Result<BulkLoad, DatastoreError> {
+        let cmd = Command::BeginBulkLoad();
+        let receiver = self.requester.request(cmd).unwrap();
+
+        _unwrap_response(receiver)?;
+        Ok(BulkLoad {
+            requester: Some(self.requester.clone()),
+        })
+    }
+
     pub fn get_key_values(&self, pattern: &str) -> Result<HashMap<String, String>, DatastoreError> {
+        if let Some(read_pool) = self.committed_read_pool() {
+            return read_pool.read(|conn| query_key_values(conn, pattern));
+        }
         let cmd = Command::GetKeyValues(pattern.to_string());
         let receiver = self.requester.request(cmd).unwrap();
 
@@ -488,6 +1478,9 @@ impl Datastore {
     }
 
     pub fn get_key_value(&self, key: &str) -> Result<String, DatastoreError> {
+        if let Some(read_pool) = self.committed_read_pool() {
+            return read_pool.read(|conn| query_key_value(conn, key));
+        }
         let cmd = Command::GetKeyValue(key.to_string());
         let receiver = self.requester.request(cmd).unwrap();
 
@@ -514,9 +1507,80 @@ impl Datastore {
         _unwrap_response(receiver)
     }
 
+    /// Get a snapshot of the revision of every bucket as of the last commit
+    ///
+    /// The snapshot can later be passed to buckets_modified_since to find out if data derived
+    /// from the buckets in a time interval is still up to date. Reads made after taking the
+    /// snapshot see at least the writes it includes, so the data is never newer than the
+    /// snapshot claims, only sometimes older.
+    pub fn get_bucket_revisions(&self) -> Result<HashMap<String, u64>, DatastoreError> {
+        Ok(self.shared.revisions.read().unwrap().clone())
+    }
+
+    /// Returns true if buckets have been created or deleted since the revisions snapshot was
+    /// taken, or if events between starttime and endtime have been modified in any of them
+    pub fn buckets_modified_since(
+        &self,
+        revisions: &HashMap<String, u64>,
+        starttime: DateTime<Utc>,
+        endtime: DateTime<Utc>,
+    ) -> Result<bool, DatastoreError> {
+        let cmd = Command::BucketsModifiedSince(revisions.clone(), starttime, endtime);
+        let receiver = self.requester.request(cmd).unwrap();
+        match receiver.collect().unwrap() {
+            Ok(r) => match r {
+                Response::Bool(modified) => Ok(modified),
+                _ => panic!("Invalid response"),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn get_stored_queries(&self) -> Result<HashMap<String, StoredQuery>, DatastoreError> {
+        let cmd = Command::GetStoredQueries();
+        let receiver = self.requester.request(cmd).unwrap();
+        match receiver.collect().unwrap() {
+            Ok(r) => match r {
+                Response::StoredQueries(queries) => Ok(queries),
+                _ => panic!("Invalid response"),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn get_stored_query(&self, name: &str) -> Result<StoredQuery, DatastoreError> {
+        let cmd = Command::GetStoredQuery(name.to_string());
+        let receiver = self.requester.request(cmd).unwrap();
+        match receiver.collect().unwrap() {
+            Ok(r) => match r {
+                Response::StoredQuery(query) => Ok(query),
+                _ => panic!("Invalid response"),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Stores the query under query.name, replacing any previous query with that name
+    pub fn set_stored_query(&self, query: &StoredQuery) -> Result<(), DatastoreError> {
+        let cmd = Command::SetStoredQuery(query.clone());
+        let receiver = self.requester.request(cmd).unwrap();
+
+        _unwrap_response(receiver)
+    }
+
+    pub fn delete_stored_query(&self, name: &str) -> Result<(), DatastoreError> {
+        let cmd = Command::DeleteStoredQuery(name.to_string());
+        let receiver = self.requester.request(cmd).unwrap();
+
+        _unwrap_response(receiver)
+    }
+
     // Should block until worker has stopped
     pub fn close(&self) {
         info!("Sending close request to database");
+        if let Some(read_pool) = &self.read_pool {
+            read_pool.close();
+        }
         let receiver = self.requester.request(Command::Close()).unwrap();
 
         match receiver.collect().unwrap() {
@@ -528,3 +1592,33 @@ impl Datastore {
         }
     }
 }
+
+/// A bulk load in progress, see Datastore::begin_bulk_load
+#[must_use = "the bulk load ends as soon as it is dropped"]
+pub struct BulkLoad {
+    requester: Option<RequestSender>,
+}
+
+impl BulkLoad {
+    /// Ends the bulk load, committing everything written during it once no other bulk load is
+    /// in progress
+    pub fn end(mut self) -> Result<(), DatastoreError> {
+        let requester = self.requester.take().unwrap();
+        let receiver = requester.request(Command::EndBulkLoad()).unwrap();
+
+        _unwrap_response(receiver)
+    }
+}
+
+impl Drop for BulkLoad {
+    fn drop(&mut self) {
+        if let Some(requester) = self.requester.take() {
+            // The worker might be gone already if this is dropped while unwinding
+            if let Ok(receiver) = requester.request(Command::EndBulkLoad()) {
+                if let Ok(Err(err)) = receiver.collect() {
+                    error!("Failed to end bulk load: {err:?}");
+                }
+            }
+        }
+    }
+}
@@ -9,15 +9,31 @@ extern crate appdirs;
 
 #[cfg(test)]
 mod datastore_tests {
+    use chrono::DateTime;
     use chrono::Duration;
+    use chrono::DurationRound;
     use chrono::Utc;
     use serde_json::json;
 
+    use aw_datastore::DataFilter;
     use aw_datastore::Datastore;
+    use aw_datastore::DatastoreError;
+    use aw_datastore::DatastoreOptions;
+    use aw_datastore::EventCursor;
+    use aw_datastore::EventQuery;
+    use aw_datastore::RedactionAction;
+    use aw_datastore::RedactionRule;
+    use aw_datastore::RetentionAction;
+    use aw_datastore::RetentionPolicy;
+    use aw_datastore::SyncMode;
 
     use aw_models::Bucket;
     use aw_models::BucketMetadata;
     use aw_models::Event;
+    use aw_models::EventOrder;
+    use aw_models::EventPatch;
+    use aw_models::StoredQuery;
+    use aw_models::TryVec;
 
     fn test_bucket() -> Bucket {
         Bucket {
@@ -131,6 +147,7 @@ mod datastore_tests {
             timestamp: Utc::now(),
             duration: Duration::seconds(0),
             data: json_map! {"key": json!("value")},
+            origin: None,
         };
         let mut e2 = e1.clone();
         e2.timestamp += Duration::nanoseconds(1);
@@ -159,6 +176,7 @@ mod datastore_tests {
             timestamp: Utc::now(),
             duration: Duration::seconds(0),
             data: json_map! {"key": json!("value")},
+            origin: None,
         };
         let mut e2 = e1.clone();
         e2.timestamp += Duration::nanoseconds(1);
@@ -210,6 +228,236 @@ mod datastore_tests {
     }
 
     /// Tests that events that cover a timeperiod get included when that timeperiod is queried.
+    fn insert_data_filter_events(ds: &Datastore, bucket: &Bucket) {
+        let datas = [
+            json_map! {"app": json!("firefox"), "title": json!("GitHub - aw-server"), "n": json!(1)},
+            json_map! {"app": json!("firefox"), "title": json!("News"), "n": json!(2.5)},
+            json_map! {"app": json!("code"), "title": json!("main.rs"), "n": json!(true)},
+            json_map! {"app": json!(null), "title": json!(1), "n": json!("1")},
+            json_map! {"title": json!("github.com")},
+        ];
+        let events: Vec<Event> = datas
+            .into_iter()
+            .enumerate()
+            .map(|(i, data)| Event {
+                id: None,
+                timestamp: Utc::now() + Duration::seconds(i as i64),
+                duration: Duration::seconds(1),
+                data,
+                origin: None,
+            })
+            .collect();
+        ds.insert_events(&bucket.id, &events).unwrap();
+        // Reads from the read pool only see committed events
+        ds.force_commit().unwrap();
+    }
+
+    fn filtered_titles(ds: &Datastore, bucket: &Bucket, filters: &[DataFilter]) -> Vec<String> {
+        let mut titles: Vec<String> = ds
+            .get_events_filtered(&bucket.id, None, None, None, filters)
+            .unwrap()
+            .iter()
+            .map(|e| e.data["title"].to_string())
+            .collect();
+        titles.sort();
+        titles
+    }
+
+    fn test_data_filters(ds: &Datastore) {
+        let bucket = create_test_bucket(ds);
+        insert_data_filter_events(ds, &bucket);
+
+        let eq = |key: &str, value| DataFilter::Equals(key.to_string(), value);
+        let re = |key: &str, pattern: &str| DataFilter::Regex(key.to_string(), pattern.to_string());
+        assert_eq!(
+            filtered_titles(ds, &bucket, &[eq("app", json!("firefox"))]),
+            vec![r#""GitHub - aw-server""#, r#""News""#]
+        );
+        // Types have to match, so true is not 1 and "1" is not 1
+        assert_eq!(
+            filtered_titles(ds, &bucket, &[eq("n", json!(1))]),
+            vec![r#""GitHub - aw-server""#]
+        );
+        assert_eq!(
+            filtered_titles(ds, &bucket, &[eq("n", json!(2.5))]),
+            vec![r#""News""#]
+        );
+        assert_eq!(
+            filtered_titles(ds, &bucket, &[eq("n", json!(true))]),
+            vec![r#""main.rs""#]
+        );
+        assert_eq!(
+            filtered_titles(ds, &bucket, &[eq("n", json!("1"))]),
+            vec!["1"]
+        );
+        // A null value is not the same as a missing key
+        assert_eq!(
+            filtered_titles(ds, &bucket, &[eq("app", json!(null))]),
+            vec!["1"]
+        );
+        // Regexes only match strings
+        assert_eq!(
+            filtered_titles(ds, &bucket, &[re("title", "(?i)github")]),
+            vec![r#""GitHub - aw-server""#, r#""github.com""#]
+        );
+        assert_eq!(
+            filtered_titles(ds, &bucket, &[re("title", "^1$")]),
+            Vec::<String>::new()
+        );
+        // All filters have to match
+        assert_eq!(
+            filtered_titles(
+                ds,
+                &bucket,
+                &[eq("app", json!("firefox")), re("title", "^N")]
+            ),
+            vec![r#""News""#]
+        );
+        // Filters are applied before the limit
+        let events = ds
+            .get_events_filtered(&bucket.id, None, None, Some(1), &[eq("app", json!("code"))])
+            .unwrap();
+        assert_eq!(events.len(), 1);
+
+        for filter in [
+            re("title", "("),
+            eq("title", json!(["a"])),
+            eq("", json!("a")),
+            eq("a\"b", json!("a")),
+        ] {
+            match ds.get_events_filtered(&bucket.id, None, None, None, &[filter]) {
+                Err(DatastoreError::InvalidFilter(_)) => (),
+                res => panic!("Expected InvalidFilter, got {res:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_events_get_data_filters() {
+        // Through the worker
+        let ds = Datastore::new_in_memory(false);
+        test_data_filters(&ds);
+        // Through the read pool
+        let (ds, _) = new_file_datastore("datastore-filters-unittest.db");
+        test_data_filters(&ds);
+    }
+
+    // Walks all events in the bucket in pages of page_size, returning the ids in the order seen
+    fn walk_pages(ds: &Datastore, bucket: &Bucket, order: EventOrder, page_size: u64) -> Vec<i64> {
+        let mut ids = Vec::new();
+        let mut query = EventQuery {
+            limit: Some(page_size),
+            order,
+            ..EventQuery::default()
+        };
+        loop {
+            let page = ds.get_events_page(&bucket.id, &query).unwrap();
+            assert!(page.events.len() as u64 <= page_size);
+            ids.extend(page.events.iter().map(|e| e.id.unwrap()));
+            match page.next_cursor {
+                // Cursors are passed around as strings
+                Some(cursor) => query.cursor = Some(cursor.to_string().parse().unwrap()),
+                None => break,
+            }
+        }
+        ids
+    }
+
+    fn test_pagination(ds: &Datastore) {
+        let bucket = create_test_bucket(ds);
+        let now = Utc::now();
+        // Several events share a timestamp, so ids have to break the ties
+        let events: Vec<Event> = [0, 1, 1, 1, 2, 3, 3]
+            .iter()
+            .map(|s| Event {
+                id: None,
+                timestamp: now + Duration::seconds(*s),
+                duration: Duration::seconds(0),
+                data: json_map! {},
+                origin: None,
+            })
+            .collect();
+        let inserted = ds.insert_events(&bucket.id, &events).unwrap();
+        ds.force_commit().unwrap();
+        let mut ascending: Vec<(i64, _)> = inserted
+            .iter()
+            .map(|e| (e.id.unwrap(), e.timestamp))
+            .collect();
+        ascending.sort_by_key(|(id, timestamp)| (*timestamp, *id));
+        let ascending: Vec<i64> = ascending.into_iter().map(|(id, _)| id).collect();
+        // Events with the same timestamp stay in insertion order when newest first
+        let mut descending: Vec<(i64, _)> = inserted
+            .iter()
+            .map(|e| (e.id.unwrap(), e.timestamp))
+            .collect();
+        descending.sort_by_key(|(id, timestamp)| (std::cmp::Reverse(*timestamp), *id));
+        let descending: Vec<i64> = descending.into_iter().map(|(id, _)| id).collect();
+
+        for page_size in [1, 2, 3, 7, 10] {
+            assert_eq!(
+                walk_pages(ds, &bucket, EventOrder::Ascending, page_size),
+                ascending
+            );
+            assert_eq!(
+                walk_pages(ds, &bucket, EventOrder::Descending, page_size),
+                descending
+            );
+        }
+        // The default order is newest first
+        let page = ds
+            .get_events_page(&bucket.id, &EventQuery::default())
+            .unwrap();
+        let ids: Vec<i64> = page.events.iter().map(|e| e.id.unwrap()).collect();
+        assert_eq!(ids, descending);
+        assert_eq!(page.next_cursor, None);
+
+        // Events inserted behind the cursor are not seen, events ahead of it are
+        let mut query = EventQuery {
+            limit: Some(3),
+            order: EventOrder::Ascending,
+            ..EventQuery::default()
+        };
+        let page = ds.get_events_page(&bucket.id, &query).unwrap();
+        query.cursor = page.next_cursor;
+        let behind = Event {
+            id: None,
+            timestamp: now,
+            duration: Duration::seconds(0),
+            data: json_map! {},
+            origin: None,
+        };
+        let ahead = Event {
+            timestamp: now + Duration::seconds(4),
+            ..behind.clone()
+        };
+        ds.insert_events(&bucket.id, &[behind, ahead]).unwrap();
+        ds.force_commit().unwrap();
+        let page = ds.get_events_page(&bucket.id, &query).unwrap();
+        let ids: Vec<i64> = page.events.iter().map(|e| e.id.unwrap()).collect();
+        assert_eq!(ids, ascending[3..6]);
+        query.cursor = page.next_cursor;
+        let page = ds.get_events_page(&bucket.id, &query).unwrap();
+        assert_eq!(page.events.len(), 2);
+        assert_eq!(page.events[1].timestamp, now + Duration::seconds(4));
+    }
+
+    #[test]
+    fn test_events_page() {
+        let ds = Datastore::new_in_memory(false);
+        test_pagination(&ds);
+        let (ds, _) = new_file_datastore("datastore-pagination-unittest.db");
+        test_pagination(&ds);
+    }
+
+    #[test]
+    fn test_event_cursor_parse() {
+        let cursor: EventCursor = "1a2b.3".parse().unwrap();
+        assert_eq!(cursor.to_string(), "1a2b.3");
+        assert!("".parse::<EventCursor>().is_err());
+        assert!("1a2b".parse::<EventCursor>().is_err());
+        assert!("1a2b.x".parse::<EventCursor>().is_err());
+    }
+
     #[test]
     fn test_get_events_filters_cover() {
         // TODO: Also test event-cutoff, although perhaps that happens in the transforms/queries?
@@ -226,6 +474,7 @@ mod datastore_tests {
             timestamp: Utc::now(),
             duration: Duration::seconds(100),
             data: json_map! {"key": json!("value")},
+            origin: None,
         };
 
         let event_list = [e1];
@@ -258,6 +507,7 @@ mod datastore_tests {
             timestamp: Utc::now(),
             duration: Duration::seconds(0),
             data: json_map! {"key": json!("value")},
+            origin: None,
         };
         let mut e2 = e1.clone();
         e2.timestamp += Duration::seconds(1);
@@ -310,6 +560,7 @@ mod datastore_tests {
             timestamp: Utc::now(),
             duration: Duration::seconds(0),
             data: json_map! {"key": json!("value")},
+            origin: None,
         };
         let mut e2 = e1.clone();
         e2.timestamp += Duration::nanoseconds(1);
@@ -336,6 +587,7 @@ mod datastore_tests {
             timestamp: Utc::now(),
             duration: Duration::seconds(0),
             data: json_map! {"key": json!("value")},
+            origin: None,
         };
         let mut e2 = e1.clone();
         e2.timestamp += Duration::seconds(1);
@@ -385,6 +637,7 @@ mod datastore_tests {
             timestamp: Utc::now(),
             duration: Duration::seconds(0),
             data: json_map! {"key": json!("value")},
+            origin: None,
         };
         let mut e1 = e.clone();
         e1.data = json_map! {"key": json!("value1")};
@@ -434,64 +687,1684 @@ mod datastore_tests {
     }
 
     #[test]
-    fn test_datastore_reload() {
-        // Create tmp datastore path
-        let mut db_path = get_cache_dir().unwrap();
-        db_path.push("datastore-unittest.db");
-        let db_path_str = db_path.to_str().unwrap().to_string();
+    fn test_event_replace_by_origin() {
+        // Setup datastore
+        let ds = Datastore::new_in_memory(false);
+        let bucket = create_test_bucket(&ds);
+        let bucket2 = Bucket {
+            id: "testid2".to_string(),
+            ..bucket.clone()
+        };
+        ds.create_bucket(&bucket2).unwrap();
 
-        if db_path.exists() {
-            std::fs::remove_file(db_path.clone())
-                .expect("Failed to remove datastore-unittest.db file");
+        let now = Utc::now();
+        let e1 = Event {
+            id: None,
+            timestamp: now,
+            duration: Duration::seconds(1),
+            data: json_map! {"key": json!("value1")},
+            origin: Some("device:1".to_string()),
+        };
+        let e2 = Event {
+            timestamp: now + Duration::seconds(1),
+            origin: Some("device:2".to_string()),
+            ..e1.clone()
+        };
+        let events_ret = ds
+            .insert_events(&bucket.id, &[e1.clone(), e2.clone()])
+            .unwrap();
+        let fetched_events = ds.get_events(&bucket.id, None, None, None).unwrap();
+        assert_eq!(fetched_events.len(), 2);
+        assert_eq!(fetched_events[1].origin, e1.origin);
+        assert_eq!(fetched_events[0].origin, e2.origin);
+
+        // Inserting an event with a known origin updates the event instead, even when it is moved
+        let e1_modified = Event {
+            timestamp: now - Duration::seconds(10),
+            duration: Duration::seconds(5),
+            data: json_map! {"key": json!("value1_modified")},
+            ..e1.clone()
+        };
+        let events_ret2 = ds
+            .insert_events(&bucket.id, std::slice::from_ref(&e1_modified))
+            .unwrap();
+        assert_eq!(events_ret2[0].id, events_ret[0].id);
+        let fetched_events = ds.get_events(&bucket.id, None, None, None).unwrap();
+        assert_eq!(fetched_events.len(), 2);
+        assert_eq!(fetched_events[1], e1_modified);
+        assert_eq!(fetched_events[1].id, events_ret[0].id);
+        assert_eq!(
+            ds.get_event(&bucket.id, events_ret[0].id.unwrap())
+                .unwrap()
+                .origin,
+            e1.origin
+        );
+
+        // Origins only have to be unique within a bucket, and events without one never conflict
+        ds.insert_events(&bucket2.id, std::slice::from_ref(&e1))
+            .unwrap();
+        assert_eq!(ds.get_event_count(&bucket2.id, None, None).unwrap(), 1);
+        let no_origin = Event {
+            origin: None,
+            ..e2.clone()
+        };
+        ds.insert_events(&bucket.id, &[no_origin.clone(), no_origin])
+            .unwrap();
+        assert_eq!(ds.get_event_count(&bucket.id, None, None).unwrap(), 4);
+    }
+
+    #[test]
+    fn test_event_changes() {
+        // Setup datastore
+        let ds = Datastore::new_in_memory(false);
+        let bucket = create_test_bucket(&ds);
+
+        let changes = ds.get_event_changes(&bucket.id, None, 10).unwrap();
+        assert!(changes.events.is_empty());
+        assert!(!changes.more);
+        let empty_token = changes.token;
+
+        let now = Utc::now();
+        let events: Vec<Event> = (0..5)
+            .map(|i| Event {
+                id: None,
+                timestamp: now + Duration::seconds(i),
+                duration: Duration::seconds(1),
+                data: json_map! {"i": json!(i)},
+                origin: None,
+            })
+            .collect();
+        let inserted = ds.insert_events(&bucket.id, &events).unwrap();
+
+        // Changes are listed in batches in the order they were made
+        let changes = ds.get_event_changes(&bucket.id, None, 3).unwrap();
+        assert_eq!(changes.events, events[..3]);
+        assert!(changes.more);
+        let changes = ds
+            .get_event_changes(&bucket.id, Some(&changes.token), 3)
+            .unwrap();
+        assert_eq!(changes.events, events[3..]);
+        assert!(!changes.more);
+        let token = changes.token;
+        assert_eq!(
+            ds.get_event_changes(&bucket.id, Some(&empty_token), 10)
+                .unwrap()
+                .events
+                .len(),
+            5
+        );
+
+        // Modified events are listed again, deleted events as deleted
+        let e0_modified = Event {
+            data: json_map! {"i": json!("modified")},
+            ..inserted[0].clone()
+        };
+        ds.insert_events(&bucket.id, std::slice::from_ref(&e0_modified))
+            .unwrap();
+        ds.delete_events_by_id(&bucket.id, vec![inserted[1].id.unwrap()])
+            .unwrap();
+        let changes = ds.get_event_changes(&bucket.id, Some(&token), 10).unwrap();
+        assert_eq!(changes.events, vec![e0_modified]);
+        assert_eq!(changes.events[0].id, inserted[0].id);
+        assert_eq!(changes.deleted.len(), 1);
+        assert_eq!(changes.deleted[0].id, inserted[1].id.unwrap());
+        assert_eq!(changes.deleted[0].origin, None);
+        let token = changes.token;
+
+        // Deletions keep the origin of the event so it can be deleted where it was synced to
+        let synced = Event {
+            origin: Some("device:1".to_string()),
+            ..events[2].clone()
+        };
+        ds.insert_events(&bucket.id, &[synced]).unwrap();
+        assert_eq!(
+            ds.delete_events_by_origin(&bucket.id, vec!["device:1".to_string()])
+                .unwrap(),
+            1
+        );
+        let changes = ds.get_event_changes(&bucket.id, Some(&token), 10).unwrap();
+        assert!(changes.events.is_empty());
+        assert_eq!(changes.deleted[0].origin.as_deref(), Some("device:1"));
+
+        // Tokens which weren't given out by this database are refused
+        for invalid in ["abc", "-1", "1000000"] {
+            match ds.get_event_changes(&bucket.id, Some(invalid), 10) {
+                Err(DatastoreError::InvalidToken(_)) => (),
+                res => panic!("Expected InvalidToken, got {res:?}"),
+            }
         }
 
-        let empty_bucket = test_bucket();
-        let mut populated_bucket = empty_bucket.clone();
-        populated_bucket.id = "testid2".to_string();
+        // The changes are gone with the bucket
+        ds.delete_bucket(&bucket.id).unwrap();
+        let bucket = create_test_bucket(&ds);
+        let changes = ds.get_event_changes(&bucket.id, None, 10).unwrap();
+        assert!(changes.events.is_empty());
+        assert!(changes.deleted.is_empty());
+    }
+
+    #[test]
+    fn test_event_changes_heartbeat() {
+        let ds = Datastore::new_in_memory(false);
+        let bucket = create_test_bucket(&ds);
+
+        let now = Utc::now();
+        let event = Event {
+            id: None,
+            timestamp: now,
+            duration: Duration::seconds(0),
+            data: json_map! {"key": json!("value")},
+            origin: None,
+        };
+        ds.heartbeat(&bucket.id, event.clone(), 10.0).unwrap();
+        let token = ds.get_event_changes(&bucket.id, None, 10).unwrap().token;
+
+        // Every heartbeat merged into the event moves its change to the end
+        for i in 1..4 {
+            let heartbeat = Event {
+                timestamp: now + Duration::seconds(i),
+                ..event.clone()
+            };
+            ds.heartbeat(&bucket.id, heartbeat, 10.0).unwrap();
+        }
+        let changes = ds.get_event_changes(&bucket.id, Some(&token), 10).unwrap();
+        assert_eq!(changes.events.len(), 1);
+        assert_eq!(changes.events[0].duration, Duration::seconds(3));
+        assert!(changes.token.parse::<i64>().unwrap() > token.parse::<i64>().unwrap());
+        assert!(ds
+            .get_event_changes(&bucket.id, Some(&changes.token), 10)
+            .unwrap()
+            .events
+            .is_empty());
+    }
+
+    #[test]
+    fn test_event_changes_pruning() {
+        let options = DatastoreOptions {
+            change_horizon: std::time::Duration::ZERO,
+            ..DatastoreOptions::default()
+        };
+        let ds = Datastore::new_in_memory_with_options(false, options);
+        let bucket = create_test_bucket(&ds);
+
+        let now = Utc::now();
+        let events: Vec<Event> = (0..3)
+            .map(|i| Event {
+                id: None,
+                timestamp: now + Duration::seconds(i),
+                duration: Duration::seconds(1),
+                data: json_map! {"i": json!(i)},
+                origin: None,
+            })
+            .collect();
+        let inserted = ds.insert_events(&bucket.id, &events).unwrap();
+        let token = ds.get_event_changes(&bucket.id, None, 10).unwrap().token;
+        ds.delete_events_by_id(&bucket.id, vec![inserted[0].id.unwrap()])
+            .unwrap();
+        let token_after_delete = ds
+            .get_event_changes(&bucket.id, Some(&token), 10)
+            .unwrap()
+            .token;
+
+        // The deleted event is forgotten, tokens from before it was deleted are refused
+        ds.apply_retention().unwrap();
+        match ds.get_event_changes(&bucket.id, Some(&token), 10) {
+            Err(DatastoreError::InvalidToken(_)) => (),
+            res => panic!("Expected InvalidToken, got {res:?}"),
+        }
+        let changes = ds.get_event_changes(&bucket.id, None, 10).unwrap();
+        assert_eq!(changes.events.len(), 2);
+        assert!(changes.deleted.is_empty());
+
+        // Later tokens are still fine
+        ds.delete_events_by_id(&bucket.id, vec![inserted[1].id.unwrap()])
+            .unwrap();
+        let changes = ds
+            .get_event_changes(&bucket.id, Some(&token_after_delete), 10)
+            .unwrap();
+        assert_eq!(changes.deleted.len(), 1);
+        assert_eq!(changes.deleted[0].id, inserted[1].id.unwrap());
+    }
+
+    #[test]
+    fn test_event_update() {
+        // Setup datastore
+        let ds = Datastore::new_in_memory(false);
+        let bucket = create_test_bucket(&ds);
+
+        let now = Utc::now();
+        let e1 = Event {
+            id: None,
+            timestamp: now,
+            duration: Duration::seconds(1),
+            data: json_map! {"key": json!("value1")},
+            origin: None,
+        };
+        let e2 = Event {
+            id: None,
+            timestamp: now + Duration::seconds(1),
+            duration: Duration::seconds(10),
+            data: json_map! {"key": json!("value2")},
+            origin: None,
+        };
+        let inserted = ds.insert_events(&bucket.id, &[e1, e2]).unwrap();
+        let e2_id = inserted[1].id.unwrap();
+
+        // The id of the passed event doesn't matter
+        let mut e2_updated = inserted[1].clone();
+        e2_updated.id = None;
+        e2_updated.duration = Duration::seconds(2);
+        e2_updated.data = json_map! {"key": json!("value2"), "label": json!("work")};
+        let ret = ds.update_event(&bucket.id, e2_id, &e2_updated).unwrap();
+        assert_eq!(ret.id, Some(e2_id));
+        assert_eq!(ret, e2_updated);
+        let fetched = ds.get_event(&bucket.id, e2_id).unwrap();
+        assert_eq!(fetched, e2_updated);
+        assert_eq!(ds.get_event_count(&bucket.id, None, None).unwrap(), 2);
+
+        // The event was at the end of the bucket, which has to move back with it
+        let bucket_fetched = ds.get_bucket(&bucket.id).unwrap();
+        assert_eq!(bucket_fetched.metadata.start, Some(now));
+        assert_eq!(
+            bucket_fetched.metadata.end,
+            Some(e2_updated.calculate_endtime())
+        );
+
+        // Moving it before the start of the bucket moves the start
+        let mut e2_moved = e2_updated.clone();
+        e2_moved.timestamp = now - Duration::seconds(5);
+        ds.update_event(&bucket.id, e2_id, &e2_moved).unwrap();
+        let bucket_fetched = ds.get_bucket(&bucket.id).unwrap();
+        assert_eq!(bucket_fetched.metadata.start, Some(e2_moved.timestamp));
+        assert_eq!(
+            bucket_fetched.metadata.end,
+            Some(now + Duration::seconds(1))
+        );
+
+        match ds.update_event(&bucket.id, 1000, &e2_updated) {
+            Err(DatastoreError::NoSuchEvent(id)) => assert_eq!(id, 1000),
+            res => panic!("Expected NoSuchEvent, got {res:?}"),
+        }
+        match ds.update_event("nosuchbucket", e2_id, &e2_updated) {
+            Err(DatastoreError::NoSuchBucket(_)) => (),
+            res => panic!("Expected NoSuchBucket, got {res:?}"),
+        }
+    }
+
+    #[test]
+    fn test_event_patch() {
+        let options = DatastoreOptions {
+            commit_interval: std::time::Duration::from_secs(3600),
+            ..DatastoreOptions::default()
+        };
+        let (ds, _) = new_file_datastore_with_options("datastore-patch-unittest.db", options);
+        let bucket = create_test_bucket(&ds);
+        let inserted = ds.insert_events(&bucket.id, &[single_event(0)]).unwrap();
+        let id = inserted[0].id.unwrap();
+
+        // Patches made before the event is committed apply on top of each other
+        let patch = |data| EventPatch {
+            data: Some(data),
+            ..EventPatch::default()
+        };
+        ds.patch_event(&bucket.id, id, &patch(json_map! {"a": json!(1)}))
+            .unwrap();
+        let patched = ds
+            .patch_event(&bucket.id, id, &patch(json_map! {"b": json!(2)}))
+            .unwrap();
+        assert_eq!(patched.data, json_map! {"a": json!(1), "b": json!(2)});
+        assert_eq!(patched.timestamp, inserted[0].timestamp);
+        assert_eq!(ds.get_event(&bucket.id, id).unwrap(), patched);
+
+        match ds.patch_event(&bucket.id, 1000, &patch(json_map! {})) {
+            Err(DatastoreError::NoSuchEvent(id)) => assert_eq!(id, 1000),
+            res => panic!("Expected NoSuchEvent, got {res:?}"),
+        }
+    }
+
+    #[test]
+    fn test_event_update_heartbeat() {
+        let ds = Datastore::new_in_memory(false);
+        let bucket = create_test_bucket(&ds);
+
         let e1 = Event {
             id: None,
             timestamp: Utc::now(),
             duration: Duration::seconds(0),
             data: json_map! {"key": json!("value")},
+            origin: None,
         };
-        {
-            // Initialize database and create buckets
-            let ds = Datastore::new(db_path_str.clone(), false);
-            ds.create_bucket(&empty_bucket).unwrap();
-            ds.create_bucket(&populated_bucket).unwrap();
-            // Insert event
-            ds.insert_events(&populated_bucket.id, &[e1.clone()])
-                .unwrap();
+        ds.heartbeat(&bucket.id, e1.clone(), 10.0).unwrap();
+        let hb = ds
+            .get_events(&bucket.id, None, None, None)
+            .unwrap()
+            .remove(0);
 
-            // Check that all cached bucket data is correct
-            let buckets = ds.get_buckets().unwrap();
-            assert_eq!(buckets[&empty_bucket.id].metadata.start, None);
-            assert_eq!(buckets[&empty_bucket.id].metadata.end, None);
-            assert_eq!(
-                buckets[&populated_bucket.id].metadata.start,
-                Some(e1.calculate_endtime())
-            );
-            assert_eq!(
-                buckets[&populated_bucket.id].metadata.end,
-                Some(e1.timestamp)
-            );
-            ds.force_commit().unwrap();
+        // Relabel the last heartbeat, a heartbeat with the old data must not merge into it
+        let mut relabeled = hb.clone();
+        relabeled.data = json_map! {"key": json!("relabeled")};
+        ds.update_event(&bucket.id, hb.id.unwrap(), &relabeled)
+            .unwrap();
+        let mut e2 = e1;
+        e2.timestamp += Duration::seconds(1);
+        ds.heartbeat(&bucket.id, e2, 10.0).unwrap();
+        let fetched_events = ds.get_events(&bucket.id, None, None, None).unwrap();
+        assert_eq!(fetched_events.len(), 2);
+        assert_eq!(fetched_events[1], relabeled);
+    }
+
+    #[test]
+    fn test_delete_events() {
+        let ds = Datastore::new_in_memory(false);
+        let bucket = create_test_bucket(&ds);
+
+        let now = Utc::now();
+        let events: Vec<Event> = ["a", "bank", "b", "bank", "c"]
+            .iter()
+            .enumerate()
+            .map(|(i, title)| Event {
+                id: None,
+                timestamp: now + Duration::seconds(10 * i as i64),
+                duration: Duration::seconds(5),
+                data: json_map! {"title": json!(title)},
+                origin: None,
+            })
+            .collect();
+        ds.insert_events(&bucket.id, &events).unwrap();
+
+        // Events which overlap the range count, the counts match what would be deleted
+        let start = Some(now + Duration::seconds(4));
+        let end = Some(now + Duration::seconds(20));
+        assert_eq!(ds.get_event_count(&bucket.id, start, end).unwrap(), 3);
+        let bank = [DataFilter::Regex("title".to_string(), "^bank$".to_string())];
+        assert_eq!(
+            ds.get_event_count_filtered(&bucket.id, None, None, &bank)
+                .unwrap(),
+            2
+        );
+
+        ds.force_commit().unwrap();
+        let revisions = ds.get_bucket_revisions().unwrap();
+        assert_eq!(
+            ds.delete_events_filtered(&bucket.id, None, None, &bank)
+                .unwrap(),
+            2
+        );
+        assert_eq!(filtered_titles(&ds, &bucket, &bank).len(), 0);
+        assert_eq!(ds.get_event_count(&bucket.id, None, None).unwrap(), 3);
+        // Only the time range of the deleted events has been modified
+        assert!(ds
+            .buckets_modified_since(
+                &revisions,
+                now + Duration::seconds(10),
+                now + Duration::seconds(11)
+            )
+            .unwrap());
+        assert!(!ds
+            .buckets_modified_since(
+                &revisions,
+                now + Duration::seconds(40),
+                now + Duration::seconds(45)
+            )
+            .unwrap());
+
+        // Deleting the first and last events moves the bucket start and end
+        assert_eq!(
+            ds.delete_events_in_range(&bucket.id, None, Some(now + Duration::seconds(1)))
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            ds.delete_events_in_range(&bucket.id, Some(now + Duration::seconds(40)), None)
+                .unwrap(),
+            1
+        );
+        let titles: Vec<String> = ds
+            .get_events(&bucket.id, None, None, None)
+            .unwrap()
+            .iter()
+            .map(|e| e.data["title"].to_string())
+            .collect();
+        assert_eq!(titles, vec![r#""b""#]);
+        let bucket_fetched = ds.get_bucket(&bucket.id).unwrap();
+        assert_eq!(
+            bucket_fetched.metadata.start,
+            Some(now + Duration::seconds(20))
+        );
+        assert_eq!(
+            bucket_fetched.metadata.end,
+            Some(now + Duration::seconds(25))
+        );
+
+        // Nothing to delete
+        assert_eq!(
+            ds.delete_events_filtered(&bucket.id, None, None, &bank)
+                .unwrap(),
+            0
+        );
+        let invalid = [DataFilter::Regex("title".to_string(), "(".to_string())];
+        match ds.delete_events_filtered(&bucket.id, None, None, &invalid) {
+            Err(DatastoreError::InvalidFilter(_)) => (),
+            res => panic!("Expected InvalidFilter, got {res:?}"),
         }
-        {
-            // Load database again
-            let ds = Datastore::new(db_path_str, false);
-            // Check that all bucket data is correct after reload
-            let buckets = ds.get_buckets().unwrap();
-            assert_eq!(buckets[&empty_bucket.id].metadata.start, None);
-            assert_eq!(buckets[&empty_bucket.id].metadata.end, None);
-            assert_eq!(
-                buckets[&populated_bucket.id].metadata.start,
-                Some(e1.timestamp)
-            );
-            assert_eq!(
-                buckets[&populated_bucket.id].metadata.end,
-                Some(e1.calculate_endtime())
-            );
+    }
+
+    #[test]
+    fn test_delete_events_clipped() {
+        let ds = Datastore::new_in_memory(false);
+        let bucket = create_test_bucket(&ds);
+
+        let now = Utc::now();
+        let event = |start: i64, duration: i64, title: &str| Event {
+            id: None,
+            timestamp: now + Duration::seconds(start),
+            duration: Duration::seconds(duration),
+            data: json_map! {"title": json!(title)},
+            origin: None,
+        };
+        ds.insert_events(
+            &bucket.id,
+            &[
+                event(0, 10, "before"),
+                event(12, 2, "inside"),
+                event(15, 10, "after"),
+                event(30, 20, "covering"),
+                event(60, 5, "outside"),
+            ],
+        )
+        .unwrap();
+
+        let intervals = |ds: &Datastore| {
+            let mut intervals: Vec<(String, i64, i64)> = ds
+                .get_events(&bucket.id, None, None, None)
+                .unwrap()
+                .iter()
+                .map(|e| {
+                    (
+                        e.data["title"].as_str().unwrap().to_string(),
+                        (e.timestamp - now).num_seconds(),
+                        e.duration.num_seconds(),
+                    )
+                })
+                .collect();
+            intervals.sort_by_key(|(_, start, _)| *start);
+            intervals
+        };
+
+        // Clipped events are counted the same as deleted ones
+        let start = Some(now + Duration::seconds(5));
+        let end = Some(now + Duration::seconds(20));
+        assert_eq!(ds.get_event_count(&bucket.id, start, end).unwrap(), 3);
+        assert_eq!(
+            ds.delete_events_clipped(&bucket.id, start, end, &[])
+                .unwrap(),
+            3
+        );
+        assert_eq!(
+            intervals(&ds),
+            vec![
+                ("before".to_string(), 0, 5),
+                ("after".to_string(), 20, 5),
+                ("covering".to_string(), 30, 20),
+                ("outside".to_string(), 60, 5),
+            ]
+        );
+
+        // An event which covers the whole range is split in two
+        let start = Some(now + Duration::seconds(35));
+        let end = Some(now + Duration::seconds(40));
+        assert_eq!(
+            ds.delete_events_clipped(&bucket.id, start, end, &[])
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            intervals(&ds),
+            vec![
+                ("before".to_string(), 0, 5),
+                ("after".to_string(), 20, 5),
+                ("covering".to_string(), 30, 5),
+                ("covering".to_string(), 40, 10),
+                ("outside".to_string(), 60, 5),
+            ]
+        );
+
+        // Filters apply to the clipped events as well
+        let outside = [DataFilter::Regex(
+            "title".to_string(),
+            "^outside$".to_string(),
+        )];
+        assert_eq!(
+            ds.delete_events_clipped(
+                &bucket.id,
+                None,
+                Some(now + Duration::seconds(62)),
+                &outside
+            )
+            .unwrap(),
+            1
+        );
+        assert_eq!(intervals(&ds)[4], ("outside".to_string(), 62, 3));
+        assert_eq!(intervals(&ds)[0], ("before".to_string(), 0, 5));
+        let bucket_fetched = ds.get_bucket(&bucket.id).unwrap();
+        assert_eq!(
+            bucket_fetched.metadata.end,
+            Some(now + Duration::seconds(65))
+        );
+    }
+
+    #[test]
+    fn test_bucket_revisions() {
+        // Setup datastore
+        let ds = Datastore::new_in_memory(false);
+        let bucket = create_test_bucket(&ds);
+
+        let now = Utc::now();
+        let e1 = Event {
+            id: None,
+            timestamp: now,
+            duration: Duration::seconds(0),
+            data: json_map! {"key": json!("value")},
+            origin: None,
+        };
+        let yesterday = (now - Duration::days(2), now - Duration::days(1));
+        let today = (now - Duration::hours(1), now + Duration::hours(1));
+
+        let revisions = ds.get_bucket_revisions().unwrap();
+        assert!(revisions.contains_key(&bucket.id));
+        assert!(!ds
+            .buckets_modified_since(&revisions, today.0, today.1)
+            .unwrap());
+
+        // Heartbeats only modify the period they are in
+        ds.heartbeat(&bucket.id, e1.clone(), 10.0).unwrap();
+        let mut e2 = e1.clone();
+        e2.timestamp += Duration::seconds(5);
+        ds.heartbeat(&bucket.id, e2, 10.0).unwrap();
+        assert!(ds
+            .buckets_modified_since(&revisions, today.0, today.1)
+            .unwrap());
+        assert!(!ds
+            .buckets_modified_since(&revisions, yesterday.0, yesterday.1)
+            .unwrap());
+        // The snapshot only includes committed writes
+        assert_eq!(ds.get_bucket_revisions().unwrap(), revisions);
+        ds.force_commit().unwrap();
+        let new_revisions = ds.get_bucket_revisions().unwrap();
+        assert!(new_revisions[&bucket.id] > revisions[&bucket.id]);
+
+        // Deleting events modifies everything
+        let revisions = new_revisions;
+        let events = ds.get_events(&bucket.id, None, None, None).unwrap();
+        ds.delete_events_by_id(&bucket.id, vec![events[0].id.unwrap()])
+            .unwrap();
+        assert!(ds
+            .buckets_modified_since(&revisions, yesterday.0, yesterday.1)
+            .unwrap());
+
+        // Creating a bucket modifies everything
+        let revisions = ds.get_bucket_revisions().unwrap();
+        let mut bucket2 = test_bucket();
+        bucket2.id = "testid2".to_string();
+        ds.create_bucket(&bucket2).unwrap();
+        assert!(ds
+            .buckets_modified_since(&revisions, yesterday.0, yesterday.1)
+            .unwrap());
+    }
+
+    #[test]
+    fn test_stored_queries() {
+        // Setup datastore
+        let ds = Datastore::new_in_memory(false);
+        assert_eq!(ds.get_stored_queries().unwrap().len(), 0);
+
+        let mut query = StoredQuery {
+            name: "test".to_string(),
+            query: vec!["a = 1;".to_string(), "return a;".to_string()],
+            description: "Returns one".to_string(),
+            last_modified: None,
+        };
+        ds.set_stored_query(&query).unwrap();
+        let fetched = ds.get_stored_query("test").unwrap();
+        assert_eq!(fetched.query, query.query);
+        assert_eq!(fetched.description, query.description);
+        assert!(fetched.last_modified.is_some());
+
+        // Storing a query with the same name replaces it
+        query.query = vec!["return 2;".to_string()];
+        ds.set_stored_query(&query).unwrap();
+        let queries = ds.get_stored_queries().unwrap();
+        assert_eq!(queries.len(), 1);
+        assert_eq!(queries["test"].query, query.query);
+
+        ds.delete_stored_query("test").unwrap();
+        match ds.get_stored_query("test") {
+            Err(DatastoreError::NoSuchQuery(name)) => assert_eq!(name, "test"),
+            res => panic!("Expected NoSuchQuery, got {res:?}"),
+        }
+        match ds.delete_stored_query("test") {
+            Err(DatastoreError::NoSuchQuery(_)) => (),
+            res => panic!("Expected NoSuchQuery, got {res:?}"),
+        }
+    }
+
+    #[test]
+    fn test_datastore_reload() {
+        // Create tmp datastore path
+        let mut db_path = get_cache_dir().unwrap();
+        db_path.push("datastore-unittest.db");
+        let db_path_str = db_path.to_str().unwrap().to_string();
+
+        if db_path.exists() {
+            std::fs::remove_file(db_path.clone())
+                .expect("Failed to remove datastore-unittest.db file");
+        }
+
+        let empty_bucket = test_bucket();
+        let mut populated_bucket = empty_bucket.clone();
+        populated_bucket.id = "testid2".to_string();
+        let e1 = Event {
+            id: None,
+            timestamp: Utc::now(),
+            duration: Duration::seconds(0),
+            data: json_map! {"key": json!("value")},
+            origin: None,
+        };
+        {
+            // Initialize database and create buckets
+            let ds = Datastore::new(db_path_str.clone(), false);
+            ds.create_bucket(&empty_bucket).unwrap();
+            ds.create_bucket(&populated_bucket).unwrap();
+            // Insert event
+            ds.insert_events(&populated_bucket.id, std::slice::from_ref(&e1))
+                .unwrap();
+            ds.force_commit().unwrap();
+
+            // Check that all cached bucket data is correct
+            let buckets = ds.get_buckets().unwrap();
+            assert_eq!(buckets[&empty_bucket.id].metadata.start, None);
+            assert_eq!(buckets[&empty_bucket.id].metadata.end, None);
+            assert_eq!(
+                buckets[&populated_bucket.id].metadata.start,
+                Some(e1.calculate_endtime())
+            );
+            assert_eq!(
+                buckets[&populated_bucket.id].metadata.end,
+                Some(e1.timestamp)
+            );
+            ds.force_commit().unwrap();
+        }
+        {
+            // Load database again
+            let ds = Datastore::new(db_path_str, false);
+            // Check that all bucket data is correct after reload
+            let buckets = ds.get_buckets().unwrap();
+            assert_eq!(buckets[&empty_bucket.id].metadata.start, None);
+            assert_eq!(buckets[&empty_bucket.id].metadata.end, None);
+            assert_eq!(
+                buckets[&populated_bucket.id].metadata.start,
+                Some(e1.timestamp)
+            );
+            assert_eq!(
+                buckets[&populated_bucket.id].metadata.end,
+                Some(e1.calculate_endtime())
+            );
+        }
+    }
+
+    fn new_file_datastore(name: &str) -> (Datastore, String) {
+        new_file_datastore_with_options(name, DatastoreOptions::default())
+    }
+
+    fn new_file_datastore_with_options(
+        name: &str,
+        options: DatastoreOptions,
+    ) -> (Datastore, String) {
+        let mut db_path = get_cache_dir().unwrap();
+        db_path.push(name);
+        let db_path_str = db_path.to_str().unwrap().to_string();
+        for suffix in ["", "-wal", "-shm"] {
+            let path = PathBuf::from(format!("{db_path_str}{suffix}"));
+            if path.exists() {
+                fs::remove_file(path).expect("Failed to remove old datastore file");
+            }
+        }
+        (
+            Datastore::new_with_options(db_path_str.clone(), false, options),
+            db_path_str,
+        )
+    }
+
+    #[test]
+    fn test_read_pool() {
+        let (ds, db_path) = new_file_datastore("datastore-readpool-unittest.db");
+
+        let conn = rusqlite::Connection::open(&db_path).unwrap();
+        let journal_mode: String = conn
+            .pragma_query_value(None, "journal_mode", |row| row.get(0))
+            .unwrap();
+        assert_eq!(journal_mode, "wal");
+
+        let bucket = create_test_bucket(&ds);
+        let e1 = Event {
+            id: None,
+            timestamp: Utc::now(),
+            duration: Duration::seconds(1),
+            data: json_map! {"key": json!("value")},
+            origin: None,
+        };
+        let inserted = ds
+            .insert_events(&bucket.id, std::slice::from_ref(&e1))
+            .unwrap();
+        ds.force_commit().unwrap();
+        let fetched = ds.get_events(&bucket.id, None, None, None).unwrap();
+        assert_eq!(fetched, inserted);
+        assert_eq!(ds.get_event_count(&bucket.id, None, None).unwrap(), 1);
+        let fetched_event = ds.get_event(&bucket.id, inserted[0].id.unwrap()).unwrap();
+        assert_eq!(fetched_event, inserted[0]);
+        let buckets = ds.get_buckets().unwrap();
+        assert_eq!(buckets[&bucket.id].metadata.start, Some(e1.timestamp));
+        assert_eq!(ds.get_bucket(&bucket.id).unwrap().id, bucket.id);
+
+        let mut e2 = e1.clone();
+        e2.duration = Duration::seconds(3);
+        ds.heartbeat(&bucket.id, e2.clone(), 10.0).unwrap();
+        ds.force_commit().unwrap();
+        let fetched = ds.get_events(&bucket.id, None, None, None).unwrap();
+        assert_eq!(fetched.len(), 1);
+        assert_eq!(fetched[0].duration, e2.duration);
+
+        // Settings are committed right away
+        ds.set_key_value("settings.test", "value").unwrap();
+        assert_eq!(ds.get_key_value("settings.test").unwrap(), "value");
+        assert_eq!(ds.get_key_values("settings.%").unwrap().len(), 1);
+        ds.delete_key_value("settings.test").unwrap();
+        match ds.get_key_value("settings.test") {
+            Err(DatastoreError::NoSuchKey(_)) => (),
+            res => panic!("Expected NoSuchKey, got {res:?}"),
+        }
+
+        ds.delete_bucket(&bucket.id).unwrap();
+        match ds.get_events(&bucket.id, None, None, None) {
+            Err(DatastoreError::NoSuchBucket(_)) => (),
+            res => panic!("Expected NoSuchBucket, got {res:?}"),
+        }
+        match ds.get_bucket(&bucket.id) {
+            Err(DatastoreError::NoSuchBucket(_)) => (),
+            res => panic!("Expected NoSuchBucket, got {res:?}"),
+        }
+    }
+
+    #[test]
+    fn test_read_pool_uncommitted() {
+        let options = DatastoreOptions {
+            commit_interval: std::time::Duration::from_secs(3600),
+            ..DatastoreOptions::default()
+        };
+        let (ds, db_path) =
+            new_file_datastore_with_options("datastore-readpool-uncommitted-unittest.db", options);
+        let bucket = create_test_bucket(&ds);
+
+        // Writes are read back right away, before they are committed
+        let event = single_event(0);
+        let inserted = ds
+            .insert_events(&bucket.id, std::slice::from_ref(&event))
+            .unwrap();
+        assert_eq!(committed_event_count(&db_path), 0);
+        let id = inserted[0].id.unwrap();
+        assert_eq!(ds.get_event_count(&bucket.id, None, None).unwrap(), 1);
+        assert_eq!(ds.get_event(&bucket.id, id).unwrap(), inserted[0]);
+        assert_eq!(
+            ds.get_events(&bucket.id, None, None, None).unwrap(),
+            inserted
+        );
+        let end = Some(event.timestamp + event.duration);
+        assert_eq!(ds.get_bucket(&bucket.id).unwrap().metadata.end, end);
+        assert_eq!(ds.get_buckets().unwrap()[&bucket.id].metadata.end, end);
+
+        let mut updated = inserted[0].clone();
+        updated.data = json_map! {"key": json!("updated")};
+        ds.update_event(&bucket.id, id, &updated).unwrap();
+        assert_eq!(ds.get_event(&bucket.id, id).unwrap().data, updated.data);
+
+        // Once committed the reads are served by the read pool again
+        ds.force_commit().unwrap();
+        assert_eq!(committed_event_count(&db_path), 1);
+        assert_eq!(ds.get_event(&bucket.id, id).unwrap().data, updated.data);
+    }
+
+    #[test]
+    fn test_read_pool_concurrent() {
+        let (ds, _) = new_file_datastore("datastore-readpool-concurrent-unittest.db");
+        let bucket = create_test_bucket(&ds);
+
+        let batches = 50;
+        let batch_size = 10;
+        let writer = {
+            let ds = ds.clone();
+            let bucket_id = bucket.id.clone();
+            std::thread::spawn(move || {
+                for batch in 0..batches {
+                    let events: Vec<Event> = (0..batch_size)
+                        .map(|i| Event {
+                            id: None,
+                            timestamp: Utc::now() + Duration::seconds(batch * batch_size + i),
+                            duration: Duration::seconds(1),
+                            data: json_map! {},
+                            origin: None,
+                        })
+                        .collect();
+                    ds.insert_events(&bucket_id, &events).unwrap();
+                }
+            })
+        };
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let ds = ds.clone();
+                let bucket_id = bucket.id.clone();
+                std::thread::spawn(move || {
+                    let mut last_count = 0;
+                    while last_count < batches * batch_size {
+                        // A read never sees only a part of a batch, or fewer events than an
+                        // earlier read
+                        let count = ds.get_event_count(&bucket_id, None, None).unwrap();
+                        assert_eq!(count % batch_size, 0);
+                        assert!(count >= last_count);
+                        let events = ds.get_events(&bucket_id, None, None, None).unwrap();
+                        assert!(events.len() as i64 >= count);
+                        assert_eq!(events.len() as i64 % batch_size, 0);
+                        last_count = count;
+                    }
+                })
+            })
+            .collect();
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(
+            ds.get_event_count(&bucket.id, None, None).unwrap(),
+            batches * batch_size
+        );
+    }
+
+    // Counts the events visible to other processes, which only see committed data
+    fn committed_event_count(db_path: &str) -> i64 {
+        let conn = rusqlite::Connection::open(db_path).unwrap();
+        conn.query_row("SELECT count(*) FROM events", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    fn single_event(offset: i64) -> Event {
+        Event {
+            id: None,
+            timestamp: Utc::now() + Duration::seconds(offset),
+            duration: Duration::seconds(1),
+            data: json_map! {},
+            origin: None,
+        }
+    }
+
+    #[test]
+    fn test_commit_policy() {
+        let options = DatastoreOptions {
+            commit_interval: std::time::Duration::from_secs(3600),
+            commit_events: 3,
+            sync: SyncMode::Normal,
+            ..DatastoreOptions::default()
+        };
+        let (ds, db_path) =
+            new_file_datastore_with_options("datastore-commit-events-unittest.db", options);
+        let bucket = create_test_bucket(&ds);
+
+        ds.insert_events(&bucket.id, &[single_event(0)]).unwrap();
+        ds.insert_events(&bucket.id, &[single_event(1)]).unwrap();
+        assert_eq!(committed_event_count(&db_path), 0);
+        ds.insert_events(&bucket.id, &[single_event(2)]).unwrap();
+        assert_eq!(committed_event_count(&db_path), 3);
+
+        let options = DatastoreOptions {
+            commit_interval: std::time::Duration::from_millis(100),
+            commit_events: 100,
+            sync: SyncMode::Off,
+            ..DatastoreOptions::default()
+        };
+        let (ds, db_path) =
+            new_file_datastore_with_options("datastore-commit-interval-unittest.db", options);
+        let bucket = create_test_bucket(&ds);
+        ds.insert_events(&bucket.id, &[single_event(0)]).unwrap();
+        assert_eq!(committed_event_count(&db_path), 0);
+        // Writes are committed once the interval runs out, without waiting for another request
+        std::thread::sleep(std::time::Duration::from_millis(300));
+        assert_eq!(committed_event_count(&db_path), 1);
+        // The first write after the interval ran out is committed right away
+        ds.insert_events(&bucket.id, &[single_event(1)]).unwrap();
+        assert_eq!(committed_event_count(&db_path), 2);
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        let options = DatastoreOptions {
+            commit_events: 1,
+            ..DatastoreOptions::default()
+        };
+        let (ds, db_path) = new_file_datastore_with_options("datastore-bulk-unittest.db", options);
+
+        let bulk_load = ds.begin_bulk_load().unwrap();
+        // Creating a bucket normally commits right away
+        let bucket = create_test_bucket(&ds);
+        for i in 0..10 {
+            ds.insert_events(&bucket.id, &[single_event(i)]).unwrap();
+        }
+        assert_eq!(committed_event_count(&db_path), 0);
+
+        // Nested bulk loads are committed when the outermost one ends
+        let nested = ds.begin_bulk_load().unwrap();
+        ds.insert_events(&bucket.id, &[single_event(10)]).unwrap();
+        nested.end().unwrap();
+        assert_eq!(committed_event_count(&db_path), 0);
+        bulk_load.end().unwrap();
+        assert_eq!(committed_event_count(&db_path), 11);
+
+        // Reads during a bulk load see its writes before they are committed
+        let bulk_load = ds.begin_bulk_load().unwrap();
+        ds.insert_events(&bucket.id, &[single_event(11)]).unwrap();
+        assert_eq!(ds.get_event_count(&bucket.id, None, None).unwrap(), 12);
+        assert_eq!(committed_event_count(&db_path), 11);
+        bulk_load.end().unwrap();
+        assert_eq!(ds.get_event_count(&bucket.id, None, None).unwrap(), 12);
+
+        // A bulk load which is dropped without being ended, because of an error, still ends
+        let result = std::thread::scope(|s| {
+            s.spawn(|| {
+                let _bulk_load = ds.begin_bulk_load().unwrap();
+                ds.insert_events(&bucket.id, &[single_event(12)]).unwrap();
+                panic!("Import failed");
+            })
+            .join()
+        });
+        assert!(result.is_err());
+        assert_eq!(committed_event_count(&db_path), 13);
+        ds.insert_events(&bucket.id, &[single_event(13)]).unwrap();
+        assert_eq!(committed_event_count(&db_path), 14);
+    }
+
+    #[test]
+    fn test_sync_mode_parse() {
+        assert_eq!("full".parse::<SyncMode>(), Ok(SyncMode::Full));
+        assert_eq!("normal".parse::<SyncMode>(), Ok(SyncMode::Normal));
+        assert_eq!("off".parse::<SyncMode>(), Ok(SyncMode::Off));
+        assert!("FULL".parse::<SyncMode>().is_err());
+        assert_eq!(SyncMode::Normal.to_string(), "normal");
+    }
+
+    #[test]
+    fn test_data_indexes() {
+        let (ds, db_path) = new_file_datastore("datastore-data-indexes-unittest.db");
+        let bucket = create_test_bucket(&ds);
+        let mut bucket2 = test_bucket();
+        bucket2.id = "testid2".to_string();
+        ds.create_bucket(&bucket2).unwrap();
+        insert_data_filter_events(&ds, &bucket);
+
+        let index_exists = || {
+            let conn = rusqlite::Connection::open(&db_path).unwrap();
+            let count: i64 = conn
+                .query_row(
+                    "SELECT count(*) FROM sqlite_master WHERE type = 'index' AND name LIKE 'events_data_%'",
+                    [],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            count > 0
+        };
+
+        ds.create_data_index(&bucket.id, "app").unwrap();
+        ds.create_data_index(&bucket2.id, "app").unwrap();
+        // Creating an index twice is fine
+        ds.create_data_index(&bucket.id, "app").unwrap();
+        assert_eq!(ds.get_data_indexes(&bucket.id).unwrap(), vec!["app"]);
+        assert!(index_exists());
+
+        // The index is used for filtering
+        let conn = rusqlite::Connection::open(&db_path).unwrap();
+        let plan: Vec<String> = conn
+            .prepare(
+                "EXPLAIN QUERY PLAN SELECT id FROM events
+                WHERE bucketrow = 1 AND json_extract(data, '$.\"app\"') = 'firefox'",
+            )
+            .unwrap()
+            .query_map([], |row| row.get(3))
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+        assert!(
+            plan.iter().any(|step| step.contains("events_data_")),
+            "{plan:?}"
+        );
+        let filter = DataFilter::Equals("app".to_string(), json!("firefox"));
+        assert_eq!(filtered_titles(&ds, &bucket, &[filter]).len(), 2);
+
+        // The index is shared, so it is only dropped when no bucket uses it
+        ds.delete_data_index(&bucket.id, "app").unwrap();
+        assert_eq!(
+            ds.get_data_indexes(&bucket.id).unwrap(),
+            Vec::<String>::new()
+        );
+        assert!(index_exists());
+        match ds.delete_data_index(&bucket.id, "app") {
+            Err(DatastoreError::NoSuchKey(_)) => (),
+            res => panic!("Expected NoSuchKey, got {res:?}"),
+        }
+        ds.delete_bucket(&bucket2.id).unwrap();
+        assert!(!index_exists());
+
+        match ds.create_data_index(&bucket.id, "a\"b") {
+            Err(DatastoreError::InvalidFilter(_)) => (),
+            res => panic!("Expected InvalidFilter, got {res:?}"),
+        }
+    }
+
+    fn retention_datastore(policies: Vec<RetentionPolicy>) -> Datastore {
+        let options = DatastoreOptions {
+            retention: policies,
+            // Only enforced when requested
+            retention_interval: std::time::Duration::from_secs(3600 * 24 * 365),
+            ..DatastoreOptions::default()
+        };
+        Datastore::new_in_memory_with_options(false, options)
+    }
+
+    // Events one minute apart starting at the hour of the offset in days from now, so that
+    // they are all within the same merge period
+    fn retention_events(ds: &Datastore, bucket_id: &str, days: i64, titles: &[&str]) {
+        let start = (Utc::now() + Duration::days(days))
+            .duration_trunc(Duration::hours(1))
+            .unwrap();
+        let events: Vec<Event> = titles
+            .iter()
+            .enumerate()
+            .map(|(i, title)| Event {
+                id: None,
+                timestamp: start + Duration::minutes(i as i64),
+                duration: Duration::seconds(30),
+                data: json_map! {"app": json!(title), "title": json!(format!("{title} {i}"))},
+                origin: None,
+            })
+            .collect();
+        ds.insert_events(bucket_id, &events).unwrap();
+    }
+
+    #[test]
+    fn test_retention_delete() {
+        let policy = RetentionPolicy {
+            bucket: Some("test*".to_string()),
+            bucket_type: None,
+            days: 7,
+            action: RetentionAction::Delete,
+        };
+        let ds = retention_datastore(vec![policy.clone()]);
+        let bucket = create_test_bucket(&ds);
+        let mut other = test_bucket();
+        other.id = "otherid".to_string();
+        ds.create_bucket(&other).unwrap();
+        for bucket_id in [&bucket.id, &other.id] {
+            retention_events(&ds, bucket_id, -10, &["a", "b", "c"]);
+            retention_events(&ds, bucket_id, -1, &["d"]);
+        }
+
+        // The report doesn't change anything
+        let report = ds.retention_report().unwrap();
+        assert_eq!(report.len(), 1);
+        assert_eq!(report[0].bucket_id, bucket.id);
+        assert_eq!(report[0].policy, policy);
+        assert_eq!(report[0].events, 3);
+        assert_eq!(ds.get_event_count(&bucket.id, None, None).unwrap(), 4);
+
+        let results = ds.apply_retention().unwrap();
+        assert_eq!(results[0].events, 3);
+        assert_eq!(ds.get_event_count(&bucket.id, None, None).unwrap(), 1);
+        assert_eq!(ds.get_event_count(&other.id, None, None).unwrap(), 4);
+        let bucket_fetched = ds.get_bucket(&bucket.id).unwrap();
+        assert!(bucket_fetched.metadata.start.unwrap() > Utc::now() - Duration::days(2));
+
+        assert_eq!(ds.apply_retention().unwrap()[0].events, 0);
+    }
+
+    #[test]
+    fn test_retention_strip_keys() {
+        let policy = RetentionPolicy {
+            bucket: None,
+            bucket_type: Some("testtype".to_string()),
+            days: 7,
+            action: RetentionAction::StripKeys {
+                keys: vec!["title".to_string()],
+            },
+        };
+        let ds = retention_datastore(vec![policy]);
+        let bucket = create_test_bucket(&ds);
+        retention_events(&ds, &bucket.id, -10, &["a", "b"]);
+        retention_events(&ds, &bucket.id, -1, &["c"]);
+
+        assert_eq!(ds.retention_report().unwrap()[0].events, 2);
+        assert_eq!(ds.apply_retention().unwrap()[0].events, 2);
+        let events = ds.get_events(&bucket.id, None, None, None).unwrap();
+        assert_eq!(events.len(), 3);
+        // Newest first
+        assert_eq!(events[0].data["title"], json!("c 0"));
+        assert_eq!(events[1].data, json_map! {"app": json!("b")});
+        assert_eq!(events[2].data, json_map! {"app": json!("a")});
+
+        // Already stripped events aren't counted again
+        assert_eq!(ds.retention_report().unwrap()[0].events, 0);
+    }
+
+    #[test]
+    fn test_retention_merge() {
+        let policy = RetentionPolicy {
+            bucket: None,
+            bucket_type: None,
+            days: 7,
+            action: RetentionAction::Merge {
+                keys: vec!["app".to_string()],
+                minutes: 60,
+            },
+        };
+        let ds = retention_datastore(vec![policy]);
+        let bucket = create_test_bucket(&ds);
+        retention_events(&ds, &bucket.id, -20, &["a", "b", "a", "a"]);
+        retention_events(&ds, &bucket.id, -1, &["a", "a"]);
+
+        let report = ds.retention_report().unwrap();
+        assert_eq!(ds.get_event_count(&bucket.id, None, None).unwrap(), 6);
+        assert_eq!(report[0].events, 4);
+        assert_eq!(ds.apply_retention().unwrap()[0].events, 4);
+
+        let events = ds.get_events(&bucket.id, None, None, None).unwrap();
+        assert_eq!(events.len(), 4);
+        let old: Vec<&Event> = events
+            .iter()
+            .filter(|e| e.timestamp < Utc::now() - Duration::days(7))
+            .collect();
+        assert_eq!(old.len(), 2);
+        let a = old.iter().find(|e| e.data["app"] == json!("a")).unwrap();
+        assert_eq!(a.data, json_map! {"app": json!("a")});
+        assert_eq!(a.duration, Duration::seconds(90));
+        let b = old.iter().find(|e| e.data["app"] == json!("b")).unwrap();
+        assert_eq!(b.duration, Duration::seconds(30));
+
+        // Merged events are left alone the next time
+        assert_eq!(ds.apply_retention().unwrap()[0].events, 0);
+        assert_eq!(ds.get_event_count(&bucket.id, None, None).unwrap(), 4);
+
+        // Events inserted before where the last merge stopped are still merged
+        retention_events(&ds, &bucket.id, -19, &["a", "a"]);
+        assert_eq!(ds.apply_retention().unwrap()[0].events, 2);
+        assert_eq!(ds.get_event_count(&bucket.id, None, None).unwrap(), 5);
+    }
+
+    #[test]
+    fn test_retention_merge_period_boundary() {
+        let policy = RetentionPolicy {
+            bucket: None,
+            bucket_type: None,
+            days: 7,
+            action: RetentionAction::Merge {
+                keys: vec!["app".to_string()],
+                minutes: 60,
+            },
+        };
+        let ds = retention_datastore(vec![policy]);
+        let bucket = create_test_bucket(&ds);
+        // The next merge starts at the hour the cutoff of the last one is in
+        let from = (Utc::now() - Duration::days(7))
+            .duration_trunc(Duration::hours(1))
+            .unwrap();
+        let event = |timestamp, seconds| Event {
+            id: None,
+            timestamp,
+            duration: Duration::seconds(seconds),
+            data: json_map! {"app": json!("a")},
+            origin: None,
+        };
+        // An event of the earlier period which ends in the next one, where another event is
+        let events = [event(from - Duration::minutes(10), 1200), event(from, 1)];
+        ds.insert_events(&bucket.id, &events).unwrap();
+        let total = |ds: &Datastore| {
+            ds.get_events(&bucket.id, None, None, None)
+                .unwrap()
+                .iter()
+                .map(|e| e.duration)
+                .sum::<Duration>()
+        };
+
+        ds.apply_retention().unwrap();
+        assert_eq!(total(&ds), Duration::seconds(1201));
+        ds.apply_retention().unwrap();
+        let events = ds.get_events(&bucket.id, None, None, None).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].timestamp, from - Duration::minutes(10));
+        assert_eq!(events[1].duration, Duration::seconds(1200));
+        assert_eq!(total(&ds), Duration::seconds(1201));
+    }
+
+    #[test]
+    fn test_retention_bucket_policies() {
+        let policy = RetentionPolicy {
+            bucket: None,
+            bucket_type: None,
+            days: 7,
+            action: RetentionAction::Delete,
+        };
+        let ds = retention_datastore(vec![policy]);
+        let mut bucket = test_bucket();
+        bucket.data = json_map! {"retention": json!([{"days": 30, "action": "delete"}])};
+        ds.create_bucket(&bucket).unwrap();
+        let mut invalid = test_bucket();
+        invalid.id = "invalidid".to_string();
+        invalid.data = json_map! {"retention": json!([{"days": 1, "action": "merge", "keys": []}])};
+        ds.create_bucket(&invalid).unwrap();
+        retention_events(&ds, &bucket.id, -40, &["a"]);
+        retention_events(&ds, &bucket.id, -10, &["b"]);
+        retention_events(&ds, &invalid.id, -10, &["a"]);
+
+        // The policies of a bucket replace the configured ones, invalid ones disable retention
+        let results = ds.apply_retention().unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].policy.days, 30);
+        assert_eq!(results[0].events, 1);
+        assert_eq!(ds.get_event_count(&bucket.id, None, None).unwrap(), 1);
+        assert_eq!(ds.get_event_count(&invalid.id, None, None).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_retention_periodic() {
+        let options = DatastoreOptions {
+            retention: vec![RetentionPolicy {
+                bucket: None,
+                bucket_type: None,
+                days: 7,
+                action: RetentionAction::Delete,
+            }],
+            retention_interval: std::time::Duration::from_millis(200),
+            ..DatastoreOptions::default()
+        };
+        let ds = Datastore::new_in_memory_with_options(false, options);
+        let bucket = create_test_bucket(&ds);
+        retention_events(&ds, &bucket.id, -10, &["a", "b"]);
+        assert_eq!(ds.get_event_count(&bucket.id, None, None).unwrap(), 2);
+        // Retention is enforced once the interval runs out, without waiting for a request
+        std::thread::sleep(std::time::Duration::from_millis(600));
+        assert_eq!(ds.get_event_count(&bucket.id, None, None).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_retention_policy_parse() {
+        let policy: RetentionPolicy = serde_json::from_value(
+            json!({"bucket": "aw-watcher-window_*", "days": 90, "action": "merge", "keys": ["app"]}),
+        )
+        .unwrap();
+        assert_eq!(
+            policy.action,
+            RetentionAction::Merge {
+                keys: vec!["app".to_string()],
+                minutes: 60
+            }
+        );
+        assert!(policy.validate().is_ok());
+
+        let mut bucket = test_bucket();
+        bucket.id = "aw-watcher-window_host".to_string();
+        assert!(policy.applies_to(&bucket));
+        bucket.id = "aw-watcher-afk_host".to_string();
+        assert!(!policy.applies_to(&bucket));
+
+        let policy: RetentionPolicy =
+            serde_json::from_value(json!({"type": "?fk", "days": 1, "action": "delete"})).unwrap();
+        bucket._type = "afk".to_string();
+        assert!(policy.applies_to(&bucket));
+        bucket._type = "afkstatus".to_string();
+        assert!(!policy.applies_to(&bucket));
+
+        let invalid: RetentionPolicy =
+            serde_json::from_value(json!({"days": 1, "action": "strip_keys", "keys": ["a\"b"]}))
+                .unwrap();
+        assert!(invalid.validate().is_err());
+        assert!(serde_json::from_value::<RetentionPolicy>(json!({"days": 1})).is_err());
+    }
+
+    fn redaction_rule(key: Option<&str>, regex: &str, action: RedactionAction) -> RedactionRule {
+        RedactionRule {
+            bucket: None,
+            bucket_type: None,
+            key: key.map(|k| k.to_string()),
+            regex: regex.to_string(),
+            ignore_case: true,
+            action,
+        }
+    }
+
+    fn redacted_datastore(rules: Vec<RedactionRule>) -> Datastore {
+        let options = DatastoreOptions {
+            redaction: rules,
+            ..DatastoreOptions::default()
+        };
+        Datastore::new_in_memory_with_options(false, options)
+    }
+
+    fn window_event(offset: i64, app: &str, title: &str) -> Event {
+        let start = DateTime::parse_from_rfc3339("2018-01-01T14:00:00Z").unwrap();
+        Event {
+            id: None,
+            timestamp: start.with_timezone(&Utc) + Duration::seconds(offset),
+            duration: Duration::seconds(1),
+            data: json_map! {"app": json!(app), "title": json!(title)},
+            origin: None,
+        }
+    }
+
+    #[test]
+    fn test_redaction_insert() {
+        let ds = redacted_datastore(vec![
+            redaction_rule(Some("app"), "^keepassxc$", RedactionAction::Drop),
+            redaction_rule(
+                Some("title"),
+                "private browsing",
+                RedactionAction::Replace {
+                    replacement: "private".to_string(),
+                },
+            ),
+            redaction_rule(None, r"\d{4}-\d{4}", RedactionAction::Hash),
+        ]);
+        let bucket = create_test_bucket(&ds);
+
+        let inserted = ds
+            .insert_events(
+                &bucket.id,
+                &[
+                    window_event(0, "KeePassXC", "bank.kdbx"),
+                    window_event(1, "firefox", "Bank - Private Browsing"),
+                    window_event(2, "firefox", "card 1234-5678"),
+                    window_event(3, "firefox", "news"),
+                ],
+            )
+            .unwrap();
+        assert_eq!(inserted.len(), 3);
+
+        let events = ds.get_events(&bucket.id, None, None, None).unwrap();
+        let titles: Vec<&str> = events
+            .iter()
+            .rev()
+            .map(|e| e.data["title"].as_str().unwrap())
+            .collect();
+        assert_eq!(titles[0], "private");
+        assert!(titles[1].starts_with("sha256:"));
+        assert_eq!(titles[2], "news");
+        assert_eq!(events[2].data["app"], json!("firefox"));
+    }
+
+    #[test]
+    fn test_redaction_heartbeat() {
+        let ds = redacted_datastore(vec![
+            redaction_rule(Some("app"), "keepassxc", RedactionAction::Drop),
+            redaction_rule(Some("title"), "secret", RedactionAction::Hash),
+        ]);
+        let bucket = create_test_bucket(&ds);
+
+        ds.heartbeat(&bucket.id, window_event(0, "keepassxc", "db"), 10.0)
+            .unwrap();
+        assert_eq!(ds.get_event_count(&bucket.id, None, None).unwrap(), 0);
+
+        // Hashed values are the same for the same title, so heartbeats still merge
+        ds.heartbeat(&bucket.id, window_event(1, "editor", "secret plan"), 10.0)
+            .unwrap();
+        ds.heartbeat(&bucket.id, window_event(2, "editor", "secret plan"), 10.0)
+            .unwrap();
+        let events = ds.get_events(&bucket.id, None, None, None).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].duration, Duration::seconds(2));
+        assert_ne!(events[0].data["title"], json!("secret plan"));
+
+        // Rules only apply to the buckets they match
+        let mut other = test_bucket();
+        other.id = "otherid".to_string();
+        ds.create_bucket(&other).unwrap();
+        let rule = RedactionRule {
+            bucket: Some("test*".to_string()),
+            ..redaction_rule(None, "plan", RedactionAction::Drop)
+        };
+        let ds_scoped = redacted_datastore(vec![rule]);
+        ds_scoped.create_bucket(&test_bucket()).unwrap();
+        ds_scoped.create_bucket(&other).unwrap();
+        for bucket_id in ["testid", "otherid"] {
+            ds_scoped
+                .heartbeat(bucket_id, window_event(0, "editor", "plan"), 10.0)
+                .unwrap();
+        }
+        assert_eq!(ds_scoped.get_event_count("testid", None, None).unwrap(), 0);
+        assert_eq!(ds_scoped.get_event_count("otherid", None, None).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_redaction_import() {
+        let ds = redacted_datastore(vec![
+            redaction_rule(Some("app"), "keepassxc", RedactionAction::Drop),
+            redaction_rule(Some("title"), "secret", RedactionAction::Hash),
+        ]);
+        let mut bucket = test_bucket();
+        bucket.events = Some(TryVec::new(vec![
+            window_event(0, "keepassxc", "db"),
+            window_event(1, "editor", "secret plan"),
+            window_event(2, "editor", "notes"),
+        ]));
+        ds.create_bucket(&bucket).unwrap();
+
+        let events = ds.get_events(&bucket.id, None, None, None).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].data["title"], json!("notes"));
+        assert!(events[1].data["title"]
+            .as_str()
+            .unwrap()
+            .starts_with("sha256:"));
+    }
+
+    #[test]
+    fn test_redaction_update() {
+        let ds = redacted_datastore(vec![
+            redaction_rule(Some("app"), "keepassxc", RedactionAction::Drop),
+            redaction_rule(Some("title"), "secret", RedactionAction::Hash),
+        ]);
+        let bucket = create_test_bucket(&ds);
+        let inserted = ds
+            .insert_events(
+                &bucket.id,
+                &[
+                    window_event(0, "editor", "notes"),
+                    window_event(1, "editor", "todo"),
+                ],
+            )
+            .unwrap();
+        let (first, second) = (inserted[0].id.unwrap(), inserted[1].id.unwrap());
+
+        // Updated data is redacted before it is stored
+        let updated = ds
+            .update_event(&bucket.id, first, &window_event(0, "editor", "secret plan"))
+            .unwrap();
+        assert!(updated.data["title"]
+            .as_str()
+            .unwrap()
+            .starts_with("sha256:"));
+        let stored = ds.get_event(&bucket.id, first).unwrap();
+        assert_eq!(stored.data, updated.data);
+
+        // Events which would be dropped are deleted
+        match ds.update_event(&bucket.id, second, &window_event(1, "keepassxc", "db")) {
+            Err(DatastoreError::EventDropped(id)) => assert_eq!(id, second),
+            res => panic!("Expected EventDropped, got {res:?}"),
+        }
+        let events = ds.get_events(&bucket.id, None, None, None).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].id, Some(first));
+
+        // Patches are redacted like updates
+        let patch = EventPatch {
+            data: Some(json_map! {"app": json!("keepassxc")}),
+            ..EventPatch::default()
+        };
+        match ds.patch_event(&bucket.id, first, &patch) {
+            Err(DatastoreError::EventDropped(id)) => assert_eq!(id, first),
+            res => panic!("Expected EventDropped, got {res:?}"),
+        }
+        assert_eq!(ds.get_event_count(&bucket.id, None, None).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_redaction_rule_parse() {
+        let rule: RedactionRule = serde_json::from_value(
+            json!({"key": "title", "regex": "incognito", "action": "replace"}),
+        )
+        .unwrap();
+        assert_eq!(
+            rule.action,
+            RedactionAction::Replace {
+                replacement: "[redacted]".to_string()
+            }
+        );
+        assert!(rule.validate().is_ok());
+
+        let invalid: RedactionRule =
+            serde_json::from_value(json!({"regex": "(", "action": "drop"})).unwrap();
+        assert!(invalid.validate().is_err());
+        let invalid: RedactionRule =
+            serde_json::from_value(json!({"key": "", "regex": "a", "action": "hash"})).unwrap();
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn test_repair_bucket_metadata() {
+        let ds = Datastore::new_in_memory(false);
+        let bucket = create_test_bucket(&ds);
+        let inserted = ds
+            .insert_events(&bucket.id, &[single_event(0), single_event(1)])
+            .unwrap();
+        assert!(ds.repair_bucket_metadata().unwrap().is_empty());
+        let end = ds.get_bucket(&bucket.id).unwrap().metadata.end;
+
+        // Deleting events by ID leaves the end of the bucket where it was
+        ds.delete_events_by_id(&bucket.id, vec![inserted[1].id.unwrap()])
+            .unwrap();
+        assert_eq!(ds.get_bucket(&bucket.id).unwrap().metadata.end, end);
+
+        let repairs = ds.repair_bucket_metadata().unwrap();
+        assert_eq!(repairs.len(), 1);
+        assert_eq!(repairs[0].bucket_id, bucket.id);
+        assert_eq!(repairs[0].old_end, end);
+        let new_end = Some(inserted[0].calculate_endtime());
+        assert_eq!(repairs[0].new_end, new_end);
+        assert_eq!(repairs[0].new_start, repairs[0].old_start);
+        assert_eq!(ds.get_bucket(&bucket.id).unwrap().metadata.end, new_end);
+        assert!(ds.repair_bucket_metadata().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_maintenance() {
+        let (ds, db_path) = new_file_datastore("datastore-maintenance-unittest.db");
+        let bucket = create_test_bucket(&ds);
+        let events: Vec<Event> = (0..1000)
+            .map(|i| Event {
+                data: json_map! {"title": json!("x".repeat(100))},
+                ..single_event(i)
+            })
+            .collect();
+        ds.insert_events(&bucket.id, &events).unwrap();
+
+        let stats = ds.get_database_stats().unwrap();
+        assert_eq!(stats.version, 8);
+        assert_eq!(stats.buckets.len(), 1);
+        assert_eq!(stats.buckets[0].bucket_id, bucket.id);
+        assert_eq!(stats.buckets[0].events, 1000);
+        assert_eq!(stats.buckets[0].data_bytes, 1000 * 112);
+        assert!(ds.integrity_check().unwrap().ok);
+        assert!(ds.repair_bucket_metadata().unwrap().is_empty());
+
+        // Vacuuming releases the space of deleted events
+        ds.delete_events_in_range(&bucket.id, None, None).unwrap();
+        let bulk_load = ds.begin_bulk_load().unwrap();
+        assert!(ds.vacuum().is_err());
+        bulk_load.end().unwrap();
+        let report = ds.vacuum().unwrap();
+        assert!(report.before.freelist_count > 0);
+        assert_eq!(report.after.freelist_count, 0);
+        assert!(report.after.bytes < report.before.bytes);
+        ds.insert_events(&bucket.id, &[single_event(0)]).unwrap();
+        ds.close();
+
+        // Orphaned events can only be left behind by something other than the datastore, which
+        // has foreign keys enforced
+        {
+            let conn = rusqlite::Connection::open(&db_path).unwrap();
+            conn.pragma_update(None, "foreign_keys", false).unwrap();
+            conn.execute(
+                "INSERT INTO events(bucketrow, starttime, endtime, data) VALUES (99, 0, 1, '{}')",
+                [],
+            )
+            .unwrap();
+        }
+        let ds = Datastore::new(db_path.clone(), false);
+        let report = ds.find_orphaned_events(false).unwrap();
+        assert_eq!(report.orphans.len(), 1);
+        assert_eq!(report.orphans[0].bucketrow, 99);
+        assert_eq!(report.orphans[0].events, 1);
+        assert!(!report.deleted);
+        assert!(ds.find_orphaned_events(true).unwrap().deleted);
+        assert!(ds.find_orphaned_events(false).unwrap().orphans.is_empty());
+        assert_eq!(committed_event_count(&db_path), 1);
+        ds.close();
+    }
+
+    #[test]
+    fn test_backup_restore() {
+        let options = DatastoreOptions {
+            commit_interval: std::time::Duration::from_secs(3600),
+            ..DatastoreOptions::default()
+        };
+        let (ds, db_path) =
+            new_file_datastore_with_options("datastore-backup-unittest.db", options);
+        let bucket = create_test_bucket(&ds);
+        ds.insert_events(&bucket.id, &[single_event(0), single_event(1)])
+            .unwrap();
+
+        // Uncommitted writes are part of the backup
+        let backup_path = format!("{db_path}.backup");
+        ds.backup(&backup_path).unwrap();
+        assert_eq!(committed_event_count(&backup_path), 2);
+        assert!(!PathBuf::from(format!("{backup_path}.tmp")).exists());
+
+        let bulk_load = ds.begin_bulk_load().unwrap();
+        assert!(ds.backup(&backup_path).is_err());
+        bulk_load.end().unwrap();
+
+        // The datastore keeps the database locked while it is open
+        ds.insert_events(&bucket.id, &[single_event(2)]).unwrap();
+        assert!(aw_datastore::restore_backup(&backup_path, &db_path).is_err());
+        ds.close();
+
+        let report = aw_datastore::restore_backup(&backup_path, &db_path).unwrap();
+        assert_eq!(report.backup_version, 8);
+        assert_eq!(report.version, 8);
+        let ds = Datastore::new(db_path.clone(), false);
+        assert_eq!(ds.get_event_count(&bucket.id, None, None).unwrap(), 2);
+        ds.close();
+
+        // Backups from newer versions or of other databases are refused before anything is
+        // replaced
+        {
+            let conn = rusqlite::Connection::open(&backup_path).unwrap();
+            conn.pragma_update(None, "user_version", 9).unwrap();
         }
+        assert!(aw_datastore::restore_backup(&backup_path, &db_path).is_err());
+        fs::write(&backup_path, "not a database").unwrap();
+        assert!(aw_datastore::restore_backup(&backup_path, &db_path).is_err());
+        assert_eq!(committed_event_count(&db_path), 2);
+        fs::remove_file(&backup_path).unwrap();
     }
 }
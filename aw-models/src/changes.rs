@@ -0,0 +1,33 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::Event;
+
+/// The events in a bucket which changed after a resume token
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+pub struct EventChanges {
+    /// Events which were inserted or modified, in the order they last changed in
+    pub events: Vec<Event>,
+    /// Events which were deleted
+    pub deleted: Vec<DeletedEvent>,
+    /// Pass this to get the changes after these, it is meant to be stored as is
+    pub token: String,
+    /// Set if the limit was reached, in which case there may be more changes after these
+    pub more: bool,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct DeletedEvent {
+    pub id: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+}
+
+/// Changes to make to the synced events of a bucket, which are identified by their origin
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+pub struct OriginChanges {
+    /// Events to insert or replace the event with the same origin with, they all need an origin
+    pub events: Vec<Event>,
+    /// Origins of the events to delete
+    pub deleted: Vec<String>,
+}
@@ -1,3 +1,6 @@
+use std::fmt;
+use std::str::FromStr;
+
 use chrono::DateTime;
 use chrono::Duration;
 use chrono::Utc;
@@ -28,6 +31,13 @@ pub struct Event {
     /// Can contain any arbitrary JSON data that represents the value of the event.
     /// All events in a bucket should follow the format of it's respective bucket-type.
     pub data: Map<String, Value>,
+    /// A globally unique identifier of where this event was first recorded, set by aw-sync as
+    /// `{device_id}:{id}` from the datastore the event was synced from.
+    ///
+    /// Inserting an event into a bucket which already has an event with the same origin replaces
+    /// that event instead of adding a duplicate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
 }
 
 impl Event {
@@ -37,6 +47,7 @@ impl Event {
             timestamp,
             duration,
             data,
+            origin: None,
         }
     }
     pub fn calculate_endtime(&self) -> DateTime<Utc> {
@@ -62,6 +73,7 @@ impl Default for Event {
             timestamp: Utc::now(),
             duration: Duration::seconds(0),
             data: serde_json::Map::new(),
+            origin: None,
         }
     }
 }
@@ -70,6 +82,86 @@ fn default_duration() -> Duration {
     Duration::seconds(0)
 }
 
+/// Changes to make to an event, fields which are left out are kept as they are
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, PartialEq)]
+pub struct EventPatch {
+    pub timestamp: Option<DateTime<Utc>>,
+    /// Duration in seconds
+    pub duration: Option<f64>,
+    /// Merged into the data of the event as a JSON merge patch (RFC 7396), so nested objects
+    /// are merged as well and keys which are set to null are removed
+    pub data: Option<Map<String, Value>>,
+}
+
+impl EventPatch {
+    pub fn apply(&self, event: &mut Event) {
+        if let Some(timestamp) = self.timestamp {
+            event.timestamp = timestamp;
+        }
+        if let Some(duration) = self.duration {
+            event.duration = Duration::nanoseconds((duration * 1_000_000_000.0) as i64);
+        }
+        if let Some(data) = &self.data {
+            merge_patch(&mut event.data, data);
+        }
+    }
+}
+
+fn merge_patch(target: &mut Map<String, Value>, patch: &Map<String, Value>) {
+    for (key, value) in patch {
+        match value {
+            Value::Null => {
+                target.remove(key);
+            }
+            Value::Object(patch_obj) => {
+                let target_value = target
+                    .entry(key.clone())
+                    .or_insert_with(|| Value::Object(Map::new()));
+                if !target_value.is_object() {
+                    *target_value = Value::Object(Map::new());
+                }
+                if let Value::Object(target_obj) = target_value {
+                    merge_patch(target_obj, patch_obj);
+                }
+            }
+            _ => {
+                target.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+/// The order events are listed in, by timestamp and then by id
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EventOrder {
+    #[serde(rename = "asc")]
+    Ascending,
+    #[default]
+    #[serde(rename = "desc")]
+    Descending,
+}
+
+impl FromStr for EventOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<EventOrder, String> {
+        match s {
+            "asc" => Ok(EventOrder::Ascending),
+            "desc" => Ok(EventOrder::Descending),
+            _ => Err(format!("Invalid order '{s}', expected asc or desc")),
+        }
+    }
+}
+
+impl fmt::Display for EventOrder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EventOrder::Ascending => write!(f, "asc"),
+            EventOrder::Descending => write!(f, "desc"),
+        }
+    }
+}
+
 #[test]
 fn test_event() {
     use serde_json::json;
@@ -79,6 +171,62 @@ fn test_event() {
         timestamp: Utc::now(),
         duration: Duration::seconds(1),
         data: json_map! {"test": json!(1)},
+        origin: None,
     };
     debug!("event: {:?}", e);
 }
+
+#[test]
+fn test_event_origin() {
+    use serde_json::json;
+
+    let mut e = Event::default();
+    assert!(serde_json::to_value(&e).unwrap().get("origin").is_none());
+
+    e.origin = Some("device:1".to_string());
+    let value = serde_json::to_value(&e).unwrap();
+    assert_eq!(value["origin"], json!("device:1"));
+    let e2: Event = serde_json::from_value(value).unwrap();
+    assert_eq!(e2.origin, e.origin);
+    // The origin is not part of what the event contains
+    assert_eq!(e2, Event { origin: None, ..e });
+}
+
+#[test]
+fn test_event_order() {
+    assert_eq!("asc".parse(), Ok(EventOrder::Ascending));
+    assert_eq!("desc".parse(), Ok(EventOrder::Descending));
+    assert!("up".parse::<EventOrder>().is_err());
+    assert_eq!(EventOrder::default().to_string(), "desc");
+    assert_eq!(
+        serde_json::to_string(&EventOrder::Ascending).unwrap(),
+        r#""asc""#
+    );
+}
+
+#[test]
+fn test_event_patch() {
+    use serde_json::json;
+
+    let mut e = Event {
+        id: Some(1),
+        timestamp: Utc::now(),
+        duration: Duration::seconds(1),
+        data: json_map! {"app": json!("code"), "title": json!("main.rs"), "meta": json!({"a": 1, "b": 2})},
+        origin: None,
+    };
+    let timestamp = e.timestamp;
+    let patch: EventPatch = serde_json::from_value(json!({
+        "duration": 2.5,
+        "data": {"label": "work", "title": null, "meta": {"b": null, "c": 3}}
+    }))
+    .unwrap();
+    patch.apply(&mut e);
+    assert_eq!(e.id, Some(1));
+    assert_eq!(e.timestamp, timestamp);
+    assert_eq!(e.duration, Duration::milliseconds(2500));
+    assert_eq!(
+        e.data,
+        json_map! {"app": json!("code"), "label": json!("work"), "meta": json!({"a": 1, "c": 3})}
+    );
+}
@@ -17,6 +17,7 @@ macro_rules! json_map {
 }
 
 mod bucket;
+mod changes;
 mod duration;
 mod event;
 mod info;
@@ -27,8 +28,16 @@ mod tryvec;
 pub use self::bucket::Bucket;
 pub use self::bucket::BucketMetadata;
 pub use self::bucket::BucketsExport;
+pub use self::changes::DeletedEvent;
+pub use self::changes::EventChanges;
+pub use self::changes::OriginChanges;
 pub use self::event::Event;
+pub use self::event::EventOrder;
+pub use self::event::EventPatch;
 pub use self::info::Info;
 pub use self::query::Query;
+pub use self::query::StoredQuery;
+pub use self::query::StoredQueryRun;
 pub use self::timeinterval::TimeInterval;
+pub use self::timeinterval::TimePeriod;
 pub use self::tryvec::TryVec;
@@ -1,11 +1,50 @@
-use serde::Deserialize;
+use std::collections::HashMap;
 
-use crate::TimeInterval;
+use chrono::DateTime;
+use chrono::Utc;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::value::Value;
+
+use crate::TimePeriod;
 
 // TODO Implement serialize once TimeInterval has implemented it
 #[derive(Deserialize, Clone, Debug)]
 pub struct Query {
     //#[serde(with = "DurationSerialization")]
-    pub timeperiods: Vec<TimeInterval>,
+    pub timeperiods: Vec<TimePeriod>,
+    pub query: Vec<String>,
+    /// Values for the $name placeholders in the query
+    #[serde(default)]
+    pub params: HashMap<String, Value>,
+    /// IANA timezone to resolve relative timeperiods in, overrides the server config
+    #[serde(default)]
+    pub timezone: Option<String>,
+    /// Time of day (HH:MM) when days start for relative timeperiods, overrides the server config
+    #[serde(default)]
+    pub start_of_day: Option<String>,
+}
+
+/// A query saved in the datastore, which can be run by name or imported into other queries
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct StoredQuery {
+    #[serde(default)]
+    pub name: String,
     pub query: Vec<String>,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default, skip_deserializing)]
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+/// Request to run a stored query
+#[derive(Deserialize, Clone, Debug)]
+pub struct StoredQueryRun {
+    pub timeperiods: Vec<TimePeriod>,
+    #[serde(default)]
+    pub params: HashMap<String, Value>,
+    #[serde(default)]
+    pub timezone: Option<String>,
+    #[serde(default)]
+    pub start_of_day: Option<String>,
 }
@@ -5,7 +5,10 @@ use std::fmt;
 use serde::de::{self, Deserialize, Deserializer, Visitor};
 
 use chrono::DateTime;
+use chrono::Datelike;
 use chrono::Duration;
+use chrono::NaiveDate;
+use chrono::TimeZone;
 use chrono::Utc;
 
 // TODO: Implement serialize
@@ -140,6 +143,165 @@ impl<'de> Deserialize<'de> for TimeInterval {
     }
 }
 
+/// A timeperiod as given to queries, either an explicit interval or one relative to the
+/// current day such as "today", "yesterday", "this-week", "last-week", "this-month",
+/// "last-month" or "last-30d" (the 30 days up to and including today)
+#[derive(Clone, Debug)]
+pub enum TimePeriod {
+    Interval(TimeInterval),
+    Today,
+    Yesterday,
+    ThisWeek,
+    LastWeek,
+    ThisMonth,
+    LastMonth,
+    LastDays(u32),
+}
+
+impl TimePeriod {
+    pub fn new_from_string(period: &str) -> Result<TimePeriod, TimeIntervalError> {
+        let period = match period {
+            "today" => TimePeriod::Today,
+            "yesterday" => TimePeriod::Yesterday,
+            "this-week" => TimePeriod::ThisWeek,
+            "last-week" => TimePeriod::LastWeek,
+            "this-month" => TimePeriod::ThisMonth,
+            "last-month" => TimePeriod::LastMonth,
+            _ => match period
+                .strip_prefix("last-")
+                .and_then(|s| s.strip_suffix('d'))
+            {
+                Some(days) => match days.parse() {
+                    Ok(days) if days > 0 => TimePeriod::LastDays(days),
+                    _ => return Err(TimeIntervalError::ParseError()),
+                },
+                None => TimePeriod::Interval(TimeInterval::new_from_string(period)?),
+            },
+        };
+        Ok(period)
+    }
+
+    /// Resolves the timeperiod to an interval
+    ///
+    /// Days start day_start after midnight in the timezone tz, so with a day_start of 4 hours
+    /// 03:00 still belongs to the previous day. Days are not always 24 hours long, as the
+    /// timezone might change between daylight saving and standard time.
+    pub fn resolve<Tz: TimeZone>(
+        &self,
+        now: DateTime<Utc>,
+        tz: &Tz,
+        day_start: Duration,
+    ) -> TimeInterval {
+        let today = (now.with_timezone(tz).naive_local() - day_start).date();
+        let day_start_of = |date: NaiveDate| local_day_start(tz, date, day_start);
+        let week_start = today - Duration::days(today.weekday().num_days_from_monday() as i64);
+        let month_start = today.with_day(1).unwrap();
+        match self {
+            TimePeriod::Interval(interval) => interval.clone(),
+            TimePeriod::Today => {
+                TimeInterval::new(day_start_of(today), day_start_of(today + Duration::days(1)))
+            }
+            TimePeriod::Yesterday => {
+                TimeInterval::new(day_start_of(today - Duration::days(1)), day_start_of(today))
+            }
+            TimePeriod::ThisWeek => TimeInterval::new(
+                day_start_of(week_start),
+                day_start_of(week_start + Duration::weeks(1)),
+            ),
+            TimePeriod::LastWeek => TimeInterval::new(
+                day_start_of(week_start - Duration::weeks(1)),
+                day_start_of(week_start),
+            ),
+            TimePeriod::ThisMonth => TimeInterval::new(
+                day_start_of(month_start),
+                day_start_of(next_month(month_start)),
+            ),
+            TimePeriod::LastMonth => TimeInterval::new(
+                day_start_of(previous_month(month_start)),
+                day_start_of(month_start),
+            ),
+            TimePeriod::LastDays(days) => TimeInterval::new(
+                day_start_of(today - Duration::days(*days as i64 - 1)),
+                day_start_of(today + Duration::days(1)),
+            ),
+        }
+    }
+}
+
+fn next_month(month_start: NaiveDate) -> NaiveDate {
+    match month_start.month() {
+        12 => NaiveDate::from_ymd_opt(month_start.year() + 1, 1, 1).unwrap(),
+        month => NaiveDate::from_ymd_opt(month_start.year(), month + 1, 1).unwrap(),
+    }
+}
+
+fn previous_month(month_start: NaiveDate) -> NaiveDate {
+    match month_start.month() {
+        1 => NaiveDate::from_ymd_opt(month_start.year() - 1, 12, 1).unwrap(),
+        month => NaiveDate::from_ymd_opt(month_start.year(), month - 1, 1).unwrap(),
+    }
+}
+
+/// The instant date starts at, if the local time is skipped by a daylight saving time change
+/// the day starts when the clocks have been turned forward and if it occurs twice it starts the
+/// first time.
+fn local_day_start<Tz: TimeZone>(tz: &Tz, date: NaiveDate, day_start: Duration) -> DateTime<Utc> {
+    let mut local = date.and_hms_opt(0, 0, 0).unwrap() + day_start;
+    loop {
+        if let Some(dt) = tz.from_local_datetime(&local).earliest() {
+            return dt.with_timezone(&Utc);
+        }
+        local += Duration::minutes(1);
+    }
+}
+
+impl fmt::Display for TimePeriod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TimePeriod::Interval(interval) => write!(f, "{interval}"),
+            TimePeriod::Today => write!(f, "today"),
+            TimePeriod::Yesterday => write!(f, "yesterday"),
+            TimePeriod::ThisWeek => write!(f, "this-week"),
+            TimePeriod::LastWeek => write!(f, "last-week"),
+            TimePeriod::ThisMonth => write!(f, "this-month"),
+            TimePeriod::LastMonth => write!(f, "last-month"),
+            TimePeriod::LastDays(days) => write!(f, "last-{days}d"),
+        }
+    }
+}
+
+struct TimePeriodVisitor;
+
+impl<'de> Visitor<'de> for TimePeriodVisitor {
+    type Value = TimePeriod;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a string in ISO timeinterval format (such as 2000-01-01T00:00:00+01:00/2001-02-02T01:01:01+01:00) or one of today, yesterday, this-week, last-week, this-month, last-month or last-<n>d")
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        match TimePeriod::new_from_string(value) {
+            Ok(tp) => Ok(tp),
+            Err(e) => {
+                warn!("{:?}", e);
+                Err(de::Error::invalid_value(Unexpected::Str(value), &self))
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for TimePeriod {
+    fn deserialize<D>(deserializer: D) -> Result<TimePeriod, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(TimePeriodVisitor)
+    }
+}
+
 #[test]
 fn test_timeinterval() {
     use std::str::FromStr;
@@ -176,3 +338,113 @@ fn test_timeinterval_intersection() {
     );
     assert!(!tp1.intersects(&tp2));
 }
+
+#[test]
+fn test_timeperiod_parse() {
+    for period in [
+        "today",
+        "yesterday",
+        "this-week",
+        "last-week",
+        "this-month",
+        "last-month",
+        "last-30d",
+        "2000-01-01T00:00:00+00:00/2000-01-02T00:00:00+00:00",
+    ] {
+        let tp = TimePeriod::new_from_string(period).unwrap();
+        assert_eq!(tp.to_string(), period);
+    }
+    for period in ["tomorrow", "last-0d", "last-xd", "last-30", "2000-01-01"] {
+        assert!(TimePeriod::new_from_string(period).is_err(), "{period}");
+    }
+}
+
+#[test]
+fn test_timeperiod_resolve() {
+    use chrono::FixedOffset;
+    use std::str::FromStr;
+
+    let ts = |s: &str| DateTime::<Utc>::from_str(s).unwrap();
+    let tz = FixedOffset::east_opt(2 * 3600).unwrap();
+    // Thursday 2000-03-02 01:00 in local time
+    let now = ts("2000-03-01T23:00:00Z");
+
+    let resolve = |period: &str, day_start: Duration| {
+        TimePeriod::new_from_string(period)
+            .unwrap()
+            .resolve(now, &tz, day_start)
+            .to_string()
+    };
+    let zero = Duration::zero();
+    assert_eq!(
+        resolve("today", zero),
+        "2000-03-01T22:00:00+00:00/2000-03-02T22:00:00+00:00"
+    );
+    assert_eq!(
+        resolve("yesterday", zero),
+        "2000-02-29T22:00:00+00:00/2000-03-01T22:00:00+00:00"
+    );
+    assert_eq!(
+        resolve("this-week", zero),
+        "2000-02-27T22:00:00+00:00/2000-03-05T22:00:00+00:00"
+    );
+    assert_eq!(
+        resolve("last-week", zero),
+        "2000-02-20T22:00:00+00:00/2000-02-27T22:00:00+00:00"
+    );
+    assert_eq!(
+        resolve("this-month", zero),
+        "2000-02-29T22:00:00+00:00/2000-03-31T22:00:00+00:00"
+    );
+    assert_eq!(
+        resolve("last-month", zero),
+        "2000-01-31T22:00:00+00:00/2000-02-29T22:00:00+00:00"
+    );
+    assert_eq!(
+        resolve("last-3d", zero),
+        "2000-02-28T22:00:00+00:00/2000-03-02T22:00:00+00:00"
+    );
+
+    // At 01:00 a day starting at 04:00 is still yesterday
+    let four_hours = Duration::hours(4);
+    assert_eq!(
+        resolve("today", four_hours),
+        "2000-03-01T02:00:00+00:00/2000-03-02T02:00:00+00:00"
+    );
+    assert_eq!(
+        resolve("yesterday", four_hours),
+        "2000-02-29T02:00:00+00:00/2000-03-01T02:00:00+00:00"
+    );
+}
+
+#[test]
+fn test_timeperiod_resolve_dst() {
+    use chrono_tz::Europe::Stockholm;
+    use std::str::FromStr;
+
+    let ts = |s: &str| DateTime::<Utc>::from_str(s).unwrap();
+    let today = TimePeriod::Today;
+
+    // Clocks went from 02:00 to 03:00 on 2021-03-28, making the day 23 hours long
+    let tp = today.resolve(ts("2021-03-28T12:00:00Z"), &Stockholm, Duration::zero());
+    assert_eq!(
+        tp.to_string(),
+        "2021-03-27T23:00:00+00:00/2021-03-28T22:00:00+00:00"
+    );
+    assert_eq!(tp.duration(), Duration::hours(23));
+
+    // And from 03:00 to 02:00 on 2021-10-31, making the day 25 hours long
+    let tp = today.resolve(ts("2021-10-31T12:00:00Z"), &Stockholm, Duration::zero());
+    assert_eq!(tp.duration(), Duration::hours(25));
+
+    // A day starting at 02:30 on 2021-03-28 starts at 03:00 as 02:30 never happened
+    let tp = today.resolve(
+        ts("2021-03-28T12:00:00Z"),
+        &Stockholm,
+        Duration::minutes(150),
+    );
+    assert_eq!(
+        tp.to_string(),
+        "2021-03-28T01:00:00+00:00/2021-03-29T00:30:00+00:00"
+    );
+}
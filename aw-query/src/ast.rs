@@ -22,12 +22,36 @@ pub enum Expr_ {
     Mod(Box<Expr>, Box<Expr>),
 
     Equal(Box<Expr>, Box<Expr>),
+    NotEqual(Box<Expr>, Box<Expr>),
+    Less(Box<Expr>, Box<Expr>),
+    LessEqual(Box<Expr>, Box<Expr>),
+    Greater(Box<Expr>, Box<Expr>),
+    GreaterEqual(Box<Expr>, Box<Expr>),
+    In(Box<Expr>, Box<Expr>),
+
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
 
     Var(String),
+    // $name, a value given when running the query
+    Param(String),
     Assign(String, Box<Expr>),
     Function(String, Box<Expr>),
+    // value[key] and value.key
+    Index(Box<Expr>, Box<Expr>),
     If(Vec<(Box<Expr>, Vec<Expr>)>),
+    // for var in list { body }
+    For(String, Box<Expr>, Vec<Expr>),
+    // [expr for var in list if cond]
+    Comprehension(Box<Expr>, String, Box<Expr>, Option<Box<Expr>>),
     Return(Box<Expr>),
+    // def name(params) { body }
+    Def(String, Vec<String>, Vec<Expr>),
+    // lambda params: expr, the body is a single return statement
+    Lambda(Vec<String>, Vec<Expr>),
+    // import "name", runs a stored query in place
+    Import(String),
 
     Bool(bool),
     Number(f64),
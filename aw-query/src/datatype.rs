@@ -1,17 +1,19 @@
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::fmt;
+use std::sync::Arc;
 
 use super::functions;
+use super::interpret::Lambda;
 use super::QueryError;
 use aw_models::Event;
 use aw_transform::classify::{RegexRule, Rule};
+use chrono::{DateTime, Duration, Utc};
 
 use serde::{Serialize, Serializer};
 use serde_json::value::Value;
 use serde_json::Number;
 
-// TODO: greater/less comparisons
-
 #[derive(Clone, Serialize)]
 #[serde(untagged)]
 pub enum DataType {
@@ -19,11 +21,13 @@ pub enum DataType {
     Bool(bool),
     Number(f64),
     String(String),
-    Event(Event),
+    Event(Box<Event>),
     List(Vec<DataType>),
     Dict(HashMap<String, DataType>),
     #[serde(serialize_with = "serialize_function")]
     Function(String, functions::QueryFn),
+    #[serde(serialize_with = "serialize_lambda")]
+    Lambda(Arc<Lambda>),
 }
 
 #[allow(clippy::trivially_copy_pass_by_ref)]
@@ -39,6 +43,16 @@ where
     //element.id.serialize(serializer)
 }
 
+fn serialize_lambda<S>(lambda: &Arc<Lambda>, _serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    Err(serde::ser::Error::custom(format!(
+        "Cannot serialize function {}",
+        lambda.name()
+    )))
+}
+
 // Needed because of a limitation in rust where you cannot derive(Debug) on a
 // enum which has a fn with reference parameters which our QueryFn has
 // https://stackoverflow.com/questions/53380040/function-pointer-with-a-reference-argument-cannot-derive-debug
@@ -53,6 +67,24 @@ impl fmt::Debug for DataType {
             DataType::List(l) => write!(f, "List({l:?})"),
             DataType::Dict(d) => write!(f, "Dict({d:?})"),
             DataType::Function(name, _fun) => write!(f, "Function({name})"),
+            DataType::Lambda(lambda) => write!(f, "Lambda({})", lambda.name()),
+        }
+    }
+}
+
+impl From<&Value> for DataType {
+    fn from(value: &Value) -> Self {
+        match value {
+            Value::Null => DataType::None(),
+            Value::Bool(b) => DataType::Bool(*b),
+            Value::Number(n) => DataType::Number(n.as_f64().unwrap()),
+            Value::String(s) => DataType::String(s.clone()),
+            Value::Array(a) => DataType::List(a.iter().map(DataType::from).collect()),
+            Value::Object(o) => DataType::Dict(
+                o.iter()
+                    .map(|(k, v)| (k.clone(), DataType::from(v)))
+                    .collect(),
+            ),
         }
     }
 }
@@ -76,6 +108,139 @@ impl DataType {
             ))),
         }
     }
+
+    /* Ordering used by <, <=, > and >=, only numbers, strings and events (by timestamp) can
+     * be ordered and only against a value of the same type */
+    pub fn query_cmp(&self, other: &DataType) -> Result<Ordering, QueryError> {
+        match (self, other) {
+            (DataType::Number(n1), DataType::Number(n2)) => match n1.partial_cmp(n2) {
+                Some(ord) => Ok(ord),
+                None => Err(QueryError::MathError(format!(
+                    "Cannot order {n1} and {n2}"
+                ))),
+            },
+            (DataType::String(s1), DataType::String(s2)) => Ok(s1.cmp(s2)),
+            (DataType::Event(e1), DataType::Event(e2)) => Ok(e1.timestamp.cmp(&e2.timestamp)),
+            _ => Err(QueryError::InvalidType(format!(
+                "Cannot order values {self:?} and {other:?}, only numbers, strings and events of the same type can be ordered"
+            ))),
+        }
+    }
+
+    /* Used by the in operator: list membership, dict key or substring */
+    pub fn query_contains(&self, item: &DataType) -> Result<bool, QueryError> {
+        match (self, item) {
+            (DataType::List(l), _) => {
+                for elem in l {
+                    // Items of other types than the searched one are never equal
+                    if elem == item {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            (DataType::Dict(d), DataType::String(key)) => Ok(d.contains_key(key)),
+            (DataType::String(s), DataType::String(substr)) => Ok(s.contains(substr.as_str())),
+            _ => Err(QueryError::InvalidType(format!(
+                "Cannot check if {item:?} is in {self:?}"
+            ))),
+        }
+    }
+
+    /* Used by indexing, value[key] and value.key, returns None if there is no such key.
+     * Lists are indexed by number where negative numbers count from the end, dicts by key
+     * and events by field name (timestamp, duration, data or id) */
+    pub fn get_item(&self, key: &DataType) -> Result<Option<DataType>, QueryError> {
+        match (self, key) {
+            (DataType::List(l), DataType::Number(n)) => {
+                Ok(list_index(l.len(), *n)?.map(|i| l[i].clone()))
+            }
+            (DataType::Dict(d), DataType::String(k)) => Ok(d.get(k).cloned()),
+            (DataType::Event(e), DataType::String(field)) => Ok(match field.as_str() {
+                "timestamp" => Some(DataType::String(e.timestamp.to_rfc3339())),
+                "duration" => Some(DataType::Number(
+                    (e.duration.num_milliseconds() as f64) / 1000.0,
+                )),
+                "data" => Some(DataType::Dict(
+                    e.data
+                        .iter()
+                        .map(|(k, v)| (k.clone(), DataType::from(v)))
+                        .collect(),
+                )),
+                "id" => Some(match e.id {
+                    Some(id) => DataType::Number(id as f64),
+                    None => DataType::None(),
+                }),
+                _ => None,
+            }),
+            _ => Err(QueryError::InvalidType(format!(
+                "Cannot index {self:?} with {key:?}"
+            ))),
+        }
+    }
+
+    /* Returns a copy with the item at key replaced, the counterpart of get_item */
+    pub fn set_item(self, key: &DataType, val: DataType) -> Result<DataType, QueryError> {
+        match (self, key) {
+            (DataType::List(mut l), DataType::Number(n)) => match list_index(l.len(), *n)? {
+                Some(i) => {
+                    l[i] = val;
+                    Ok(DataType::List(l))
+                }
+                None => Err(QueryError::KeyError(format!(
+                    "Index {n} is out of range for a list of length {}",
+                    l.len()
+                ))),
+            },
+            (DataType::Dict(mut d), DataType::String(k)) => {
+                d.insert(k.clone(), val);
+                Ok(DataType::Dict(d))
+            }
+            (DataType::Event(mut e), DataType::String(field)) => {
+                match (field.as_str(), val) {
+                    ("timestamp", DataType::String(s)) => {
+                        e.timestamp = DateTime::parse_from_rfc3339(&s)
+                            .map_err(|err| {
+                                QueryError::InvalidType(format!(
+                                    "Invalid event timestamp '{s}': {err}"
+                                ))
+                            })?
+                            .with_timezone(&Utc);
+                    }
+                    ("duration", DataType::Number(secs)) => {
+                        e.duration = Duration::nanoseconds((secs * 1e9) as i64);
+                    }
+                    ("data", val @ DataType::Dict(_)) => match Value::try_from(&val)? {
+                        Value::Object(data) => e.data = data,
+                        _ => unreachable!(),
+                    },
+                    (field, val) => {
+                        return Err(QueryError::InvalidType(format!(
+                            "Cannot set event field '{field}' to {val:?}, only timestamp (string), duration (number) and data (dict) can be set"
+                        )))
+                    }
+                }
+                Ok(DataType::Event(e))
+            }
+            (container, key) => Err(QueryError::InvalidType(format!(
+                "Cannot set {key:?} in {container:?}"
+            ))),
+        }
+    }
+}
+
+fn list_index(len: usize, n: f64) -> Result<Option<usize>, QueryError> {
+    if n.fract() != 0.0 {
+        return Err(QueryError::InvalidType(format!(
+            "List indices have to be integers, got {n}"
+        )));
+    }
+    let i = if n < 0.0 { len as f64 + n } else { n };
+    if i < 0.0 || i >= len as f64 {
+        Ok(None)
+    } else {
+        Ok(Some(i as usize))
+    }
 }
 
 /* Required for query_eq when comparing two dicts */
@@ -140,7 +305,7 @@ impl TryFrom<&DataType> for Vec<Event> {
         let mut events = Vec::new();
         for event in tagged_events.drain(..) {
             match event {
-                DataType::Event(e) => events.push(e.clone()),
+                DataType::Event(e) => events.push(*e),
                 ref invalid_type => {
                     return Err(QueryError::InvalidFunctionParameters(format!(
                         "Expected function parameter of type List of Events, list contains {invalid_type:?}"
@@ -250,6 +415,13 @@ impl TryFrom<&DataType> for Value {
                 }
                 Ok(Value::Array(values))
             }
+            DataType::Dict(d) => {
+                let mut map = serde_json::Map::new();
+                for (k, v) in d {
+                    map.insert(k.clone(), v.try_into()?);
+                }
+                Ok(Value::Object(map))
+            }
             ref invalid_type => Err(QueryError::InvalidFunctionParameters(format!(
                 "Query2 support for parsing values is limited, does not support parsing {invalid_type:?}"
             ))),
@@ -82,6 +82,10 @@ pub fn fill_env(env: &mut VarEnv) {
             qfunctions::filter_keyvals_regex,
         ),
     );
+    env.insert(
+        "extract_regex".to_string(),
+        DataType::Function("extract_regex".to_string(), qfunctions::extract_regex),
+    );
     env.insert(
         "filter_period_intersect".to_string(),
         DataType::Function(
@@ -93,6 +97,14 @@ pub fn fill_env(env: &mut VarEnv) {
         "split_url_events".to_string(),
         DataType::Function("split_url_events".to_string(), qfunctions::split_url_events),
     );
+    env.insert(
+        "split_by_interval".to_string(),
+        DataType::Function("split_by_interval".into(), qfunctions::split_by_interval),
+    );
+    env.insert(
+        "group_by_time".to_string(),
+        DataType::Function("group_by_time".into(), qfunctions::group_by_time),
+    );
     env.insert(
         "concat".to_string(),
         DataType::Function("concat".to_string(), qfunctions::concat),
@@ -113,14 +125,61 @@ pub fn fill_env(env: &mut VarEnv) {
         "union_no_overlap".to_string(),
         DataType::Function("union_no_overlap".into(), qfunctions::union_no_overlap),
     );
+    env.insert(
+        "map".to_string(),
+        DataType::Function("map".into(), qfunctions::map),
+    );
+    env.insert(
+        "filter".to_string(),
+        DataType::Function("filter".into(), qfunctions::filter),
+    );
+    env.insert(
+        "reduce".to_string(),
+        DataType::Function("reduce".into(), qfunctions::reduce),
+    );
+    env.insert(
+        "sort_by".to_string(),
+        DataType::Function("sort_by".into(), qfunctions::sort_by),
+    );
+    env.insert(
+        "range".to_string(),
+        DataType::Function("range".into(), qfunctions::range),
+    );
+    env.insert(
+        "keys".to_string(),
+        DataType::Function("keys".into(), qfunctions::keys),
+    );
+    env.insert(
+        "values".to_string(),
+        DataType::Function("values".into(), qfunctions::values),
+    );
+    env.insert(
+        "len".to_string(),
+        DataType::Function("len".into(), qfunctions::len),
+    );
+    env.insert(
+        "get".to_string(),
+        DataType::Function("get".into(), qfunctions::get),
+    );
+    env.insert(
+        "set".to_string(),
+        DataType::Function("set".into(), qfunctions::set),
+    );
+    env.insert(
+        "event".to_string(),
+        DataType::Function("event".into(), qfunctions::event),
+    );
 }
 
 mod qfunctions {
+    use std::collections::HashMap;
+
     use aw_datastore::Datastore;
     use aw_models::Event;
     use aw_transform::classify::Rule;
 
     use super::validate;
+    use crate::interpret::{call_function, MAX_ITERATIONS};
     use crate::DataType;
     use crate::QueryError;
     use crate::VarEnv;
@@ -162,7 +221,7 @@ mod qfunctions {
         };
         let mut ret = Vec::new();
         for event in events {
-            ret.push(DataType::Event(event));
+            ret.push(DataType::Event(Box::new(event)));
         }
         Ok(DataType::List(ret))
     }
@@ -270,7 +329,7 @@ mod qfunctions {
         // Put events back into DataType::Event container
         let mut tagged_flooded_events = Vec::new();
         for event in flooded_events.drain(..) {
-            tagged_flooded_events.push(DataType::Event(event));
+            tagged_flooded_events.push(DataType::Event(Box::new(event)));
         }
         Ok(DataType::List(tagged_flooded_events))
     }
@@ -289,7 +348,7 @@ mod qfunctions {
         // Put events back into DataType::Event container
         let mut tagged_flooded_events = Vec::new();
         for event in flooded_events.drain(..) {
-            tagged_flooded_events.push(DataType::Event(event));
+            tagged_flooded_events.push(DataType::Event(Box::new(event)));
         }
         Ok(DataType::List(tagged_flooded_events))
     }
@@ -308,7 +367,7 @@ mod qfunctions {
         // Put events back into DataType::Event container
         let mut tagged_flooded_events = Vec::new();
         for event in flooded_events.drain(..) {
-            tagged_flooded_events.push(DataType::Event(event));
+            tagged_flooded_events.push(DataType::Event(Box::new(event)));
         }
         Ok(DataType::List(tagged_flooded_events))
     }
@@ -327,7 +386,7 @@ mod qfunctions {
         // Put events back into DataType::Event container
         let mut tagged_sorted_events = Vec::new();
         for event in sorted_events.drain(..) {
-            tagged_sorted_events.push(DataType::Event(event));
+            tagged_sorted_events.push(DataType::Event(Box::new(event)));
         }
         Ok(DataType::List(tagged_sorted_events))
     }
@@ -347,7 +406,7 @@ mod qfunctions {
         }
         let mut limited_tagged_events = Vec::new();
         for event in events.drain(0..limit) {
-            limited_tagged_events.push(DataType::Event(event));
+            limited_tagged_events.push(DataType::Event(Box::new(event)));
         }
         Ok(DataType::List(limited_tagged_events))
     }
@@ -366,7 +425,7 @@ mod qfunctions {
         // Put events back into DataType::Event container
         let mut tagged_sorted_events = Vec::new();
         for event in sorted_events.drain(..) {
-            tagged_sorted_events.push(DataType::Event(event));
+            tagged_sorted_events.push(DataType::Event(Box::new(event)));
         }
         Ok(DataType::List(tagged_sorted_events))
     }
@@ -403,7 +462,7 @@ mod qfunctions {
         let mut merged_events = aw_transform::merge_events_by_keys(events, keys);
         let mut merged_tagged_events = Vec::new();
         for event in merged_events.drain(..) {
-            merged_tagged_events.push(DataType::Event(event));
+            merged_tagged_events.push(DataType::Event(Box::new(event)));
         }
         Ok(DataType::List(merged_tagged_events))
     }
@@ -421,7 +480,7 @@ mod qfunctions {
         let mut merged_events = aw_transform::chunk_events_by_key(events, &key);
         let mut merged_tagged_events = Vec::new();
         for event in merged_events.drain(..) {
-            merged_tagged_events.push(DataType::Event(event));
+            merged_tagged_events.push(DataType::Event(Box::new(event)));
         }
         Ok(DataType::List(merged_tagged_events))
     }
@@ -440,7 +499,7 @@ mod qfunctions {
         let mut filtered_events = aw_transform::filter_keyvals(events, &key, &vals);
         let mut filtered_tagged_events = Vec::new();
         for event in filtered_events.drain(..) {
-            filtered_tagged_events.push(DataType::Event(event));
+            filtered_tagged_events.push(DataType::Event(Box::new(event)));
         }
         Ok(DataType::List(filtered_tagged_events))
     }
@@ -469,11 +528,46 @@ mod qfunctions {
         let mut filtered_events = aw_transform::filter_keyvals_regex(events, &key, &regex);
         let mut filtered_tagged_events = Vec::new();
         for event in filtered_events.drain(..) {
-            filtered_tagged_events.push(DataType::Event(event));
+            filtered_tagged_events.push(DataType::Event(Box::new(event)));
         }
         Ok(DataType::List(filtered_tagged_events))
     }
 
+    pub fn extract_regex(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 4)?;
+        let events = (&args[0]).try_into()?;
+        let key: String = (&args[1]).try_into()?;
+        let regex_str: String = (&args[2]).try_into()?;
+        let target_keys: Vec<String> = (&args[3]).try_into()?;
+        let regex = match RegexBuilder::new(&regex_str).build() {
+            Ok(regex) => regex,
+            Err(e) => {
+                return Err(QueryError::RegexCompileError(format!(
+                    "Failed to compile regex string '{regex_str}': {e}"
+                )))
+            }
+        };
+        for target_key in &target_keys {
+            if !regex.capture_names().any(|name| name == Some(target_key)) {
+                return Err(QueryError::InvalidFunctionParameters(format!(
+                    "Regex '{regex_str}' has no capture group named '{target_key}'"
+                )));
+            }
+        }
+
+        let mut extracted_events = aw_transform::extract_regex(events, &key, &regex, &target_keys);
+        let mut extracted_tagged_events = Vec::new();
+        for event in extracted_events.drain(..) {
+            extracted_tagged_events.push(DataType::Event(Box::new(event)));
+        }
+        Ok(DataType::List(extracted_tagged_events))
+    }
+
     pub fn exclude_keyvals(
         args: Vec<DataType>,
         _env: &VarEnv,
@@ -488,7 +582,7 @@ mod qfunctions {
         let mut filtered_events = aw_transform::exclude_keyvals(events, &key, &vals);
         let mut filtered_tagged_events = Vec::new();
         for event in filtered_events.drain(..) {
-            filtered_tagged_events.push(DataType::Event(event));
+            filtered_tagged_events.push(DataType::Event(Box::new(event)));
         }
         Ok(DataType::List(filtered_tagged_events))
     }
@@ -506,7 +600,7 @@ mod qfunctions {
         let mut filtered_events = aw_transform::filter_period_intersect(events, filter_events);
         let mut filtered_tagged_events = Vec::new();
         for event in filtered_events.drain(..) {
-            filtered_tagged_events.push(DataType::Event(event));
+            filtered_tagged_events.push(DataType::Event(Box::new(event)));
         }
         Ok(DataType::List(filtered_tagged_events))
     }
@@ -523,11 +617,60 @@ mod qfunctions {
         let mut tagged_split_url_events = Vec::new();
         for mut event in events.drain(..) {
             aw_transform::split_url_event(&mut event);
-            tagged_split_url_events.push(DataType::Event(event));
+            tagged_split_url_events.push(DataType::Event(Box::new(event)));
         }
         Ok(DataType::List(tagged_split_url_events))
     }
 
+    pub fn split_by_interval(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 3)?;
+        let events: Vec<Event> = (&args[0]).try_into()?;
+        let bin = validate::time_bin(&args[1])?;
+        let tz = validate::timezone(&args[2])?;
+
+        let split_events = aw_transform::split_by_interval(events, &bin, &tz);
+        Ok(DataType::List(
+            split_events
+                .into_iter()
+                .map(|e| DataType::Event(Box::new(e)))
+                .collect(),
+        ))
+    }
+
+    /// group_by_time(events, bin, tz) or group_by_time(events, bin, tz, keys)
+    pub fn group_by_time(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        let keys: Vec<String> = match args.len() {
+            3 => Vec::new(),
+            4 => (&args[3]).try_into()?,
+            n => {
+                return Err(QueryError::InvalidFunctionParameters(format!(
+                    "Expected 3 or 4 parameters in function group_by_time, got {n}"
+                )))
+            }
+        };
+        let events: Vec<Event> = (&args[0]).try_into()?;
+        let bin = validate::time_bin(&args[1])?;
+        let tz = validate::timezone(&args[2])?;
+
+        let grouped_events = aw_transform::group_by_time(events, &bin, &tz, &keys);
+        Ok(DataType::List(
+            grouped_events
+                .into_iter()
+                .map(|e| DataType::Event(Box::new(e)))
+                .collect(),
+        ))
+    }
+
     pub fn concat(
         args: Vec<DataType>,
         _env: &VarEnv,
@@ -537,7 +680,7 @@ mod qfunctions {
         for arg in args {
             let mut events: Vec<Event> = (&arg).try_into()?;
             for event in events.drain(..) {
-                event_list.push(DataType::Event(event));
+                event_list.push(DataType::Event(Box::new(event)));
             }
         }
         Ok(DataType::List(event_list))
@@ -556,7 +699,7 @@ mod qfunctions {
         let mut result = aw_transform::period_union(&events1, &events2);
         let mut result_tagged = Vec::new();
         for event in result.drain(..) {
-            result_tagged.push(DataType::Event(event));
+            result_tagged.push(DataType::Event(Box::new(event)));
         }
         Ok(DataType::List(result_tagged))
     }
@@ -574,15 +717,280 @@ mod qfunctions {
         let mut result = aw_transform::union_no_overlap(events1, events2);
         let mut result_tagged = Vec::new();
         for event in result.drain(..) {
-            result_tagged.push(DataType::Event(event));
+            result_tagged.push(DataType::Event(Box::new(event)));
         }
         Ok(DataType::List(result_tagged))
     }
+
+    pub fn map(args: Vec<DataType>, env: &VarEnv, ds: &Datastore) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 2)?;
+        let list: Vec<DataType> = (&args[0]).try_into()?;
+        let fun = validate::function(&args[1])?;
+
+        let mut mapped = Vec::new();
+        for item in list {
+            mapped.push(call_function(fun, vec![item], env, ds)?);
+        }
+        Ok(DataType::List(mapped))
+    }
+
+    pub fn filter(
+        args: Vec<DataType>,
+        env: &VarEnv,
+        ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 2)?;
+        let list: Vec<DataType> = (&args[0]).try_into()?;
+        let fun = validate::function(&args[1])?;
+
+        let mut filtered = Vec::new();
+        for item in list {
+            match call_function(fun, vec![item.clone()], env, ds)? {
+                DataType::Bool(true) => filtered.push(item),
+                DataType::Bool(false) => (),
+                res => {
+                    return Err(QueryError::InvalidType(format!(
+                        "function passed to filter returned {res:?}, expected type Bool"
+                    )))
+                }
+            }
+        }
+        Ok(DataType::List(filtered))
+    }
+
+    pub fn reduce(
+        args: Vec<DataType>,
+        env: &VarEnv,
+        ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 3)?;
+        let list: Vec<DataType> = (&args[0]).try_into()?;
+        let fun = validate::function(&args[1])?;
+
+        let mut acc = args[2].clone();
+        for item in list {
+            acc = call_function(fun, vec![acc, item], env, ds)?;
+        }
+        Ok(acc)
+    }
+
+    pub fn sort_by(
+        args: Vec<DataType>,
+        env: &VarEnv,
+        ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 2)?;
+        let list: Vec<DataType> = (&args[0]).try_into()?;
+        let fun = validate::function(&args[1])?;
+
+        // Compute all keys first, so errors can be returned before sorting
+        let mut keyed = Vec::new();
+        for item in list {
+            let key = call_function(fun, vec![item.clone()], env, ds)?;
+            keyed.push((key, item));
+        }
+        let all_numbers = keyed
+            .iter()
+            .all(|(key, _)| matches!(key, DataType::Number(_)));
+        let all_strings = keyed
+            .iter()
+            .all(|(key, _)| matches!(key, DataType::String(_)));
+        if !all_numbers && !all_strings {
+            return Err(QueryError::InvalidType(
+                "function passed to sort_by has to return only numbers or only strings".to_string(),
+            ));
+        }
+        keyed.sort_by(|(k1, _), (k2, _)| match (k1, k2) {
+            (DataType::Number(n1), DataType::Number(n2)) => n1.total_cmp(n2),
+            (DataType::String(s1), DataType::String(s2)) => s1.cmp(s2),
+            _ => unreachable!(),
+        });
+        Ok(DataType::List(
+            keyed.into_iter().map(|(_key, item)| item).collect(),
+        ))
+    }
+
+    /// range(end) or range(start, end), a list of the numbers from start (0 by default) up
+    /// to but not including end
+    pub fn range(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        let (start, end): (usize, usize) = match args.len() {
+            1 => (0, (&args[0]).try_into()?),
+            2 => ((&args[0]).try_into()?, (&args[1]).try_into()?),
+            n => {
+                return Err(QueryError::InvalidFunctionParameters(format!(
+                    "Expected 1 or 2 parameters in function range, got {n}"
+                )))
+            }
+        };
+        // A list longer than this could never be iterated over anyway
+        if end.saturating_sub(start) > MAX_ITERATIONS {
+            return Err(QueryError::IterationLimit(format!(
+                "range of {} numbers is longer than the max number of iterations {MAX_ITERATIONS}",
+                end - start
+            )));
+        }
+        Ok(DataType::List(
+            (start..end).map(|n| DataType::Number(n as f64)).collect(),
+        ))
+    }
+
+    pub fn keys(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 1)?;
+        let dict = validate::dict(&args[0])?;
+
+        let mut keys: Vec<&String> = dict.keys().collect();
+        keys.sort();
+        Ok(DataType::List(
+            keys.into_iter()
+                .map(|k| DataType::String(k.clone()))
+                .collect(),
+        ))
+    }
+
+    /// Values of a dict, in the same order as the keys returned by keys()
+    pub fn values(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 1)?;
+        let dict = validate::dict(&args[0])?;
+
+        let mut items: Vec<(&String, &DataType)> = dict.iter().collect();
+        items.sort_by_key(|(k, _)| *k);
+        Ok(DataType::List(
+            items.into_iter().map(|(_k, v)| v.clone()).collect(),
+        ))
+    }
+
+    pub fn len(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 1)?;
+        let len = match &args[0] {
+            DataType::List(l) => l.len(),
+            DataType::Dict(d) => d.len(),
+            DataType::String(s) => s.chars().count(),
+            other => {
+                return Err(QueryError::InvalidFunctionParameters(format!(
+                    "Expected a list, dict or string in function len, got {other:?}"
+                )))
+            }
+        };
+        Ok(DataType::Number(len as f64))
+    }
+
+    /// get(value, key, default) or get(value, key), where key can also be a list of keys to
+    /// look up in nested values. Returns default, or None, if a key does not exist.
+    pub fn get(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        let default = match args.len() {
+            2 => DataType::None(),
+            3 => args[2].clone(),
+            n => {
+                return Err(QueryError::InvalidFunctionParameters(format!(
+                    "Expected 2 or 3 parameters in function get, got {n}"
+                )))
+            }
+        };
+        let path = validate::key_path(&args[1]);
+
+        let mut val = args[0].clone();
+        for key in path {
+            val = match val.get_item(key)? {
+                Some(item) => item,
+                None => return Ok(default),
+            };
+        }
+        Ok(val)
+    }
+
+    /// set(value, key, item), returns a copy of value with the item set. With a list of keys
+    /// nested values are set, missing dicts along the way are created.
+    pub fn set(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 3)?;
+        let mut args = args;
+        let item = args.pop().unwrap();
+        let path = validate::key_path(&args[1]);
+        if path.is_empty() {
+            return Err(QueryError::InvalidFunctionParameters(
+                "Expected at least one key in function set".to_string(),
+            ));
+        }
+
+        fn set_path(
+            val: DataType,
+            path: &[DataType],
+            item: DataType,
+        ) -> Result<DataType, QueryError> {
+            match path.split_first() {
+                None => Ok(item),
+                Some((key, rest)) => {
+                    let inner = if rest.is_empty() {
+                        DataType::None()
+                    } else {
+                        val.get_item(key)?
+                            .unwrap_or_else(|| DataType::Dict(HashMap::new()))
+                    };
+                    let inner = set_path(inner, rest, item)?;
+                    val.set_item(key, inner)
+                }
+            }
+        }
+        set_path(args[0].clone(), path, item)
+    }
+
+    /// event(timestamp, duration, data) creates a new event
+    pub fn event(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 3)?;
+        validate::dict(&args[2])?;
+
+        let event = DataType::Event(Box::default());
+        let event = event.set_item(&DataType::String("timestamp".into()), args[0].clone())?;
+        let event = event.set_item(&DataType::String("duration".into()), args[1].clone())?;
+        event.set_item(&DataType::String("data".into()), args[2].clone())
+    }
 }
 
 mod validate {
+    use std::collections::HashMap;
+
     use crate::{DataType, QueryError, VarEnv};
     use aw_models::TimeInterval;
+    use aw_transform::TimeBin;
+    use chrono_tz::Tz;
 
     pub fn args_length(args: &[DataType], len: usize) -> Result<(), QueryError> {
         if args.len() != len {
@@ -595,6 +1003,47 @@ mod validate {
         Ok(())
     }
 
+    pub fn function(arg: &DataType) -> Result<&DataType, QueryError> {
+        match arg {
+            DataType::Function(..) | DataType::Lambda(..) => Ok(arg),
+            _ => Err(QueryError::InvalidFunctionParameters(format!(
+                "Expected function parameter of type Function, got {arg:?}"
+            ))),
+        }
+    }
+
+    pub fn dict(arg: &DataType) -> Result<&HashMap<String, DataType>, QueryError> {
+        match arg {
+            DataType::Dict(d) => Ok(d),
+            _ => Err(QueryError::InvalidFunctionParameters(format!(
+                "Expected function parameter of type Dict, got {arg:?}"
+            ))),
+        }
+    }
+
+    /// A single key, or a list of keys to nested values
+    pub fn key_path(arg: &DataType) -> &[DataType] {
+        match arg {
+            DataType::List(keys) => keys,
+            key => std::slice::from_ref(key),
+        }
+    }
+
+    pub fn time_bin(arg: &DataType) -> Result<TimeBin, QueryError> {
+        let bin_str: String = arg.try_into()?;
+        bin_str
+            .parse()
+            .map_err(QueryError::InvalidFunctionParameters)
+    }
+
+    /// An IANA timezone name such as "Europe/Stockholm" or "UTC"
+    pub fn timezone(arg: &DataType) -> Result<Tz, QueryError> {
+        let tz_str: String = arg.try_into()?;
+        tz_str.parse().map_err(|_| {
+            QueryError::InvalidFunctionParameters(format!("Unknown timezone '{tz_str}'"))
+        })
+    }
+
     pub fn get_timeinterval(env: &VarEnv) -> Result<TimeInterval, QueryError> {
         let interval_str = match env.get("TIMEINTERVAL") {
             Some(data_ti) => match data_ti {
@@ -1,19 +1,148 @@
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
 use crate::functions;
 
-use aw_datastore::Datastore;
+use aw_datastore::{Datastore, DatastoreError};
 use aw_models::TimeInterval;
 
 use crate::ast::*;
+use crate::CancellationToken;
 use crate::DataType;
 use crate::QueryError;
 
 pub type VarEnv = HashMap<String, DataType>;
 
-fn init_env(ti: &TimeInterval) -> VarEnv {
+/// Max number of nested calls to user-defined functions, protects against stack overflows
+/// caused by unbounded recursion. As many calls fit on the default stack of 2 MiB with room to
+/// spare, also in debug builds.
+pub const MAX_CALL_DEPTH: usize = 32;
+
+/// Max number of loop and comprehension iterations in total for a whole query
+pub const MAX_ITERATIONS: usize = 1_000_000;
+
+/// State shared by everything executed as part of one query
+struct ExecContext {
+    cancel: CancellationToken,
+    call_depth: AtomicUsize,
+    iterations: AtomicUsize,
+    // Names of the stored queries currently being imported, to detect circular imports
+    imports: Mutex<Vec<String>>,
+}
+
+/// A function defined in the query, either with def or lambda
+pub struct Lambda {
+    name: Option<String>,
+    params: Vec<String>,
+    body: Vec<Expr>,
+    // Variables of the enclosing function(s) at the time the lambda was defined
+    captured: VarEnv,
+    ctx: Arc<ExecContext>,
+}
+
+impl Lambda {
+    pub fn name(&self) -> &str {
+        match &self.name {
+            Some(name) => name,
+            None => "lambda",
+        }
+    }
+}
+
+/// Variables visible to the code being interpreted
+///
+/// Top-level code reads and writes the globals directly. Code in a function writes to its own
+/// locals and looks up variables in its locals, then in the variables captured where the
+/// function was defined and last in the globals. As globals are not captured a function can
+/// call itself and functions defined after it.
+enum Scope<'a> {
+    Global(&'a mut VarEnv),
+    Function {
+        locals: VarEnv,
+        captured: &'a VarEnv,
+        globals: &'a VarEnv,
+        returned: Option<DataType>,
+    },
+}
+
+impl Scope<'_> {
+    fn get(&self, var: &str) -> Option<&DataType> {
+        match self {
+            Scope::Global(globals) => globals.get(var),
+            Scope::Function {
+                locals,
+                captured,
+                globals,
+                ..
+            } => locals
+                .get(var)
+                .or_else(|| captured.get(var))
+                .or_else(|| globals.get(var)),
+        }
+    }
+
+    fn set(&mut self, var: String, val: DataType) {
+        match self {
+            Scope::Global(globals) => globals.insert(var, val),
+            Scope::Function { locals, .. } => locals.insert(var, val),
+        };
+    }
+
+    fn remove(&mut self, var: &str) -> Option<DataType> {
+        match self {
+            Scope::Global(globals) => globals.remove(var),
+            Scope::Function { locals, .. } => locals.remove(var),
+        }
+    }
+
+    fn globals(&self) -> &VarEnv {
+        match self {
+            Scope::Global(globals) => globals,
+            Scope::Function { globals, .. } => globals,
+        }
+    }
+
+    fn set_return(&mut self, val: DataType) {
+        match self {
+            // TODO: Once RETURN is deprecated we can fix this
+            Scope::Global(globals) => {
+                globals.insert("RETURN".to_string(), val);
+            }
+            Scope::Function { returned, .. } => *returned = Some(val),
+        }
+    }
+
+    // Top-level code keeps running after a return, functions do not
+    fn has_returned(&self) -> bool {
+        match self {
+            Scope::Global(_) => false,
+            Scope::Function { returned, .. } => returned.is_some(),
+        }
+    }
+
+    /// Variables to capture in a function defined in this scope
+    fn capture(&self) -> VarEnv {
+        match self {
+            Scope::Global(_) => VarEnv::new(),
+            Scope::Function {
+                locals, captured, ..
+            } => {
+                let mut vars = (*captured).clone();
+                vars.extend(locals.iter().map(|(k, v)| (k.clone(), v.clone())));
+                vars
+            }
+        }
+    }
+}
+
+fn init_env(ti: &TimeInterval, params: &HashMap<String, DataType>) -> VarEnv {
     let mut env = HashMap::new();
     env.insert("TIMEINTERVAL".to_string(), DataType::String(ti.to_string()));
+    // Identifiers cannot start with $, so params can never collide with variables
+    for (name, val) in params {
+        env.insert(format!("${name}"), val.clone());
+    }
     functions::fill_env(&mut env);
     env
 }
@@ -22,10 +151,20 @@ pub fn interpret_prog(
     p: Program,
     ti: &TimeInterval,
     ds: &Datastore,
+    params: &HashMap<String, DataType>,
+    cancel: &CancellationToken,
 ) -> Result<DataType, QueryError> {
-    let mut env = init_env(ti);
+    let mut env = init_env(ti, params);
+    let ctx = Arc::new(ExecContext {
+        cancel: cancel.clone(),
+        call_depth: AtomicUsize::new(0),
+        iterations: AtomicUsize::new(0),
+        imports: Mutex::new(Vec::new()),
+    });
+    let mut scope = Scope::Global(&mut env);
     for expr in p.stmts {
-        interpret_expr(&mut env, ds, expr)?;
+        cancel.check()?;
+        interpret_expr(&mut scope, ds, &ctx, expr)?;
     }
     match env.remove("RETURN") {
         Some(ret) => Ok(ret),
@@ -33,213 +172,472 @@ pub fn interpret_prog(
     }
 }
 
+/// Calls a builtin or user-defined function
+///
+/// Used by the interpreter as well as by builtins taking functions as arguments.
+pub fn call_function(
+    fun: &DataType,
+    args: Vec<DataType>,
+    env: &VarEnv,
+    ds: &Datastore,
+) -> Result<DataType, QueryError> {
+    match fun {
+        DataType::Function(_name, fun) => fun(args, env, ds),
+        DataType::Lambda(lambda) => call_lambda(lambda, args, env, ds),
+        _ => Err(QueryError::InvalidType(format!(
+            "Expected a function, got {fun:?}"
+        ))),
+    }
+}
+
+fn call_lambda(
+    lambda: &Arc<Lambda>,
+    args: Vec<DataType>,
+    globals: &VarEnv,
+    ds: &Datastore,
+) -> Result<DataType, QueryError> {
+    if args.len() != lambda.params.len() {
+        return Err(QueryError::InvalidFunctionParameters(format!(
+            "Expected {} parameters in function {}, got {}",
+            lambda.params.len(),
+            lambda.name(),
+            args.len()
+        )));
+    }
+    let ctx = &lambda.ctx;
+    ctx.cancel.check()?;
+    if ctx.call_depth.fetch_add(1, Ordering::Relaxed) >= MAX_CALL_DEPTH {
+        ctx.call_depth.fetch_sub(1, Ordering::Relaxed);
+        return Err(QueryError::RecursionLimit(format!(
+            "Max call depth of {MAX_CALL_DEPTH} exceeded in function {}",
+            lambda.name()
+        )));
+    }
+
+    let res = run_lambda(lambda, args, globals, ds);
+    ctx.call_depth.fetch_sub(1, Ordering::Relaxed);
+    res
+}
+
+fn run_lambda(
+    lambda: &Arc<Lambda>,
+    args: Vec<DataType>,
+    globals: &VarEnv,
+    ds: &Datastore,
+) -> Result<DataType, QueryError> {
+    let mut locals = VarEnv::new();
+    if let Some(name) = &lambda.name {
+        // Makes recursion work for functions defined within other functions
+        locals.insert(name.clone(), DataType::Lambda(lambda.clone()));
+    }
+    locals.extend(lambda.params.iter().cloned().zip(args));
+    let mut scope = Scope::Function {
+        locals,
+        captured: &lambda.captured,
+        globals,
+        returned: None,
+    };
+    interpret_block(&mut scope, ds, &lambda.ctx, lambda.body.clone())?;
+    match scope {
+        Scope::Function { returned, .. } => Ok(returned.unwrap_or(DataType::None())),
+        Scope::Global(_) => unreachable!(),
+    }
+}
+
+/// Interprets statements until the end of the block or until a function returns
+fn interpret_block(
+    scope: &mut Scope,
+    ds: &Datastore,
+    ctx: &Arc<ExecContext>,
+    block: Vec<Expr>,
+) -> Result<(), QueryError> {
+    for expr in block {
+        ctx.cancel.check()?;
+        interpret_expr(scope, ds, ctx, expr)?;
+        if scope.has_returned() {
+            break;
+        }
+    }
+    Ok(())
+}
+
 fn interpret_expr(
-    env: &mut HashMap<String, DataType>,
+    scope: &mut Scope,
     ds: &Datastore,
+    ctx: &Arc<ExecContext>,
     expr: Expr,
 ) -> Result<DataType, QueryError> {
     use crate::ast::Expr_::*;
+    // Every call to a user-defined function takes several stack frames of this function, so the
+    // arms which need more than a few locals are kept out of it
     match expr.node {
-        Add(a, b) => {
-            let a_res = interpret_expr(env, ds, *a)?;
-            let b_res = interpret_expr(env, ds, *b)?;
-            let res = match a_res {
-                DataType::Number(n1) => match b_res {
-                    DataType::Number(n2) => DataType::Number(n1 + n2),
-                    _ => {
-                        return Err(QueryError::InvalidType(
-                            "Cannot use + on something that is not a number with a number!"
-                                .to_string(),
-                        ))
-                    }
-                },
-                DataType::List(mut l1) => match b_res {
-                    DataType::List(mut l2) => {
-                        l1.append(&mut l2);
-                        DataType::List(l1)
-                    }
-                    _ => {
-                        return Err(QueryError::InvalidType(
-                            "Cannot use + on something that is not a list with a list!".to_string(),
-                        ))
-                    }
-                },
-                DataType::String(s1) => match b_res {
-                    DataType::String(s2) => {
-                        let mut new_string = s1;
-                        new_string.push_str(&s2);
-                        DataType::String(new_string)
-                    }
-                    _ => {
-                        return Err(QueryError::InvalidType(
-                            "Cannot use + on something that is not a list with a list!".to_string(),
-                        ))
-                    }
-                },
-                _ => {
-                    return Err(QueryError::InvalidType(
-                        "Cannot use + on something that is not a number, list or string!"
-                            .to_string(),
-                    ))
-                }
-            };
-            Ok(res)
-        }
-        Sub(a, b) => {
-            let a_res = interpret_expr(env, ds, *a)?;
-            let b_res = interpret_expr(env, ds, *b)?;
-            let a_num = match a_res {
-                DataType::Number(n) => n,
-                _ => {
-                    return Err(QueryError::InvalidType(
-                        "Cannot sub something that is not a number!".to_string(),
-                    ))
-                }
-            };
-            let b_num = match b_res {
-                DataType::Number(n) => n,
-                _ => {
-                    return Err(QueryError::InvalidType(
-                        "Cannot sub something that is not a number!".to_string(),
-                    ))
-                }
-            };
-            Ok(DataType::Number(a_num - b_num))
-        }
-        Mul(a, b) => {
-            let a_res = interpret_expr(env, ds, *a)?;
-            let b_res = interpret_expr(env, ds, *b)?;
-            let a_num = match a_res {
-                DataType::Number(n) => n,
-                _ => {
-                    return Err(QueryError::InvalidType(
-                        "Cannot sub something that is not a number!".to_string(),
-                    ))
-                }
-            };
-            let b_num = match b_res {
-                DataType::Number(n) => n,
-                _ => {
-                    return Err(QueryError::InvalidType(
-                        "Cannot sub something that is not a number!".to_string(),
-                    ))
-                }
-            };
-            Ok(DataType::Number(a_num * b_num))
-        }
-        Div(a, b) => {
-            let a_res = interpret_expr(env, ds, *a)?;
-            let b_res = interpret_expr(env, ds, *b)?;
-            let a_num = match a_res {
-                DataType::Number(n) => n,
-                _ => {
-                    return Err(QueryError::InvalidType(
-                        "Cannot sub something that is not a number!".to_string(),
-                    ))
-                }
-            };
-            let b_num = match b_res {
-                DataType::Number(n) => n,
-                _ => {
-                    return Err(QueryError::InvalidType(
-                        "Cannot sub something that is not a number!".to_string(),
-                    ))
-                }
-            };
-            if b_num == 0.0 {
+        Add(a, b) => interpret_binary(scope, ds, ctx, *a, *b, add),
+        Sub(a, b) => interpret_binary(scope, ds, ctx, *a, *b, |a, b| {
+            let (a, b) = number_operands(a, b)?;
+            Ok(DataType::Number(a - b))
+        }),
+        Mul(a, b) => interpret_binary(scope, ds, ctx, *a, *b, |a, b| {
+            let (a, b) = number_operands(a, b)?;
+            Ok(DataType::Number(a * b))
+        }),
+        Div(a, b) => interpret_binary(scope, ds, ctx, *a, *b, |a, b| {
+            let (a, b) = number_operands(a, b)?;
+            if b == 0.0 {
                 return Err(QueryError::MathError(
                     "Tried to divide by zero!".to_string(),
                 ));
             }
-            Ok(DataType::Number(a_num / b_num))
-        }
-        Mod(a, b) => {
-            let a_res = interpret_expr(env, ds, *a)?;
-            let b_res = interpret_expr(env, ds, *b)?;
-            let a_num = match a_res {
-                DataType::Number(n) => n,
-                _ => {
-                    return Err(QueryError::InvalidType(
-                        "Cannot sub something that is not a number!".to_string(),
-                    ))
-                }
-            };
-            let b_num = match b_res {
-                DataType::Number(n) => n,
-                _ => {
-                    return Err(QueryError::InvalidType(
-                        "Cannot sub something that is not a number!".to_string(),
-                    ))
-                }
-            };
-            Ok(DataType::Number(a_num % b_num))
+            Ok(DataType::Number(a / b))
+        }),
+        Mod(a, b) => interpret_binary(scope, ds, ctx, *a, *b, |a, b| {
+            let (a, b) = number_operands(a, b)?;
+            Ok(DataType::Number(a % b))
+        }),
+        Equal(lhs, rhs) => interpret_binary(scope, ds, ctx, *lhs, *rhs, |lhs, rhs| {
+            Ok(DataType::Bool(lhs.query_eq(&rhs)?))
+        }),
+        NotEqual(lhs, rhs) => interpret_binary(scope, ds, ctx, *lhs, *rhs, |lhs, rhs| {
+            Ok(DataType::Bool(!lhs.query_eq(&rhs)?))
+        }),
+        Less(lhs, rhs) => interpret_binary(scope, ds, ctx, *lhs, *rhs, |lhs, rhs| {
+            Ok(DataType::Bool(lhs.query_cmp(&rhs)?.is_lt()))
+        }),
+        LessEqual(lhs, rhs) => interpret_binary(scope, ds, ctx, *lhs, *rhs, |lhs, rhs| {
+            Ok(DataType::Bool(lhs.query_cmp(&rhs)?.is_le()))
+        }),
+        Greater(lhs, rhs) => interpret_binary(scope, ds, ctx, *lhs, *rhs, |lhs, rhs| {
+            Ok(DataType::Bool(lhs.query_cmp(&rhs)?.is_gt()))
+        }),
+        GreaterEqual(lhs, rhs) => interpret_binary(scope, ds, ctx, *lhs, *rhs, |lhs, rhs| {
+            Ok(DataType::Bool(lhs.query_cmp(&rhs)?.is_ge()))
+        }),
+        In(lhs, rhs) => interpret_binary(scope, ds, ctx, *lhs, *rhs, |lhs, rhs| {
+            Ok(DataType::Bool(rhs.query_contains(&lhs)?))
+        }),
+        // The right hand side of and/or is only evaluated if needed
+        And(lhs, rhs) => {
+            if !interpret_bool(scope, ds, ctx, *lhs, "and")? {
+                return Ok(DataType::Bool(false));
+            }
+            Ok(DataType::Bool(interpret_bool(scope, ds, ctx, *rhs, "and")?))
         }
-        Equal(lhs, rhs) => {
-            let lhs_res = interpret_expr(env, ds, *lhs)?;
-            let rhs_res = interpret_expr(env, ds, *rhs)?;
-            Ok(DataType::Bool(lhs_res.query_eq(&rhs_res)?))
+        Or(lhs, rhs) => {
+            if interpret_bool(scope, ds, ctx, *lhs, "or")? {
+                return Ok(DataType::Bool(true));
+            }
+            Ok(DataType::Bool(interpret_bool(scope, ds, ctx, *rhs, "or")?))
         }
+        Not(e) => Ok(DataType::Bool(!interpret_bool(scope, ds, ctx, *e, "not")?)),
+        Index(val, key) => interpret_binary(scope, ds, ctx, *val, *key, |val, key| {
+            match val.get_item(&key)? {
+                Some(item) => Ok(item),
+                None => Err(QueryError::KeyError(format!(
+                    "{key:?} not found in {val:?}"
+                ))),
+            }
+        }),
         Assign(var, b) => {
-            let val = interpret_expr(env, ds, *b)?;
-            env.insert(var, val);
+            let val = interpret_expr(scope, ds, ctx, *b)?;
+            scope.set(var, val);
             Ok(DataType::None())
         }
         // FIXME: avoid clone, it's slow
-        Var(var) => match env.get(&var) {
+        Var(var) => match scope.get(&var) {
             Some(v) => Ok(v.clone()),
             None => Err(QueryError::VariableNotDefined(var.to_string())),
         },
+        Param(name) => match scope.get(&format!("${name}")) {
+            Some(v) => Ok(v.clone()),
+            None => Err(QueryError::VariableNotDefined(format!("${name}"))),
+        },
         Bool(lit) => Ok(DataType::Bool(lit)),
         Number(lit) => Ok(DataType::Number(lit)),
         String(litstr) => Ok(DataType::String(litstr)),
         Return(e) => {
-            let val = interpret_expr(env, ds, *e)?;
-            // TODO: Once RETURN is deprecated we can fix this
-            env.insert("RETURN".to_string(), val);
+            let val = interpret_expr(scope, ds, ctx, *e)?;
+            scope.set_return(val);
             Ok(DataType::None())
         }
-        If(ifs) => {
-            for (cond, block) in ifs {
-                let c = interpret_expr(env, ds, *cond)?;
-                if c.query_eq(&DataType::Bool(true))? {
-                    for expr in block {
-                        interpret_expr(env, ds, expr)?;
-                    }
-                    break;
-                }
-            }
+        Def(name, params, body) => {
+            let lambda = self::Lambda {
+                name: Some(name.clone()),
+                params,
+                body,
+                captured: scope.capture(),
+                ctx: ctx.clone(),
+            };
+            scope.set(name, DataType::Lambda(Arc::new(lambda)));
             Ok(DataType::None())
         }
-        Function(fname, e) => {
-            let args = match interpret_expr(env, ds, *e)? {
-                DataType::List(l) => l,
-                _ => unreachable!(),
-            };
-            let var = match env.get(&fname[..]) {
-                Some(v) => v,
-                None => return Err(QueryError::VariableNotDefined(fname.clone())),
-            };
-            let (_name, fun) = match var {
-                DataType::Function(name, fun) => (name, fun),
-                _data => return Err(QueryError::InvalidType(fname.to_string())),
+        Lambda(params, body) => {
+            let lambda = self::Lambda {
+                name: None,
+                params,
+                body,
+                captured: scope.capture(),
+                ctx: ctx.clone(),
             };
-            fun(args, env, ds)
+            Ok(DataType::Lambda(Arc::new(lambda)))
         }
-        List(list) => {
-            let mut l = Vec::new();
-            for entry in list {
-                let res = interpret_expr(env, ds, entry)?;
-                l.push(res);
+        Import(name) => interpret_import(scope, ds, ctx, name),
+        If(ifs) => interpret_if(scope, ds, ctx, ifs),
+        For(var, list, body) => interpret_for(scope, ds, ctx, var, *list, body),
+        Comprehension(item, var, list, cond) => {
+            interpret_comprehension(scope, ds, ctx, *item, var, *list, cond.map(|c| *c))
+        }
+        Function(fname, e) => interpret_call(scope, ds, ctx, fname, *e),
+        List(list) => interpret_list_literal(scope, ds, ctx, list),
+        Dict(d) => interpret_dict_literal(scope, ds, ctx, d),
+    }
+}
+
+/// Interprets both operands and then applies the operator to them
+fn interpret_binary(
+    scope: &mut Scope,
+    ds: &Datastore,
+    ctx: &Arc<ExecContext>,
+    lhs: Expr,
+    rhs: Expr,
+    op: fn(DataType, DataType) -> Result<DataType, QueryError>,
+) -> Result<DataType, QueryError> {
+    let lhs = interpret_expr(scope, ds, ctx, lhs)?;
+    let rhs = interpret_expr(scope, ds, ctx, rhs)?;
+    op(lhs, rhs)
+}
+
+fn add(a: DataType, b: DataType) -> Result<DataType, QueryError> {
+    match a {
+        DataType::Number(n1) => match b {
+            DataType::Number(n2) => Ok(DataType::Number(n1 + n2)),
+            _ => Err(QueryError::InvalidType(
+                "Cannot use + on something that is not a number with a number!".to_string(),
+            )),
+        },
+        DataType::List(mut l1) => match b {
+            DataType::List(mut l2) => {
+                l1.append(&mut l2);
+                Ok(DataType::List(l1))
             }
-            Ok(DataType::List(l))
+            _ => Err(QueryError::InvalidType(
+                "Cannot use + on something that is not a list with a list!".to_string(),
+            )),
+        },
+        DataType::String(s1) => match b {
+            DataType::String(s2) => {
+                let mut new_string = s1;
+                new_string.push_str(&s2);
+                Ok(DataType::String(new_string))
+            }
+            _ => Err(QueryError::InvalidType(
+                "Cannot use + on something that is not a list with a list!".to_string(),
+            )),
+        },
+        _ => Err(QueryError::InvalidType(
+            "Cannot use + on something that is not a number, list or string!".to_string(),
+        )),
+    }
+}
+
+fn number_operands(a: DataType, b: DataType) -> Result<(f64, f64), QueryError> {
+    match (a, b) {
+        (DataType::Number(a), DataType::Number(b)) => Ok((a, b)),
+        _ => Err(QueryError::InvalidType(
+            "Cannot sub something that is not a number!".to_string(),
+        )),
+    }
+}
+
+fn interpret_import(
+    scope: &mut Scope,
+    ds: &Datastore,
+    ctx: &Arc<ExecContext>,
+    name: String,
+) -> Result<DataType, QueryError> {
+    let stored_query = match ds.get_stored_query(&name) {
+        Ok(stored_query) => stored_query,
+        Err(DatastoreError::NoSuchQuery(_)) => {
+            return Err(QueryError::ImportError(format!(
+                "No stored query named '{name}'"
+            )))
+        }
+        Err(e) => {
+            return Err(QueryError::ImportError(format!(
+                "Failed to get stored query '{name}': {e:?}"
+            )))
+        }
+    };
+    let program = crate::parse(&stored_query.query.join("\n"))?;
+    {
+        let mut imports = ctx.imports.lock().unwrap();
+        if imports.contains(&name) {
+            return Err(QueryError::ImportError(format!(
+                "Circular import of '{name}'"
+            )));
+        }
+        imports.push(name);
+    }
+    // Run the imported statements as if they were written in place of the import
+    let res = interpret_block(scope, ds, ctx, program.stmts);
+    ctx.imports.lock().unwrap().pop();
+    res?;
+    Ok(DataType::None())
+}
+
+fn interpret_if(
+    scope: &mut Scope,
+    ds: &Datastore,
+    ctx: &Arc<ExecContext>,
+    ifs: Vec<(Box<Expr>, Vec<Expr>)>,
+) -> Result<DataType, QueryError> {
+    for (cond, block) in ifs {
+        let c = interpret_expr(scope, ds, ctx, *cond)?;
+        if c.query_eq(&DataType::Bool(true))? {
+            interpret_block(scope, ds, ctx, block)?;
+            break;
+        }
+    }
+    Ok(DataType::None())
+}
+
+fn interpret_call(
+    scope: &mut Scope,
+    ds: &Datastore,
+    ctx: &Arc<ExecContext>,
+    fname: String,
+    args: Expr,
+) -> Result<DataType, QueryError> {
+    let args = match interpret_expr(scope, ds, ctx, args)? {
+        DataType::List(l) => l,
+        _ => unreachable!(),
+    };
+    let fun = match scope.get(&fname[..]) {
+        Some(fun @ DataType::Function(..)) | Some(fun @ DataType::Lambda(..)) => fun.clone(),
+        Some(_data) => return Err(QueryError::InvalidType(fname.to_string())),
+        None => return Err(QueryError::VariableNotDefined(fname.clone())),
+    };
+    ctx.cancel.check()?;
+    call_function(&fun, args, scope.globals(), ds)
+}
+
+fn interpret_list_literal(
+    scope: &mut Scope,
+    ds: &Datastore,
+    ctx: &Arc<ExecContext>,
+    list: Vec<Expr>,
+) -> Result<DataType, QueryError> {
+    let mut l = Vec::new();
+    for entry in list {
+        let res = interpret_expr(scope, ds, ctx, entry)?;
+        l.push(res);
+    }
+    Ok(DataType::List(l))
+}
+
+fn interpret_dict_literal(
+    scope: &mut Scope,
+    ds: &Datastore,
+    ctx: &Arc<ExecContext>,
+    d: HashMap<String, Expr>,
+) -> Result<DataType, QueryError> {
+    let mut dict = HashMap::new();
+    for (key, val_uninterpreted) in d {
+        let val = interpret_expr(scope, ds, ctx, val_uninterpreted)?;
+        dict.insert(key.clone(), val);
+    }
+    Ok(DataType::Dict(dict))
+}
+
+fn interpret_bool(
+    scope: &mut Scope,
+    ds: &Datastore,
+    ctx: &Arc<ExecContext>,
+    expr: Expr,
+    operator: &str,
+) -> Result<bool, QueryError> {
+    match interpret_expr(scope, ds, ctx, expr)? {
+        DataType::Bool(b) => Ok(b),
+        other => Err(QueryError::InvalidType(format!(
+            "Operands of '{operator}' have to be booleans, got {other:?}"
+        ))),
+    }
+}
+
+fn interpret_list(
+    scope: &mut Scope,
+    ds: &Datastore,
+    ctx: &Arc<ExecContext>,
+    expr: Expr,
+) -> Result<Vec<DataType>, QueryError> {
+    match interpret_expr(scope, ds, ctx, expr)? {
+        DataType::List(l) => Ok(l),
+        other => Err(QueryError::InvalidType(format!(
+            "Can only iterate over lists, got {other:?}"
+        ))),
+    }
+}
+
+fn next_iteration(ctx: &ExecContext) -> Result<(), QueryError> {
+    ctx.cancel.check()?;
+    if ctx.iterations.fetch_add(1, Ordering::Relaxed) >= MAX_ITERATIONS {
+        return Err(QueryError::IterationLimit(format!(
+            "Max number of iterations of {MAX_ITERATIONS} exceeded"
+        )));
+    }
+    Ok(())
+}
+
+fn interpret_for(
+    scope: &mut Scope,
+    ds: &Datastore,
+    ctx: &Arc<ExecContext>,
+    var: String,
+    list: Expr,
+    body: Vec<Expr>,
+) -> Result<DataType, QueryError> {
+    let list = interpret_list(scope, ds, ctx, list)?;
+    for item in list {
+        next_iteration(ctx)?;
+        scope.set(var.clone(), item);
+        interpret_block(scope, ds, ctx, body.clone())?;
+        if scope.has_returned() {
+            break;
         }
-        Dict(d) => {
-            let mut dict = HashMap::new();
-            for (key, val_uninterpreted) in d {
-                let val = interpret_expr(env, ds, val_uninterpreted)?;
-                dict.insert(key.clone(), val);
+    }
+    Ok(DataType::None())
+}
+
+fn interpret_comprehension(
+    scope: &mut Scope,
+    ds: &Datastore,
+    ctx: &Arc<ExecContext>,
+    item_expr: Expr,
+    var: String,
+    list: Expr,
+    cond: Option<Expr>,
+) -> Result<DataType, QueryError> {
+    let list = interpret_list(scope, ds, ctx, list)?;
+    // The variable is only visible within the comprehension
+    let shadowed = scope.remove(&var);
+    let mut res = Vec::new();
+    for item in list {
+        next_iteration(ctx)?;
+        scope.set(var.clone(), item);
+        if let Some(cond) = &cond {
+            match interpret_expr(scope, ds, ctx, cond.clone())? {
+                DataType::Bool(true) => (),
+                DataType::Bool(false) => continue,
+                other => {
+                    return Err(QueryError::InvalidType(format!(
+                        "Condition of list comprehension has to be a boolean, got {other:?}"
+                    )))
+                }
             }
-            Ok(DataType::Dict(dict))
         }
+        res.push(interpret_expr(scope, ds, ctx, item_expr.clone())?);
+    }
+    scope.remove(&var);
+    if let Some(val) = shadowed {
+        scope.set(var, val);
     }
+    Ok(DataType::List(res))
 }
@@ -3,11 +3,20 @@ use plex::lexer;
 #[derive(Debug, Clone)]
 pub enum Token {
     Ident(String),
+    Param(String),
 
     If,
     ElseIf,
     Else,
     Return,
+    Def,
+    Lambda,
+    Import,
+    For,
+    And,
+    Or,
+    Not,
+    In,
 
     Bool(bool),
     Number(f64),
@@ -18,6 +27,11 @@ pub enum Token {
     Slash,
     Percent,
     Equals,
+    NotEquals,
+    Less,
+    LessEquals,
+    Greater,
+    GreaterEquals,
     Assign,
     LParen,
     RParen,
@@ -28,6 +42,7 @@ pub enum Token {
     Comma,
     Colon,
     Semi,
+    Dot,
 
     Whitespace,
     Newline,
@@ -46,6 +61,14 @@ lexer! {
     r#"elif"# => (Token::ElseIf, text),
     r#"else"# => (Token::Else, text),
     r#"return"# => (Token::Return, text),
+    r#"def"# => (Token::Def, text),
+    r#"lambda"# => (Token::Lambda, text),
+    r#"import"# => (Token::Import, text),
+    r#"for"# => (Token::For, text),
+    r#"and"# => (Token::And, text),
+    r#"or"# => (Token::Or, text),
+    r#"not"# => (Token::Not, text),
+    r#"in"# => (Token::In, text),
 
     r#"true"# => (Token::Bool(true), text),
     r#"false"# => (Token::Bool(false), text),
@@ -66,8 +89,14 @@ lexer! {
     }
 
     r#"[a-zA-Z_][a-zA-Z0-9_]*"# => (Token::Ident(text.to_owned()), text),
+    r#"\$[a-zA-Z_][a-zA-Z0-9_]*"# => (Token::Param(text[1..].to_owned()), text),
 
     r#"=="# => (Token::Equals, text),
+    r#"!="# => (Token::NotEquals, text),
+    r#"<"# => (Token::Less, text),
+    r#"<="# => (Token::LessEquals, text),
+    r#">"# => (Token::Greater, text),
+    r#">="# => (Token::GreaterEquals, text),
     r#"="# => (Token::Assign, text),
     r#"\+"# => (Token::Plus, text),
     r#"-"# => (Token::Minus, text),
@@ -83,6 +112,7 @@ lexer! {
     r#","# => (Token::Comma, text),
     r#":"# => (Token::Colon, text),
     r#";"# => (Token::Semi, text),
+    r#"\."# => (Token::Dot, text),
 }
 
 pub struct Lexer<'a> {
@@ -3,6 +3,7 @@ extern crate log;
 extern crate serde;
 extern crate serde_json;
 
+use std::collections::HashMap;
 use std::fmt;
 
 use aw_models::TimeInterval;
@@ -12,16 +13,19 @@ use aw_datastore::Datastore;
 pub mod datatype;
 
 mod ast;
+mod cancel;
 mod functions;
 mod interpret;
 mod lexer;
 #[allow(
     clippy::match_single_binding,
+    clippy::ptr_arg,
     clippy::redundant_closure_call,
     unused_braces
 )]
 mod parser;
 
+pub use crate::cancel::CancellationToken;
 pub use crate::datatype::DataType;
 pub use crate::interpret::VarEnv;
 
@@ -38,10 +42,18 @@ pub enum QueryError {
     VariableNotDefined(String),
     MathError(String),
     InvalidType(String),
+    KeyError(String),
     InvalidFunctionParameters(String),
     TimeIntervalError(String),
     BucketQueryError(String),
     RegexCompileError(String),
+    RecursionLimit(String),
+    IterationLimit(String),
+    ImportError(String),
+
+    // Aborted
+    Cancelled(),
+    Timeout(),
 }
 
 impl fmt::Display for QueryError {
@@ -50,15 +62,52 @@ impl fmt::Display for QueryError {
     }
 }
 
+/// Returns the query code with whitespace and comments stripped
+///
+/// Two queries which normalize to the same string are parsed to the same program.
+pub fn normalize_query(code: &str) -> String {
+    lexer::Lexer::new(code)
+        .map(|(tok, _span)| format!("{tok:?}"))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
 pub fn query(code: &str, ti: &TimeInterval, ds: &Datastore) -> Result<DataType, QueryError> {
+    query_cancellable(code, ti, ds, &CancellationToken::new())
+}
+
+/// Like query, but stops with QueryError::Cancelled or QueryError::Timeout as soon as the
+/// token says so. The token is checked between statements and before every function call.
+pub fn query_cancellable(
+    code: &str,
+    ti: &TimeInterval,
+    ds: &Datastore,
+    cancel: &CancellationToken,
+) -> Result<DataType, QueryError> {
+    query_with_params(code, ti, ds, &HashMap::new(), cancel)
+}
+
+/// Like query_cancellable, with values for the $name placeholders in the query
+pub fn query_with_params(
+    code: &str,
+    ti: &TimeInterval,
+    ds: &Datastore,
+    params: &HashMap<String, DataType>,
+    cancel: &CancellationToken,
+) -> Result<DataType, QueryError> {
+    cancel.check()?;
+    let program = parse(code)?;
+    interpret::interpret_prog(program, ti, ds, params, cancel)
+}
+
+fn parse(code: &str) -> Result<ast::Program, QueryError> {
     let lexer = lexer::Lexer::new(code);
-    let program = match parser::parse(lexer) {
-        Ok(p) => p,
+    match parser::parse(lexer) {
+        Ok(p) => Ok(p),
         Err(e) => {
             // TODO: Improve parsing error message
             warn!("ParsingError: {:?}", e);
-            return Err(QueryError::ParsingError(format!("{e:?}")));
+            Err(QueryError::ParsingError(format!("{e:?}")))
         }
-    };
-    interpret::interpret_prog(program, ti, ds)
+    }
 }
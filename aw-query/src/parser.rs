@@ -44,7 +44,35 @@ parser! {
 
     statement: Expr {
         ifs[x] => x,
+        def[x] => x,
+        For Ident(var) In binop[list] LBrace statements[body] RBrace => Expr {
+            span: span!(),
+            node: Expr_::For(var, Box::new(list), body),
+        },
         ret[x] Semi => x,
+        Import String(name) Semi => Expr {
+            span: span!(),
+            node: Expr_::Import(name),
+        },
+    }
+
+    def: Expr {
+        Def Ident(name) LParen _params[params] RParen LBrace statements[body] RBrace => Expr {
+            span: span!(),
+            node: Expr_::Def(name, params, body),
+        },
+        Def Ident(name) LParen RParen LBrace statements[body] RBrace => Expr {
+            span: span!(),
+            node: Expr_::Def(name, Vec::new(), body),
+        },
+    }
+
+    _params: Vec<std::string::String> {
+        Ident(param) => vec![param],
+        _params[mut params] Comma Ident(param) => {
+            params.push(param);
+            params
+        },
     }
 
     ifs: Expr {
@@ -119,37 +147,139 @@ parser! {
     }
 
     assign: Expr {
-        Ident(var) Assign binop[rhs] => Expr {
+        Ident(var) Assign value[rhs] => Expr {
             span: span!(),
             node: Expr_::Assign(var, Box::new(rhs)),
         },
-        binop[x] => x
+        value[x] => x
+    }
+
+    value: Expr {
+        lambda[x] => x,
+        binop[x] => x,
     }
 
+    lambda: Expr {
+        Lambda _params[params] Colon value[body] => Expr {
+            span: span!(),
+            node: {
+                let ret = Expr { span: body.span, node: Expr_::Return(Box::new(body)) };
+                Expr_::Lambda(params, vec![ret])
+            }
+        },
+        Lambda Colon value[body] => Expr {
+            span: span!(),
+            node: {
+                let ret = Expr { span: body.span, node: Expr_::Return(Box::new(body)) };
+                Expr_::Lambda(Vec::new(), vec![ret])
+            }
+        },
+    }
+
+    // Operator precedence from lowest to highest: or, and, not, comparisons, + -, * / %
     binop: Expr {
-        binop[lhs] Plus func[rhs] => Expr {
+        binop[lhs] Or _and[rhs] => Expr {
+            span: span!(),
+            node: Expr_::Or(Box::new(lhs), Box::new(rhs)),
+        },
+        _and[x] => x
+    }
+
+    _and: Expr {
+        _and[lhs] And _not[rhs] => Expr {
+            span: span!(),
+            node: Expr_::And(Box::new(lhs), Box::new(rhs)),
+        },
+        _not[x] => x
+    }
+
+    _not: Expr {
+        Not _not[x] => Expr {
+            span: span!(),
+            node: Expr_::Not(Box::new(x)),
+        },
+        _cmp[x] => x
+    }
+
+    // Comparisons don't chain, a < b < c is a syntax error
+    _cmp: Expr {
+        _sum[lhs] Equals _sum[rhs] => Expr {
+            span: span!(),
+            node: Expr_::Equal(Box::new(lhs), Box::new(rhs)),
+        },
+        _sum[lhs] NotEquals _sum[rhs] => Expr {
+            span: span!(),
+            node: Expr_::NotEqual(Box::new(lhs), Box::new(rhs)),
+        },
+        _sum[lhs] Less _sum[rhs] => Expr {
+            span: span!(),
+            node: Expr_::Less(Box::new(lhs), Box::new(rhs)),
+        },
+        _sum[lhs] LessEquals _sum[rhs] => Expr {
+            span: span!(),
+            node: Expr_::LessEqual(Box::new(lhs), Box::new(rhs)),
+        },
+        _sum[lhs] Greater _sum[rhs] => Expr {
+            span: span!(),
+            node: Expr_::Greater(Box::new(lhs), Box::new(rhs)),
+        },
+        _sum[lhs] GreaterEquals _sum[rhs] => Expr {
+            span: span!(),
+            node: Expr_::GreaterEqual(Box::new(lhs), Box::new(rhs)),
+        },
+        _sum[lhs] In _sum[rhs] => Expr {
+            span: span!(),
+            node: Expr_::In(Box::new(lhs), Box::new(rhs)),
+        },
+        _sum[lhs] Not In _sum[rhs] => Expr {
+            span: span!(),
+            node: {
+                let in_expr = Expr { span: span!(), node: Expr_::In(Box::new(lhs), Box::new(rhs)) };
+                Expr_::Not(Box::new(in_expr))
+            }
+        },
+        _sum[x] => x
+    }
+
+    _sum: Expr {
+        _sum[lhs] Plus _term[rhs] => Expr {
             span: span!(),
             node: Expr_::Add(Box::new(lhs), Box::new(rhs)),
         },
-        binop[lhs] Minus func[rhs] => Expr {
+        _sum[lhs] Minus _term[rhs] => Expr {
             span: span!(),
             node: Expr_::Sub(Box::new(lhs), Box::new(rhs)),
         },
-        binop[lhs] Star func[rhs] => Expr {
+        _term[x] => x
+    }
+
+    _term: Expr {
+        _term[lhs] Star _postfix[rhs] => Expr {
             span: span!(),
             node: Expr_::Mul(Box::new(lhs), Box::new(rhs)),
         },
-        binop[lhs] Slash func[rhs] => Expr {
+        _term[lhs] Slash _postfix[rhs] => Expr {
             span: span!(),
             node: Expr_::Div(Box::new(lhs), Box::new(rhs)),
         },
-        binop[lhs] Percent func[rhs] => Expr {
+        _term[lhs] Percent _postfix[rhs] => Expr {
             span: span!(),
             node: Expr_::Mod(Box::new(lhs), Box::new(rhs)),
         },
-        binop[lhs] Equals func[rhs] => Expr {
+        _postfix[x] => x
+    }
+
+    _postfix: Expr {
+        _postfix[val] LBracket binop[key] RBracket => Expr {
             span: span!(),
-            node: Expr_::Equal(Box::new(lhs), Box::new(rhs)),
+            node: Expr_::Index(Box::new(val), Box::new(key)),
+        },
+        _postfix[val] Dot Ident(key) => Expr {
+            span: span!(),
+            node: {
+                let key = Expr { span: span!(), node: Expr_::String(key) };
+                Expr_::Index(Box::new(val), Box::new(key))
+            }
         },
         func[x] => x
     }
@@ -185,6 +315,14 @@ parser! {
 
     list: Expr {
         LBracket _inner_list[l] RBracket => l,
+        LBracket value[item] For Ident(var) In binop[list] RBracket => Expr {
+            span: span!(),
+            node: Expr_::Comprehension(Box::new(item), var, Box::new(list), None),
+        },
+        LBracket value[item] For Ident(var) In binop[list] If binop[cond] RBracket => Expr {
+            span: span!(),
+            node: Expr_::Comprehension(Box::new(item), var, Box::new(list), Some(Box::new(cond))),
+        },
         LBracket RBracket => Expr {
             span: span!(),
             node: {
@@ -195,7 +333,7 @@ parser! {
     }
 
     _inner_list: Expr {
-        binop[o] => Expr {
+        value[o] => Expr {
             span: span!(),
             node: {
                 let mut list = Vec::new();
@@ -203,7 +341,7 @@ parser! {
                 Expr_::List(list)
             }
         },
-        _inner_list[l] Comma binop[o] => Expr {
+        _inner_list[l] Comma value[o] => Expr {
             span: span!(),
             node: {
                 match l.node {
@@ -218,7 +356,7 @@ parser! {
     }
 
     dict: Expr {
-        String(k) Colon binop[v] => Expr {
+        String(k) Colon value[v] => Expr {
             span: span!(),
             node: {
                 let mut dict = HashMap::new();
@@ -226,7 +364,7 @@ parser! {
                 Expr_::Dict(dict)
             }
         },
-        dict[d] Comma String(k) Colon binop[v] => Expr {
+        dict[d] Comma String(k) Colon value[v] => Expr {
             span: span!(),
             node: {
                 match d.node {
@@ -246,6 +384,10 @@ parser! {
             span: span!(),
             node: Expr_::Var(v),
         },
+        Param(p) => Expr {
+            span: span!(),
+            node: Expr_::Param(p),
+        },
         Bool(b) => Expr {
             span: span!(),
             node: Expr_::Bool(b),
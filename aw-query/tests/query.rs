@@ -23,7 +23,9 @@ mod query_tests {
 
     use chrono::Duration;
     use serde_json::json;
+    use std::collections::HashMap;
     use std::convert::TryFrom;
+    use std::str::FromStr;
 
     use aw_query::DataType;
     use aw_query::QueryError;
@@ -33,6 +35,7 @@ mod query_tests {
     use aw_models::Bucket;
     use aw_models::BucketMetadata;
     use aw_models::Event;
+    use aw_models::StoredQuery;
     use aw_models::TimeInterval;
 
     static TIME_INTERVAL: &str = "1980-01-01T00:00:00Z/2080-01-02T00:00:00Z";
@@ -69,6 +72,7 @@ mod query_tests {
             timestamp: chrono::Utc::now(),
             duration: Duration::seconds(0),
             data: json_map! {"key": json!("value")},
+            origin: None,
         };
         let mut e2 = e1.clone();
         e2.timestamp = chrono::Utc::now();
@@ -604,6 +608,103 @@ mod query_tests {
         assert_eq!(res, DataType::Bool(false));
     }
 
+    #[test]
+    fn test_extract_regex() {
+        let ds = setup_datastore_populated();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        let code = String::from(
+            r#"events = query_bucket("testid");
+            events = extract_regex(events, "key", "^(?P<prefix>va)(?P<rest>\w+)$", ["rest"]);
+            return events;"#,
+        );
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        let events: Vec<Event> = (&res).try_into().unwrap();
+        assert_eq!(events.len(), 2);
+        for event in events {
+            assert_eq!(event.data["key"], json!("value"));
+            assert_eq!(event.data["rest"], json!("lue"));
+            assert!(!event.data.contains_key("prefix"));
+        }
+
+        let code = String::from(
+            r#"return extract_regex(query_bucket("testid"), "key", "(?P<a>\w+)", ["b"]);"#,
+        );
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidFunctionParameters(_));
+
+        let code =
+            String::from(r#"return extract_regex(query_bucket("testid"), "key", "(", ["a"]);"#);
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::RegexCompileError(_));
+    }
+
+    #[test]
+    fn test_group_by_time() {
+        let ds = setup_datastore_with_bucket();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+        let e1 = Event {
+            id: None,
+            timestamp: chrono::DateTime::from_str("2000-01-01T22:30:00Z").unwrap(),
+            duration: Duration::hours(2),
+            data: json_map! {"app": json!("a")},
+            origin: None,
+        };
+        let mut e2 = e1.clone();
+        e2.timestamp = chrono::DateTime::from_str("2000-01-02T01:00:00Z").unwrap();
+        e2.duration = Duration::minutes(30);
+        e2.data = json_map! {"app": json!("b")};
+        ds.insert_events(BUCKET_ID, &[e1, e2]).unwrap();
+
+        let code = String::from(
+            r#"events = query_bucket("testid");
+            return split_by_interval(events, "1h", "UTC");"#,
+        );
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        let events: Vec<Event> = (&res).try_into().unwrap();
+        assert_eq!(events.len(), 4);
+
+        // Days in UTC+1 start at 23:00 UTC
+        let code = String::from(
+            r#"events = query_bucket("testid");
+            return group_by_time(events, "1d", "Europe/Stockholm");"#,
+        );
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        let events: Vec<Event> = (&res).try_into().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            events[0].timestamp.to_rfc3339(),
+            "1999-12-31T23:00:00+00:00"
+        );
+        assert_eq!(events[0].duration, Duration::minutes(30));
+        assert_eq!(
+            events[1].timestamp.to_rfc3339(),
+            "2000-01-01T23:00:00+00:00"
+        );
+        assert_eq!(events[1].duration, Duration::minutes(120));
+
+        let code = String::from(
+            r#"events = query_bucket("testid");
+            return group_by_time(events, "1w", "UTC", ["app"]);"#,
+        );
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        let mut events: Vec<Event> = (&res).try_into().unwrap();
+        events.sort_by_key(|e| e.data["app"].to_string());
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].data["app"], json!("a"));
+        assert_eq!(events[0].duration, Duration::hours(2));
+        assert_eq!(events[1].data["app"], json!("b"));
+        assert_eq!(events[1].duration, Duration::minutes(30));
+
+        let code = String::from(r#"return group_by_time([], "1d", "Not/AZone");"#);
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidFunctionParameters(_));
+
+        let code = String::from(r#"return group_by_time([], "3d", "UTC");"#);
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidFunctionParameters(_));
+    }
+
     #[test]
     fn test_math() {
         let ds = setup_datastore_empty();
@@ -649,4 +750,620 @@ mod query_tests {
             num => panic!("Expected number, got {num:?}"),
         };
     }
+
+    #[test]
+    fn test_comparisons() {
+        let ds = setup_datastore_empty();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        let cases = [
+            ("return 1 != 2;", true),
+            ("return 1 != 1;", false),
+            ("return 1 < 2;", true),
+            ("return 2 < 1;", false),
+            ("return 1 <= 1;", true),
+            ("return 2 > 1;", true),
+            ("return 1 >= 2;", false),
+            (r#"return "abc" < "abd";"#, true),
+            (r#"return "b" >= "a";"#, true),
+            (r#"return "a" != "a";"#, false),
+            ("return 1 in [1, 2];", true),
+            ("return 3 in [1, 2];", false),
+            (r#"return "a" in {"a": 1};"#, true),
+            (r#"return "b" not in {"a": 1};"#, true),
+            (r#"return "ell" in "hello";"#, true),
+            (r#"return "x" not in "hello";"#, true),
+        ];
+        for (code, expected) in cases {
+            let res = aw_query::query(code, &interval, &ds).unwrap();
+            assert_eq!(res, DataType::Bool(expected), "{code}");
+        }
+
+        // Only numbers, strings and events of the same type can be ordered
+        let code = String::from(r#"return 1 < "2";"#);
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+
+        let code = String::from("return [1] < [2];");
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+
+        let code = String::from(r#"return 1 != "1";"#);
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+
+        let code = String::from("return 1 in 2;");
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+
+        // Comparisons don't chain
+        let code = String::from("return 1 < 2 < 3;");
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::ParsingError(_));
+
+        // Events are ordered by timestamp
+        let ds = setup_datastore_populated();
+        let code = String::from(
+            r#"events = sort_by_timestamp(query_bucket("testid"));
+            last = reduce(events, lambda acc, e: e, 0);
+            return [map(events, lambda e: e <= last), map(events, lambda e: e > last)];"#,
+        );
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        let t = DataType::Bool(true);
+        let f = DataType::Bool(false);
+        assert_eq!(
+            res,
+            DataType::List(vec![
+                DataType::List(vec![t.clone(), t]),
+                DataType::List(vec![f.clone(), f]),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_boolean_operators() {
+        let ds = setup_datastore_empty();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        let cases = [
+            ("return True and True;", true),
+            ("return True and False;", false),
+            ("return False or True;", true),
+            ("return False or False;", false),
+            ("return not False;", true),
+            ("return not not True;", true),
+            // and binds tighter than or, not binds tighter than and
+            ("return True or True and False;", true),
+            ("return (True or True) and False;", false),
+            ("return not True or True;", true),
+            ("return not (True or True);", false),
+            // Comparisons bind tighter than boolean operators, arithmetic tighter than comparisons
+            ("return 1 < 2 and 2 < 3;", true),
+            ("return not 1 == 2;", true),
+            ("return 1 + 2 * 3 == 7;", true),
+            ("return 10 - 4 / 2 == 8;", true),
+            ("return 1 + 1 in [2];", true),
+        ];
+        for (code, expected) in cases {
+            let res = aw_query::query(code, &interval, &ds).unwrap();
+            assert_eq!(res, DataType::Bool(expected), "{code}");
+        }
+
+        // The right hand side is only evaluated if needed
+        let code = String::from("return False and undefined;");
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(res, DataType::Bool(false));
+
+        let code = String::from("return True or undefined;");
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(res, DataType::Bool(true));
+
+        let code = String::from("return True and undefined;");
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::VariableNotDefined(_));
+
+        // Operands have to be booleans
+        let code = String::from("return 1 and True;");
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+
+        let code = String::from("return False or 1;");
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+
+        let code = String::from(r#"return not "a";"#);
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+
+        let code = String::from("a = 3; if a > 1 and a <= 3 { return 1; } else { return 0; }");
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(res, DataType::Number(1.0));
+    }
+
+    #[test]
+    fn test_cancellation() {
+        let ds = setup_datastore_empty();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+        let code = String::from("a = 1; return a;");
+
+        let token = aw_query::CancellationToken::new();
+        let res = aw_query::query_cancellable(&code, &interval, &ds, &token).unwrap();
+        assert_eq!(res, DataType::Number(1.0));
+
+        // Cancelling one clone cancels all of them
+        token.clone().cancel();
+        let res = aw_query::query_cancellable(&code, &interval, &ds, &token);
+        assert_err_type!(res, QueryError::Cancelled());
+
+        let token = aw_query::CancellationToken::with_timeout(std::time::Duration::ZERO);
+        let res = aw_query::query_cancellable(&code, &interval, &ds, &token);
+        assert_err_type!(res, QueryError::Timeout());
+    }
+
+    #[test]
+    fn test_def() {
+        let ds = setup_datastore_empty();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        let code = String::from("def add(a, b) { return a + b; } return add(1, 2);");
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(res, DataType::Number(3.0));
+
+        // Return stops the function, also from within an if block
+        let code = String::from(
+            "def f(a) { if a == 1 { return \"one\"; } return \"other\"; }
+            return [f(1), f(2)];",
+        );
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(
+            res,
+            DataType::List(vec![
+                DataType::String("one".into()),
+                DataType::String("other".into())
+            ])
+        );
+
+        // Functions without a return statement return None
+        let code = String::from("def f() { a = 1; } return f();");
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(res, DataType::None());
+
+        // Locals don't leak into the global scope, globals are visible in functions
+        let code = String::from("b = 2; def f() { a = 1; return b; } f(); return a;");
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::VariableNotDefined(_));
+        let code = String::from("a = 1; def f() { a = 2; return a; } return [f(), a];");
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(
+            res,
+            DataType::List(vec![DataType::Number(2.0), DataType::Number(1.0)])
+        );
+
+        // Recursion
+        let code = String::from(
+            "def fib(n) { if n == 0 { return 0; } elif n == 1 { return 1; } return fib(n - 1) + fib(n - 2); }
+            return fib(10);",
+        );
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(res, DataType::Number(55.0));
+
+        // Recursing up to the limit of 32 calls fits on the stack of the test thread, also in
+        // debug builds
+        let code =
+            String::from("def f(n) { if n == 0 { return 0; } return f(n - 1) + 1; } return f(31);");
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(res, DataType::Number(31.0));
+
+        let code = String::from("def f(n) { return f(n + 1); } return f(0);");
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::RecursionLimit(_));
+
+        // Wrong number of arguments
+        let code = String::from("def f(a) { return a; } return f(1, 2);");
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidFunctionParameters(_));
+    }
+
+    #[test]
+    fn test_lambda() {
+        let ds = setup_datastore_empty();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        let code = String::from("f = lambda a, b: a * b; return f(2, 3);");
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(res, DataType::Number(6.0));
+
+        let code = String::from("f = lambda: 1; return f();");
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(res, DataType::Number(1.0));
+
+        // Lambdas capture the variables of the function they are defined in
+        let code = String::from(
+            "def make_adder(n) { return lambda x: x + n; }
+            add2 = make_adder(2);
+            add3 = make_adder(3);
+            return [add2(1), add3(1)];",
+        );
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(
+            res,
+            DataType::List(vec![DataType::Number(3.0), DataType::Number(4.0)])
+        );
+
+        // Nested functions can call themselves
+        let code = String::from(
+            "def outer(n) { def count(i) { if i == n { return i; } return count(i + 1); } return count(0); }
+            return outer(5);",
+        );
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(res, DataType::Number(5.0));
+    }
+
+    #[test]
+    fn test_for() {
+        let ds = setup_datastore_populated();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        let code = String::from("n = 0; for x in [1, 2, 3] { n = n + x; } return n;");
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(res, DataType::Number(6.0));
+
+        let code = String::from(
+            "events = [];
+            for bucket in query_bucket_names() {
+                events = concat(events, query_bucket(bucket));
+            }
+            return events;",
+        );
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        let events: Vec<Event> = (&res).try_into().unwrap();
+        assert_eq!(events.len(), 2);
+
+        // Nested loops and returning from within a loop in a function
+        let code = String::from(
+            "def find(l, target) {
+                for x in l { for y in l { if x + y == target { return [x, y]; } } }
+                return [];
+            }
+            return find([1, 2, 3], 5);",
+        );
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(
+            res,
+            DataType::List(vec![DataType::Number(2.0), DataType::Number(3.0)])
+        );
+
+        let code = String::from("for x in 1 { }");
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+
+        // Loops are limited to a total number of iterations
+        let code = String::from("for x in range(1001) { for y in range(1000) { } } return 1;");
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::IterationLimit(_));
+
+        let code = String::from("return range(2000000);");
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::IterationLimit(_));
+    }
+
+    #[test]
+    fn test_comprehension() {
+        let ds = setup_datastore_populated();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        let code = String::from("return [x * 2 for x in range(1, 4)];");
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(
+            res,
+            DataType::List(vec![
+                DataType::Number(2.0),
+                DataType::Number(4.0),
+                DataType::Number(6.0)
+            ])
+        );
+
+        let code = String::from("return [x for x in range(8) if x % 3 == 0 and x > 0];");
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(
+            res,
+            DataType::List(vec![DataType::Number(3.0), DataType::Number(6.0)])
+        );
+
+        let code = String::from(
+            "return [sum_durations(query_bucket(bucket)) for bucket in query_bucket_names()];",
+        );
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(res, DataType::List(vec![DataType::Number(0.0)]));
+
+        // The variable doesn't leak out of the comprehension
+        let code = String::from("x = 5; l = [x for x in [1, 2]]; return x;");
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(res, DataType::Number(5.0));
+
+        let code = String::from("l = [x for x in [1, 2]]; return x;");
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::VariableNotDefined(_));
+
+        let code = String::from("return [x for x in [1, 2] if x];");
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+    }
+
+    #[test]
+    fn test_indexing() {
+        let ds = setup_datastore_populated();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        let cases = [
+            ("return [1, 2, 3][0];", DataType::Number(1.0)),
+            ("return [1, 2, 3][0 - 1];", DataType::Number(3.0)),
+            (
+                "l = [[1, 2], [3, 4]]; return l[1][0] + l[0][1];",
+                DataType::Number(5.0),
+            ),
+            (
+                r#"d = {"a": {"b": 2}}; return d["a"]["b"];"#,
+                DataType::Number(2.0),
+            ),
+            (
+                r#"d = {"a": {"b": 2}}; return d.a.b * 2;"#,
+                DataType::Number(4.0),
+            ),
+            (r#"return {"a": 1}.a;"#, DataType::Number(1.0)),
+            (
+                r#"events = query_bucket("testid"); return events[0].data.key;"#,
+                DataType::String("value".to_string()),
+            ),
+            (
+                r#"events = query_bucket("testid"); return events[0].duration;"#,
+                DataType::Number(0.0),
+            ),
+        ];
+        for (code, expected) in cases {
+            let res = aw_query::query(code, &interval, &ds).unwrap();
+            assert_eq!(res, expected, "{code}");
+        }
+
+        let code = String::from(r#"return [x.data.key for x in query_bucket("testid")];"#);
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        let value = DataType::String("value".to_string());
+        assert_eq!(res, DataType::List(vec![value.clone(), value]));
+
+        let code = String::from("return [1][1];");
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::KeyError(_));
+
+        let code = String::from(r#"return {"a": 1}.b;"#);
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::KeyError(_));
+
+        let code = String::from(r#"return [1]["a"];"#);
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+
+        let code = String::from("return [1][0.5];");
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+    }
+
+    #[test]
+    fn test_dict_list_functions() {
+        let ds = setup_datastore_populated();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        let s = |s: &str| DataType::String(s.to_string());
+        let n = DataType::Number;
+        let cases = [
+            (
+                r#"return keys({"b": 1, "a": 2});"#,
+                DataType::List(vec![s("a"), s("b")]),
+            ),
+            (
+                r#"return values({"b": 1, "a": 2});"#,
+                DataType::List(vec![n(2.0), n(1.0)]),
+            ),
+            ("return len([1, 2, 3]);", n(3.0)),
+            (r#"return len({"a": 1});"#, n(1.0)),
+            (r#"return len("abc");"#, n(3.0)),
+            (r#"return get({"a": 1}, "a");"#, n(1.0)),
+            (r#"return get({"a": 1}, "b");"#, DataType::None()),
+            (r#"return get({"a": 1}, "b", 0);"#, n(0.0)),
+            (r#"return get({"a": {"b": 1}}, ["a", "b"]);"#, n(1.0)),
+            (r#"return get({"a": {"b": 1}}, ["a", "c"], 2);"#, n(2.0)),
+            ("return get([1, 2], 5, 0);", n(0.0)),
+            (r#"return set({"a": 1}, "a", 2).a;"#, n(2.0)),
+            (
+                "return set([1, 2], 1, 3);",
+                DataType::List(vec![n(1.0), n(3.0)]),
+            ),
+            (r#"return set({}, ["a", "b"], 1).a.b;"#, n(1.0)),
+            // Values are never modified in place
+            (r#"d = {"a": 1}; d2 = set(d, "a", 2); return d.a;"#, n(1.0)),
+        ];
+        for (code, expected) in cases {
+            let res = aw_query::query(code, &interval, &ds).unwrap();
+            assert_eq!(res, expected, "{code}");
+        }
+
+        // Derived fields can be set on events
+        let code = String::from(
+            r#"events = query_bucket("testid");
+            return [set(e, ["data", "project"], e.data.key + "-project") for e in events];"#,
+        );
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        let events: Vec<Event> = (&res).try_into().unwrap();
+        assert_eq!(events.len(), 2);
+        for event in events {
+            assert_eq!(event.data["key"], json!("value"));
+            assert_eq!(event.data["project"], json!("value-project"));
+        }
+
+        let code = String::from(
+            r#"e = event("2000-01-01T00:00:00Z", 1.5, {"app": "test"});
+            return [e.timestamp, e.duration, e.data.app];"#,
+        );
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(
+            res,
+            DataType::List(vec![s("2000-01-01T00:00:00+00:00"), n(1.5), s("test")])
+        );
+
+        let code = String::from(r#"return event("not a timestamp", 1, {});"#);
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+
+        let code = String::from("return set([1], 1, 2);");
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::KeyError(_));
+
+        let code = String::from("return keys([1]);");
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidFunctionParameters(_));
+    }
+
+    #[test]
+    fn test_higher_order_functions() {
+        let ds = setup_datastore_populated();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        let code = String::from("return map([1, 2, 3], lambda x: x * 2);");
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(
+            res,
+            DataType::List(vec![
+                DataType::Number(2.0),
+                DataType::Number(4.0),
+                DataType::Number(6.0)
+            ])
+        );
+
+        let code = String::from("return filter([1, 2, 3], lambda x: x % 2 == 1);");
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(
+            res,
+            DataType::List(vec![DataType::Number(1.0), DataType::Number(3.0)])
+        );
+
+        let code = String::from("return filter([1, 2, 3], lambda x: x);");
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+
+        let code =
+            String::from("def add(a, b) { return a + b; } return reduce([1, 2, 3], add, 10);");
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(res, DataType::Number(16.0));
+
+        let code = String::from(r#"return sort_by(["b", "c", "a"], lambda s: s);"#);
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(
+            res,
+            DataType::List(vec![
+                DataType::String("a".into()),
+                DataType::String("b".into()),
+                DataType::String("c".into())
+            ])
+        );
+
+        let code = String::from(r#"return sort_by([1, "a"], lambda x: x);"#);
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+
+        let code = String::from("return map([1], 1);");
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidFunctionParameters(_));
+
+        // Builtins can be passed as well, and functions can be used over event lists
+        let code = format!(
+            r#"events = query_bucket("{BUCKET_ID}");
+            return map([events, events], sum_durations);"#
+        );
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(
+            res,
+            DataType::List(vec![DataType::Number(0.0), DataType::Number(0.0)])
+        );
+        let code = format!(
+            r#"events = query_bucket("{BUCKET_ID}");
+            return sort_by(events, lambda e: sum_durations([e]));"#
+        );
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        match res {
+            DataType::List(l) => assert_eq!(l.len(), 2),
+            data => panic!("Expected list, got {data:?}"),
+        }
+    }
+
+    #[test]
+    fn test_params() {
+        let ds = setup_datastore_empty();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+        let token = aw_query::CancellationToken::new();
+
+        let params = HashMap::from([
+            ("hostname".to_string(), DataType::String("host".into())),
+            (
+                "categories".to_string(),
+                DataType::from(&json!([["Work"], ["Other"]])),
+            ),
+        ]);
+        let code = String::from("def f() { return $hostname; } return [f(), $categories];");
+        let res = aw_query::query_with_params(&code, &interval, &ds, &params, &token).unwrap();
+        assert_eq!(
+            res,
+            DataType::List(vec![
+                DataType::String("host".into()),
+                DataType::List(vec![
+                    DataType::List(vec![DataType::String("Work".into())]),
+                    DataType::List(vec![DataType::String("Other".into())]),
+                ])
+            ])
+        );
+
+        let code = String::from("return $missing;");
+        let res = aw_query::query_with_params(&code, &interval, &ds, &params, &token);
+        assert_err_type!(res, QueryError::VariableNotDefined(_));
+    }
+
+    #[test]
+    fn test_import() {
+        let ds = setup_datastore_empty();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        let stored_query = |name: &str, query: &str| StoredQuery {
+            name: name.to_string(),
+            query: vec![query.to_string()],
+            description: String::new(),
+            last_modified: None,
+        };
+        ds.set_stored_query(&stored_query(
+            "lib",
+            "def double(x) { return x * 2; } one = 1;",
+        ))
+        .unwrap();
+        ds.set_stored_query(&stored_query("circular", r#"import "circular";"#))
+            .unwrap();
+
+        let code = String::from(r#"import "lib"; return double(one);"#);
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(res, DataType::Number(2.0));
+
+        // Imports can be nested and used within functions
+        ds.set_stored_query(&stored_query(
+            "lib2",
+            r#"import "lib"; def quadruple(x) { return double(double(x)); }"#,
+        ))
+        .unwrap();
+        let code = String::from(r#"import "lib2"; return quadruple(one);"#);
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(res, DataType::Number(4.0));
+
+        let code = String::from(r#"import "missing"; return 1;"#);
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::ImportError(_));
+
+        let code = String::from(r#"import "circular"; return 1;"#);
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::ImportError(_));
+    }
 }
@@ -37,6 +37,58 @@ pub struct AWConfig {
     // custom visualizations are located.
     #[serde(default = "default_custom_static")]
     pub custom_static: std::collections::HashMap<String, String>,
+
+    // Number of seconds a query may run before it is aborted, 0 means no limit
+    #[serde(default = "default_query_timeout")]
+    pub query_timeout: u64,
+
+    // Max number of query results to keep cached, 0 disables the cache
+    #[serde(default = "default_query_cache_size")]
+    pub query_cache_size: usize,
+
+    // IANA timezone (such as "Europe/Stockholm") which relative query timeperiods such as
+    // "today" are resolved in, the system timezone is used if not set
+    #[serde(default)]
+    pub timezone: Option<String>,
+
+    // Time of day (HH:MM) when a new day starts for relative query timeperiods
+    #[serde(default = "default_start_of_day")]
+    pub start_of_day: String,
+
+    // Max number of seconds writes to the database are left uncommitted
+    #[serde(default = "default_db_commit_interval")]
+    pub db_commit_interval: u64,
+
+    // Number of inserted or updated events which triggers a commit of the database
+    #[serde(default = "default_db_commit_events")]
+    pub db_commit_events: usize,
+
+    // Durability of database commits, one of "full", "normal" or "off"
+    #[serde(default = "default_db_sync")]
+    pub db_sync: String,
+
+    // Directory which backups made through the API are written to, defaults to a backups
+    // directory next to the database
+    #[serde(default)]
+    pub backup_dir: Option<String>,
+
+    // Number of seconds between enforcements of the retention policies
+    #[serde(default = "default_retention_interval")]
+    pub retention_interval: u64,
+
+    // Number of days deleted events are kept track of for aw-sync, syncs which last ran before
+    // that compare all events again
+    #[serde(default = "default_change_horizon_days")]
+    pub change_horizon_days: u64,
+
+    // Policies for how long events are kept, see aw_datastore::RetentionPolicy. This and
+    // redact are kept last as they are written as arrays of tables.
+    #[serde(default)]
+    pub retention: Vec<aw_datastore::RetentionPolicy>,
+
+    // Rules for scrubbing events before they are stored, see aw_datastore::RedactionRule
+    #[serde(default)]
+    pub redact: Vec<aw_datastore::RedactionRule>,
 }
 
 impl Default for AWConfig {
@@ -47,6 +99,18 @@ impl Default for AWConfig {
             testing: default_testing(),
             cors: default_cors(),
             custom_static: default_custom_static(),
+            query_timeout: default_query_timeout(),
+            query_cache_size: default_query_cache_size(),
+            timezone: None,
+            start_of_day: default_start_of_day(),
+            db_commit_interval: default_db_commit_interval(),
+            db_commit_events: default_db_commit_events(),
+            db_sync: default_db_sync(),
+            backup_dir: None,
+            retention_interval: default_retention_interval(),
+            change_horizon_days: default_change_horizon_days(),
+            retention: Vec::new(),
+            redact: Vec::new(),
         }
     }
 }
@@ -73,6 +137,50 @@ impl AWConfig {
 
         config
     }
+
+    /// Checks the settings which are only used when handling requests, so that the server
+    /// refuses to start with them instead of failing the requests
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(timezone) = &self.timezone {
+            parse_timezone(timezone)?;
+        }
+        parse_start_of_day(&self.start_of_day)?;
+        Ok(())
+    }
+
+    pub fn datastore_options(&self) -> Result<aw_datastore::DatastoreOptions, String> {
+        if self.db_commit_events == 0 {
+            return Err("db_commit_events has to be at least 1".to_string());
+        }
+        if self.retention_interval == 0 {
+            return Err("retention_interval has to be at least 1".to_string());
+        }
+        for policy in &self.retention {
+            policy.validate()?;
+        }
+        for rule in &self.redact {
+            rule.validate()?;
+        }
+        Ok(aw_datastore::DatastoreOptions {
+            commit_interval: std::time::Duration::from_secs(self.db_commit_interval),
+            commit_events: self.db_commit_events,
+            sync: self.db_sync.parse()?,
+            retention: self.retention.clone(),
+            retention_interval: std::time::Duration::from_secs(self.retention_interval),
+            change_horizon: std::time::Duration::from_secs(
+                self.change_horizon_days.saturating_mul(24 * 60 * 60),
+            ),
+            redaction: self.redact.clone(),
+            key: None,
+        })
+    }
+
+    pub fn query_cancellation_token(&self) -> aw_query::CancellationToken {
+        match self.query_timeout {
+            0 => aw_query::CancellationToken::new(),
+            secs => aw_query::CancellationToken::with_timeout(std::time::Duration::from_secs(secs)),
+        }
+    }
 }
 
 fn default_address() -> String {
@@ -99,6 +207,55 @@ fn default_custom_static() -> std::collections::HashMap<String, String> {
     std::collections::HashMap::new()
 }
 
+fn default_query_timeout() -> u64 {
+    0
+}
+
+fn default_query_cache_size() -> usize {
+    100
+}
+
+fn default_db_commit_interval() -> u64 {
+    15
+}
+
+fn default_db_commit_events() -> usize {
+    100
+}
+
+fn default_db_sync() -> String {
+    "full".to_string()
+}
+
+fn default_retention_interval() -> u64 {
+    3600
+}
+
+fn default_change_horizon_days() -> u64 {
+    30
+}
+
+fn default_start_of_day() -> String {
+    "00:00".to_string()
+}
+
+/// Parses an IANA timezone name such as "Europe/Stockholm"
+pub fn parse_timezone(timezone: &str) -> Result<chrono_tz::Tz, String> {
+    timezone
+        .parse()
+        .map_err(|_| format!("Unknown timezone '{timezone}'"))
+}
+
+/// Parses a time of day such as "04:00" into the duration since midnight
+pub fn parse_start_of_day(start_of_day: &str) -> Result<chrono::Duration, String> {
+    match chrono::NaiveTime::parse_from_str(start_of_day, "%H:%M") {
+        Ok(time) => Ok(time.signed_duration_since(chrono::NaiveTime::MIN)),
+        Err(_) => Err(format!(
+            "Invalid start of day '{start_of_day}', expected a time such as 04:00"
+        )),
+    }
+}
+
 pub fn create_config(testing: bool) -> AWConfig {
     set_testing(testing);
     let mut config_path = dirs::get_config_dir().unwrap();
@@ -93,6 +93,14 @@ pub fn get_log_dir(module: &str) -> Result<PathBuf, ()> {
     panic!("not implemented on Android");
 }
 
+/// Where backups made through the API are written unless another directory is configured
+pub fn get_backup_dir() -> Result<PathBuf, ()> {
+    let mut dir = get_data_dir()?;
+    dir.push("backups");
+    std::fs::create_dir_all(dir.clone()).expect("Unable to create backup dir");
+    Ok(dir)
+}
+
 pub fn db_path(testing: bool) -> Result<PathBuf, ()> {
     debug!("[修改版本2] db_path 开始执行，testing: {}", testing);
     
@@ -173,6 +181,7 @@ fn test_get_dirs() {
 
     get_cache_dir().unwrap();
     get_log_dir("aw-server-rust").unwrap();
+    get_backup_dir().unwrap();
     db_path(true).unwrap();
     db_path(false).unwrap();
 }
\ No newline at end of file
@@ -0,0 +1,62 @@
+use std::path::PathBuf;
+
+use chrono::Utc;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::Serialize;
+
+use crate::config::AWConfig;
+use crate::dirs;
+use crate::endpoints::{HttpErrorJson, ServerState};
+
+#[derive(Serialize)]
+pub struct BackupInfo {
+    pub path: String,
+}
+
+/// Backs up the datastore to a file in the backup directory
+///
+/// Only a file name can be given, so that the API can't be used to write anywhere else. The
+/// name defaults to one with the current time in it.
+#[post("/?<name>")]
+pub fn backup_create(
+    name: Option<String>,
+    state: &State<ServerState>,
+    config: &State<AWConfig>,
+) -> Result<Json<BackupInfo>, HttpErrorJson> {
+    let name = match name {
+        Some(name) => name,
+        None => format!(
+            "sqlite-backup-{}.db",
+            Utc::now().format("%Y-%m-%dT%H-%M-%SZ")
+        ),
+    };
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+        return Err(HttpErrorJson::new(
+            Status::BadRequest,
+            format!("Invalid backup name '{name}', it has to be a plain file name"),
+        ));
+    }
+    let mut path = match &config.backup_dir {
+        Some(dir) => {
+            let dir = PathBuf::from(dir);
+            if let Err(err) = std::fs::create_dir_all(&dir) {
+                return Err(HttpErrorJson::new(
+                    Status::InternalServerError,
+                    format!("Failed to create backup directory {}: {err}", dir.display()),
+                ));
+            }
+            dir
+        }
+        None => dirs::get_backup_dir().expect("Unable to get backup dir"),
+    };
+    path.push(name);
+    let path = path.display().to_string();
+
+    let datastore = endpoints_get_lock!(state.datastore);
+    match datastore.backup(&path) {
+        Ok(()) => Ok(Json(BackupInfo { path })),
+        Err(err) => Err(err.into()),
+    }
+}
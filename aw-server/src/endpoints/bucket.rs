@@ -9,12 +9,21 @@ use chrono::Utc;
 use aw_models::Bucket;
 use aw_models::BucketsExport;
 use aw_models::Event;
+use aw_models::EventChanges;
+use aw_models::EventPatch;
+use aw_models::OriginChanges;
 use aw_models::TryVec;
 
+use aw_datastore::DataFilter;
+use aw_datastore::EventCursor;
+use aw_datastore::EventQuery;
+use aw_models::EventOrder;
+use serde_json::Value;
+
 use rocket::http::Status;
 use rocket::State;
 
-use crate::endpoints::util::BucketsExportRocket;
+use crate::endpoints::util::{BucketsExportRocket, EventPageRocket};
 use crate::endpoints::{HttpErrorJson, ServerState};
 
 #[get("/")]
@@ -70,43 +79,91 @@ pub fn bucket_new(
     }
 }
 
-#[get("/<bucket_id>/events?<start>&<end>&<limit>")]
+/// Parses data filters given as key:value, where the value is parsed as JSON if possible and
+/// used as a string otherwise, and regex filters given as key:regex
+fn parse_data_filters(
+    filter: Vec<String>,
+    regex: Vec<String>,
+) -> Result<Vec<DataFilter>, HttpErrorJson> {
+    let split = |param: &str, s: String| match s.split_once(':') {
+        Some((key, value)) => Ok((key.to_string(), value.to_string())),
+        None => Err(HttpErrorJson::new(
+            Status::BadRequest,
+            format!("Invalid {param} '{s}', expected key:value"),
+        )),
+    };
+    let mut filters = Vec::new();
+    for s in filter {
+        let (key, value) = split("filter", s)?;
+        let value = serde_json::from_str(&value).unwrap_or(Value::String(value));
+        filters.push(DataFilter::Equals(key, value));
+    }
+    for s in regex {
+        let (key, pattern) = split("regex", s)?;
+        filters.push(DataFilter::Regex(key, pattern));
+    }
+    Ok(filters)
+}
+
+fn parse_time_param(
+    name: &str,
+    value: Option<String>,
+) -> Result<Option<DateTime<Utc>>, HttpErrorJson> {
+    match value {
+        Some(dt_str) => match DateTime::parse_from_rfc3339(&dt_str) {
+            Ok(dt) => Ok(Some(dt.with_timezone(&Utc))),
+            Err(e) => {
+                let err_msg =
+                    format!("Failed to parse {name}, datetime needs to be in rfc3339 format: {e}");
+                warn!("{}", err_msg);
+                Err(HttpErrorJson::new(Status::BadRequest, err_msg))
+            }
+        },
+        None => Ok(None),
+    }
+}
+
+#[get("/<bucket_id>/events?<start>&<end>&<limit>&<filter>&<regex>&<order>&<cursor>")]
+#[allow(clippy::too_many_arguments)]
 pub fn bucket_events_get(
     bucket_id: &str,
     start: Option<String>,
     end: Option<String>,
     limit: Option<u64>,
+    filter: Vec<String>,
+    regex: Vec<String>,
+    order: Option<String>,
+    cursor: Option<String>,
     state: &State<ServerState>,
-) -> Result<Json<Vec<Event>>, HttpErrorJson> {
-    let starttime: Option<DateTime<Utc>> = match start {
-        Some(dt_str) => match DateTime::parse_from_rfc3339(&dt_str) {
-            Ok(dt) => Some(dt.with_timezone(&Utc)),
-            Err(e) => {
-                let err_msg = format!(
-                    "Failed to parse starttime, datetime needs to be in rfc3339 format: {e}"
-                );
-                warn!("{}", err_msg);
-                return Err(HttpErrorJson::new(Status::BadRequest, err_msg));
-            }
+) -> Result<EventPageRocket, HttpErrorJson> {
+    let starttime = parse_time_param("starttime", start)?;
+    let endtime = parse_time_param("endtime", end)?;
+    let filters = parse_data_filters(filter, regex)?;
+    let order: EventOrder = match order {
+        Some(order) => match order.parse() {
+            Ok(order) => order,
+            Err(err) => return Err(HttpErrorJson::new(Status::BadRequest, err)),
         },
-        None => None,
+        None => EventOrder::default(),
     };
-    let endtime: Option<DateTime<Utc>> = match end {
-        Some(dt_str) => match DateTime::parse_from_rfc3339(&dt_str) {
-            Ok(dt) => Some(dt.with_timezone(&Utc)),
-            Err(e) => {
-                let err_msg =
-                    format!("Failed to parse endtime, datetime needs to be in rfc3339 format: {e}");
-                warn!("{}", err_msg);
-                return Err(HttpErrorJson::new(Status::BadRequest, err_msg));
-            }
+    let cursor: Option<EventCursor> = match cursor {
+        Some(cursor) => match cursor.parse() {
+            Ok(cursor) => Some(cursor),
+            Err(err) => return Err(HttpErrorJson::new(Status::BadRequest, err)),
         },
         None => None,
     };
+    let query = EventQuery {
+        starttime,
+        endtime,
+        limit,
+        filters,
+        order,
+        cursor,
+    };
     let datastore = endpoints_get_lock!(state.datastore);
-    let res = datastore.get_events(bucket_id, starttime, endtime, limit);
-    match res {
-        Ok(events) => Ok(Json(events)),
+    match datastore.get_events_page(bucket_id, &query) {
+        Ok(page) => Ok(page.into()),
         Err(err) => Err(err.into()),
     }
 }
@@ -161,6 +218,65 @@ pub fn bucket_events_heartbeat(
     }
 }
 
+/// Number of changes bucket_changes_get returns if no limit is given, and the most it returns
+const CHANGES_LIMIT_DEFAULT: u64 = 1000;
+const CHANGES_LIMIT_MAX: u64 = 10000;
+
+/// Gets the events which were inserted, modified or deleted since the resume token, for
+/// syncing the bucket to another server. The token of the response gets the changes after it.
+#[get("/<bucket_id>/changes?<token>&<limit>")]
+pub fn bucket_changes_get(
+    bucket_id: &str,
+    token: Option<String>,
+    limit: Option<u64>,
+    state: &State<ServerState>,
+) -> Result<Json<EventChanges>, HttpErrorJson> {
+    let limit = limit
+        .unwrap_or(CHANGES_LIMIT_DEFAULT)
+        .clamp(1, CHANGES_LIMIT_MAX);
+    let datastore = endpoints_get_lock!(state.datastore);
+    match datastore.get_event_changes(bucket_id, token.as_deref(), limit) {
+        Ok(changes) => Ok(Json(changes)),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Inserts events synced from another server, replacing the events with the same origin, and
+/// deletes the events with the deleted origins
+#[post(
+    "/<bucket_id>/changes",
+    data = "<changes>",
+    format = "application/json"
+)]
+pub fn bucket_changes_apply(
+    bucket_id: &str,
+    changes: Json<OriginChanges>,
+    state: &State<ServerState>,
+) -> Result<(), HttpErrorJson> {
+    let OriginChanges {
+        mut events,
+        deleted,
+    } = changes.into_inner();
+    if events.iter().any(|e| e.origin.is_none()) {
+        return Err(HttpErrorJson::new(
+            Status::BadRequest,
+            "All events need an origin".to_string(),
+        ));
+    }
+    // Ids are only unique on the server the events came from
+    for event in &mut events {
+        event.id = None;
+    }
+    let datastore = endpoints_get_lock!(state.datastore);
+    if let Err(err) = datastore.insert_events(bucket_id, &events) {
+        return Err(err.into());
+    }
+    match datastore.delete_events_by_origin(bucket_id, deleted) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
 #[get("/<bucket_id>/events/count")]
 pub fn bucket_event_count(
     bucket_id: &str,
@@ -174,6 +290,47 @@ pub fn bucket_event_count(
     }
 }
 
+/// Deletes the events which overlap the time range and match the filters, returning how many
+/// were deleted. With dry_run nothing is deleted and the number that would be is returned.
+///
+/// Events which only partly overlap the range are deleted in full, including their time outside
+/// of it. With clip they are cut off at the range instead, so nothing outside of it is lost, and
+/// they are included in the count.
+#[allow(clippy::too_many_arguments)]
+#[delete("/<bucket_id>/events?<start>&<end>&<filter>&<regex>&<dry_run>&<clip>")]
+pub fn bucket_events_delete(
+    bucket_id: &str,
+    start: Option<String>,
+    end: Option<String>,
+    filter: Vec<String>,
+    regex: Vec<String>,
+    dry_run: Option<bool>,
+    clip: Option<bool>,
+    state: &State<ServerState>,
+) -> Result<Json<u64>, HttpErrorJson> {
+    let starttime = parse_time_param("starttime", start)?;
+    let endtime = parse_time_param("endtime", end)?;
+    let filters = parse_data_filters(filter, regex)?;
+    // A request without any conditions is far more likely to be a mistake than intentional
+    if starttime.is_none() && endtime.is_none() && filters.is_empty() {
+        return Err(HttpErrorJson::new(
+            Status::BadRequest,
+            "Refusing to delete all events, give a start, end or filter (or delete the bucket)"
+                .to_string(),
+        ));
+    }
+    let datastore = endpoints_get_lock!(state.datastore);
+    let res = match (dry_run.unwrap_or(false), clip.unwrap_or(false)) {
+        (true, _) => datastore.get_event_count_filtered(bucket_id, starttime, endtime, &filters),
+        (false, false) => datastore.delete_events_filtered(bucket_id, starttime, endtime, &filters),
+        (false, true) => datastore.delete_events_clipped(bucket_id, starttime, endtime, &filters),
+    };
+    match res {
+        Ok(count) => Ok(Json(count as u64)),
+        Err(err) => Err(err.into()),
+    }
+}
+
 #[delete("/<bucket_id>/events/<event_id>")]
 pub fn bucket_events_delete_by_id(
     bucket_id: &str,
@@ -187,6 +344,45 @@ pub fn bucket_events_delete_by_id(
     }
 }
 
+/// Replaces the event, if the new event matches a redaction rule which drops events the stored
+/// event is deleted and 410 Gone is returned
+#[put(
+    "/<bucket_id>/events/<event_id>",
+    data = "<event>",
+    format = "application/json"
+)]
+pub fn bucket_events_update(
+    bucket_id: &str,
+    event_id: i64,
+    event: Json<Event>,
+    state: &State<ServerState>,
+) -> Result<Json<Event>, HttpErrorJson> {
+    let datastore = endpoints_get_lock!(state.datastore);
+    match datastore.update_event(bucket_id, event_id, &event) {
+        Ok(event) => Ok(Json(event)),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Applies the patch to the event, see bucket_events_update for events which are dropped
+#[patch(
+    "/<bucket_id>/events/<event_id>",
+    data = "<patch>",
+    format = "application/json"
+)]
+pub fn bucket_events_patch(
+    bucket_id: &str,
+    event_id: i64,
+    patch: Json<EventPatch>,
+    state: &State<ServerState>,
+) -> Result<Json<Event>, HttpErrorJson> {
+    let datastore = endpoints_get_lock!(state.datastore);
+    match datastore.patch_event(bucket_id, event_id, &patch) {
+        Ok(event) => Ok(Json(event)),
+        Err(err) => Err(err.into()),
+    }
+}
+
 #[get("/<bucket_id>/export")]
 pub fn bucket_export(
     bucket_id: &str,
@@ -218,3 +414,41 @@ pub fn bucket_delete(bucket_id: &str, state: &State<ServerState>) -> Result<(),
         Err(err) => Err(err.into()),
     }
 }
+
+#[get("/<bucket_id>/indexes")]
+pub fn bucket_data_indexes_get(
+    bucket_id: &str,
+    state: &State<ServerState>,
+) -> Result<Json<Vec<String>>, HttpErrorJson> {
+    let datastore = endpoints_get_lock!(state.datastore);
+    match datastore.get_data_indexes(bucket_id) {
+        Ok(keys) => Ok(Json(keys)),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[put("/<bucket_id>/indexes/<key>")]
+pub fn bucket_data_index_create(
+    bucket_id: &str,
+    key: &str,
+    state: &State<ServerState>,
+) -> Result<(), HttpErrorJson> {
+    let datastore = endpoints_get_lock!(state.datastore);
+    match datastore.create_data_index(bucket_id, key) {
+        Ok(()) => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[delete("/<bucket_id>/indexes/<key>")]
+pub fn bucket_data_index_delete(
+    bucket_id: &str,
+    key: &str,
+    state: &State<ServerState>,
+) -> Result<(), HttpErrorJson> {
+    let datastore = endpoints_get_lock!(state.datastore);
+    match datastore.delete_data_index(bucket_id, key) {
+        Ok(()) => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
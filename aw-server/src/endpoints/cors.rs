@@ -2,6 +2,7 @@ use rocket::http::Method;
 use rocket_cors::{AllowedHeaders, AllowedOrigins};
 
 use crate::config::AWConfig;
+use crate::endpoints::util::NEXT_CURSOR_HEADER;
 
 pub fn cors(config: &AWConfig) -> rocket_cors::Cors {
     let root_url = format!("http://127.0.0.1:{}", config.port);
@@ -35,20 +36,30 @@ pub fn cors(config: &AWConfig) -> rocket_cors::Cors {
     }
 
     let allowed_origins = AllowedOrigins::some(&allowed_exact_origins, &allowed_regex_origins);
-    let allowed_methods = vec![Method::Get, Method::Post, Method::Delete]
-        .into_iter()
-        .map(From::from)
-        .collect();
+    let allowed_methods = vec![
+        Method::Get,
+        Method::Post,
+        Method::Put,
+        Method::Patch,
+        Method::Delete,
+    ]
+    .into_iter()
+    .map(From::from)
+    .collect();
     let allowed_headers = AllowedHeaders::all(); // TODO: is this unsafe?
 
+    // Browsers hide response headers from scripts unless they are exposed
+    let expose_headers = [NEXT_CURSOR_HEADER.to_string()].into();
+
     // You can also deserialize this
     rocket_cors::CorsOptions {
         allowed_origins,
         allowed_methods,
         allowed_headers,
+        expose_headers,
         allow_credentials: false,
         ..Default::default()
     }
     .to_cors()
     .expect("Failed to set up CORS")
-}
\ No newline at end of file
+}
@@ -13,6 +13,14 @@ use crate::endpoints::{HttpErrorJson, ServerState};
 
 fn import(datastore_mutex: &Mutex<Datastore>, import: BucketsExport) -> Result<(), HttpErrorJson> {
     let datastore = endpoints_get_lock!(datastore_mutex);
+    // Commit the whole import at once instead of once per bucket
+    let bulk_load = datastore.begin_bulk_load()?;
+    import_buckets(&datastore, import)?;
+    bulk_load.end()?;
+    Ok(())
+}
+
+fn import_buckets(datastore: &Datastore, import: BucketsExport) -> Result<(), HttpErrorJson> {
     for (_bucketname, bucket) in import.buckets {
         match datastore.create_bucket(&bucket) {
             Ok(_) => (),
@@ -0,0 +1,22 @@
+use rocket::serde::json::Json;
+use rocket::State;
+
+use aw_datastore::MetadataRepair;
+
+use crate::endpoints::{HttpErrorJson, ServerState};
+
+/// Recomputes the start and end of the buckets from their events, returns the buckets which
+/// were wrong
+///
+/// The server keeps them up to date as events change, except when events are deleted by ID,
+/// which leaves the start and end as they were until the server is restarted or this is run.
+#[post("/repair-metadata")]
+pub fn repair_metadata(
+    state: &State<ServerState>,
+) -> Result<Json<Vec<MetadataRepair>>, HttpErrorJson> {
+    let datastore = endpoints_get_lock!(state.datastore);
+    match datastore.repair_bucket_metadata() {
+        Ok(repairs) => Ok(Json(repairs)),
+        Err(err) => Err(err.into()),
+    }
+}
@@ -47,13 +47,18 @@ pub struct ServerState {
 
 #[macro_use]
 mod util;
+mod backup;
 mod bucket;
 mod cors;
 mod export;
 mod hostcheck;
 mod import;
+mod maintenance;
 mod query;
+mod querycache;
+mod retention;
 mod settings;
+mod storedquery;
 
 pub use util::HttpErrorJson;
 
@@ -156,6 +161,7 @@ pub fn build_rocket(server_state: ServerState, config: AWConfig) -> rocket::Rock
     let cors = cors::cors(&config);
     let hostcheck = hostcheck::HostCheck::new(&config);
     let custom_static = config.custom_static.clone();
+    let query_cache = querycache::QueryCache::new(config.query_cache_size);
 
     let mut rocket = rocket::custom(config.to_rocket_config())
         .attach(cors.clone())
@@ -163,6 +169,7 @@ pub fn build_rocket(server_state: ServerState, config: AWConfig) -> rocket::Rock
         .attach(CSPFairing) // 添加 CSP Fairing here
         .manage(cors)
         .manage(server_state)
+        .manage(query_cache)
         .manage(config)
         .mount(
             "/",
@@ -191,17 +198,44 @@ pub fn build_rocket(server_state: ServerState, config: AWConfig) -> rocket::Rock
                 bucket::bucket_events_create,
                 bucket::bucket_events_heartbeat,
                 bucket::bucket_event_count,
+                bucket::bucket_changes_get,
+                bucket::bucket_changes_apply,
                 bucket::bucket_events_get_single,
+                bucket::bucket_events_delete,
                 bucket::bucket_events_delete_by_id,
-                bucket::bucket_export
+                bucket::bucket_events_update,
+                bucket::bucket_events_patch,
+                bucket::bucket_export,
+                bucket::bucket_data_indexes_get,
+                bucket::bucket_data_index_create,
+                bucket::bucket_data_index_delete
+            ],
+        )
+        .mount(
+            "/api/0/query",
+            routes![query::query, query::query_stream, query::query_cache_stats],
+        )
+        .mount(
+            "/api/0/queries",
+            routes![
+                storedquery::stored_queries_get,
+                storedquery::stored_query_get,
+                storedquery::stored_query_set,
+                storedquery::stored_query_delete,
+                storedquery::stored_query_run,
             ],
         )
-        .mount("/api/0/query", routes![query::query])
         .mount(
             "/api/0/import",
             routes![import::bucket_import_json, import::bucket_import_form],
         )
         .mount("/api/0/export", routes![export::buckets_export])
+        .mount("/api/0/backup", routes![backup::backup_create])
+        .mount("/api/0/maintenance", routes![maintenance::repair_metadata])
+        .mount(
+            "/api/0/retention",
+            routes![retention::retention_report, retention::retention_apply],
+        )
         .mount(
             "/api/0/settings",
             routes![
@@ -1,19 +1,88 @@
-use rocket::http::Status;
+use std::collections::HashMap;
+
+use rocket::http::{ContentType, Status};
+use rocket::response::stream::TextStream;
 use rocket::serde::json::{json, Json, Value};
 use rocket::State;
 
-use aw_models::Query;
+use chrono::{Local, Utc};
+
+use aw_datastore::Datastore;
+use aw_models::{Query, TimeInterval, TimePeriod};
+use aw_query::CancellationToken;
 
+use crate::config::{parse_start_of_day, parse_timezone, AWConfig};
+use crate::endpoints::querycache::{QueryCache, QueryCacheStats};
 use crate::endpoints::{HttpErrorJson, ServerState};
 
 #[post("/", data = "<query_req>", format = "application/json")]
-pub fn query(query_req: Json<Query>, state: &State<ServerState>) -> Result<Value, HttpErrorJson> {
+pub fn query(
+    query_req: Json<Query>,
+    state: &State<ServerState>,
+    config: &State<AWConfig>,
+    cache: &State<QueryCache>,
+) -> Result<Value, HttpErrorJson> {
     let query_code = query_req.0.query.join("\n");
-    let intervals = &query_req.0.timeperiods;
+    let intervals = resolve_timeperiods(
+        &query_req.0.timeperiods,
+        query_req.0.timezone.as_deref(),
+        query_req.0.start_of_day.as_deref(),
+        config,
+    )?;
+    // The Datastore is only a handle to the worker thread, so clone it and release the lock
+    // instead of blocking other requests for as long as the query runs
+    let datastore = endpoints_get_lock!(state.datastore).clone();
+    run_query(
+        &query_code,
+        &query_req.0.params,
+        &intervals,
+        &datastore,
+        config,
+        cache,
+    )
+}
+
+/// Resolves relative timeperiods such as "today", using the timezone and start of day of the
+/// query if given and the ones in the config otherwise
+pub fn resolve_timeperiods(
+    timeperiods: &[TimePeriod],
+    timezone: Option<&str>,
+    start_of_day: Option<&str>,
+    config: &AWConfig,
+) -> Result<Vec<TimeInterval>, HttpErrorJson> {
+    let start_of_day = start_of_day.unwrap_or(&config.start_of_day);
+    let day_start = parse_start_of_day(start_of_day)
+        .map_err(|msg| HttpErrorJson::new(Status::BadRequest, msg))?;
+    let now = Utc::now();
+    match timezone.or(config.timezone.as_deref()) {
+        Some(tz_name) => {
+            let tz = parse_timezone(tz_name)
+                .map_err(|msg| HttpErrorJson::new(Status::BadRequest, msg))?;
+            Ok(timeperiods
+                .iter()
+                .map(|tp| tp.resolve(now, &tz, day_start))
+                .collect())
+        }
+        None => Ok(timeperiods
+            .iter()
+            .map(|tp| tp.resolve(now, &Local, day_start))
+            .collect()),
+    }
+}
+
+/// Runs the query for every interval, responding with a list of the results
+pub fn run_query(
+    query_code: &str,
+    params: &HashMap<String, Value>,
+    intervals: &[TimeInterval],
+    datastore: &Datastore,
+    config: &AWConfig,
+    cache: &QueryCache,
+) -> Result<Value, HttpErrorJson> {
     let mut results = Vec::new();
-    let datastore = endpoints_get_lock!(state.datastore);
+    let cancel = config.query_cancellation_token();
     for interval in intervals {
-        let result = match aw_query::query(&query_code, interval, &datastore) {
+        let result = match cache.query(query_code, params, interval, datastore, &cancel) {
             Ok(data) => data,
             Err(e) => {
                 warn!("Query failed: {:?}", e);
@@ -27,3 +96,80 @@ pub fn query(query_req: Json<Query>, state: &State<ServerState>) -> Result<Value
     }
     Ok(json!(results))
 }
+
+/// Cancels the token when dropped, which happens when rocket drops the response stream
+/// because the client disconnected
+struct CancelOnDrop(CancellationToken);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+/// Same as query, but responds with newline-delimited JSON where each line is the result of
+/// one timeperiod, sent as soon as that timeperiod has been computed.
+///
+/// If a timeperiod fails, a line with an error message is sent and the stream ends.
+#[post("/stream", data = "<query_req>", format = "application/json")]
+pub fn query_stream(
+    query_req: Json<Query>,
+    state: &State<ServerState>,
+    config: &State<AWConfig>,
+    cache: &State<QueryCache>,
+) -> Result<(ContentType, TextStream![String]), HttpErrorJson> {
+    let query_code = query_req.0.query.join("\n");
+    let intervals = resolve_timeperiods(
+        &query_req.0.timeperiods,
+        query_req.0.timezone.as_deref(),
+        query_req.0.start_of_day.as_deref(),
+        config,
+    )?;
+    let params = query_req.0.params;
+    let datastore = endpoints_get_lock!(state.datastore).clone();
+    let cancel = config.query_cancellation_token();
+    let guard = CancelOnDrop(cancel.clone());
+    let cache = cache.inner().clone();
+
+    let stream = TextStream! {
+        let _guard = guard;
+        for interval in intervals {
+            let code = query_code.clone();
+            let params = params.clone();
+            let ds = datastore.clone();
+            let token = cancel.clone();
+            let cache = cache.clone();
+            let res = rocket::tokio::task::spawn_blocking(move || {
+                cache.query(&code, &params, &interval, &ds, &token)
+            })
+            .await;
+            let line = match res {
+                Ok(Ok(data)) => serde_json::to_string(&data),
+                Ok(Err(e)) => {
+                    warn!("Query failed: {:?}", e);
+                    yield json!({ "message": e.to_string() }).to_string() + "\n";
+                    break;
+                }
+                Err(e) => {
+                    warn!("Query task failed: {:?}", e);
+                    yield json!({ "message": "Query task failed" }).to_string() + "\n";
+                    break;
+                }
+            };
+            match line {
+                Ok(line) => yield line + "\n",
+                Err(e) => {
+                    warn!("Failed to serialize query result: {:?}", e);
+                    yield json!({ "message": e.to_string() }).to_string() + "\n";
+                    break;
+                }
+            }
+        }
+    };
+    Ok((ContentType::new("application", "x-ndjson"), stream))
+}
+
+#[get("/cache")]
+pub fn query_cache_stats(cache: &State<QueryCache>) -> Json<QueryCacheStats> {
+    Json(cache.stats())
+}
@@ -0,0 +1,174 @@
+//! Cache for query results
+//!
+//! Entries are keyed on the normalized query code, params and timeperiod, and hold a snapshot of
+//! the bucket revisions taken right before the query was run. A cached result is only used
+//! if no bucket has been created, deleted or had events modified within the timeperiod since
+//! the snapshot, so closed historical periods can be answered without reading any events
+//! while periods which are still being written to get recomputed.
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use aw_datastore::Datastore;
+use aw_models::TimeInterval;
+use aw_query::{CancellationToken, DataType, QueryError};
+
+#[derive(Serialize, Clone, Debug)]
+pub struct QueryCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub capacity: usize,
+}
+
+struct CacheEntry {
+    revisions: HashMap<String, u64>,
+    result: DataType,
+    last_used: u64,
+}
+
+struct QueryCacheInner {
+    capacity: usize,
+    entries: HashMap<(String, String, String), CacheEntry>,
+    // Incremented on every access, used to find the least recently used entry
+    tick: u64,
+    // Incremented when the cache is cleared, results of queries which were running then aren't
+    // cached as they may have been computed from what the cache was cleared for
+    generation: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl QueryCacheInner {
+    fn evict_least_recently_used(&mut self) {
+        let lru_key = self
+            .entries
+            .iter()
+            .min_by_key(|(_key, entry)| entry.last_used)
+            .map(|(key, _entry)| key.clone());
+        if let Some(key) = lru_key {
+            self.entries.remove(&key);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct QueryCache {
+    inner: Arc<Mutex<QueryCacheInner>>,
+}
+
+impl QueryCache {
+    /// Creates a cache holding at most capacity results, a capacity of 0 disables caching
+    pub fn new(capacity: usize) -> QueryCache {
+        QueryCache {
+            inner: Arc::new(Mutex::new(QueryCacheInner {
+                capacity,
+                entries: HashMap::new(),
+                tick: 0,
+                generation: 0,
+                hits: 0,
+                misses: 0,
+            })),
+        }
+    }
+
+    /// Drops all cached results, needed when something the queries depend on which is not
+    /// tracked by the bucket revisions changes, such as stored queries
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.entries.clear();
+        inner.generation += 1;
+    }
+
+    pub fn stats(&self) -> QueryCacheStats {
+        let inner = self.inner.lock().unwrap();
+        QueryCacheStats {
+            hits: inner.hits,
+            misses: inner.misses,
+            entries: inner.entries.len(),
+            capacity: inner.capacity,
+        }
+    }
+
+    /// Runs the query for a single timeperiod, unless there is a cached result which is still
+    /// up to date
+    pub fn query(
+        &self,
+        code: &str,
+        params: &HashMap<String, Value>,
+        interval: &TimeInterval,
+        ds: &Datastore,
+        cancel: &CancellationToken,
+    ) -> Result<DataType, QueryError> {
+        let query_params = params
+            .iter()
+            .map(|(name, val)| (name.clone(), DataType::from(val)))
+            .collect();
+        if self.inner.lock().unwrap().capacity == 0 {
+            return aw_query::query_with_params(code, interval, ds, &query_params, cancel);
+        }
+        // Maps in serde_json are sorted, so equal params always serialize the same way
+        let params_str = serde_json::to_string(&params.iter().collect::<BTreeMap<_, _>>())
+            .expect("Failed to serialize query params");
+        let key = (
+            aw_query::normalize_query(code),
+            params_str,
+            interval.to_string(),
+        );
+
+        let cached_revisions = self
+            .inner
+            .lock()
+            .unwrap()
+            .entries
+            .get(&key)
+            .map(|entry| entry.revisions.clone());
+        if let Some(revisions) = cached_revisions {
+            let modified = ds
+                .buckets_modified_since(&revisions, *interval.start(), *interval.end())
+                .map_err(|e| QueryError::BucketQueryError(format!("{e:?}")))?;
+            if !modified {
+                let mut inner = self.inner.lock().unwrap();
+                inner.tick += 1;
+                let tick = inner.tick;
+                if let Some(entry) = inner.entries.get_mut(&key) {
+                    entry.last_used = tick;
+                    let result = entry.result.clone();
+                    inner.hits += 1;
+                    return Ok(result);
+                }
+            }
+        }
+
+        // The snapshot has to be taken before running the query, so that modifications made
+        // while the query is running make the result stale. It only includes committed writes,
+        // so a result which saw uncommitted writes is computed again once they are committed.
+        let generation = self.inner.lock().unwrap().generation;
+        let revisions = ds
+            .get_bucket_revisions()
+            .map_err(|e| QueryError::BucketQueryError(format!("{e:?}")))?;
+        let result = aw_query::query_with_params(code, interval, ds, &query_params, cancel)?;
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.misses += 1;
+        if inner.generation != generation {
+            return Ok(result);
+        }
+        inner.tick += 1;
+        if !inner.entries.contains_key(&key) && inner.entries.len() >= inner.capacity {
+            inner.evict_least_recently_used();
+        }
+        let last_used = inner.tick;
+        inner.entries.insert(
+            key,
+            CacheEntry {
+                revisions,
+                result: result.clone(),
+                last_used,
+            },
+        );
+        Ok(result)
+    }
+}
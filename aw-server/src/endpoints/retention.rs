@@ -0,0 +1,30 @@
+use rocket::serde::json::Json;
+use rocket::State;
+
+use aw_datastore::RetentionResult;
+
+use crate::endpoints::{HttpErrorJson, ServerState};
+
+/// Reports what enforcing the retention policies would do without changing any events
+#[get("/")]
+pub fn retention_report(
+    state: &State<ServerState>,
+) -> Result<Json<Vec<RetentionResult>>, HttpErrorJson> {
+    let datastore = endpoints_get_lock!(state.datastore);
+    match datastore.retention_report() {
+        Ok(results) => Ok(Json(results)),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Enforces the retention policies now rather than waiting for the next periodic enforcement
+#[post("/")]
+pub fn retention_apply(
+    state: &State<ServerState>,
+) -> Result<Json<Vec<RetentionResult>>, HttpErrorJson> {
+    let datastore = endpoints_get_lock!(state.datastore);
+    match datastore.apply_retention() {
+        Ok(results) => Ok(Json(results)),
+        Err(err) => Err(err.into()),
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+
+use rocket::http::Status;
+use rocket::serde::json::{Json, Value};
+use rocket::State;
+
+use aw_models::{StoredQuery, StoredQueryRun};
+
+use crate::config::AWConfig;
+use crate::endpoints::query::{resolve_timeperiods, run_query};
+use crate::endpoints::querycache::QueryCache;
+use crate::endpoints::{HttpErrorJson, ServerState};
+
+// Names end up in URLs and in import statements, so keep them simple
+fn validate_name(name: &str) -> Result<(), HttpErrorJson> {
+    if name.is_empty() || name.len() >= 128 {
+        Err(HttpErrorJson::new(
+            Status::BadRequest,
+            "Query name has to be between 1 and 127 characters long".to_string(),
+        ))
+    } else if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+    {
+        Err(HttpErrorJson::new(
+            Status::BadRequest,
+            format!(
+                "Invalid query name '{name}', only a-z, A-Z, 0-9, '_', '-' and '.' are allowed"
+            ),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+#[get("/")]
+pub fn stored_queries_get(
+    state: &State<ServerState>,
+) -> Result<Json<HashMap<String, StoredQuery>>, HttpErrorJson> {
+    let datastore = endpoints_get_lock!(state.datastore);
+    match datastore.get_stored_queries() {
+        Ok(queries) => Ok(Json(queries)),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[get("/<name>")]
+pub fn stored_query_get(
+    state: &State<ServerState>,
+    name: &str,
+) -> Result<Json<StoredQuery>, HttpErrorJson> {
+    let datastore = endpoints_get_lock!(state.datastore);
+    match datastore.get_stored_query(name) {
+        Ok(query) => Ok(Json(query)),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[post("/<name>", data = "<query>", format = "application/json")]
+pub fn stored_query_set(
+    state: &State<ServerState>,
+    cache: &State<QueryCache>,
+    name: &str,
+    query: Json<StoredQuery>,
+) -> Result<Status, HttpErrorJson> {
+    validate_name(name)?;
+    let mut query = query.into_inner();
+    query.name = name.to_string();
+
+    let datastore = endpoints_get_lock!(state.datastore);
+    match datastore.set_stored_query(&query) {
+        Ok(_) => {
+            // Cached results of queries importing this one are now stale
+            cache.clear();
+            Ok(Status::Created)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[delete("/<name>")]
+pub fn stored_query_delete(
+    state: &State<ServerState>,
+    cache: &State<QueryCache>,
+    name: &str,
+) -> Result<(), HttpErrorJson> {
+    let datastore = endpoints_get_lock!(state.datastore);
+    match datastore.delete_stored_query(name) {
+        Ok(_) => {
+            cache.clear();
+            Ok(())
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[post("/<name>/run", data = "<run_req>", format = "application/json")]
+pub fn stored_query_run(
+    state: &State<ServerState>,
+    config: &State<AWConfig>,
+    cache: &State<QueryCache>,
+    name: &str,
+    run_req: Json<StoredQueryRun>,
+) -> Result<Value, HttpErrorJson> {
+    let intervals = resolve_timeperiods(
+        &run_req.timeperiods,
+        run_req.timezone.as_deref(),
+        run_req.start_of_day.as_deref(),
+        config,
+    )?;
+    let datastore = endpoints_get_lock!(state.datastore).clone();
+    let query = datastore.get_stored_query(name)?;
+    run_query(
+        &query.query.join("\n"),
+        &run_req.params,
+        &intervals,
+        &datastore,
+        config,
+        cache,
+    )
+}
@@ -7,6 +7,7 @@ use rocket::request::Request;
 use rocket::response::{self, Responder, Response};
 use serde::Serialize;
 
+use aw_datastore::EventPage;
 use aw_models::BucketsExport;
 
 #[derive(Serialize, Debug)]
@@ -67,6 +68,35 @@ impl<'r> Responder<'r, 'static> for BucketsExportRocket {
     }
 }
 
+/// Header with the cursor to get the next page of events with, left out on the last page
+pub const NEXT_CURSOR_HEADER: &str = "X-Next-Cursor";
+
+pub struct EventPageRocket {
+    inner: EventPage,
+}
+
+impl From<EventPage> for EventPageRocket {
+    fn from(val: EventPage) -> Self {
+        EventPageRocket { inner: val }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for EventPageRocket {
+    fn respond_to(self, _: &Request) -> response::Result<'static> {
+        // TODO: Fix unwrap
+        let body = serde_json::to_string(&self.inner.events).unwrap();
+        let mut response = Response::build();
+        response
+            .status(Status::Ok)
+            .sized_body(body.len(), Cursor::new(body))
+            .header(ContentType::new("application", "json"));
+        if let Some(cursor) = self.inner.next_cursor {
+            response.header(Header::new(NEXT_CURSOR_HEADER, cursor.to_string()));
+        }
+        response.ok()
+    }
+}
+
 use aw_datastore::DatastoreError;
 
 impl From<DatastoreError> for HttpErrorJson {
@@ -80,10 +110,25 @@ impl From<DatastoreError> for HttpErrorJson {
                 Status::NotModified,
                 format!("Bucket '{bucket_id}' already exists"),
             ),
+            DatastoreError::NoSuchEvent(event_id) => HttpErrorJson::new(
+                Status::NotFound,
+                format!("The requested event '{event_id}' does not exist"),
+            ),
+            DatastoreError::EventDropped(event_id) => HttpErrorJson::new(
+                Status::Gone,
+                format!("The event '{event_id}' was deleted, as the update matches a redaction rule which drops events"),
+            ),
             DatastoreError::NoSuchKey(key) => HttpErrorJson::new(
                 Status::NotFound,
                 format!("The requested key(s) '{key}' do not exist"),
             ),
+            DatastoreError::NoSuchQuery(name) => HttpErrorJson::new(
+                Status::NotFound,
+                format!("The requested query '{name}' does not exist"),
+            ),
+            DatastoreError::InvalidFilter(msg) => HttpErrorJson::new(Status::BadRequest, msg),
+            DatastoreError::InvalidToken(msg) => HttpErrorJson::new(Status::BadRequest, msg),
+            DatastoreError::InvalidKey(msg) => HttpErrorJson::new(Status::InternalServerError, msg),
             DatastoreError::MpscError => HttpErrorJson::new(
                 Status::InternalServerError,
                 "Unexpected Mpsc error!".to_string(),
@@ -27,6 +27,7 @@ pub mod device_id;
 pub mod dirs;
 pub mod endpoints;
 pub mod logging;
+pub mod maintenance;
 pub mod plugins;
 
 #[cfg(target_os = "android")]
@@ -6,6 +6,7 @@ use std::path::PathBuf;
 
 use clap::crate_version;
 use clap::Parser;
+use clap::Subcommand;
 
 use aw_server::*;
 mod plugins;
@@ -58,6 +59,15 @@ struct Opts {
     /// Don't import from aw-server-python if no aw-server-rust db found
     #[clap(long)]
     no_legacy_import: bool,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Maintain the database instead of running the server, the server can keep running
+    Db(maintenance::DbOpts),
 }
 
 #[rocket::main]
@@ -73,6 +83,23 @@ async fn main() -> Result<(), rocket::Error> {
         testing = true;
     }
 
+    // Logging is left out as the output of the subcommands is meant to be parsed
+    if let Some(Command::Db(db_opts)) = &opts.command {
+        let db_path = match &opts.dbpath {
+            Some(dbpath) => dbpath.clone(),
+            None => dirs::db_path(testing)
+                .expect("Failed to get db path")
+                .to_str()
+                .unwrap()
+                .to_string(),
+        };
+        let config = config::create_config(testing);
+        let address = opts.host.clone().unwrap_or(config.address);
+        let port = opts.port.clone().unwrap_or(config.port.to_string());
+        let server_address = format!("{address}:{port}");
+        std::process::exit(maintenance::run(&db_path, &server_address, db_opts));
+    }
+
     logging::setup_logger("aw-server-rust", testing, opts.verbose)
         .expect("Failed to setup logging");
 
@@ -142,10 +169,27 @@ async fn main() -> Result<(), rocket::Error> {
         device_id::get_device_id()
     };
 
+    if let Err(err) = config.validate() {
+        error!("Invalid config: {err}");
+        std::process::exit(1);
+    }
+
+    let datastore_options = match config.datastore_options() {
+        Ok(options) => options,
+        Err(err) => {
+            error!("Invalid datastore config: {err}");
+            std::process::exit(1);
+        }
+    };
+
     let server_state = endpoints::ServerState {
         // Even if legacy_import is set to true it is disabled on Android so
         // it will not happen there
-        datastore: Mutex::new(aw_datastore::Datastore::new(db_path, legacy_import)),
+        datastore: Mutex::new(aw_datastore::Datastore::new_with_options(
+            db_path,
+            legacy_import,
+            datastore_options,
+        )),
         asset_resolver: endpoints::AssetResolver::new(asset_path),
         device_id,
     };
@@ -0,0 +1,212 @@
+//! The `db` subcommands for maintaining the datastore file
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+
+use clap::{Args, Subcommand};
+use serde::Serialize;
+
+use aw_datastore::{Datastore, DatastoreOptions, MetadataRepair};
+
+#[derive(Args)]
+pub struct DbOpts {
+    /// Print the results as JSON
+    #[clap(long)]
+    pub json: bool,
+
+    #[clap(subcommand)]
+    pub command: DbCommand,
+}
+
+#[derive(Subcommand)]
+pub enum DbCommand {
+    /// Release unused space and update the query planner statistics
+    Vacuum,
+    /// Check the database for corruption
+    IntegrityCheck,
+    /// Show the size of the database and the number of events per bucket
+    Stats,
+    /// Find events whose bucket no longer exists
+    Orphans {
+        /// Delete the orphaned events
+        #[clap(long)]
+        delete: bool,
+    },
+    /// Recompute the start and end of the buckets from their events on the running server
+    RepairMetadata,
+    /// Write a consistent copy of the database to the path
+    Backup { path: String },
+    /// Replace the database with a backup, the server has to be stopped first
+    Restore { path: String },
+}
+
+/// Runs the subcommand on the database at db_path and prints its result, returns the exit code
+///
+/// The start and end of the buckets are only kept in the memory of the server, so they are
+/// repaired by the server listening at server_address instead.
+///
+/// The exit code is 0 on success, 1 if the subcommand failed and 2 if the integrity check
+/// found problems.
+pub fn run(db_path: &str, server_address: &str, opts: &DbOpts) -> i32 {
+    if let DbCommand::RepairMetadata = &opts.command {
+        return repair_metadata(server_address, opts.json);
+    }
+    // The datastore keeps the database locked, so it can't be opened for a restore
+    if let DbCommand::Restore { path } = &opts.command {
+        return match aw_datastore::restore_backup(path, db_path) {
+            Ok(report) => {
+                let text = format!(
+                    "Restored {path} to {db_path}, migrated from version {} to {}",
+                    report.backup_version, report.version
+                );
+                output(opts.json, &report, text)
+            }
+            Err(err) => {
+                print_error(opts.json, &format!("{err:?}"));
+                1
+            }
+        };
+    }
+    // Opening the datastore would create an empty database instead
+    if !Path::new(db_path).is_file() {
+        print_error(opts.json, &format!("No database at {db_path}"));
+        return 1;
+    }
+    // The configured retention isn't enforced, so that maintenance never changes events
+    let datastore =
+        Datastore::new_with_options(db_path.to_string(), false, DatastoreOptions::default());
+    let code = run_command(&datastore, opts);
+    datastore.close();
+    code
+}
+
+fn run_command(datastore: &Datastore, opts: &DbOpts) -> i32 {
+    let res = match &opts.command {
+        DbCommand::Vacuum => datastore.vacuum().map(|report| {
+            let text = format!(
+                "Database size {} -> {} bytes",
+                report.before.bytes, report.after.bytes
+            );
+            output(opts.json, &report, text)
+        }),
+        DbCommand::IntegrityCheck => datastore.integrity_check().map(|report| {
+            let text = match report.ok {
+                true => "ok".to_string(),
+                false => report.errors.join("\n"),
+            };
+            output(opts.json, &report, text);
+            if report.ok {
+                0
+            } else {
+                2
+            }
+        }),
+        DbCommand::Stats => datastore.get_database_stats().map(|stats| {
+            let mut text = format!(
+                "Database version {}, {} bytes of which {} pages are free",
+                stats.version, stats.size.bytes, stats.size.freelist_count
+            );
+            for bucket in &stats.buckets {
+                text.push_str(&format!(
+                    "\n{}: {} events, {} bytes of data",
+                    bucket.bucket_id, bucket.events, bucket.data_bytes
+                ));
+            }
+            output(opts.json, &stats, text)
+        }),
+        DbCommand::Orphans { delete } => datastore.find_orphaned_events(*delete).map(|report| {
+            let mut text = match report.orphans.is_empty() {
+                true => "No orphaned events".to_string(),
+                false => String::new(),
+            };
+            for orphans in &report.orphans {
+                text.push_str(&format!(
+                    "{} events of missing bucket row {}{}\n",
+                    orphans.events,
+                    orphans.bucketrow,
+                    if report.deleted { " deleted" } else { "" }
+                ));
+            }
+            output(opts.json, &report, text.trim_end().to_string())
+        }),
+        DbCommand::Backup { path } => datastore.backup(path).map(|()| {
+            let text = format!("Backed up to {path}");
+            output(opts.json, &serde_json::json!({ "path": path }), text)
+        }),
+        DbCommand::RepairMetadata | DbCommand::Restore { .. } => {
+            unreachable!("Run without a datastore")
+        }
+    };
+    match res {
+        Ok(code) => code,
+        Err(err) => {
+            print_error(opts.json, &format!("{err:?}"));
+            1
+        }
+    }
+}
+
+fn repair_metadata(server_address: &str, json: bool) -> i32 {
+    let res = post(server_address, "/api/0/maintenance/repair-metadata").and_then(|body| {
+        serde_json::from_str::<Vec<MetadataRepair>>(&body)
+            .map_err(|err| format!("Invalid response from the server: {err}"))
+    });
+    let repairs = match res {
+        Ok(repairs) => repairs,
+        Err(err) => {
+            print_error(json, &err);
+            return 1;
+        }
+    };
+    let mut text = match repairs.is_empty() {
+        true => "The start and end of all buckets are correct".to_string(),
+        false => String::new(),
+    };
+    for repair in &repairs {
+        text.push_str(&format!(
+            "{}: start {:?} -> {:?}, end {:?} -> {:?}\n",
+            repair.bucket_id, repair.old_start, repair.new_start, repair.old_end, repair.new_end
+        ));
+    }
+    output(json, &repairs, text.trim_end().to_string())
+}
+
+/// Sends a POST request without a body to the server, returns the body of the response
+fn post(server_address: &str, path: &str) -> Result<String, String> {
+    let mut stream = TcpStream::connect(server_address)
+        .map_err(|err| format!("Could not connect to the server at {server_address}: {err}"))?;
+    let request = format!(
+        "POST {path} HTTP/1.1\r\nHost: {server_address}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+    );
+    let mut response = String::new();
+    stream
+        .write_all(request.as_bytes())
+        .and_then(|()| stream.read_to_string(&mut response))
+        .map_err(|err| format!("Request to the server at {server_address} failed: {err}"))?;
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or("Invalid response from the server")?;
+    let status_line = head.lines().next().unwrap_or_default();
+    match status_line.split(' ').nth(1) {
+        Some("200") => Ok(body.to_string()),
+        _ => Err(format!("The server responded with {status_line}: {body}")),
+    }
+}
+
+fn output<T: Serialize>(json: bool, value: &T, text: String) -> i32 {
+    if json {
+        println!("{}", serde_json::to_string_pretty(value).unwrap());
+    } else {
+        println!("{text}");
+    }
+    0
+}
+
+fn print_error(json: bool, err: &str) {
+    if json {
+        println!("{}", serde_json::json!({ "error": err }));
+    } else {
+        eprintln!("Error: {err}");
+    }
+}
@@ -16,7 +16,7 @@ mod api_tests {
     use aw_server::config;
     use aw_server::endpoints;
 
-    use aw_models::{Bucket, BucketsExport};
+    use aw_models::{Bucket, BucketsExport, StoredQuery, TimeInterval};
     use rocket::local::blocking::Client;
 
     fn setup_testserver() -> rocket::Rocket<rocket::Build> {
@@ -29,6 +29,29 @@ mod api_tests {
         endpoints::build_rocket(state, aw_config)
     }
 
+    /// A server with a datastore stored in a file, which is returned so that tests can commit
+    fn setup_file_testserver(
+        name: &str,
+    ) -> (rocket::Rocket<rocket::Build>, aw_datastore::Datastore) {
+        let path = std::env::temp_dir().join(format!("aw-server-{name}-{}.db", std::process::id()));
+        let path = path.display().to_string();
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{path}{suffix}"));
+        }
+        let options = aw_datastore::DatastoreOptions {
+            commit_interval: std::time::Duration::from_secs(3600),
+            ..aw_datastore::DatastoreOptions::default()
+        };
+        let datastore = aw_datastore::Datastore::new_with_options(path, false, options);
+        let state = endpoints::ServerState {
+            datastore: Mutex::new(datastore.clone()),
+            asset_resolver: endpoints::AssetResolver::new(None),
+            device_id: "test_id".to_string(),
+        };
+        let server = endpoints::build_rocket(state, config::AWConfig::default());
+        (server, datastore)
+    }
+
     #[test]
     fn test_bucket() {
         let server = setup_testserver();
@@ -268,6 +291,777 @@ mod api_tests {
         assert_eq!(res.status(), rocket::http::Status::Ok);
     }
 
+    #[test]
+    fn test_events_read_after_write() {
+        let (server, datastore) = setup_file_testserver("read-after-write");
+        let client = Client::untracked(server).expect("valid instance");
+
+        let res = client
+            .post("/api/0/buckets/id")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"id": "id", "type": "type", "client": "client", "hostname": "hostname"}"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+
+        // Events are read back right away, before they are committed
+        let res = client
+            .post("/api/0/buckets/id/events")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"[{"timestamp": "2018-01-01T01:01:01Z", "duration": 1.0, "data": {"a": 1}}]"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let events: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        let url = format!("/api/0/buckets/id/events/{}", events[0]["id"]);
+        let get_event = || {
+            let res = client
+                .get(&url)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .dispatch();
+            assert_eq!(res.status(), rocket::http::Status::Ok);
+            serde_json::from_str::<Value>(&res.into_string().unwrap()).unwrap()
+        };
+        assert_eq!(get_event()["data"], json!({"a": 1}));
+        let res = client
+            .get("/api/0/buckets/id")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        let bucket: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(bucket["metadata"]["end"], "2018-01-01T01:01:02Z");
+
+        let res = client
+            .put(&url)
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"timestamp": "2018-01-01T01:01:01Z", "duration": 1.0, "data": {"a": 2}}"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(get_event()["data"], json!({"a": 2}));
+
+        // Patches made within a commit interval apply on top of each other
+        for body in [r#"{"data": {"b": 1}}"#, r#"{"data": {"c": 1}}"#] {
+            let res = client
+                .patch(&url)
+                .header(ContentType::JSON)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .body(body)
+                .dispatch();
+            assert_eq!(res.status(), rocket::http::Status::Ok);
+        }
+        assert_eq!(get_event()["data"], json!({"a": 2, "b": 1, "c": 1}));
+
+        datastore.force_commit().unwrap();
+        assert_eq!(get_event()["data"], json!({"a": 2, "b": 1, "c": 1}));
+    }
+
+    #[test]
+    fn test_events_data_filters() {
+        let server = setup_testserver();
+        let client = Client::untracked(server).expect("valid instance");
+
+        let res = client
+            .post("/api/0/buckets/id")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"id": "id", "type": "type", "client": "client", "hostname": "hostname"}"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let res = client
+            .post("/api/0/buckets/id/events")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(
+                r#"[
+                {"timestamp": "2018-01-01T01:01:01Z", "duration": 1.0, "data": {"app": "firefox", "n": 1}},
+                {"timestamp": "2018-01-01T01:01:02Z", "duration": 1.0, "data": {"app": "firefox", "n": 2}},
+                {"timestamp": "2018-01-01T01:01:03Z", "duration": 1.0, "data": {"app": "code", "n": 3}}
+            ]"#,
+            )
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+
+        let get_ns = |url: &str| -> Vec<i64> {
+            let res = client
+                .get(url)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .dispatch();
+            assert_eq!(res.status(), rocket::http::Status::Ok);
+            let events: Vec<Value> = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+            events
+                .iter()
+                .map(|e| e["data"]["n"].as_i64().unwrap())
+                .collect()
+        };
+        assert_eq!(
+            get_ns("/api/0/buckets/id/events?filter=app:firefox"),
+            vec![2, 1]
+        );
+        // Values are parsed as JSON when possible
+        assert_eq!(get_ns("/api/0/buckets/id/events?filter=n:3"), vec![3]);
+        assert_eq!(
+            get_ns("/api/0/buckets/id/events?filter=app:firefox&filter=n:1"),
+            vec![1]
+        );
+        assert_eq!(get_ns("/api/0/buckets/id/events?regex=app:%5Ec"), vec![3]);
+
+        // Index the field
+        let res = client
+            .put("/api/0/buckets/id/indexes/app")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let res = client
+            .get("/api/0/buckets/id/indexes")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.into_string().unwrap(), r#"["app"]"#);
+        assert_eq!(get_ns("/api/0/buckets/id/events?filter=app:code"), vec![3]);
+        let res = client
+            .delete("/api/0/buckets/id/indexes/app")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+
+        // Invalid filters
+        for url in [
+            "/api/0/buckets/id/events?filter=app",
+            "/api/0/buckets/id/events?regex=app:(",
+        ] {
+            let res = client
+                .get(url)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .dispatch();
+            assert_eq!(res.status(), rocket::http::Status::BadRequest);
+        }
+    }
+
+    #[test]
+    fn test_event_update() {
+        let server = setup_testserver();
+        let client = Client::untracked(server).expect("valid instance");
+
+        let res = client
+            .post("/api/0/buckets/id")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"id": "id", "type": "type", "client": "client", "hostname": "hostname"}"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let res = client
+            .post("/api/0/buckets/id/events")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"[{"timestamp": "2018-01-01T01:01:01Z", "duration": 1.0, "data": {"app": "code", "title": "main.rs"}}]"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let events: Vec<Value> = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        let event_id = events[0]["id"].as_i64().unwrap();
+        let url = format!("/api/0/buckets/id/events/{event_id}");
+
+        // Replace the event
+        let res = client
+            .put(url.clone())
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"timestamp": "2018-01-01T01:01:01Z", "duration": 2.0, "data": {"app": "code"}}"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let event: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(event["id"], event_id);
+        assert_eq!(event["duration"], 2.0);
+        assert_eq!(event["data"], json!({"app": "code"}));
+
+        // Patch the data, keeping the rest
+        let res = client
+            .patch(url.clone())
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"data": {"label": "work", "app": null}}"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let res = client
+            .get(url.clone())
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        let event: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(event["id"], event_id);
+        assert_eq!(event["duration"], 2.0);
+        assert_eq!(event["timestamp"], "2018-01-01T01:01:01Z");
+        assert_eq!(event["data"], json!({"label": "work"}));
+
+        // The bucket end follows the event
+        let res = client
+            .get("/api/0/buckets/id")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        let bucket: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(bucket["metadata"]["end"], "2018-01-01T01:01:03Z");
+
+        for res in [
+            client
+                .patch("/api/0/buckets/id/events/1000")
+                .header(ContentType::JSON)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .body(r#"{"data": {}}"#)
+                .dispatch(),
+            client
+                .put("/api/0/buckets/id/events/1000")
+                .header(ContentType::JSON)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .body(r#"{"timestamp": "2018-01-01T01:01:01Z", "duration": 1.0, "data": {}}"#)
+                .dispatch(),
+        ] {
+            assert_eq!(res.status(), rocket::http::Status::NotFound);
+        }
+    }
+
+    #[test]
+    fn test_events_delete() {
+        let server = setup_testserver();
+        let client = Client::untracked(server).expect("valid instance");
+
+        let res = client
+            .post("/api/0/buckets/id")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"id": "id", "type": "type", "client": "client", "hostname": "hostname"}"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let res = client
+            .post("/api/0/buckets/id/events")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(
+                r#"[
+                {"timestamp": "2018-01-01T14:10:00Z", "duration": 60.0, "data": {"title": "bank"}},
+                {"timestamp": "2018-01-01T14:20:00Z", "duration": 60.0, "data": {"title": "news"}},
+                {"timestamp": "2018-01-01T16:00:00Z", "duration": 60.0, "data": {"title": "my bank"}}
+            ]"#,
+            )
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+
+        let delete = |url: &str| {
+            let res = client
+                .delete(url)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .dispatch();
+            let status = res.status();
+            (status, res.into_string().unwrap())
+        };
+        let count = || {
+            let res = client
+                .get("/api/0/buckets/id/events/count")
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .dispatch();
+            res.into_string().unwrap()
+        };
+
+        // Dry run only counts
+        let range = "start=2018-01-01T14:00:00Z&end=2018-01-01T15:00:00Z";
+        assert_eq!(
+            delete(&format!("/api/0/buckets/id/events?{range}&dry_run=true")),
+            (rocket::http::Status::Ok, "2".to_string())
+        );
+        assert_eq!(count(), "3");
+
+        assert_eq!(
+            delete("/api/0/buckets/id/events?regex=title:bank"),
+            (rocket::http::Status::Ok, "2".to_string())
+        );
+        assert_eq!(
+            delete(&format!("/api/0/buckets/id/events?{range}")),
+            (rocket::http::Status::Ok, "1".to_string())
+        );
+        assert_eq!(count(), "0");
+
+        // With clip, the part of an event outside of the range is kept
+        let res = client
+            .post("/api/0/buckets/id/events")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"[{"timestamp": "2018-01-01T14:50:00Z", "duration": 1200.0, "data": {}}]"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(
+            delete(&format!("/api/0/buckets/id/events?{range}&clip=true")),
+            (rocket::http::Status::Ok, "1".to_string())
+        );
+        let res = client
+            .get("/api/0/buckets/id/events")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        let events: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(events.as_array().unwrap().len(), 1);
+        assert_eq!(events[0]["timestamp"], "2018-01-01T15:00:00Z");
+        assert_eq!(events[0]["duration"], 600.0);
+
+        // Deleting everything takes deleting the bucket
+        assert_eq!(
+            delete("/api/0/buckets/id/events").0,
+            rocket::http::Status::BadRequest
+        );
+        assert_eq!(
+            delete("/api/0/buckets/id/events?start=yesterday").0,
+            rocket::http::Status::BadRequest
+        );
+        assert_eq!(
+            delete("/api/0/buckets/nosuchbucket/events?regex=title:bank").0,
+            rocket::http::Status::NotFound
+        );
+    }
+
+    #[test]
+    fn test_repair_metadata() {
+        let server = setup_testserver();
+        let client = Client::untracked(server).expect("valid instance");
+
+        let res = client
+            .post("/api/0/buckets/id")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"id": "id", "type": "type", "client": "client", "hostname": "hostname"}"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let res = client
+            .post("/api/0/buckets/id/events")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(
+                r#"[
+                {"timestamp": "2018-01-01T14:10:00Z", "duration": 60.0, "data": {}},
+                {"timestamp": "2018-01-01T16:00:00Z", "duration": 60.0, "data": {}}
+            ]"#,
+            )
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+
+        let bucket_end = || {
+            let res = client
+                .get("/api/0/buckets/id")
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .dispatch();
+            let bucket: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+            bucket["metadata"]["end"].clone()
+        };
+        let repair = || {
+            let res = client
+                .post("/api/0/maintenance/repair-metadata")
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .dispatch();
+            assert_eq!(res.status(), rocket::http::Status::Ok);
+            serde_json::from_str::<Value>(&res.into_string().unwrap()).unwrap()
+        };
+        assert_eq!(repair(), json!([]));
+
+        // Deleting the last event by ID leaves the end of the bucket stale
+        let res = client
+            .get("/api/0/buckets/id/events?limit=1")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        let events: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        let last_id = events[0]["id"].as_i64().unwrap();
+        let res = client
+            .delete(format!("/api/0/buckets/id/events/{last_id}"))
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(bucket_end(), json!("2018-01-01T16:01:00Z"));
+
+        let repairs = repair();
+        assert_eq!(repairs.as_array().unwrap().len(), 1);
+        assert_eq!(repairs[0]["bucket_id"], "id");
+        assert_eq!(repairs[0]["old_end"], "2018-01-01T16:01:00Z");
+        assert_eq!(repairs[0]["new_end"], "2018-01-01T14:11:00Z");
+        assert_eq!(bucket_end(), json!("2018-01-01T14:11:00Z"));
+        assert_eq!(repair(), json!([]));
+    }
+
+    #[test]
+    fn test_bucket_changes() {
+        let server = setup_testserver();
+        let client = Client::untracked(server).expect("valid instance");
+
+        let res = client
+            .post("/api/0/buckets/id")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"id": "id", "type": "type", "client": "client", "hostname": "hostname"}"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let res = client
+            .post("/api/0/buckets/id/events")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(
+                r#"[
+                {"timestamp": "2018-01-01T14:10:00Z", "duration": 60.0, "data": {"title": "bank"}},
+                {"timestamp": "2018-01-01T14:20:00Z", "duration": 60.0, "data": {"title": "news"}}
+            ]"#,
+            )
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+
+        let get_changes = |query: &str| {
+            let res = client
+                .get(format!("/api/0/buckets/id/changes{query}"))
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .dispatch();
+            assert_eq!(res.status(), rocket::http::Status::Ok);
+            serde_json::from_str::<Value>(&res.into_string().unwrap()).unwrap()
+        };
+        let apply_changes = |body: Value| {
+            client
+                .post("/api/0/buckets/id/changes")
+                .header(ContentType::JSON)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .body(body.to_string())
+                .dispatch()
+                .status()
+        };
+
+        // Changes are fetched in batches by passing on the token
+        let changes = get_changes("?limit=1");
+        assert_eq!(changes["events"].as_array().unwrap().len(), 1);
+        assert_eq!(changes["more"], json!(true));
+        let changes = get_changes(&format!("?token={}", changes["token"].as_str().unwrap()));
+        assert_eq!(changes["events"][0]["data"]["title"], json!("news"));
+        assert_eq!(changes["more"], json!(false));
+        let token = changes["token"].as_str().unwrap().to_string();
+
+        // Applied changes replace and delete events by their origin
+        let event = json!({"timestamp": "2018-01-01T15:00:00Z", "duration": 1.0, "data": {}});
+        let mut synced = event.clone();
+        synced["origin"] = json!("remote:1");
+        assert_eq!(
+            apply_changes(json!({"events": [synced.clone(), synced], "deleted": []})),
+            rocket::http::Status::Ok
+        );
+        let changes = get_changes(&format!("?token={token}"));
+        assert_eq!(changes["events"].as_array().unwrap().len(), 1);
+        assert_eq!(changes["events"][0]["origin"], json!("remote:1"));
+        let token = changes["token"].as_str().unwrap().to_string();
+        assert_eq!(
+            apply_changes(json!({"events": [], "deleted": ["remote:1"]})),
+            rocket::http::Status::Ok
+        );
+        let changes = get_changes(&format!("?token={token}"));
+        assert_eq!(changes["deleted"][0]["origin"], json!("remote:1"));
+
+        // Events need an origin to be applied, and tokens have to be valid
+        assert_eq!(
+            apply_changes(json!({"events": [event], "deleted": []})),
+            rocket::http::Status::BadRequest
+        );
+        let res = client
+            .get("/api/0/buckets/id/changes?token=abc")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+        let res = client
+            .get("/api/0/buckets/nosuchbucket/changes")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::NotFound);
+    }
+
+    #[test]
+    fn test_retention() {
+        let server = setup_testserver();
+        let client = Client::untracked(server).expect("valid instance");
+
+        let res = client
+            .post("/api/0/buckets/id")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(
+                r#"{"id": "id", "type": "type", "client": "client", "hostname": "hostname",
+                "data": {"retention": [{"days": 30, "action": "delete"}]}}"#,
+            )
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let res = client
+            .post("/api/0/buckets/id/events")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(
+                r#"[
+                {"timestamp": "2018-01-01T14:10:00Z", "duration": 60.0, "data": {}},
+                {"timestamp": "2018-01-01T14:20:00Z", "duration": 60.0, "data": {}}
+            ]"#,
+            )
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+
+        let retention = |dry_run: bool| {
+            let req = match dry_run {
+                true => client.get("/api/0/retention"),
+                false => client.post("/api/0/retention"),
+            };
+            let res = req.header(Header::new("Host", "127.0.0.1:5600")).dispatch();
+            assert_eq!(res.status(), rocket::http::Status::Ok);
+            serde_json::from_str::<Value>(&res.into_string().unwrap()).unwrap()
+        };
+        let count = || {
+            let res = client
+                .get("/api/0/buckets/id/events/count")
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .dispatch();
+            res.into_string().unwrap()
+        };
+
+        let report = retention(true);
+        assert_eq!(report[0]["bucket_id"], "id");
+        assert_eq!(report[0]["policy"]["action"], "delete");
+        assert_eq!(report[0]["events"], 2);
+        assert_eq!(count(), "2");
+        assert_eq!(retention(false)[0]["events"], 2);
+        assert_eq!(count(), "0");
+    }
+
+    #[test]
+    fn test_retention_config() {
+        let aw_config: config::AWConfig = toml::from_str(
+            r#"
+            retention_interval = 600
+
+            [[retention]]
+            bucket = "aw-watcher-window_*"
+            days = 90
+            action = "strip_keys"
+            keys = ["title"]
+            "#,
+        )
+        .unwrap();
+        let options = aw_config.datastore_options().unwrap();
+        assert_eq!(options.retention.len(), 1);
+        assert_eq!(options.retention_interval.as_secs(), 600);
+
+        let aw_config: config::AWConfig = toml::from_str(
+            r#"
+            [[retention]]
+            days = 90
+            action = "merge"
+            keys = []
+            "#,
+        )
+        .unwrap();
+        assert!(aw_config.datastore_options().is_err());
+
+        let aw_config: config::AWConfig = toml::from_str("retention_interval = 0").unwrap();
+        assert!(aw_config.datastore_options().is_err());
+
+        // The default config is written out as toml
+        toml::to_string(&config::AWConfig::default()).unwrap();
+    }
+
+    #[test]
+    fn test_redaction() {
+        let aw_config: config::AWConfig = toml::from_str(
+            r#"
+            [[redact]]
+            key = "title"
+            regex = "private browsing"
+            ignore_case = true
+            action = "replace"
+            replacement = "private"
+
+            [[redact]]
+            type = "currentwindow"
+            key = "app"
+            regex = "^keepassxc$"
+            action = "drop"
+            "#,
+        )
+        .unwrap();
+        let options = aw_config.datastore_options().unwrap();
+        let state = endpoints::ServerState {
+            datastore: Mutex::new(aw_datastore::Datastore::new_in_memory_with_options(
+                false, options,
+            )),
+            asset_resolver: endpoints::AssetResolver::new(None),
+            device_id: "test_id".to_string(),
+        };
+        let server = endpoints::build_rocket(state, aw_config);
+        let client = Client::untracked(server).expect("valid instance");
+
+        let res = client
+            .post("/api/0/buckets/id")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"id": "id", "type": "currentwindow", "client": "client", "hostname": "hostname"}"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        for body in [
+            r#"{"timestamp": "2018-01-01T14:10:00Z", "duration": 0.0, "data": {"app": "keepassxc", "title": "db"}}"#,
+            r#"{"timestamp": "2018-01-01T14:20:00Z", "duration": 0.0, "data": {"app": "firefox", "title": "Private Browsing"}}"#,
+        ] {
+            let res = client
+                .post("/api/0/buckets/id/heartbeat?pulsetime=10")
+                .header(ContentType::JSON)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .body(body)
+                .dispatch();
+            assert_eq!(res.status(), rocket::http::Status::Ok);
+        }
+
+        let res = client
+            .get("/api/0/buckets/id/events")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        let events: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(events.as_array().unwrap().len(), 1);
+        assert_eq!(events[0]["data"]["title"], "private");
+
+        // An update which would be dropped deletes the event, which the response tells
+        let res = client
+            .patch(format!("/api/0/buckets/id/events/{}", events[0]["id"]))
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"data": {"app": "keepassxc"}}"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Gone);
+        let res = client
+            .get("/api/0/buckets/id/events")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        let events: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(events.as_array().unwrap().len(), 0);
+
+        let aw_config: config::AWConfig =
+            toml::from_str("[[redact]]\nregex = \"(\"\naction = \"drop\"\n").unwrap();
+        assert!(aw_config.datastore_options().is_err());
+    }
+
+    #[test]
+    fn test_backup() {
+        let mut backup_dir = std::env::temp_dir();
+        backup_dir.push(format!("aw-server-backup-test-{}", std::process::id()));
+        let state = endpoints::ServerState {
+            datastore: Mutex::new(aw_datastore::Datastore::new_in_memory(false)),
+            asset_resolver: endpoints::AssetResolver::new(None),
+            device_id: "test_id".to_string(),
+        };
+        let aw_config = config::AWConfig {
+            backup_dir: Some(backup_dir.display().to_string()),
+            ..config::AWConfig::default()
+        };
+        let server = endpoints::build_rocket(state, aw_config);
+        let client = Client::untracked(server).expect("valid instance");
+
+        let res = client
+            .post("/api/0/buckets/id")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"id": "id", "type": "type", "client": "client", "hostname": "hostname"}"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+
+        let backup = |url: &str| {
+            let res = client
+                .post(url)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .dispatch();
+            let status = res.status();
+            (status, res.into_string().unwrap())
+        };
+        let (status, body) = backup("/api/0/backup?name=nightly.db");
+        assert_eq!(status, rocket::http::Status::Ok);
+        let path: String = serde_json::from_str::<Value>(&body).unwrap()["path"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert_eq!(path, backup_dir.join("nightly.db").display().to_string());
+        let backup_ds = aw_datastore::Datastore::new(path, false);
+        assert!(backup_ds.get_buckets().unwrap().contains_key("id"));
+        backup_ds.close();
+
+        let (status, _) = backup("/api/0/backup");
+        assert_eq!(status, rocket::http::Status::Ok);
+        assert_eq!(std::fs::read_dir(&backup_dir).unwrap().count(), 2);
+
+        for name in ["../escape.db", ".hidden", ""] {
+            let (status, _) = backup(&format!("/api/0/backup?name={name}"));
+            assert_eq!(status, rocket::http::Status::BadRequest);
+        }
+        std::fs::remove_dir_all(&backup_dir).unwrap();
+    }
+
+    #[test]
+    fn test_events_pagination() {
+        let server = setup_testserver();
+        let client = Client::untracked(server).expect("valid instance");
+
+        let res = client
+            .post("/api/0/buckets/id")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"id": "id", "type": "type", "client": "client", "hostname": "hostname"}"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let res = client
+            .post("/api/0/buckets/id/events")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(
+                r#"[
+                {"timestamp": "2018-01-01T01:01:01Z", "duration": 1.0, "data": {"n": 1}},
+                {"timestamp": "2018-01-01T01:01:02Z", "duration": 1.0, "data": {"n": 2}},
+                {"timestamp": "2018-01-01T01:01:02Z", "duration": 1.0, "data": {"n": 3}},
+                {"timestamp": "2018-01-01T01:01:03Z", "duration": 1.0, "data": {"n": 4}},
+                {"timestamp": "2018-01-01T01:01:04Z", "duration": 1.0, "data": {"n": 5}}
+            ]"#,
+            )
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+
+        let walk = |order: &str| -> Vec<i64> {
+            let mut ns = Vec::new();
+            let mut url = format!("/api/0/buckets/id/events?limit=2&order={order}");
+            loop {
+                let res = client
+                    .get(url.clone())
+                    .header(Header::new("Host", "127.0.0.1:5600"))
+                    .dispatch();
+                assert_eq!(res.status(), rocket::http::Status::Ok);
+                let cursor = res.headers().get_one("X-Next-Cursor").map(str::to_string);
+                let events: Vec<Value> = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+                ns.extend(events.iter().map(|e| e["data"]["n"].as_i64().unwrap()));
+                match cursor {
+                    Some(cursor) => {
+                        url = format!(
+                            "/api/0/buckets/id/events?limit=2&order={order}&cursor={cursor}"
+                        )
+                    }
+                    None => break,
+                }
+            }
+            ns
+        };
+        assert_eq!(walk("asc"), vec![1, 2, 3, 4, 5]);
+        assert_eq!(walk("desc"), vec![5, 4, 2, 3, 1]);
+
+        // No cursor without a limit
+        let res = client
+            .get("/api/0/buckets/id/events")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(res.headers().get_one("X-Next-Cursor"), None);
+
+        for url in [
+            "/api/0/buckets/id/events?order=newest",
+            "/api/0/buckets/id/events?cursor=abc",
+        ] {
+            let res = client
+                .get(url)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .dispatch();
+            assert_eq!(res.status(), rocket::http::Status::BadRequest);
+        }
+    }
+
     #[test]
     fn test_import_export() {
         let server = setup_testserver();
@@ -477,6 +1271,320 @@ mod api_tests {
         assert_eq!(res.into_string().unwrap(), r#"{"message":"EmptyQuery"}"#);
     }
 
+    #[test]
+    fn test_query_stream() {
+        let server = setup_testserver();
+        let client = Client::untracked(server).expect("valid instance");
+
+        // One line per timeperiod
+        let res = client
+            .post("/api/0/query/stream")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(
+                r#"{
+                "timeperiods": ["2000-01-01T00:00:00Z/2010-01-01T00:00:00Z", "2010-01-01T00:00:00Z/2020-01-01T00:00:00Z"],
+                "query": ["return 1;"]
+            }"#,
+            )
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(
+            res.content_type(),
+            Some(ContentType::new("application", "x-ndjson"))
+        );
+        assert_eq!(res.into_string().unwrap(), "1.0\n1.0\n");
+
+        // Errors end the stream
+        let res = client
+            .post("/api/0/query/stream")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(
+                r#"{
+                "timeperiods": ["2000-01-01T00:00:00Z/2010-01-01T00:00:00Z", "2010-01-01T00:00:00Z/2020-01-01T00:00:00Z"],
+                "query": [""]
+            }"#,
+            )
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(res.into_string().unwrap(), "{\"message\":\"EmptyQuery\"}\n");
+    }
+
+    #[test]
+    fn test_query_timeperiods() {
+        let server = setup_testserver();
+        let client = Client::untracked(server).expect("valid instance");
+
+        let query = |body: &str| {
+            client
+                .post("/api/0/query")
+                .header(ContentType::JSON)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .body(body)
+                .dispatch()
+        };
+
+        // Days start at 04:00 in UTC+2, so at 02:00 UTC
+        let res = query(
+            r#"{
+            "timeperiods": ["today", "last-7d"],
+            "timezone": "Etc/GMT-2",
+            "start_of_day": "04:00",
+            "query": ["return TIMEINTERVAL;"]
+        }"#,
+        );
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let res: Vec<String> = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(res.len(), 2);
+        for ti in res {
+            let ti = TimeInterval::new_from_string(&ti).unwrap();
+            assert_eq!(ti.start().format("%H:%M").to_string(), "02:00");
+            assert_eq!(ti.end().format("%H:%M").to_string(), "02:00");
+        }
+
+        // Invalid timezone
+        let res = query(
+            r#"{
+            "timeperiods": ["today"],
+            "timezone": "Mars/Olympus_Mons",
+            "query": ["return 1;"]
+        }"#,
+        );
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+
+        // Invalid start of day
+        let res = query(
+            r#"{
+            "timeperiods": ["today"],
+            "start_of_day": "25:00",
+            "query": ["return 1;"]
+        }"#,
+        );
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+
+        // Unknown shortcut
+        let res = query(
+            r#"{
+            "timeperiods": ["tomorrow"],
+            "query": ["return 1;"]
+        }"#,
+        );
+        assert_eq!(res.status().code, 422);
+
+        // The server refuses to start with an invalid timezone or start of day in the config
+        assert!(config::AWConfig::default().validate().is_ok());
+        let aw_config: config::AWConfig =
+            toml::from_str("timezone = \"Europe/Stockholm\"\nstart_of_day = \"04:00\"").unwrap();
+        assert!(aw_config.validate().is_ok());
+        let aw_config: config::AWConfig =
+            toml::from_str("timezone = \"Mars/Olympus_Mons\"").unwrap();
+        assert!(aw_config.validate().is_err());
+        let aw_config: config::AWConfig = toml::from_str("start_of_day = \"25:00\"").unwrap();
+        assert!(aw_config.validate().is_err());
+    }
+
+    #[test]
+    fn test_query_cache() {
+        let (server, datastore) = setup_file_testserver("query-cache");
+        let client = Client::untracked(server).expect("valid instance");
+
+        let query = |body: &str| {
+            let res = client
+                .post("/api/0/query")
+                .header(ContentType::JSON)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .body(body)
+                .dispatch();
+            assert_eq!(res.status(), rocket::http::Status::Ok);
+            res.into_string().unwrap()
+        };
+        let cache_stats = || {
+            let res = client
+                .get("/api/0/query/cache")
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .dispatch();
+            assert_eq!(res.status(), rocket::http::Status::Ok);
+            serde_json::from_str::<Value>(&res.into_string().unwrap()).unwrap()
+        };
+
+        // Create bucket with an event
+        let res = client
+            .post("/api/0/buckets/id")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"id": "id", "type": "type", "client": "client", "hostname": "hostname"}"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let res = client
+            .post("/api/0/buckets/id/events")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"[{"timestamp": "2018-01-01T01:01:01Z", "duration": 1.0, "data": {}}]"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let event_id =
+            serde_json::from_str::<Value>(&res.into_string().unwrap()).unwrap()[0]["id"].clone();
+        datastore.force_commit().unwrap();
+
+        let body = r#"{
+            "timeperiods": ["2018-01-01T00:00:00Z/2018-01-02T00:00:00Z", "2019-01-01T00:00:00Z/2019-01-02T00:00:00Z"],
+            "query": ["events = query_bucket(\"id\");", "return sum_durations(events);"]
+        }"#;
+        assert_eq!(query(body), "[1.0,0.0]");
+        assert_eq!(cache_stats()["misses"], 2);
+        assert_eq!(cache_stats()["hits"], 0);
+
+        // Same query with different formatting is answered from the cache
+        let body_reformatted = r##"{
+            "timeperiods": ["2018-01-01T00:00:00Z/2018-01-02T00:00:00Z", "2019-01-01T00:00:00Z/2019-01-02T00:00:00Z"],
+            "query": ["# comment", "events =  query_bucket(\"id\");return sum_durations(events);"]
+        }"##;
+        assert_eq!(query(body_reformatted), "[1.0,0.0]");
+        assert_eq!(cache_stats()["hits"], 2);
+
+        // Inserting an event only invalidates the timeperiod it is in
+        let res = client
+            .post("/api/0/buckets/id/events")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"[{"timestamp": "2019-01-01T01:01:01Z", "duration": 2.0, "data": {}}]"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        datastore.force_commit().unwrap();
+        assert_eq!(query(body), "[1.0,2.0]");
+        assert_eq!(cache_stats()["hits"], 3);
+        assert_eq!(cache_stats()["misses"], 3);
+        assert_eq!(cache_stats()["entries"], 2);
+
+        // A result computed before a write is committed isn't kept once it is committed
+        let res = client
+            .put(format!("/api/0/buckets/id/events/{event_id}"))
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"timestamp": "2018-01-01T01:01:01Z", "duration": 5.0, "data": {}}"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(query(body), "[5.0,2.0]");
+        datastore.force_commit().unwrap();
+        assert_eq!(query(body), "[5.0,2.0]");
+        assert_eq!(query(body), "[5.0,2.0]");
+        assert_eq!(cache_stats()["misses"], 5);
+    }
+
+    #[test]
+    fn test_stored_queries() {
+        let server = setup_testserver();
+        let client = Client::untracked(server).expect("valid instance");
+
+        // Store a query
+        let res = client
+            .post("/api/0/queries/count")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"query": ["return $a + 1;"], "description": "Adds one"}"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Created);
+
+        // Invalid names are rejected
+        let res = client
+            .post("/api/0/queries/in%20valid")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"query": ["return 1;"]}"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+
+        // Get stored queries
+        let res = client
+            .get("/api/0/queries/")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let queries: HashMap<String, StoredQuery> =
+            serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(queries.len(), 1);
+        assert_eq!(queries["count"].description, "Adds one");
+
+        let res = client
+            .get("/api/0/queries/count")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let query: StoredQuery = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(query.name, "count");
+        assert_eq!(query.query, vec!["return $a + 1;"]);
+
+        // Run it with params
+        let res = client
+            .post("/api/0/queries/count/run")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"timeperiods": ["2000-01-01T00:00:00Z/2000-01-02T00:00:00Z"], "params": {"a": 1}}"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(res.into_string().unwrap(), "[2.0]");
+
+        // Results for different params are cached separately
+        let res = client
+            .post("/api/0/queries/count/run")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"timeperiods": ["2000-01-01T00:00:00Z/2000-01-02T00:00:00Z"], "params": {"a": 2}}"#)
+            .dispatch();
+        assert_eq!(res.into_string().unwrap(), "[3.0]");
+
+        // Import it from an ad hoc query
+        let import_query = r#"{
+            "timeperiods": ["2000-01-01T00:00:00Z/2000-01-02T00:00:00Z"],
+            "query": ["a = 1;", "import \"count\";"],
+            "params": {"a": 10}
+        }"#;
+        let res = client
+            .post("/api/0/query/")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(import_query)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(res.into_string().unwrap(), "[11.0]");
+
+        // Updating the stored query invalidates cached results of queries importing it
+        let res = client
+            .post("/api/0/queries/count")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"query": ["return $a + 2;"]}"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Created);
+        let res = client
+            .post("/api/0/query/")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(import_query)
+            .dispatch();
+        assert_eq!(res.into_string().unwrap(), "[12.0]");
+
+        // Delete it
+        let res = client
+            .delete("/api/0/queries/count")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let res = client
+            .get("/api/0/queries/count")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+        let res = client
+            .post("/api/0/queries/count/run")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"timeperiods": ["2000-01-01T00:00:00Z/2000-01-02T00:00:00Z"]}"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+    }
+
     fn set_setting_request(client: &Client, key: &str, value: &Value) -> Status {
         let body = serde_json::to_string(value).unwrap();
         let res = client
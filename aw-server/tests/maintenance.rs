@@ -0,0 +1,97 @@
+extern crate aw_datastore;
+extern crate aw_server;
+
+#[cfg(test)]
+mod maintenance_tests {
+    use std::net::TcpStream;
+    use std::sync::Mutex;
+    use std::thread;
+
+    use chrono::{Duration, Utc};
+
+    use aw_models::{Bucket, Event};
+    use aw_server::config;
+    use aw_server::endpoints;
+    use aw_server::maintenance::{self, DbCommand, DbOpts};
+
+    // Random port, but still not guaranteed to not be bound
+    static PORT: u16 = 41297;
+
+    fn opts(command: DbCommand) -> DbOpts {
+        DbOpts {
+            json: true,
+            command,
+        }
+    }
+
+    #[test]
+    fn test_missing_db() {
+        let path =
+            std::env::temp_dir().join(format!("aw-server-missing-{}.db", std::process::id()));
+        let db_path = path.display().to_string();
+        let code = maintenance::run(&db_path, "127.0.0.1:1", &opts(DbCommand::Stats));
+        assert_eq!(code, 1);
+        // No empty database is created in its place
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_repair_metadata() {
+        // Nothing listens on the port
+        let code = maintenance::run("", "127.0.0.1:1", &opts(DbCommand::RepairMetadata));
+        assert_eq!(code, 1);
+
+        let datastore = aw_datastore::Datastore::new_in_memory(false);
+        let bucket: Bucket = serde_json::from_value(serde_json::json!({
+            "id": "id", "type": "type", "client": "client", "hostname": "hostname"
+        }))
+        .unwrap();
+        datastore.create_bucket(&bucket).unwrap();
+        let now = Utc::now();
+        let events: Vec<Event> = (0..2)
+            .map(|i| {
+                Event::new(
+                    now + Duration::seconds(i),
+                    Duration::seconds(1),
+                    Default::default(),
+                )
+            })
+            .collect();
+        let inserted = datastore.insert_events("id", &events).unwrap();
+        // Deleting by ID leaves the end of the bucket as it was
+        datastore
+            .delete_events_by_id("id", vec![inserted[1].id.unwrap()])
+            .unwrap();
+
+        let state = endpoints::ServerState {
+            datastore: Mutex::new(datastore.clone()),
+            asset_resolver: endpoints::AssetResolver::new(None),
+            device_id: "test_id".to_string(),
+        };
+        let aw_config = config::AWConfig {
+            port: PORT,
+            ..Default::default()
+        };
+        let server = endpoints::build_rocket(state, aw_config);
+        let server = rocket::execute(server.ignite()).unwrap();
+        let shutdown = server.shutdown();
+        thread::spawn(move || {
+            let _ = rocket::execute(server.launch());
+        });
+        let server_address = format!("127.0.0.1:{PORT}");
+        for i in 0..20 {
+            match TcpStream::connect(&server_address) {
+                Ok(_) => break,
+                Err(err) if i == 19 => panic!("Timed out starting aw-server: {err:?}"),
+                Err(_) => thread::sleep(std::time::Duration::from_millis(500)),
+            }
+        }
+
+        let code = maintenance::run("", &server_address, &opts(DbCommand::RepairMetadata));
+        assert_eq!(code, 0);
+        let bucket = datastore.get_bucket("id").unwrap();
+        assert_eq!(bucket.metadata.end, Some(now + Duration::seconds(1)));
+
+        shutdown.notify();
+    }
+}
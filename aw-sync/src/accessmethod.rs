@@ -5,7 +5,7 @@ use chrono::{DateTime, Utc};
 use reqwest::StatusCode;
 
 use aw_datastore::{Datastore, DatastoreError};
-use aw_models::{Bucket, Event};
+use aw_models::{Bucket, Event, EventChanges, OriginChanges};
 
 // This trait should be implemented by both AwClient and Datastore, unifying them under a single API
 pub trait AccessMethod: std::fmt::Debug {
@@ -21,21 +21,27 @@ pub trait AccessMethod: std::fmt::Debug {
     ) -> Result<Vec<Event>, String>;
     fn insert_events(&self, bucket_id: &str, events: Vec<Event>) -> Result<(), String>;
     fn get_event_count(&self, bucket_id: &str) -> Result<i64, String>;
-    fn heartbeat(&self, bucket_id: &str, event: Event, duration: f64) -> Result<(), String>;
+    fn delete_events(&self, bucket_id: &str, event_ids: Vec<i64>) -> Result<(), String>;
+    fn get_event_changes(
+        &self,
+        bucket_id: &str,
+        token: Option<&str>,
+        limit: u64,
+    ) -> Result<EventChanges, DatastoreError>;
+    fn apply_event_changes(&self, bucket_id: &str, changes: OriginChanges) -> Result<(), String>;
     fn close(&self);
 }
 
 impl AccessMethod for Datastore {
     fn get_buckets(&self) -> Result<HashMap<String, Bucket>, String> {
-        Ok(Datastore::get_buckets(self).unwrap())
+        Datastore::get_buckets(self).map_err(|e| format!("{e:?}"))
     }
     fn get_bucket(&self, bucket_id: &str) -> Result<Bucket, DatastoreError> {
         Datastore::get_bucket(self, bucket_id)
     }
     fn create_bucket(&self, bucket: &Bucket) -> Result<(), DatastoreError> {
         Datastore::create_bucket(self, bucket)?;
-        self.force_commit().unwrap();
-        Ok(())
+        self.force_commit()
     }
     fn get_events(
         &self,
@@ -44,20 +50,36 @@ impl AccessMethod for Datastore {
         end: Option<DateTime<Utc>>,
         limit: Option<u64>,
     ) -> Result<Vec<Event>, String> {
-        Ok(Datastore::get_events(self, bucket_id, start, end, limit).unwrap())
-    }
-    fn heartbeat(&self, bucket_id: &str, event: Event, duration: f64) -> Result<(), String> {
-        Datastore::heartbeat(self, bucket_id, event, duration).unwrap();
-        self.force_commit().unwrap();
-        Ok(())
+        Datastore::get_events(self, bucket_id, start, end, limit).map_err(|e| format!("{e:?}"))
     }
     fn insert_events(&self, bucket_id: &str, events: Vec<Event>) -> Result<(), String> {
-        Datastore::insert_events(self, bucket_id, &events[..]).unwrap();
-        self.force_commit().unwrap();
-        Ok(())
+        Datastore::insert_events(self, bucket_id, &events[..]).map_err(|e| format!("{e:?}"))?;
+        self.force_commit().map_err(|e| format!("{e:?}"))
     }
     fn get_event_count(&self, bucket_id: &str) -> Result<i64, String> {
-        Ok(Datastore::get_event_count(self, bucket_id, None, None).unwrap())
+        Datastore::get_event_count(self, bucket_id, None, None).map_err(|e| format!("{e:?}"))
+    }
+    fn delete_events(&self, bucket_id: &str, event_ids: Vec<i64>) -> Result<(), String> {
+        Datastore::delete_events_by_id(self, bucket_id, event_ids).map_err(|e| format!("{e:?}"))?;
+        self.force_commit().map_err(|e| format!("{e:?}"))
+    }
+    fn get_event_changes(
+        &self,
+        bucket_id: &str,
+        token: Option<&str>,
+        limit: u64,
+    ) -> Result<EventChanges, DatastoreError> {
+        Datastore::get_event_changes(self, bucket_id, token, limit)
+    }
+    fn apply_event_changes(&self, bucket_id: &str, changes: OriginChanges) -> Result<(), String> {
+        let mut events = changes.events;
+        for event in &mut events {
+            event.id = None;
+        }
+        Datastore::insert_events(self, bucket_id, &events[..]).map_err(|e| format!("{e:?}"))?;
+        Datastore::delete_events_by_origin(self, bucket_id, changes.deleted)
+            .map_err(|e| format!("{e:?}"))?;
+        self.force_commit().map_err(|e| format!("{e:?}"))
     }
     fn close(&self) {
         Datastore::close(self);
@@ -66,21 +88,16 @@ impl AccessMethod for Datastore {
 
 impl AccessMethod for AwClient {
     fn get_buckets(&self) -> Result<HashMap<String, Bucket>, String> {
-        Ok(AwClient::get_buckets(self).unwrap())
+        AwClient::get_buckets(self).map_err(|e| e.to_string())
     }
     fn get_bucket(&self, bucket_id: &str) -> Result<Bucket, DatastoreError> {
         let bucket = AwClient::get_bucket(self, bucket_id);
         match bucket {
             Ok(bucket) => Ok(bucket),
-            Err(e) => {
-                warn!("{:?}", e);
-                let code = e.status().unwrap();
-                if code == StatusCode::NOT_FOUND {
-                    Err(DatastoreError::NoSuchBucket(bucket_id.into()))
-                } else {
-                    panic!("Unexpected error");
-                }
+            Err(e) if e.status() == Some(StatusCode::NOT_FOUND) => {
+                Err(DatastoreError::NoSuchBucket(bucket_id.into()))
             }
+            Err(e) => Err(DatastoreError::InternalError(e.to_string())),
         }
     }
     fn get_events(
@@ -90,20 +107,44 @@ impl AccessMethod for AwClient {
         end: Option<DateTime<Utc>>,
         limit: Option<u64>,
     ) -> Result<Vec<Event>, String> {
-        Ok(AwClient::get_events(self, bucket_id, start, end, limit).unwrap())
+        AwClient::get_events(self, bucket_id, start, end, limit).map_err(|e| e.to_string())
     }
     fn insert_events(&self, bucket_id: &str, events: Vec<Event>) -> Result<(), String> {
         AwClient::insert_events(self, bucket_id, events).map_err(|e| e.to_string())
     }
     fn get_event_count(&self, bucket_id: &str) -> Result<i64, String> {
-        Ok(AwClient::get_event_count(self, bucket_id).unwrap())
+        AwClient::get_event_count(self, bucket_id).map_err(|e| e.to_string())
     }
     fn create_bucket(&self, bucket: &Bucket) -> Result<(), DatastoreError> {
-        AwClient::create_bucket(self, bucket).unwrap();
+        AwClient::create_bucket(self, bucket)
+            .map_err(|e| DatastoreError::InternalError(e.to_string()))
+    }
+    fn delete_events(&self, bucket_id: &str, event_ids: Vec<i64>) -> Result<(), String> {
+        for event_id in event_ids {
+            AwClient::delete_event(self, bucket_id, event_id).map_err(|e| e.to_string())?;
+        }
         Ok(())
     }
-    fn heartbeat(&self, bucket_id: &str, event: Event, duration: f64) -> Result<(), String> {
-        AwClient::heartbeat(self, bucket_id, &event, duration).map_err(|e| format!("{e:?}"))
+    fn get_event_changes(
+        &self,
+        bucket_id: &str,
+        token: Option<&str>,
+        limit: u64,
+    ) -> Result<EventChanges, DatastoreError> {
+        match AwClient::get_event_changes(self, bucket_id, token, limit) {
+            Ok(changes) => Ok(changes),
+            // The server only refuses the request itself if the token is invalid
+            Err(e) if e.status() == Some(StatusCode::BAD_REQUEST) => {
+                Err(DatastoreError::InvalidToken(e.to_string()))
+            }
+            Err(e) if e.status() == Some(StatusCode::NOT_FOUND) => {
+                Err(DatastoreError::NoSuchBucket(bucket_id.into()))
+            }
+            Err(e) => Err(DatastoreError::InternalError(e.to_string())),
+        }
+    }
+    fn apply_event_changes(&self, bucket_id: &str, changes: OriginChanges) -> Result<(), String> {
+        AwClient::apply_event_changes(self, bucket_id, &changes).map_err(|e| e.to_string())
     }
     fn close(&self) {
         // NOP
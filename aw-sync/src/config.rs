@@ -0,0 +1,84 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+#[cfg(feature = "encryption")]
+use sha2::{Digest, Sha256};
+
+use aw_datastore::DatastoreKey;
+
+/// Configuration of aw-sync, read from config.toml in the aw-sync config directory
+#[derive(Debug, Default, Deserialize)]
+pub struct SyncConfig {
+    pub encryption: Option<EncryptionConfig>,
+}
+
+/// Encrypts the datastores aw-sync writes to the sync directory, which other devices need the
+/// same passphrase or keyfile to read
+#[derive(Debug, Deserialize)]
+#[cfg_attr(not(feature = "encryption"), allow(dead_code))]
+pub struct EncryptionConfig {
+    pub passphrase: Option<String>,
+    /// The key is derived from the contents of this file
+    pub keyfile: Option<PathBuf>,
+}
+
+#[cfg(feature = "encryption")]
+impl EncryptionConfig {
+    pub fn key(&self) -> Result<DatastoreKey, Box<dyn Error>> {
+        match (&self.passphrase, &self.keyfile) {
+            (Some(passphrase), None) => {
+                if passphrase.is_empty() {
+                    Err("Encryption passphrase is empty")?
+                }
+                Ok(DatastoreKey::Passphrase(passphrase.clone()))
+            }
+            (None, Some(keyfile)) => {
+                let contents = fs::read(keyfile)
+                    .map_err(|e| format!("Unable to read keyfile {}: {e}", keyfile.display()))?;
+                if contents.is_empty() {
+                    Err(format!("Keyfile {} is empty", keyfile.display()))?
+                }
+                Ok(DatastoreKey::Raw(Sha256::digest(&contents).into()))
+            }
+            _ => Err("Either an encryption passphrase or a keyfile has to be set, not both")?,
+        }
+    }
+}
+
+impl SyncConfig {
+    pub fn load(path: &Path) -> Result<SyncConfig, Box<dyn Error>> {
+        if !path.exists() {
+            return Ok(SyncConfig::default());
+        }
+        let config = toml::from_str(&fs::read_to_string(path)?)
+            .map_err(|e| format!("Invalid aw-sync config {}: {e}", path.display()))?;
+        Ok(config)
+    }
+
+    /// Returns the key to encrypt the datastores in the sync directory with, if encryption is
+    /// configured
+    #[cfg(feature = "encryption")]
+    pub fn encryption_key(&self) -> Result<Option<DatastoreKey>, Box<dyn Error>> {
+        match &self.encryption {
+            Some(encryption) => Ok(Some(encryption.key()?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Fails if encryption is configured, rather than syncing unencrypted
+    #[cfg(not(feature = "encryption"))]
+    pub fn encryption_key(&self) -> Result<Option<DatastoreKey>, Box<dyn Error>> {
+        match &self.encryption {
+            Some(_) => Err("Encryption is configured, but aw-sync was built without it")?,
+            None => Ok(None),
+        }
+    }
+}
+
+/// Loads the encryption key from the aw-sync config
+pub fn load_encryption_key() -> Result<Option<DatastoreKey>, Box<dyn Error>> {
+    let path = crate::dirs::get_config_dir()?.join("config.toml");
+    SyncConfig::load(&path)?.encryption_key()
+}
@@ -0,0 +1,208 @@
+/// Detecting and resolving conflicts when syncing buckets
+///
+/// A conflict is data in the destination of a sync which didn't come from the source but
+/// disagrees with it. This happens when two devices share a hostname, so their buckets are synced
+/// to the same bucket ID, or when events are added to a synced bucket by something other than
+/// aw-sync.
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use chrono::{DateTime, Duration, Utc};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use aw_models::{Bucket, Event};
+
+use crate::sync::bucket_origin;
+
+/// Key set in the data of synced events which were kept next to events they conflict with
+pub const CONFLICT_KEY: &str = "$aw.sync.conflict";
+
+/// How to resolve synced events which overlap events with different data in the destination
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictStrategy {
+    /// Delete the overlapping events in the destination and sync the events
+    PreferSource,
+    /// Keep the overlapping events in the destination and don't sync the events
+    PreferDestination,
+    /// Keep both, tagging the synced events with `$aw.sync.conflict`
+    #[default]
+    KeepBoth,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Conflict {
+    /// The source bucket had no valid hostname, so buckets of different devices may have been
+    /// synced to the same bucket. The device ID is used instead when it is known.
+    UnknownHostname { device_id: Option<String> },
+    /// The destination bucket already existed with a different value for one of its fields
+    BucketMetadata {
+        field: String,
+        source: String,
+        destination: String,
+    },
+    /// An event overlaps an event with different data which wasn't synced from the source
+    OverlappingEvents { source: Event, destination: Event },
+}
+
+/// The conflicts found when syncing a bucket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BucketConflicts {
+    pub bucket_from: String,
+    pub bucket_to: String,
+    pub conflicts: Vec<Conflict>,
+}
+
+/// The conflicts found in a sync pass, and the strategy they were resolved with
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConflictReport {
+    pub created: DateTime<Utc>,
+    pub strategy: ConflictStrategy,
+    pub buckets: Vec<BucketConflicts>,
+}
+
+impl ConflictReport {
+    pub fn new(strategy: ConflictStrategy) -> ConflictReport {
+        ConflictReport {
+            created: Utc::now(),
+            strategy,
+            buckets: Vec::new(),
+        }
+    }
+
+    pub fn conflict_count(&self) -> usize {
+        self.buckets.iter().map(|b| b.conflicts.len()).sum()
+    }
+
+    /// Writes the report as JSON, replacing the report of the previous sync pass
+    pub fn write(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+/// Compares a bucket with the existing bucket it is synced to
+pub(crate) fn bucket_conflicts(bucket_from: &Bucket, bucket_to: &Bucket) -> Vec<Conflict> {
+    let fields = [
+        ("type", &bucket_from._type, &bucket_to._type),
+        ("client", &bucket_from.client, &bucket_to.client),
+    ];
+    let mut conflicts: Vec<Conflict> = fields
+        .into_iter()
+        .filter(|(_, source, destination)| source != destination)
+        .map(|(field, source, destination)| Conflict::BucketMetadata {
+            field: field.to_string(),
+            source: source.clone(),
+            destination: destination.clone(),
+        })
+        .collect();
+    // Synced buckets remember the hostname of the bucket they were created from
+    let origin = bucket_origin(bucket_to);
+    if bucket_from.hostname != origin {
+        conflicts.push(Conflict::BucketMetadata {
+            field: "hostname".to_string(),
+            source: bucket_from.hostname.clone(),
+            destination: origin.to_string(),
+        });
+    }
+    conflicts
+}
+
+/// Events to look up the events overlapping another event in
+struct OverlapIndex {
+    /// Sorted by timestamp
+    events: Vec<Event>,
+    max_duration: Duration,
+}
+
+impl OverlapIndex {
+    pub fn new(mut events: Vec<Event>) -> OverlapIndex {
+        events.sort_by_key(|e| e.timestamp);
+        let max_duration = events
+            .iter()
+            .map(|e| e.duration)
+            .max()
+            .unwrap_or_else(Duration::zero);
+        OverlapIndex {
+            events,
+            max_duration,
+        }
+    }
+
+    /// Returns the events which overlap the event but have different data
+    pub fn conflicting(&self, event: &Event) -> Vec<&Event> {
+        let endtime = event.calculate_endtime();
+        let first = self
+            .events
+            .partition_point(|e| e.timestamp < event.timestamp - self.max_duration);
+        let last = self.events.partition_point(|e| e.timestamp <= endtime);
+        self.events[first..last.max(first)]
+            .iter()
+            .filter(|e| {
+                let overlaps = e.timestamp == event.timestamp
+                    || (e.timestamp < endtime && event.timestamp < e.calculate_endtime());
+                overlaps && e.data != event.data
+            })
+            .collect()
+    }
+}
+
+/// Finds the events which conflict with events in the destination which weren't synced from the
+/// source, and resolves them with the strategy
+pub(crate) struct ConflictResolver {
+    other_events: OverlapIndex,
+    strategy: ConflictStrategy,
+    pub conflicts: Vec<Conflict>,
+    // IDs of the destination events to delete as they are replaced by synced events
+    replaced_ids: Vec<i64>,
+}
+
+impl ConflictResolver {
+    pub fn new(other_events: Vec<Event>, strategy: ConflictStrategy) -> ConflictResolver {
+        ConflictResolver {
+            other_events: OverlapIndex::new(other_events),
+            strategy,
+            conflicts: Vec::new(),
+            replaced_ids: Vec::new(),
+        }
+    }
+
+    /// Returns false if the event shouldn't be synced, tags it if it is kept next to the events
+    /// it conflicts with
+    pub fn resolve(&mut self, event: &mut Event) -> bool {
+        let overlapping = self.other_events.conflicting(event);
+        if overlapping.is_empty() {
+            return true;
+        }
+        self.conflicts
+            .extend(overlapping.iter().map(|e| Conflict::OverlappingEvents {
+                source: event.clone(),
+                destination: (*e).clone(),
+            }));
+        match self.strategy {
+            ConflictStrategy::PreferSource => {
+                self.replaced_ids
+                    .extend(overlapping.iter().filter_map(|e| e.id));
+                true
+            }
+            ConflictStrategy::PreferDestination => false,
+            ConflictStrategy::KeepBoth => {
+                event
+                    .data
+                    .insert(CONFLICT_KEY.to_string(), serde_json::json!(true));
+                true
+            }
+        }
+    }
+
+    /// The IDs of the replaced destination events, once each
+    pub fn take_replaced_ids(&mut self) -> Vec<i64> {
+        let mut replaced_ids = std::mem::take(&mut self.replaced_ids);
+        replaced_ids.sort_unstable();
+        replaced_ids.dedup();
+        replaced_ids
+    }
+}
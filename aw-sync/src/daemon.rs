@@ -0,0 +1,384 @@
+/// Running aw-sync as a daemon
+///
+/// The daemon syncs with the sync folder on an interval, and right away when the databases of
+/// other devices in the sync folder are added or updated by the file sync. Failed passes are
+/// retried with an exponential backoff. The state of the daemon and of the synced buckets is
+/// written to a status file, so other tools can show whether syncing works.
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant, SystemTime};
+
+use aw_client_rust::blocking::AwClient;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::accessmethod::AccessMethod;
+use crate::sync::SyncMode;
+use crate::sync_wrapper::{host_buckets, pull, push};
+
+pub const DEFAULT_INTERVAL: u64 = 300;
+pub const DEFAULT_RETRY_DELAY: u64 = 30;
+pub const DEFAULT_MAX_BACKOFF: u64 = 3600;
+
+/// How often the sync folder is checked for changed databases
+const WATCH_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct DaemonOptions {
+    /// Time between sync passes
+    pub interval: Duration,
+    /// Time before retrying a failed sync pass, doubled for every consecutive failure
+    pub retry_delay: Duration,
+    /// Longest time to wait before retrying
+    pub max_backoff: Duration,
+    /// Sync when the databases of other devices in the sync folder change
+    pub watch: bool,
+    /// File to write the status to
+    pub status_path: Option<PathBuf>,
+}
+
+impl Default for DaemonOptions {
+    fn default() -> Self {
+        DaemonOptions {
+            interval: Duration::from_secs(DEFAULT_INTERVAL),
+            retry_delay: Duration::from_secs(DEFAULT_RETRY_DELAY),
+            max_backoff: Duration::from_secs(DEFAULT_MAX_BACKOFF),
+            watch: true,
+            status_path: None,
+        }
+    }
+}
+
+impl DaemonOptions {
+    /// Returns the time to wait before the next sync pass, after the given number of consecutive
+    /// failed passes
+    pub fn next_delay(&self, failures: u32) -> Duration {
+        if failures == 0 {
+            return self.interval;
+        }
+        2u32.checked_pow(failures - 1)
+            .and_then(|factor| self.retry_delay.checked_mul(factor))
+            .map_or(self.max_backoff, |delay| delay.min(self.max_backoff))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DaemonState {
+    Syncing,
+    /// Waiting for the next sync pass
+    Idle,
+    /// Waiting to retry a failed sync pass
+    BackingOff,
+    Stopped,
+}
+
+/// The sync progress of a bucket
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BucketStatus {
+    /// The bucket synced from, in the sync folder when pulling and in aw-server when pushing
+    pub bucket_id: String,
+    pub host: String,
+    pub mode: SyncMode,
+    pub last_attempt: DateTime<Utc>,
+    pub last_success: Option<DateTime<Utc>>,
+    /// Number of events in the local bucket synced to, only known for pulls
+    pub event_count: Option<i64>,
+    /// End of the last event in the local bucket synced to, only known for pulls
+    pub synced_until: Option<DateTime<Utc>>,
+    /// Error of the last attempt, if it failed
+    pub error: Option<String>,
+}
+
+/// The status of the daemon, as written to the status file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DaemonStatus {
+    pub pid: u32,
+    pub started: DateTime<Utc>,
+    pub state: DaemonState,
+    pub last_attempt: Option<DateTime<Utc>>,
+    pub last_success: Option<DateTime<Utc>>,
+    pub next_sync: Option<DateTime<Utc>>,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    pub buckets: Vec<BucketStatus>,
+}
+
+impl DaemonStatus {
+    pub fn new() -> DaemonStatus {
+        DaemonStatus {
+            pid: std::process::id(),
+            started: Utc::now(),
+            state: DaemonState::Idle,
+            last_attempt: None,
+            last_success: None,
+            next_sync: None,
+            consecutive_failures: 0,
+            last_error: None,
+            buckets: Vec::new(),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<DaemonStatus, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read status file {}: {e}", path.display()))?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        // Write to a temporary file first so readers never see a partially written status
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Records the outcome of syncing the buckets of a host, and for pulls the progress of the
+    /// local buckets they were synced to
+    ///
+    /// Pushes sync to the staging database in the sync folder, so the buckets in aw-server don't
+    /// tell how far they were synced.
+    pub fn record(
+        &mut self,
+        local: &dyn AccessMethod,
+        mode: SyncMode,
+        host: &str,
+        result: &Result<(), Box<dyn Error>>,
+    ) {
+        let now = Utc::now();
+        for bucket_id in host_buckets(host) {
+            let index = match self
+                .buckets
+                .iter()
+                .position(|b| b.bucket_id == bucket_id && b.mode == mode)
+            {
+                Some(index) => index,
+                None => {
+                    self.buckets.push(BucketStatus {
+                        bucket_id: bucket_id.clone(),
+                        host: host.to_string(),
+                        mode,
+                        last_attempt: now,
+                        last_success: None,
+                        event_count: None,
+                        synced_until: None,
+                        error: None,
+                    });
+                    self.buckets.len() - 1
+                }
+            };
+            let status = &mut self.buckets[index];
+            status.last_attempt = now;
+            match result {
+                Ok(()) => {
+                    status.last_success = Some(now);
+                    status.error = None;
+                    if mode != SyncMode::Pull {
+                        continue;
+                    }
+                    // Buckets which don't exist on either side are skipped by the sync
+                    let local_id = format!("{bucket_id}-synced-from-{host}");
+                    if let Ok(bucket) = local.get_bucket(&local_id) {
+                        status.synced_until = bucket.metadata.end;
+                        status.event_count = local.get_event_count(&local_id).ok();
+                    }
+                }
+                Err(e) => status.error = Some(e.to_string()),
+            }
+        }
+    }
+}
+
+impl Default for DaemonStatus {
+    fn default() -> Self {
+        DaemonStatus::new()
+    }
+}
+
+/// Watches the sync folder for databases of other hosts being added or modified
+pub struct RemoteWatcher {
+    sync_dir: PathBuf,
+    local_host: String,
+    seen: HashMap<PathBuf, (SystemTime, u64)>,
+}
+
+impl RemoteWatcher {
+    pub fn new(sync_dir: PathBuf, local_host: &str) -> RemoteWatcher {
+        let mut watcher = RemoteWatcher {
+            sync_dir,
+            local_host: local_host.to_string(),
+            seen: HashMap::new(),
+        };
+        watcher.seen = watcher.scan();
+        watcher
+    }
+
+    /// Returns the databases in the sync folder, structured ./{hostname}/{device_id}/*.db,
+    /// except the ones of the local host, which are written by aw-sync itself
+    fn scan(&self) -> HashMap<PathBuf, (SystemTime, u64)> {
+        let host_dirs = match fs::read_dir(&self.sync_dir) {
+            Ok(entries) => entries,
+            Err(_) => return HashMap::new(),
+        };
+        host_dirs
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_name().to_str() != Some(self.local_host.as_str()))
+            .filter_map(|entry| fs::read_dir(entry.path()).ok())
+            .flatten()
+            .filter_map(Result::ok)
+            .filter_map(|entry| fs::read_dir(entry.path()).ok())
+            .flatten()
+            .filter_map(Result::ok)
+            .filter(|entry| entry.path().extension().and_then(|ext| ext.to_str()) == Some("db"))
+            .filter_map(|entry| {
+                let metadata = entry.metadata().ok()?;
+                Some((entry.path(), (metadata.modified().ok()?, metadata.len())))
+            })
+            .collect()
+    }
+
+    /// Returns true if databases were added or modified since the last call
+    pub fn changed(&mut self) -> bool {
+        let current = self.scan();
+        let changed = current
+            .iter()
+            .any(|(path, state)| self.seen.get(path) != Some(state));
+        self.seen = current;
+        changed
+    }
+}
+
+/// Syncs until interrupted
+pub fn run_daemon(client: &AwClient, options: &DaemonOptions) -> Result<(), Box<dyn Error>> {
+    let (tx, rx) = channel();
+
+    ctrlc::set_handler(move || {
+        let _ = tx.send(());
+    })?;
+
+    let mut watcher = if options.watch {
+        Some(RemoteWatcher::new(
+            crate::dirs::get_sync_dir()?,
+            &client.hostname,
+        ))
+    } else {
+        None
+    };
+
+    let mut status = DaemonStatus::new();
+    loop {
+        status.state = DaemonState::Syncing;
+        status.last_attempt = Some(Utc::now());
+        status.next_sync = None;
+        write_status(&status, options);
+
+        match sync_cycle(client, &mut status) {
+            Ok(()) => {
+                status.last_success = Some(Utc::now());
+                status.consecutive_failures = 0;
+                status.last_error = None;
+            }
+            Err(e) => {
+                error!("Error during sync cycle: {}", e);
+                status.consecutive_failures += 1;
+                status.last_error = Some(e.to_string());
+            }
+        }
+
+        let delay = options.next_delay(status.consecutive_failures);
+        status.state = match status.consecutive_failures {
+            0 => DaemonState::Idle,
+            _ => DaemonState::BackingOff,
+        };
+        status.next_sync = chrono::Duration::from_std(delay)
+            .ok()
+            .map(|delay| Utc::now() + delay);
+        write_status(&status, options);
+        // Don't trigger on the changes made by the sync pass
+        if let Some(watcher) = watcher.as_mut() {
+            watcher.changed();
+        }
+
+        match status.state {
+            DaemonState::BackingOff => info!(
+                "Sync pass failed {} times in a row, retrying in {}s",
+                status.consecutive_failures,
+                delay.as_secs()
+            ),
+            _ => info!("Sync pass done, sleeping for {}s", delay.as_secs()),
+        }
+        if wait(&rx, delay, watcher.as_mut()) {
+            info!("Termination signal received, shutting down.");
+            break;
+        }
+    }
+
+    status.state = DaemonState::Stopped;
+    status.next_sync = None;
+    write_status(&status, options);
+    Ok(())
+}
+
+/// Waits for the delay or until watched databases change, returns true if the daemon should stop
+fn wait(rx: &Receiver<()>, delay: Duration, mut watcher: Option<&mut RemoteWatcher>) -> bool {
+    let deadline = Instant::now() + delay;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return false;
+        }
+        let timeout = match watcher {
+            Some(_) => remaining.min(WATCH_POLL_INTERVAL),
+            None => remaining,
+        };
+        match rx.recv_timeout(timeout) {
+            Ok(_) | Err(RecvTimeoutError::Disconnected) => return true,
+            Err(RecvTimeoutError::Timeout) => {
+                if let Some(watcher) = watcher.as_mut() {
+                    if watcher.changed() {
+                        info!("Databases in the sync folder changed, syncing");
+                        return false;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn write_status(status: &DaemonStatus, options: &DaemonOptions) {
+    if let Some(path) = &options.status_path {
+        if let Err(e) = status.write(path) {
+            warn!("Could not write status file {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Pulls from all hosts and pushes local data, continuing with the other hosts if one fails
+fn sync_cycle(client: &AwClient, status: &mut DaemonStatus) -> Result<(), Box<dyn Error>> {
+    let mut errors = Vec::new();
+
+    info!("Pulling from all hosts");
+    for host in crate::util::get_remotes()? {
+        let result = pull(&host, client);
+        status.record(client, SyncMode::Pull, &host, &result);
+        if let Err(e) = result {
+            errors.push(format!("pulling from {host}: {e}"));
+        }
+    }
+
+    info!("Pushing local data");
+    let result = push(client);
+    status.record(client, SyncMode::Push, &client.hostname, &result);
+    if let Err(e) = result {
+        errors.push(format!("pushing: {e}"));
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Sync failed {}", errors.join(", ")).into())
+    }
+}
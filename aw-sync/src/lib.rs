@@ -8,14 +8,28 @@ mod sync;
 pub use sync::create_datastore;
 pub use sync::sync_datastores;
 pub use sync::sync_run;
+pub use sync::SyncMode;
 pub use sync::SyncSpec;
 
 mod sync_wrapper;
 pub use sync_wrapper::push;
 pub use sync_wrapper::{pull, pull_all};
 
+mod sync_http;
+pub use sync_http::{sync_changes, sync_http, SyncTokens};
+
+mod conflict;
+pub use conflict::{BucketConflicts, Conflict, ConflictReport, ConflictStrategy, CONFLICT_KEY};
+
+mod daemon;
+pub use daemon::run_daemon;
+pub use daemon::{BucketStatus, DaemonOptions, DaemonState, DaemonStatus, RemoteWatcher};
+
 mod accessmethod;
 pub use accessmethod::AccessMethod;
 
+mod config;
+pub use config::{EncryptionConfig, SyncConfig};
+
 mod dirs;
 mod util;
@@ -17,14 +17,17 @@ use chrono::{DateTime, Utc};
 use clap::{Parser, Subcommand};
 use std::error::Error;
 use std::path::PathBuf;
-use std::sync::mpsc::{channel, RecvTimeoutError};
 use std::time::Duration;
 
 use aw_client_rust::blocking::AwClient;
 
 mod accessmethod;
+mod config;
+mod conflict;
+mod daemon;
 mod dirs;
 mod sync;
+mod sync_http;
 mod sync_wrapper;
 mod util;
 
@@ -59,8 +62,38 @@ struct Opts {
 #[derive(Subcommand)]
 enum Commands {
     /// Daemon subcommand
-    /// Starts aw-sync as a daemon, which will sync every 5 minutes.
-    Daemon {},
+    /// Starts aw-sync as a daemon, which will sync every 5 minutes by default, and when the
+    /// databases of other devices in the sync directory change.
+    Daemon {
+        /// Seconds between sync passes.
+        #[clap(
+            long,
+            default_value_t = daemon::DEFAULT_INTERVAL,
+            value_parser = clap::value_parser!(u64).range(1..)
+        )]
+        interval: u64,
+
+        /// Seconds before retrying a failed sync pass, doubled for every consecutive failure.
+        #[clap(
+            long,
+            default_value_t = daemon::DEFAULT_RETRY_DELAY,
+            value_parser = clap::value_parser!(u64).range(1..)
+        )]
+        retry_delay: u64,
+
+        /// Maximum number of seconds before retrying a failed sync pass.
+        #[clap(
+            long,
+            default_value_t = daemon::DEFAULT_MAX_BACKOFF,
+            value_parser = clap::value_parser!(u64).range(1..)
+        )]
+        max_backoff: u64,
+
+        /// Don't sync when the databases of other devices in the sync directory change,
+        /// only on the interval.
+        #[clap(long)]
+        no_watch: bool,
+    },
 
     /// Sync subcommand (basic)
     ///
@@ -100,9 +133,55 @@ enum Commands {
         /// Must be a valid absolute path to a file in the sync directory.
         #[clap(long)]
         sync_db: Option<PathBuf>,
+
+        /// How to resolve events which overlap events with different data that weren't synced
+        /// from the same bucket.
+        #[clap(long, default_value = "keep-both")]
+        conflict_strategy: conflict::ConflictStrategy,
+
+        /// Full path to write a JSON report of the conflicts found to.
+        /// If not specified, conflicts.json in the aw-sync data directory is used.
+        #[clap(long)]
+        conflict_report: Option<PathBuf>,
+    },
+    /// Sync directly with another aw-server over HTTP
+    ///
+    /// Pulls the changes to the buckets of the remote aw-server, then pushes the changes to the
+    /// local buckets to it. No sync directory is used.
+    #[clap(arg_required_else_help = true)]
+    SyncHttp {
+        /// Host of the remote aw-server.
+        #[clap(long)]
+        remote_host: String,
+
+        /// Port of the remote aw-server.
+        #[clap(long, default_value = "5600")]
+        remote_port: u16,
+
+        /// Specify buckets to sync using a comma-separated list.
+        /// If not specified, all buckets will be synced.
+        #[clap(long, value_parser=parse_list)]
+        buckets: Option<Vec<String>>,
+
+        /// Mode to sync in. Can be "push", "pull", or "both".
+        /// Defaults to "both".
+        #[clap(long, default_value = "both")]
+        mode: sync::SyncMode,
+
+        /// How to resolve events which overlap events with different data that weren't synced
+        /// from the same bucket.
+        #[clap(long, default_value = "keep-both")]
+        conflict_strategy: conflict::ConflictStrategy,
+
+        /// Full path to write a JSON report of the conflicts found to.
+        /// If not specified, http-conflicts.json in the aw-sync data directory is used.
+        #[clap(long)]
+        conflict_report: Option<PathBuf>,
     },
     /// List buckets and their sync status.
     List {},
+    /// Print the status of the daemon as JSON.
+    Status {},
 }
 
 fn parse_start_date(arg: &str) -> Result<DateTime<Utc>, chrono::ParseError> {
@@ -139,12 +218,30 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let client = AwClient::new(&opts.host, port, "aw-sync")?;
 
-    // if opts.command is None, then we're using the default subcommand (Sync)
-    match opts.command.unwrap_or(Commands::Daemon {}) {
+    // if opts.command is None, then we're using the default subcommand (Daemon)
+    let command = opts.command.unwrap_or(Commands::Daemon {
+        interval: daemon::DEFAULT_INTERVAL,
+        retry_delay: daemon::DEFAULT_RETRY_DELAY,
+        max_backoff: daemon::DEFAULT_MAX_BACKOFF,
+        no_watch: false,
+    });
+    match command {
         // Start daemon
-        Commands::Daemon {} => {
+        Commands::Daemon {
+            interval,
+            retry_delay,
+            max_backoff,
+            no_watch,
+        } => {
             info!("Starting daemon...");
-            daemon(&client)?;
+            let options = daemon::DaemonOptions {
+                interval: Duration::from_secs(interval),
+                retry_delay: Duration::from_secs(retry_delay),
+                max_backoff: Duration::from_secs(max_backoff),
+                watch: !no_watch,
+                status_path: Some(dirs::get_data_dir()?.join("status.json")),
+            };
+            daemon::run_daemon(&client, &options)?;
         }
         // Perform basic sync
         Commands::Sync { host } => {
@@ -172,6 +269,8 @@ fn main() -> Result<(), Box<dyn Error>> {
             buckets,
             mode,
             sync_db,
+            conflict_strategy,
+            conflict_report,
         } => {
             let sync_dir = dirs::get_sync_dir()?;
             if let Some(db_path) = &sync_db {
@@ -190,13 +289,50 @@ fn main() -> Result<(), Box<dyn Error>> {
                 path_db: sync_db,
                 buckets,
                 start: start_date,
+                conflict_strategy,
+                conflict_report: Some(match conflict_report {
+                    Some(path) => path,
+                    None => dirs::get_data_dir()?.join("conflicts.json"),
+                }),
+                encryption_key: config::load_encryption_key()?,
+                tokens: Some(dirs::get_data_dir()?.join("sync-tokens.json")),
             };
 
             sync::sync_run(&client, &sync_spec, mode)?
         }
 
+        // Sync directly with another aw-server
+        Commands::SyncHttp {
+            remote_host,
+            remote_port,
+            buckets,
+            mode,
+            conflict_strategy,
+            conflict_report,
+        } => {
+            let remote = AwClient::new(&remote_host, remote_port, "aw-sync")?;
+            let sync_spec = sync::SyncSpec {
+                buckets,
+                conflict_strategy,
+                conflict_report: Some(match conflict_report {
+                    Some(path) => path,
+                    None => dirs::get_data_dir()?.join("http-conflicts.json"),
+                }),
+                ..Default::default()
+            };
+            let tokens_path = dirs::get_data_dir()?.join("http-sync-tokens.json");
+            let mut tokens = sync_http::SyncTokens::load(&tokens_path)?;
+            sync_http::sync_http(&client, &remote, mode, &sync_spec, &mut tokens)?
+        }
+
         // List all buckets
         Commands::List {} => sync::list_buckets(&client)?,
+
+        // Print the status written by the daemon
+        Commands::Status {} => {
+            let status = daemon::DaemonStatus::load(&dirs::get_data_dir()?.join("status.json"))?;
+            println!("{}", serde_json::to_string_pretty(&status)?);
+        }
     }
 
     // Needed to give the datastores some time to commit before program is shut down.
@@ -206,44 +342,3 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
-
-fn daemon(client: &AwClient) -> Result<(), Box<dyn Error>> {
-    let (tx, rx) = channel();
-
-    ctrlc::set_handler(move || {
-        let _ = tx.send(());
-    })?;
-
-    loop {
-        if let Err(e) = daemon_sync_cycle(client) {
-            error!("Error during sync cycle: {}", e);
-            // Re-throw the error
-            return Err(e);
-        }
-
-        info!("Sync pass done, sleeping for 5 minutes");
-
-        // Wait for either the sleep duration or a termination signal
-        match rx.recv_timeout(Duration::from_secs(300)) {
-            Ok(_) | Err(RecvTimeoutError::Disconnected) => {
-                info!("Termination signal received, shutting down.");
-                break;
-            }
-            Err(RecvTimeoutError::Timeout) => {
-                // Continue the loop if the timeout occurs
-            }
-        }
-    }
-
-    Ok(())
-}
-
-fn daemon_sync_cycle(client: &AwClient) -> Result<(), Box<dyn Error>> {
-    info!("Pulling from all hosts");
-    sync_wrapper::pull_all(client)?;
-
-    info!("Pushing local data");
-    sync_wrapper::push(client)?;
-
-    Ok(())
-}
@@ -9,6 +9,7 @@ extern crate chrono;
 extern crate reqwest;
 extern crate serde_json;
 
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fs;
 use std::path::{Path, PathBuf};
@@ -16,13 +17,22 @@ use std::path::{Path, PathBuf};
 use aw_client_rust::blocking::AwClient;
 use chrono::{DateTime, Utc};
 
-use aw_datastore::{Datastore, DatastoreError};
-use aw_models::{Bucket, Event};
+use aw_datastore::{Datastore, DatastoreError, DatastoreKey, DatastoreOptions};
+use aw_models::{Bucket, Event, OriginChanges};
 use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
 
 use crate::accessmethod::AccessMethod;
+use crate::conflict::{
+    bucket_conflicts, BucketConflicts, Conflict, ConflictReport, ConflictResolver, ConflictStrategy,
+};
+use crate::sync_http::SyncTokens;
 
-#[derive(PartialEq, Eq, Copy, Clone, ValueEnum)]
+/// Number of changes fetched from the source bucket at once
+const CHANGES_BATCH_SIZE: u64 = 5000;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum SyncMode {
     Push,
     Pull,
@@ -40,6 +50,15 @@ pub struct SyncSpec {
     pub buckets: Option<Vec<String>>,
     /// Start of time range to sync
     pub start: Option<DateTime<Utc>>,
+    /// How to resolve events which conflict with events in the destination bucket
+    pub conflict_strategy: ConflictStrategy,
+    /// File to write a report of the conflicts found to
+    pub conflict_report: Option<PathBuf>,
+    /// Key the datastores in the sync folder are encrypted with
+    pub encryption_key: Option<DatastoreKey>,
+    /// File to keep the change tokens of the synced buckets in, so a pass only looks at the
+    /// events which changed since the last one. Without it all events are compared every pass.
+    pub tokens: Option<PathBuf>,
 }
 
 impl Default for SyncSpec {
@@ -51,6 +70,10 @@ impl Default for SyncSpec {
             path_db: None,
             buckets: None,
             start: None,
+            conflict_strategy: ConflictStrategy::default(),
+            conflict_report: None,
+            encryption_key: None,
+            tokens: None,
         }
     }
 }
@@ -69,7 +92,8 @@ pub fn sync_run(
     let device_id = info.device_id.as_str();
 
     // FIXME: Bad device_id assumption?
-    let ds_localremote = setup_local_remote(sync_spec.path.as_path(), device_id)?;
+    let key = sync_spec.encryption_key.as_ref();
+    let ds_localremote = setup_local_remote(sync_spec.path.as_path(), device_id, key)?;
     let remote_dbfiles = crate::util::find_remotes_nonlocal(
         sync_spec.path.as_path(),
         device_id,
@@ -89,9 +113,8 @@ pub fn sync_run(
     // TODO: Check for compatible remote db version before opening
     let ds_remotes: Vec<Datastore> = remote_dbfiles
         .iter()
-        .map(|p| p.as_path())
-        .map(create_datastore)
-        .collect();
+        .map(|p| create_datastore(p, key))
+        .collect::<Result<_, _>>()?;
 
     if !ds_remotes.is_empty() {
         info!(
@@ -101,18 +124,35 @@ pub fn sync_run(
         );
     }
 
+    let mut report = ConflictReport::new(sync_spec.conflict_strategy);
+
     // Pull
     if mode == SyncMode::Pull || mode == SyncMode::Both {
         info!("Pulling...");
         for ds_from in &ds_remotes {
-            sync_datastores(ds_from, client, false, None, sync_spec);
+            let conflicts = sync_datastores(ds_from, client, false, None, sync_spec)?;
+            report.buckets.extend(conflicts);
         }
     }
 
     // Push local server buckets to sync folder
     if mode == SyncMode::Push || mode == SyncMode::Both {
         info!("Pushing...");
-        sync_datastores(client, &ds_localremote, true, Some(device_id), sync_spec);
+        let conflicts = sync_datastores(client, &ds_localremote, true, Some(device_id), sync_spec)?;
+        report.buckets.extend(conflicts);
+    }
+
+    if !report.buckets.is_empty() {
+        warn!(
+            "Found {} conflicts in {} buckets, resolved them with {:?}",
+            report.conflict_count(),
+            report.buckets.len(),
+            report.strategy
+        );
+    }
+    if let Some(path) = &sync_spec.conflict_report {
+        report.write(path)?;
+        info!("Wrote conflict report to {}", path.display());
     }
 
     // Close open database connections
@@ -141,7 +181,8 @@ pub fn list_buckets(client: &AwClient) -> Result<(), Box<dyn Error>> {
 
     // FIXME: Incorrect device_id assumption?
     let device_id = info.device_id.as_str();
-    let ds_localremote = setup_local_remote(sync_directory, device_id)?;
+    let key = crate::config::load_encryption_key()?;
+    let ds_localremote = setup_local_remote(sync_directory, device_id, key.as_ref())?;
 
     let remote_dbfiles = crate::util::find_remotes_nonlocal(sync_directory, device_id, None);
     info!("Found remotes: {:?}", remote_dbfiles);
@@ -149,9 +190,8 @@ pub fn list_buckets(client: &AwClient) -> Result<(), Box<dyn Error>> {
     // TODO: Check for compatible remote db version before opening
     let ds_remotes: Vec<Datastore> = remote_dbfiles
         .iter()
-        .map(|p| p.as_path())
-        .map(create_datastore)
-        .collect();
+        .map(|p| create_datastore(p, key.as_ref()))
+        .collect::<Result<_, _>>()?;
 
     log_buckets(client);
     log_buckets(&ds_localremote);
@@ -162,7 +202,11 @@ pub fn list_buckets(client: &AwClient) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-fn setup_local_remote(path: &Path, device_id: &str) -> Result<Datastore, Box<dyn Error>> {
+fn setup_local_remote(
+    path: &Path,
+    device_id: &str,
+    key: Option<&DatastoreKey>,
+) -> Result<Datastore, Box<dyn Error>> {
     // FIXME: Don't run twice if already exists
     fs::create_dir_all(path)?;
 
@@ -176,38 +220,111 @@ fn setup_local_remote(path: &Path, device_id: &str) -> Result<Datastore, Box<dyn
         info!("Creating new database file: {}", dbfile.display());
     }
 
-    let ds_localremote = create_datastore(&dbfile);
+    // Encrypt the database if it was created before encryption was configured
+    #[cfg(feature = "encryption")]
+    if let Some(key) = key {
+        let pathstr = dbfile.as_os_str().to_str().unwrap();
+        if aw_datastore::check_key(pathstr, Some(key)).is_err()
+            && aw_datastore::check_key(pathstr, None).is_ok()
+        {
+            info!("Encrypting database file: {}", dbfile.display());
+            aw_datastore::encrypt_datastore(pathstr, key).map_err(|e| format!("{e:?}"))?;
+        }
+    }
+
+    let ds_localremote = create_datastore(&dbfile, key)?;
     Ok(ds_localremote)
 }
 
-pub fn create_datastore(path: &Path) -> Datastore {
+/// Opens a datastore in the sync folder, which is encrypted with the key if there is one
+pub fn create_datastore(
+    path: &Path,
+    key: Option<&DatastoreKey>,
+) -> Result<Datastore, Box<dyn Error>> {
     let pathstr = path.as_os_str().to_str().unwrap();
-    Datastore::new(pathstr.to_string(), false)
+    #[cfg(feature = "encryption")]
+    match aw_datastore::check_key(pathstr, key) {
+        Ok(()) => (),
+        Err(DatastoreError::InvalidKey(msg)) => Err(msg)?,
+        Err(e) => Err(format!("{e:?}"))?,
+    }
+    #[cfg(not(feature = "encryption"))]
+    if key.is_some() {
+        Err("aw-sync was built without the encryption feature")?
+    }
+    let options = DatastoreOptions {
+        key: key.cloned(),
+        ..Default::default()
+    };
+    Ok(Datastore::new_with_options(
+        pathstr.to_string(),
+        false,
+        options,
+    ))
+}
+
+/// Returns the host a bucket was originally synced from, or its own hostname if it wasn't synced
+pub(crate) fn bucket_origin(bucket: &Bucket) -> &str {
+    match bucket.data.get("$aw.sync.origin").and_then(|v| v.as_str()) {
+        Some(origin) => origin,
+        None => bucket.hostname.as_str(),
+    }
+}
+
+/// Returns the device the events of a synced bucket were recorded on, if it is known
+pub(crate) fn bucket_origin_device(bucket: &Bucket) -> Option<&str> {
+    bucket
+        .data
+        .get("$aw.sync.origin_device")
+        .and_then(|v| v.as_str())
+}
+
+/// Returns the devices whose synced events in the destination bucket belong to the source bucket
+///
+/// The device the events of the source bucket were recorded on owns them even after they have all
+/// been deleted from it. If that isn't known, because the bucket was synced before it was kept
+/// track of, the devices of the events are used.
+pub(crate) fn source_devices(
+    bucket_from: &Bucket,
+    origin_device: &str,
+    events: &[Event],
+) -> HashSet<String> {
+    let mut devices = HashSet::from([origin_device.to_string()]);
+    match bucket_origin_device(bucket_from) {
+        Some(device) => {
+            devices.insert(device.to_string());
+        }
+        None => devices.extend(
+            events
+                .iter()
+                .filter_map(|e| e.origin.as_deref())
+                .map(|origin| origin_device_id(origin).to_string()),
+        ),
+    }
+    devices
 }
 
 /// Returns the sync-destination bucket for a given bucket, creates it if it doesn't exist.
-fn get_or_create_sync_bucket(
+///
+/// src_did: device ID of the source, if known, which is where the events of the source bucket
+///          were recorded unless it was synced from somewhere else itself
+pub(crate) fn get_or_create_sync_bucket(
     bucket_from: &Bucket,
     ds_to: &dyn AccessMethod,
     is_push: bool,
-) -> Bucket {
+    src_did: Option<&str>,
+) -> Result<Bucket, Box<dyn Error>> {
     let new_id = if is_push {
         bucket_from.id.clone()
     } else {
         // Ensure the bucket ID ends in "-synced-from-{device id}"
         let orig_bucketid = bucket_from.id.split("-synced-from-").next().unwrap();
-        let fallback = serde_json::to_value(&bucket_from.hostname).unwrap();
-        let origin = bucket_from
-            .data
-            .get("$aw.sync.origin")
-            .unwrap_or(&fallback)
-            .as_str()
-            .unwrap();
+        let origin = bucket_origin(bucket_from);
         format!("{orig_bucketid}-synced-from-{origin}")
     };
 
     match ds_to.get_bucket(new_id.as_str()) {
-        Ok(bucket) => bucket,
+        Ok(bucket) => Ok(bucket),
         Err(DatastoreError::NoSuchBucket(_)) => {
             let mut bucket_new = bucket_from.clone();
             bucket_new.id = new_id.clone();
@@ -217,13 +334,20 @@ fn get_or_create_sync_bucket(
                 "$aw.sync.origin".to_string(),
                 serde_json::json!(bucket_from.hostname),
             );
-            ds_to.create_bucket(&bucket_new).unwrap();
-            match ds_to.get_bucket(new_id.as_str()) {
-                Ok(bucket) => bucket,
-                Err(e) => panic!("{e:?}"),
+            if let Some(device) = bucket_origin_device(bucket_from).or(src_did) {
+                bucket_new.data.insert(
+                    "$aw.sync.origin_device".to_string(),
+                    serde_json::json!(device),
+                );
             }
+            ds_to
+                .create_bucket(&bucket_new)
+                .map_err(|e| format!("Failed to create bucket {new_id}: {e:?}"))?;
+            ds_to
+                .get_bucket(new_id.as_str())
+                .map_err(|e| format!("Failed to get bucket {new_id}: {e:?}").into())
         }
-        Err(e) => panic!("{e:?}"),
+        Err(e) => Err(format!("Failed to get bucket {new_id}: {e:?}").into()),
     }
 }
 
@@ -232,20 +356,23 @@ fn get_or_create_sync_bucket(
 /// is_push: a bool indicating if we're pushing local buckets to the sync dir
 ///          (as opposed to pulling from remotes)
 /// src_did: source device ID
+///
+/// Returns the conflicts found in the synced buckets, or the first error syncing a bucket, in
+/// which case the buckets after it aren't synced.
 pub fn sync_datastores(
     ds_from: &dyn AccessMethod,
     ds_to: &dyn AccessMethod,
     is_push: bool,
     src_did: Option<&str>,
     sync_spec: &SyncSpec,
-) {
+) -> Result<Vec<BucketConflicts>, Box<dyn Error>> {
     // FIXME: "-synced" should only be appended when synced to the local database, not to the
     // staging area for local buckets.
     info!("Syncing {:?} to {:?}", ds_from, ds_to);
 
+    let mut unknown_hostnames: HashSet<String> = HashSet::new();
     let mut buckets_from: Vec<Bucket> = ds_from
-        .get_buckets()
-        .unwrap()
+        .get_buckets()?
         .iter_mut()
         // If buckets vec isn't empty, filter out buckets not in the buckets vec
         .filter(|tup| {
@@ -260,7 +387,10 @@ pub fn sync_datastores(
             // TODO: Refuse to sync buckets without hostname/device ID set, or if set to 'unknown'
             if tup.1.hostname == "unknown" {
                 warn!(" ! Bucket hostname/device ID was invalid, setting to device ID/hostname");
-                tup.1.hostname = src_did.unwrap().to_string();
+                unknown_hostnames.insert(tup.1.id.clone());
+                if let Some(did) = src_did {
+                    tup.1.hostname = did.to_string();
+                }
             }
             tup.1.clone()
         })
@@ -278,105 +408,366 @@ pub fn sync_datastores(
     // Sync buckets in order of most recently updated
     buckets_from.sort_by_key(|b| b.metadata.end);
 
+    // Events before the start aren't synced, so the tokens would skip them in later passes
+    let mut tokens = match (&sync_spec.tokens, sync_spec.start) {
+        (Some(path), None) => match SyncTokens::load(path) {
+            Ok(tokens) => Some(tokens),
+            Err(e) => {
+                warn!(" ! Could not load sync tokens, comparing all events: {}", e);
+                None
+            }
+        },
+        _ => None,
+    };
+
+    let mut all_conflicts = Vec::new();
     for bucket_from in buckets_from {
-        let bucket_to = get_or_create_sync_bucket(&bucket_from, ds_to, is_push);
-        sync_one(ds_from, ds_to, bucket_from, bucket_to);
+        let mut conflicts = Vec::new();
+        if unknown_hostnames.contains(&bucket_from.id) {
+            conflicts.push(Conflict::UnknownHostname {
+                device_id: src_did.map(|did| did.to_string()),
+            });
+        }
+        let bucket_to = get_or_create_sync_bucket(&bucket_from, ds_to, is_push, src_did)?;
+        conflicts.extend(bucket_conflicts(&bucket_from, &bucket_to));
+        let (bucket_from_id, bucket_to_id) = (bucket_from.id.clone(), bucket_to.id.clone());
+        // Events which don't have an origin yet were recorded on the source device
+        let origin_device = match src_did {
+            Some(did) => did.to_string(),
+            None => bucket_origin(&bucket_from).to_string(),
+        };
+        let key = token_key(&bucket_from, &bucket_to, &origin_device);
+        let token = tokens.as_ref().and_then(|tokens| tokens.get(&key));
+        let (bucket_conflicts, token) = sync_one(
+            ds_from,
+            ds_to,
+            bucket_from,
+            bucket_to,
+            &origin_device,
+            sync_spec.start,
+            sync_spec.conflict_strategy,
+            token,
+        )?;
+        conflicts.extend(bucket_conflicts);
+        if let Some(tokens) = tokens.as_mut() {
+            if let Err(e) = tokens.set(&key, &token) {
+                warn!(" ! Could not store sync token: {}", e);
+            }
+        }
+        if !conflicts.is_empty() {
+            all_conflicts.push(BucketConflicts {
+                bucket_from: bucket_from_id,
+                bucket_to: bucket_to_id,
+                conflicts,
+            });
+        }
+    }
+    Ok(all_conflicts)
+}
+
+/// Identifies a synced pair of buckets in the tokens file, the creation time tells apart source
+/// buckets with the same ID on devices with the same hostname
+fn token_key(bucket_from: &Bucket, bucket_to: &Bucket, origin_device: &str) -> String {
+    let created = bucket_from
+        .created
+        .map(|created| created.to_rfc3339())
+        .unwrap_or_default();
+    format!(
+        "{origin_device}/{}@{created}/{}",
+        bucket_from.id, bucket_to.id
+    )
+}
+
+/// The changes to the events of a source bucket
+struct SourceChanges {
+    /// Inserted and modified events, or all events in the bucket if `complete` is set
+    events: Vec<Event>,
+    /// Origins of the deleted events
+    deleted: Vec<String>,
+    /// Set if there was no token or it was refused
+    complete: bool,
+    /// Token to get the changes after these with
+    token: String,
+}
+
+/// Gets the changes to the events of a bucket after the token, or all events if there is no
+/// token or the source refuses it
+///
+/// Events which don't have an origin yet get one, as they were recorded on the source device.
+fn get_source_changes(
+    ds_from: &dyn AccessMethod,
+    bucket_id: &str,
+    token: Option<&str>,
+    origin_device: &str,
+) -> Result<SourceChanges, Box<dyn Error>> {
+    let mut token = token.map(|token| token.to_string());
+    let mut complete = token.is_none();
+    let mut events: Vec<Event> = Vec::new();
+    let mut deleted: Vec<String> = Vec::new();
+    loop {
+        let changes =
+            match ds_from.get_event_changes(bucket_id, token.as_deref(), CHANGES_BATCH_SIZE) {
+                Ok(changes) => changes,
+                Err(DatastoreError::InvalidToken(msg)) if !complete => {
+                    // The source database was replaced, by one created again for example
+                    warn!(
+                        "   ! Sync token was refused ({}), comparing all events",
+                        msg
+                    );
+                    token = None;
+                    complete = true;
+                    events.clear();
+                    deleted.clear();
+                    continue;
+                }
+                Err(e) => {
+                    return Err(format!("Failed to get the changes to {bucket_id}: {e:?}").into())
+                }
+            };
+        for mut event in changes.events {
+            // Unset ID on events, as they are not globally unique
+            let id = event.id.take().unwrap();
+            event
+                .origin
+                .get_or_insert_with(|| format!("{origin_device}:{id}"));
+            events.push(event);
+        }
+        deleted.extend(changes.deleted.into_iter().map(|d| {
+            d.origin
+                .unwrap_or_else(|| format!("{origin_device}:{}", d.id))
+        }));
+        token = Some(changes.token);
+        if !changes.more {
+            break;
+        }
+    }
+
+    // An event which changed while the changes were fetched is in them twice, the last time
+    // with its current data
+    let mut seen: HashSet<String> = HashSet::new();
+    events.reverse();
+    events.retain(|e| seen.insert(e.origin.clone().unwrap()));
+    events.reverse();
+
+    Ok(SourceChanges {
+        events,
+        deleted,
+        complete,
+        token: token.unwrap(),
+    })
+}
+
+/// Returns the device ID part of an event origin
+pub(crate) fn origin_device_id(origin: &str) -> &str {
+    match origin.rsplit_once(':') {
+        Some((device_id, _)) => device_id,
+        None => origin,
     }
 }
 
 /// Syncs a single bucket from one datastore to another
+///
+/// Events are matched by their origin, so syncing is idempotent and events which were modified
+/// or deleted in the source bucket since the last sync are updated or deleted in the destination
+/// bucket as well. Given the token of the last sync only the events which changed since are
+/// synced, and only the events in the destination bucket in their time range are looked at.
+///
+/// Events in the destination bucket which weren't synced from the source bucket are left alone,
+/// unless synced events overlap them with different data, which is resolved with the conflict
+/// strategy. Returns the overlapping events and the token to sync the next changes with.
+#[allow(clippy::too_many_arguments)]
 fn sync_one(
     ds_from: &dyn AccessMethod,
     ds_to: &dyn AccessMethod,
     bucket_from: Bucket,
     bucket_to: Bucket,
-) {
-    let eventcount_to_old = ds_to.get_event_count(bucket_to.id.as_str()).unwrap();
+    origin_device: &str,
+    start: Option<DateTime<Utc>>,
+    strategy: ConflictStrategy,
+    token: Option<&str>,
+) -> Result<(Vec<Conflict>, String), Box<dyn Error>> {
     info!(" ⟳  Syncing bucket '{}'", bucket_to.id);
-
-    // Sync events
-    // FIXME: This should use bucket_to.metadata.end, but it doesn't because it doesn't work
-    // for empty buckets (Should be None, is Some(unknown_time))
-    // let resume_sync_at = bucket_to.metadata.end;
-    let most_recent_events = ds_to
-        .get_events(bucket_to.id.as_str(), None, None, Some(1))
-        .unwrap();
-    let resume_sync_at = most_recent_events.first().map(|e| e.timestamp + e.duration);
-
-    if let Some(resume_time) = resume_sync_at {
-        info!("   + Resuming at {:?}", resume_time);
-    } else {
-        info!("   + Starting from beginning");
+    if let Some(start) = start {
+        info!("   + Syncing events since {:?}", start);
     }
 
-    // Fetch events
-    // Unset ID on events, as they are not globally unique
-    // TODO: Fetch at most ~5,000 events at a time (or so, to avoid timeout from huge buckets)
-    let mut events: Vec<Event> = ds_from
-        .get_events(bucket_from.id.as_str(), resume_sync_at, None, None)
-        .unwrap()
-        .iter()
-        .map(|e| {
-            let mut new_e = e.clone();
-            new_e.id = None;
-            new_e
-        })
-        .collect();
+    // Events overlapping the start of the time range are cut off at the start, so they can't be
+    // compared and are left as they are
+    let in_range = |e: &Event| match start {
+        Some(start) => e.timestamp > start,
+        None => true,
+    };
 
-    // Sort ascending
-    // FIXME: What happens here if two events have the same timestamp?
-    events.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+    // If the destination bucket was deleted and created again it needs all events again
+    let token = match token {
+        Some(token) if ds_to.get_event_count(bucket_to.id.as_str())? > 0 => Some(token),
+        _ => None,
+    };
+    let changes = get_source_changes(ds_from, bucket_from.id.as_str(), token, origin_device)?;
+    if !changes.complete {
+        info!(
+            "   + Resuming with {} changed and {} deleted events",
+            changes.events.len(),
+            changes.deleted.len()
+        );
+    }
+    let mut events_from = changes.events;
+    events_from.retain(in_range);
+
+    // Synced events recorded on these devices which are no longer in the source bucket have been
+    // deleted from it
+    let source_devices = source_devices(&bucket_from, origin_device, &events_from);
+
+    // Only the destination events in the time range of the changed events can be affected by them
+    let events_to = if changes.complete {
+        ds_to.get_events(bucket_to.id.as_str(), start, None, None)?
+    } else if events_from.is_empty() {
+        Vec::new()
+    } else {
+        let first = events_from.iter().map(|e| e.timestamp).min();
+        let last = events_from.iter().map(|e| e.timestamp + e.duration).max();
+        ds_to.get_events(bucket_to.id.as_str(), first, last, None)?
+    };
 
-    // TODO: Do bulk insert using insert_events instead? (for performance)
-    //       Client-side heartbeat queueing should keep things somewhat performant though?
-    // NOTE: First event needs to be inserted with heartbeat, to ensure appropriate
-    // merging/updating of pulsed events.
-    let events_total = events.len();
-    let mut events_sent = 0;
-    let mut events_iter = events.into_iter();
-    if let Some(e) = events_iter.next() {
-        ds_to.heartbeat(bucket_to.id.as_str(), e, 0.0).unwrap();
-        events_sent += 1;
+    let mut synced_events: HashMap<String, Event> = HashMap::new();
+    // Events synced before events had an origin, or not synced at all, by their timestamp
+    let mut unidentified_events: HashMap<DateTime<Utc>, Vec<Event>> = HashMap::new();
+    for event in events_to {
+        match event.origin.clone() {
+            Some(origin) => {
+                synced_events.insert(origin, event);
+            }
+            None => unidentified_events
+                .entry(event.timestamp)
+                .or_default()
+                .push(event),
+        }
     }
 
-    const BATCH_SIZE: usize = 5000;
-    if BATCH_SIZE == 1 {
-        // TODO: Don't print progress messages if not in a suitable terminal environment (such as a
-        // pipe or systemd journal)
-        for event in events_iter {
-            print!("{} ({}/{})\r", &event.timestamp, events_sent, events_total);
-            ds_to.heartbeat(bucket_to.id.as_str(), event, 0.0).unwrap();
-            events_sent += 1;
+    // Give the origin to the matching event if it was synced without one, instead of syncing it
+    // again
+    let mut adopted_events: HashMap<String, Event> = HashMap::new();
+    for event in &events_from {
+        let origin = event.origin.as_ref().unwrap();
+        if synced_events.contains_key(origin) {
+            continue;
         }
-    } else {
-        let mut batch_events = Vec::with_capacity(BATCH_SIZE);
-        for e in events_iter {
-            print!("{} ({}/{})\r", e.timestamp, events_sent, events_total);
-            batch_events.push(e);
-            events_sent += 1;
-            if batch_events.len() >= BATCH_SIZE {
-                ds_to
-                    .insert_events(bucket_to.id.as_str(), batch_events.clone())
-                    .unwrap();
-                batch_events.clear();
+        if let Some(candidates) = unidentified_events.get_mut(&event.timestamp) {
+            if let Some(i) = candidates.iter().position(|e| e.data == event.data) {
+                adopted_events.insert(origin.clone(), candidates.swap_remove(i));
             }
         }
+    }
 
-        if !batch_events.is_empty() {
-            ds_to
-                .insert_events(bucket_to.id.as_str(), batch_events)
-                .unwrap();
+    // What is left was not synced from the source bucket
+    let mut other_events: Vec<Event> = unidentified_events.into_values().flatten().collect();
+    synced_events.retain(|origin, event| {
+        let from_source = source_devices.contains(origin_device_id(origin));
+        if !from_source {
+            other_events.push(event.clone());
+        }
+        from_source
+    });
+    let mut resolver = ConflictResolver::new(other_events, strategy);
+
+    let mut events: Vec<Event> = Vec::new();
+    let mut new_count = 0;
+    let mut updated_count = 0;
+    for mut event in events_from {
+        let origin = event.origin.clone().unwrap();
+        let synced_event = synced_events.remove(&origin);
+        if !resolver.resolve(&mut event) {
+            continue;
+        }
+        match synced_event {
+            Some(synced_event) => {
+                if synced_event != event {
+                    updated_count += 1;
+                    events.push(event);
+                }
+            }
+            None => {
+                match adopted_events.remove(&origin) {
+                    Some(adopted_event) => {
+                        if adopted_event != event {
+                            updated_count += 1;
+                        }
+                        event.id = adopted_event.id;
+                    }
+                    None => new_count += 1,
+                }
+                events.push(event);
+            }
         }
     }
+    // Whatever is left has been deleted from the source bucket, when comparing all events.
+    // Otherwise the deleted events are known, and deleted by their origin.
+    let mut deleted_ids: Vec<i64> = Vec::new();
+    let mut deleted_origins: Vec<String> = Vec::new();
+    if changes.complete {
+        deleted_ids.extend(
+            synced_events
+                .into_values()
+                .filter(in_range)
+                .filter_map(|e| e.id),
+        );
+    } else {
+        deleted_origins = changes.deleted;
+    }
+    let deleted_count = deleted_ids.len() + deleted_origins.len();
+    let replaced_ids = resolver.take_replaced_ids();
+    let replaced_count = replaced_ids.len();
+    deleted_ids.extend(replaced_ids);
+
+    // Sort ascending, events with the same timestamp no longer need any special care since they
+    // are told apart by their origin
+    events.sort_by_key(|e| e.timestamp);
+
+    const BATCH_SIZE: usize = 5000;
+    let events_total = events.len();
+    let mut events_sent = 0;
+    for batch_events in events.chunks(BATCH_SIZE) {
+        // TODO: Don't print progress messages if not in a suitable terminal environment (such as a
+        // pipe or systemd journal)
+        print!(
+            "{} ({}/{})\r",
+            batch_events[0].timestamp, events_sent, events_total
+        );
+        ds_to.insert_events(bucket_to.id.as_str(), batch_events.to_vec())?;
+        events_sent += batch_events.len();
+    }
+    if !deleted_ids.is_empty() {
+        ds_to.delete_events(bucket_to.id.as_str(), deleted_ids)?;
+    }
+    if !deleted_origins.is_empty() {
+        ds_to.apply_event_changes(
+            bucket_to.id.as_str(),
+            OriginChanges {
+                events: Vec::new(),
+                deleted: deleted_origins,
+            },
+        )?;
+    }
 
-    let eventcount_to_new = ds_to.get_event_count(bucket_to.id.as_str()).unwrap();
-    let new_events_count = eventcount_to_new - eventcount_to_old;
-    assert!(new_events_count >= 0);
-    if new_events_count > 0 {
-        info!("  = Synced {} new events", new_events_count);
+    if new_count + updated_count + deleted_count > 0 {
+        info!(
+            "  = Synced {} new, {} updated and {} deleted events",
+            new_count, updated_count, deleted_count
+        );
     } else {
         info!("  ✓ Already up to date!");
     }
+    if !resolver.conflicts.is_empty() {
+        warn!(
+            "  ! {} events overlap events with different data which weren't synced from '{}'",
+            resolver.conflicts.len(),
+            bucket_from.id
+        );
+    }
+    if replaced_count > 0 {
+        info!("  = Deleted {} conflicting events", replaced_count);
+    }
+    Ok((resolver.conflicts, changes.token))
 }
 
 fn log_buckets(ds: &dyn AccessMethod) {
@@ -0,0 +1,293 @@
+/// Syncing directly between two aw-servers over HTTP
+///
+/// Unlike the folder based sync this needs no third-party file synchronizer, the servers only
+/// have to be able to reach each other (on the same LAN for example).
+///
+/// Instead of comparing all events of a bucket, only the changes made since the last sync are
+/// transferred, in batches, using the resume tokens of the changes endpoint. The tokens are kept
+/// in a file so an interrupted sync continues where it left off.
+///
+/// Conflicts with events in the destination which weren't synced from the source are resolved
+/// and reported the same way as when syncing through a sync directory.
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use aw_client_rust::blocking::AwClient;
+use aw_datastore::DatastoreError;
+use aw_models::{Bucket, Event, OriginChanges};
+use serde::{Deserialize, Serialize};
+
+use crate::accessmethod::AccessMethod;
+use crate::conflict::{
+    bucket_conflicts, BucketConflicts, Conflict, ConflictReport, ConflictResolver,
+};
+use crate::sync::{
+    get_or_create_sync_bucket, origin_device_id, source_devices, SyncMode, SyncSpec,
+};
+
+/// Number of changes fetched and applied at once
+const BATCH_SIZE: u64 = 1000;
+
+/// Resume tokens of the buckets synced so far, stored as JSON
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SyncTokens {
+    #[serde(skip)]
+    path: PathBuf,
+    tokens: HashMap<String, String>,
+}
+
+impl SyncTokens {
+    /// Loads the tokens from a file, starting without any if the file doesn't exist yet
+    pub fn load(path: &Path) -> Result<SyncTokens, Box<dyn Error>> {
+        let mut tokens = if path.exists() {
+            serde_json::from_str(&fs::read_to_string(path)?)?
+        } else {
+            SyncTokens::default()
+        };
+        tokens.path = path.to_path_buf();
+        Ok(tokens)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.tokens.get(key).map(|token| token.as_str())
+    }
+
+    /// Stores the token and writes all tokens to the file
+    pub fn set(&mut self, key: &str, token: &str) -> Result<(), Box<dyn Error>> {
+        self.tokens.insert(key.to_string(), token.to_string());
+        // Write to a temporary file first so the tokens aren't lost if we're interrupted
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Syncs the buckets of a local and a remote aw-server with each other
+///
+/// Pulled buckets get `-synced-from-{hostname}` appended to their ID, pushed buckets get the same
+/// on the remote. Buckets which were synced from somewhere else are not synced any further.
+pub fn sync_http(
+    local: &AwClient,
+    remote: &AwClient,
+    mode: SyncMode,
+    sync_spec: &SyncSpec,
+    tokens: &mut SyncTokens,
+) -> Result<(), Box<dyn Error>> {
+    let local_did = local.get_info()?.device_id;
+    let remote_did = remote.get_info()?.device_id;
+
+    let mut report = ConflictReport::new(sync_spec.conflict_strategy);
+    if mode == SyncMode::Pull || mode == SyncMode::Both {
+        info!("Pulling from {}", remote.baseurl);
+        let conflicts = sync_changes(remote, local, &remote_did, &local_did, sync_spec, tokens)?;
+        report.buckets.extend(conflicts);
+    }
+    if mode == SyncMode::Push || mode == SyncMode::Both {
+        info!("Pushing to {}", remote.baseurl);
+        let conflicts = sync_changes(local, remote, &local_did, &remote_did, sync_spec, tokens)?;
+        report.buckets.extend(conflicts);
+    }
+
+    if !report.buckets.is_empty() {
+        warn!(
+            "Found {} conflicts in {} buckets, resolved them with {:?}",
+            report.conflict_count(),
+            report.buckets.len(),
+            report.strategy
+        );
+    }
+    if let Some(path) = &sync_spec.conflict_report {
+        report.write(path)?;
+        info!("Wrote conflict report to {}", path.display());
+    }
+    Ok(())
+}
+
+/// Syncs the changes to the buckets of `ds_from` since the last sync to `ds_to`
+///
+/// src_did: device ID of `ds_from`
+/// dst_did: device ID of `ds_to`
+///
+/// Returns the conflicts found in the synced buckets.
+pub fn sync_changes(
+    ds_from: &dyn AccessMethod,
+    ds_to: &dyn AccessMethod,
+    src_did: &str,
+    dst_did: &str,
+    sync_spec: &SyncSpec,
+    tokens: &mut SyncTokens,
+) -> Result<Vec<BucketConflicts>, Box<dyn Error>> {
+    let mut buckets_from: Vec<Bucket> = ds_from
+        .get_buckets()?
+        .into_values()
+        .filter(|bucket| !bucket.data.contains_key("$aw.sync.origin"))
+        .filter(|bucket| match &sync_spec.buckets {
+            Some(buckets) => buckets.iter().any(|b_id| b_id == &bucket.id),
+            None => true,
+        })
+        .map(|mut bucket| {
+            if bucket.hostname == "unknown" {
+                warn!(" ! Bucket hostname/device ID was invalid, setting to device ID");
+                bucket.hostname = src_did.to_string();
+            }
+            bucket
+        })
+        .collect();
+    buckets_from.sort_by_key(|b| b.metadata.end);
+
+    let mut all_conflicts = Vec::new();
+    for bucket_from in buckets_from {
+        let bucket_to = get_or_create_sync_bucket(&bucket_from, ds_to, false, Some(src_did))?;
+        let key = format!("{src_did}/{dst_did}/{}", bucket_from.id);
+        let mut conflicts = bucket_conflicts(&bucket_from, &bucket_to);
+        conflicts.extend(sync_bucket_changes(
+            ds_from,
+            ds_to,
+            &bucket_from,
+            &bucket_to,
+            src_did,
+            &key,
+            sync_spec,
+            tokens,
+        )?);
+        if !conflicts.is_empty() {
+            all_conflicts.push(BucketConflicts {
+                bucket_from: bucket_from.id.clone(),
+                bucket_to: bucket_to.id.clone(),
+                conflicts,
+            });
+        }
+    }
+    Ok(all_conflicts)
+}
+
+/// Returns the conflicts with events in the destination bucket which weren't synced from the source
+#[allow(clippy::too_many_arguments)]
+fn sync_bucket_changes(
+    ds_from: &dyn AccessMethod,
+    ds_to: &dyn AccessMethod,
+    bucket_from: &Bucket,
+    bucket_to: &Bucket,
+    src_did: &str,
+    key: &str,
+    sync_spec: &SyncSpec,
+    tokens: &mut SyncTokens,
+) -> Result<Vec<Conflict>, Box<dyn Error>> {
+    let mut token = tokens.get(key).map(|token| token.to_string());
+    // If the destination bucket was deleted and created again it needs all events again
+    if token.is_some() && ds_to.get_event_count(&bucket_to.id)? == 0 {
+        token = None;
+    }
+
+    let (mut updated, mut deleted, mut replaced) = (0, 0, 0);
+    let mut conflicts = Vec::new();
+    loop {
+        let changes = match ds_from.get_event_changes(&bucket_from.id, token.as_deref(), BATCH_SIZE)
+        {
+            Ok(changes) => changes,
+            Err(DatastoreError::InvalidToken(msg)) if token.is_some() => {
+                // The source database was replaced, by a backup for example
+                warn!(
+                    " ! Resume token for {} was refused ({}), syncing all changes again",
+                    bucket_from.id, msg
+                );
+                token = None;
+                continue;
+            }
+            Err(err) => {
+                return Err(
+                    format!("Failed to get the changes to {}: {:?}", bucket_from.id, err).into(),
+                )
+            }
+        };
+
+        // Events without an origin were recorded on the source device
+        let events: Vec<_> = changes
+            .events
+            .into_iter()
+            .map(|mut event| {
+                let id = event.id.take().unwrap();
+                event
+                    .origin
+                    .get_or_insert_with(|| format!("{src_did}:{id}"));
+                event
+            })
+            .collect();
+        let mut resolver = ConflictResolver::new(
+            other_events(ds_to, bucket_from, bucket_to, src_did, &events)?,
+            sync_spec.conflict_strategy,
+        );
+        let events: Vec<Event> = events
+            .into_iter()
+            .filter_map(|mut event| resolver.resolve(&mut event).then_some(event))
+            .collect();
+        let replaced_ids = resolver.take_replaced_ids();
+        replaced += replaced_ids.len();
+        if !replaced_ids.is_empty() {
+            ds_to.delete_events(&bucket_to.id, replaced_ids)?;
+        }
+        conflicts.extend(resolver.conflicts);
+        let deleted_origins: Vec<String> = changes
+            .deleted
+            .into_iter()
+            .map(|d| d.origin.unwrap_or_else(|| format!("{src_did}:{}", d.id)))
+            .collect();
+        updated += events.len();
+        deleted += deleted_origins.len();
+
+        ds_to.apply_event_changes(
+            &bucket_to.id,
+            OriginChanges {
+                events,
+                deleted: deleted_origins,
+            },
+        )?;
+        tokens.set(key, &changes.token)?;
+        token = Some(changes.token);
+        if !changes.more {
+            break;
+        }
+    }
+
+    info!(
+        "  = Synced {} changed and {} deleted events from {} to {}",
+        updated, deleted, bucket_from.id, bucket_to.id
+    );
+    if !conflicts.is_empty() {
+        warn!(
+            "  ! {} events overlap events with different data which weren't synced from '{}'",
+            conflicts.len(),
+            bucket_from.id
+        );
+    }
+    if replaced > 0 {
+        info!("  = Deleted {} conflicting events", replaced);
+    }
+    Ok(conflicts)
+}
+
+/// Gets the events in the destination bucket in the time range of the synced events which
+/// weren't synced from the source bucket, which the synced events can conflict with
+fn other_events(
+    ds_to: &dyn AccessMethod,
+    bucket_from: &Bucket,
+    bucket_to: &Bucket,
+    src_did: &str,
+    events: &[Event],
+) -> Result<Vec<Event>, Box<dyn Error>> {
+    if events.is_empty() {
+        return Ok(Vec::new());
+    }
+    let first = events.iter().map(|e| e.timestamp).min();
+    let last = events.iter().map(|e| e.timestamp + e.duration).max();
+    let source_devices: HashSet<String> = source_devices(bucket_from, src_did, events);
+    let mut events_to = ds_to.get_events(&bucket_to.id, first, last, None)?;
+    events_to.retain(|e| match &e.origin {
+        Some(origin) => !source_devices.contains(origin_device_id(origin)),
+        None => true,
+    });
+    Ok(events_to)
+}
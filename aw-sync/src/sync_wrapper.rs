@@ -1,6 +1,7 @@
 use std::error::Error;
 use std::fs;
 
+use crate::conflict::ConflictStrategy;
 use crate::sync::{sync_run, SyncMode, SyncSpec};
 use aw_client_rust::blocking::AwClient;
 
@@ -48,11 +49,12 @@ pub fn pull(host: &str, client: &AwClient) -> Result<(), Box<dyn Error>> {
     let sync_spec = SyncSpec {
         path: sync_dir.clone(),
         path_db: Some(db.path().clone()),
-        buckets: Some(vec![
-            format!("aw-watcher-window_{}", host),
-            format!("aw-watcher-afk_{}", host),
-        ]),
+        buckets: Some(host_buckets(host)),
         start: None,
+        conflict_strategy: ConflictStrategy::default(),
+        conflict_report: Some(crate::dirs::get_data_dir()?.join(format!("conflicts-{host}.json"))),
+        encryption_key: crate::config::load_encryption_key()?,
+        tokens: Some(crate::dirs::get_data_dir()?.join("sync-tokens.json")),
     };
     sync_run(client, &sync_spec, SyncMode::Pull)?;
 
@@ -67,13 +69,22 @@ pub fn push(client: &AwClient) -> Result<(), Box<dyn Error>> {
     let sync_spec = SyncSpec {
         path: sync_dir,
         path_db: None,
-        buckets: Some(vec![
-            format!("aw-watcher-window_{}", client.hostname),
-            format!("aw-watcher-afk_{}", client.hostname),
-        ]),
+        buckets: Some(host_buckets(&client.hostname)),
         start: None,
+        conflict_strategy: ConflictStrategy::default(),
+        conflict_report: Some(crate::dirs::get_data_dir()?.join("conflicts-push.json")),
+        encryption_key: crate::config::load_encryption_key()?,
+        tokens: Some(crate::dirs::get_data_dir()?.join("sync-tokens.json")),
     };
     sync_run(client, &sync_spec, SyncMode::Push)?;
 
     Ok(())
 }
+
+/// The buckets of a host which are pushed to and pulled from the sync folder
+pub(crate) fn host_buckets(host: &str) -> Vec<String> {
+    vec![
+        format!("aw-watcher-window_{}", host),
+        format!("aw-watcher-afk_{}", host),
+    ]
+}
@@ -0,0 +1,139 @@
+extern crate aw_sync;
+
+#[cfg(test)]
+mod daemon_tests {
+    use std::error::Error;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use chrono::{TimeZone, Utc};
+    use serde_json::json;
+
+    use aw_datastore::Datastore;
+    use aw_models::{Bucket, Event};
+    use aw_sync::{DaemonOptions, DaemonState, DaemonStatus, RemoteWatcher, SyncMode};
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("aw-sync-test-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_next_delay() {
+        let options = DaemonOptions {
+            interval: Duration::from_secs(300),
+            retry_delay: Duration::from_secs(30),
+            max_backoff: Duration::from_secs(200),
+            ..Default::default()
+        };
+        let delays: Vec<u64> = (0..6).map(|n| options.next_delay(n).as_secs()).collect();
+        assert_eq!(delays, vec![300, 30, 60, 120, 200, 200]);
+        // Doesn't overflow after many failures
+        assert_eq!(options.next_delay(u32::MAX).as_secs(), 200);
+    }
+
+    #[test]
+    fn test_status_record() {
+        let ds = Datastore::new_in_memory(false);
+        let bucket: Bucket = serde_json::from_value(json!({
+            "id": "aw-watcher-window_host-a-synced-from-host-a",
+            "type": "currentwindow",
+            "hostname": "host-a",
+            "client": "aw-watcher-window",
+        }))
+        .unwrap();
+        ds.create_bucket(&bucket).unwrap();
+        let timestamp = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+        let event = Event {
+            timestamp,
+            duration: chrono::Duration::seconds(60),
+            ..Default::default()
+        };
+        ds.insert_events(&bucket.id, &[event]).unwrap();
+
+        let mut status = DaemonStatus::new();
+        status.record(&ds, SyncMode::Pull, "host-a", &Ok(()));
+        assert_eq!(status.buckets.len(), 2);
+        let window = &status.buckets[0];
+        assert_eq!(window.bucket_id, "aw-watcher-window_host-a");
+        assert!(window.last_success.is_some());
+        assert_eq!(window.event_count, Some(1));
+        assert_eq!(
+            window.synced_until,
+            Some(timestamp + chrono::Duration::seconds(60))
+        );
+        // Nothing was synced to the afk bucket
+        let afk = &status.buckets[1];
+        assert_eq!(afk.bucket_id, "aw-watcher-afk_host-a");
+        assert_eq!(afk.event_count, None);
+
+        // A failed attempt keeps the progress of the last successful one
+        let result: Result<(), Box<dyn Error>> = Err("No db found".into());
+        status.record(&ds, SyncMode::Pull, "host-a", &result);
+        assert_eq!(status.buckets.len(), 2);
+        let window = &status.buckets[0];
+        assert_eq!(window.error.as_deref(), Some("No db found"));
+        assert_eq!(window.event_count, Some(1));
+        assert!(window.last_success.unwrap() <= window.last_attempt);
+
+        // Pushing the same buckets is tracked separately, without the progress of the buckets
+        // in aw-server
+        let pushed: Bucket = serde_json::from_value(json!({
+            "id": "aw-watcher-window_host-a",
+            "type": "currentwindow",
+            "hostname": "host-a",
+            "client": "test",
+        }))
+        .unwrap();
+        ds.create_bucket(&pushed).unwrap();
+        status.record(&ds, SyncMode::Push, "host-a", &Ok(()));
+        assert_eq!(status.buckets.len(), 4);
+        assert!(status.buckets[2..]
+            .iter()
+            .all(|b| b.last_success.is_some() && b.event_count.is_none()));
+
+        let dir = test_dir("daemon-status");
+        let path = dir.join("status.json");
+        status.state = DaemonState::BackingOff;
+        status.write(&path).unwrap();
+        let loaded = DaemonStatus::load(&path).unwrap();
+        assert_eq!(loaded.state, DaemonState::BackingOff);
+        assert_eq!(loaded.buckets, status.buckets);
+        assert!(!dir.join("status.tmp").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_remote_watcher() {
+        let dir = test_dir("daemon-watch");
+        let local_dir = dir.join("host-local").join("device-local");
+        let remote_dir = dir.join("host-remote").join("device-remote");
+        fs::create_dir_all(&local_dir).unwrap();
+        fs::create_dir_all(&remote_dir).unwrap();
+
+        let mut watcher = RemoteWatcher::new(dir.clone(), "host-local");
+        assert!(!watcher.changed());
+
+        // Databases of the local host are written by the sync itself
+        fs::write(local_dir.join("test.db"), "local").unwrap();
+        assert!(!watcher.changed());
+
+        // Other files than databases are ignored
+        fs::write(remote_dir.join("test.db-wal"), "wal").unwrap();
+        assert!(!watcher.changed());
+
+        // New and modified remote databases are picked up once
+        fs::write(remote_dir.join("test.db"), "remote").unwrap();
+        assert!(watcher.changed());
+        assert!(!watcher.changed());
+        fs::write(remote_dir.join("test.db"), "remote, with more data").unwrap();
+        assert!(watcher.changed());
+        assert!(!watcher.changed());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,205 @@
+extern crate aw_sync;
+
+#[cfg(all(test, feature = "encryption"))]
+mod encryption_tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use chrono::{Duration, Utc};
+    use serde_json::json;
+
+    use aw_datastore::{Datastore, DatastoreKey};
+    use aw_models::{Bucket, Event};
+    use aw_sync::{create_datastore, SyncConfig, SyncSpec};
+
+    const SECRET_TITLE: &str = "secret-window-title";
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("aw-sync-test-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn create_source() -> Datastore {
+        let ds = Datastore::new_in_memory(false);
+        let bucket: Bucket = serde_json::from_value(json!({
+            "id": "bucket",
+            "type": "currentwindow",
+            "hostname": "device-0",
+            "client": "test",
+        }))
+        .unwrap();
+        ds.create_bucket(&bucket).unwrap();
+        let events: Vec<Event> = (0..3)
+            .map(|i| Event {
+                timestamp: Utc::now() + Duration::seconds(i),
+                data: json!({ "title": SECRET_TITLE })
+                    .as_object()
+                    .unwrap()
+                    .clone(),
+                ..Default::default()
+            })
+            .collect();
+        ds.insert_events("bucket", &events).unwrap();
+        ds
+    }
+
+    fn get_events(ds: &Datastore) -> Vec<Event> {
+        ds.get_events("bucket", None, None, None).unwrap()
+    }
+
+    fn contains(haystack: &[u8], needle: &str) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle.as_bytes())
+    }
+
+    #[test]
+    fn test_encrypted_staging_datastore() {
+        let dir = test_dir("encryption");
+        let path = dir.join("test.db");
+        let key = DatastoreKey::Passphrase("correct horse".to_string());
+        let ds_src = create_source();
+
+        // Push to an encrypted staging datastore
+        let ds_staging = create_datastore(&path, Some(&key)).unwrap();
+        aw_sync::sync_datastores(
+            &ds_src,
+            &ds_staging,
+            true,
+            Some("device-id"),
+            &SyncSpec::default(),
+        )
+        .unwrap();
+        ds_staging.force_commit().unwrap();
+        ds_staging.close();
+
+        // Neither the database nor its write-ahead log are readable without the key
+        // (the write-ahead log may be removed while this runs, as the datastore closes)
+        for entry in fs::read_dir(&dir).unwrap() {
+            let contents = match fs::read(entry.unwrap().path()) {
+                Ok(contents) => contents,
+                Err(_) => continue,
+            };
+            assert!(!contains(&contents, "SQLite format 3"));
+            assert!(!contains(&contents, SECRET_TITLE));
+        }
+
+        // Pulling with the same key works
+        let ds_staging = create_datastore(&path, Some(&key)).unwrap();
+        assert_eq!(get_events(&ds_staging), get_events(&ds_src));
+        ds_staging.close();
+
+        // But not with another key or without one
+        let wrong_key = DatastoreKey::Passphrase("wrong".to_string());
+        let err = create_datastore(&path, Some(&wrong_key)).unwrap_err();
+        assert!(err.to_string().contains("Wrong key"), "{err}");
+        let err = create_datastore(&path, None).unwrap_err();
+        assert!(err.to_string().contains("is encrypted"), "{err}");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_encrypt_existing_datastore() {
+        let dir = test_dir("encrypt-existing");
+        let path = dir.join("test.db");
+        let pathstr = path.to_str().unwrap();
+        let ds_src = create_source();
+
+        // Staging datastores created before encryption was configured are encrypted in place
+        let ds_staging = create_datastore(&path, None).unwrap();
+        aw_sync::sync_datastores(
+            &ds_src,
+            &ds_staging,
+            true,
+            Some("device-id"),
+            &SyncSpec::default(),
+        )
+        .unwrap();
+        ds_staging.force_commit().unwrap();
+        ds_staging.close();
+
+        let key = DatastoreKey::Raw([7; 32]);
+        assert!(aw_datastore::check_key(pathstr, Some(&key)).is_err());
+        aw_datastore::encrypt_datastore(pathstr, &key).unwrap();
+        assert!(aw_datastore::check_key(pathstr, None).is_err());
+        assert!(!contains(&fs::read(&path).unwrap(), SECRET_TITLE));
+
+        let ds_staging = create_datastore(&path, Some(&key)).unwrap();
+        assert_eq!(get_events(&ds_staging), get_events(&ds_src));
+        ds_staging.close();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_encryption_config() {
+        let dir = test_dir("encryption-config");
+        let config_path = dir.join("config.toml");
+
+        // Without config there is no encryption
+        let config = SyncConfig::load(&config_path).unwrap();
+        assert!(config.encryption_key().unwrap().is_none());
+
+        fs::write(&config_path, "[encryption]\npassphrase = \"hunter2\"\n").unwrap();
+        let config = SyncConfig::load(&config_path).unwrap();
+        assert_eq!(
+            config.encryption_key().unwrap(),
+            Some(DatastoreKey::Passphrase("hunter2".to_string()))
+        );
+
+        // Keys derived from the same keyfile are the same
+        let keyfile = dir.join("keyfile");
+        fs::write(&keyfile, "some random bytes").unwrap();
+        let keyfile_config = format!("[encryption]\nkeyfile = {:?}\n", keyfile.to_str().unwrap());
+        fs::write(&config_path, &keyfile_config).unwrap();
+        let key = SyncConfig::load(&config_path)
+            .unwrap()
+            .encryption_key()
+            .unwrap();
+        assert!(matches!(key, Some(DatastoreKey::Raw(_))));
+        assert_eq!(
+            SyncConfig::load(&config_path)
+                .unwrap()
+                .encryption_key()
+                .unwrap(),
+            key
+        );
+
+        // Passphrase and keyfile can't both be set, and the keyfile has to exist
+        fs::write(
+            &config_path,
+            format!("{keyfile_config}passphrase = \"hunter2\"\n"),
+        )
+        .unwrap();
+        assert!(SyncConfig::load(&config_path)
+            .unwrap()
+            .encryption_key()
+            .is_err());
+        fs::remove_file(&keyfile).unwrap();
+        fs::write(&config_path, &keyfile_config).unwrap();
+        assert!(SyncConfig::load(&config_path)
+            .unwrap()
+            .encryption_key()
+            .is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[cfg(all(test, not(feature = "encryption")))]
+mod no_encryption_tests {
+    use aw_sync::SyncConfig;
+
+    #[test]
+    fn test_encryption_config_unsupported() {
+        // Configured encryption is an error instead of syncing unencrypted
+        let config: SyncConfig =
+            toml::from_str("[encryption]\npassphrase = \"hunter2\"\n").unwrap();
+        assert!(config.encryption_key().is_err());
+        let config: SyncConfig = toml::from_str("").unwrap();
+        assert!(config.encryption_key().unwrap().is_none());
+    }
+}
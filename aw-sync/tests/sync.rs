@@ -7,11 +7,15 @@ mod sync_tests {
     use std::collections::HashMap;
     use std::path::Path;
 
+    use aw_client_rust::blocking::AwClient;
     use chrono::{DateTime, Duration, Utc};
 
     use aw_datastore::{Datastore, DatastoreError};
     use aw_models::{Bucket, Event};
-    use aw_sync::{create_datastore, AccessMethod, SyncSpec};
+    use aw_sync::{
+        create_datastore, AccessMethod, Conflict, ConflictReport, ConflictStrategy, SyncSpec,
+        CONFLICT_KEY,
+    };
 
     struct TestState {
         ds_src: Datastore,
@@ -108,13 +112,29 @@ mod sync_tests {
             false,
             None,
             &SyncSpec::default(),
-        );
+        )
+        .unwrap();
 
         let buckets_src: HashMap<String, Bucket> = state.ds_src.get_buckets().unwrap();
         let buckets_dest: HashMap<String, Bucket> = state.ds_dest.get_buckets().unwrap();
         assert!(buckets_src.len() == buckets_dest.len());
     }
 
+    #[test]
+    fn test_unreachable_server() {
+        let state = init_teststate();
+        create_bucket(&state.ds_src, 0);
+
+        // Nothing listens on the port, the error is returned so that the sync can be retried
+        let client = AwClient::new("127.0.0.1", 1, "aw-sync-test").unwrap();
+        let res =
+            aw_sync::sync_datastores(&state.ds_src, &client, false, None, &SyncSpec::default());
+        assert!(res.is_err());
+        let res =
+            aw_sync::sync_datastores(&client, &state.ds_dest, false, None, &SyncSpec::default());
+        assert!(res.is_err());
+    }
+
     fn check_synced_buckets_equal_to_src(all_buckets_map: &HashMap<String, (&Datastore, Bucket)>) {
         for (ds, bucket) in all_buckets_map.values() {
             if bucket.id.contains("-synced") {
@@ -149,7 +169,8 @@ mod sync_tests {
             false,
             None,
             &SyncSpec::default(),
-        );
+        )
+        .unwrap();
 
         let all_datastores: Vec<&Datastore> = [&state.ds_src, &state.ds_dest].to_vec();
         let all_buckets_map = get_all_buckets_map(all_datastores);
@@ -168,7 +189,8 @@ mod sync_tests {
             false,
             None,
             &SyncSpec::default(),
-        );
+        )
+        .unwrap();
 
         // Check again that new events were indeed synced
         check_synced_buckets_equal_to_src(&all_buckets_map);
@@ -187,7 +209,8 @@ mod sync_tests {
             false,
             None,
             &SyncSpec::default(),
-        );
+        )
+        .unwrap();
 
         let all_datastores: Vec<&Datastore> = [&state.ds_src, &state.ds_dest].to_vec();
         let all_buckets_map = get_all_buckets_map(all_datastores);
@@ -203,18 +226,425 @@ mod sync_tests {
             false,
             None,
             &SyncSpec::default(),
-        );
+        )
+        .unwrap();
 
         // Check again that new events were indeed synced
         check_synced_buckets_equal_to_src(&all_buckets_map);
     }
 
+    fn sync_and_get_events(state: &TestState, bucket_id: &str) -> Vec<Event> {
+        aw_sync::sync_datastores(
+            &state.ds_src,
+            &state.ds_dest,
+            false,
+            None,
+            &SyncSpec::default(),
+        )
+        .unwrap();
+        let synced_bucket_id = format!("{bucket_id}-synced-from-device-0");
+        state
+            .ds_dest
+            .get_events(&synced_bucket_id, None, None, None)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_resync_is_idempotent() {
+        let state = init_teststate();
+
+        let bucket_id = create_bucket(&state.ds_src, 0);
+        create_events(&state.ds_src, bucket_id.as_str(), 5);
+        // Events with the same timestamp are told apart by their origin
+        let event = create_event("5");
+        let events = [
+            event.clone(),
+            Event {
+                data: create_event("6").data,
+                ..event
+            },
+        ];
+        state.ds_src.insert_events(&bucket_id, &events).unwrap();
+
+        let events_synced = sync_and_get_events(&state, &bucket_id);
+        assert_eq!(events_synced.len(), 7);
+        let events_src = state
+            .ds_src
+            .get_events(&bucket_id, None, None, None)
+            .unwrap();
+        for (e_src, e_synced) in events_src.iter().zip(&events_synced) {
+            assert_eq!(e_src, e_synced);
+            let origin = format!("device-0:{}", e_src.id.unwrap());
+            assert_eq!(e_synced.origin.as_ref(), Some(&origin));
+        }
+
+        let events_resynced = sync_and_get_events(&state, &bucket_id);
+        assert_eq!(events_resynced.len(), 7);
+        for (e_synced, e_resynced) in events_synced.iter().zip(&events_resynced) {
+            assert_eq!(e_synced.id, e_resynced.id);
+        }
+    }
+
+    #[test]
+    fn test_modified_and_deleted_events() {
+        let state = init_teststate();
+
+        let bucket_id = create_bucket(&state.ds_src, 0);
+        create_events(&state.ds_src, bucket_id.as_str(), 5);
+        sync_and_get_events(&state, &bucket_id);
+
+        // Modify an event in the middle and delete another one
+        let events_src = state
+            .ds_src
+            .get_events(&bucket_id, None, None, None)
+            .unwrap();
+        let mut modified = events_src[2].clone();
+        modified.duration = Duration::seconds(1);
+        modified.data = create_event("\"modified\"").data;
+        state
+            .ds_src
+            .update_event(&bucket_id, modified.id.unwrap(), &modified)
+            .unwrap();
+        state
+            .ds_src
+            .delete_events_by_id(&bucket_id, vec![events_src[3].id.unwrap()])
+            .unwrap();
+
+        let events_synced = sync_and_get_events(&state, &bucket_id);
+        let events_src = state
+            .ds_src
+            .get_events(&bucket_id, None, None, None)
+            .unwrap();
+        assert_eq!(events_synced.len(), 4);
+        assert_eq!(events_synced, events_src);
+        assert!(events_synced.contains(&modified));
+    }
+
+    #[test]
+    fn test_incremental_sync() {
+        let state = init_teststate();
+        let tokens_path = std::env::temp_dir().join(format!(
+            "aw-sync-test-sync-tokens-{}.json",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&tokens_path);
+        let sync_spec = SyncSpec {
+            tokens: Some(tokens_path.clone()),
+            ..Default::default()
+        };
+        let sync = |sync_spec: &SyncSpec| {
+            aw_sync::sync_datastores(&state.ds_src, &state.ds_dest, false, None, sync_spec)
+                .unwrap();
+            state
+                .ds_dest
+                .get_events("bucket-0-synced-from-device-0", None, None, None)
+                .unwrap()
+        };
+
+        let bucket_id = create_bucket(&state.ds_src, 0);
+        create_events(&state.ds_src, bucket_id.as_str(), 5);
+        assert_eq!(sync(&sync_spec).len(), 5);
+        assert!(tokens_path.exists());
+
+        // A synced event which is no longer in the source bucket, but wasn't deleted since the
+        // last sync either
+        let stale = Event {
+            origin: Some("device-0:1000".to_string()),
+            ..create_event("\"stale\"")
+        };
+        state
+            .ds_dest
+            .insert_events(
+                "bucket-0-synced-from-device-0",
+                std::slice::from_ref(&stale),
+            )
+            .unwrap();
+
+        // Modify the first event, delete another one and add a new one
+        let events_src = state
+            .ds_src
+            .get_events(&bucket_id, None, None, None)
+            .unwrap();
+        let mut modified = events_src[4].clone();
+        modified.data = create_event("\"modified\"").data;
+        state
+            .ds_src
+            .update_event(&bucket_id, modified.id.unwrap(), &modified)
+            .unwrap();
+        state
+            .ds_src
+            .delete_events_by_id(&bucket_id, vec![events_src[2].id.unwrap()])
+            .unwrap();
+        create_events(&state.ds_src, bucket_id.as_str(), 1);
+
+        // Only the changes are synced, the stale event isn't looked at
+        let events_synced = sync(&sync_spec);
+        let mut events_src = state
+            .ds_src
+            .get_events(&bucket_id, None, None, None)
+            .unwrap();
+        assert_eq!(events_synced.len(), 6);
+        assert!(events_synced.contains(&modified));
+        assert!(events_synced.iter().any(|e| e.data == stale.data));
+        events_src.push(stale);
+        events_src.sort_by_key(|e| std::cmp::Reverse(e.timestamp));
+        assert_eq!(events_synced, events_src);
+
+        // Comparing all events deletes it
+        let events_synced = sync(&SyncSpec::default());
+        assert_eq!(events_synced.len(), 5);
+        assert!(!events_synced.iter().any(|e| e.data.contains_key("stale")));
+
+        std::fs::remove_file(&tokens_path).unwrap();
+    }
+
+    #[test]
+    fn test_sync_origin_is_kept() {
+        // Events keep the origin given when they were first synced when they are synced further
+        let state = init_teststate();
+        let ds_other = Datastore::new_in_memory(false);
+
+        let bucket_id = create_bucket(&state.ds_src, 0);
+        create_events(&state.ds_src, bucket_id.as_str(), 3);
+        aw_sync::sync_datastores(
+            &state.ds_src,
+            &state.ds_dest,
+            true,
+            Some("device-id"),
+            &SyncSpec::default(),
+        )
+        .unwrap();
+        aw_sync::sync_datastores(&state.ds_dest, &ds_other, false, None, &SyncSpec::default())
+            .unwrap();
+
+        let events_src = state
+            .ds_src
+            .get_events(&bucket_id, None, None, None)
+            .unwrap();
+        let events_pushed = state
+            .ds_dest
+            .get_events(&bucket_id, None, None, None)
+            .unwrap();
+        let events_pulled = ds_other
+            .get_events("bucket-0-synced-from-device-0", None, None, None)
+            .unwrap();
+        assert_eq!(events_pushed, events_src);
+        assert_eq!(events_pulled, events_src);
+        for ((e_src, e_pushed), e_pulled) in
+            events_src.iter().zip(&events_pushed).zip(&events_pulled)
+        {
+            let origin = format!("device-id:{}", e_src.id.unwrap());
+            assert_eq!(e_pushed.origin.as_ref(), Some(&origin));
+            assert_eq!(e_pulled.origin.as_ref(), Some(&origin));
+        }
+    }
+
+    #[test]
+    fn test_emptied_bucket() {
+        // Events deleted from a bucket are deleted where it was synced further, even when the
+        // bucket has no events left to tell which device it was synced from
+        let state = init_teststate();
+        let ds_other = Datastore::new_in_memory(false);
+        let bucket_id = create_bucket(&state.ds_src, 0);
+        create_events(&state.ds_src, bucket_id.as_str(), 3);
+        let sync = || {
+            aw_sync::sync_datastores(
+                &state.ds_src,
+                &state.ds_dest,
+                true,
+                Some("device-id"),
+                &SyncSpec::default(),
+            )
+            .unwrap();
+            aw_sync::sync_datastores(&state.ds_dest, &ds_other, false, None, &SyncSpec::default())
+                .unwrap();
+            ds_other
+                .get_events("bucket-0-synced-from-device-0", None, None, None)
+                .unwrap()
+        };
+        assert_eq!(sync().len(), 3);
+        let bucket = state.ds_dest.get_bucket(&bucket_id).unwrap();
+        assert_eq!(bucket.data["$aw.sync.origin_device"], "device-id");
+
+        state
+            .ds_src
+            .delete_events_in_range(&bucket_id, None, None)
+            .unwrap();
+        assert_eq!(sync(), vec![]);
+    }
+
+    #[test]
+    fn test_events_synced_without_origin() {
+        // Events synced before events had an origin are given one instead of being synced again
+        let state = init_teststate();
+
+        let bucket_id = create_bucket(&state.ds_src, 0);
+        sync_and_get_events(&state, &bucket_id);
+        create_events(&state.ds_src, bucket_id.as_str(), 3);
+        let mut events_src = state
+            .ds_src
+            .get_events(&bucket_id, None, None, None)
+            .unwrap();
+        for event in &mut events_src {
+            event.id = None;
+        }
+        let synced_bucket_id = format!("{bucket_id}-synced-from-device-0");
+        state
+            .ds_dest
+            .insert_events(&synced_bucket_id, &events_src)
+            .unwrap();
+
+        let events_synced = sync_and_get_events(&state, &bucket_id);
+        assert_eq!(events_synced, events_src);
+        assert!(events_synced.iter().all(|e| e.origin.is_some()));
+    }
+
+    #[test]
+    fn test_conflicting_events() {
+        // Two devices with the same hostname are synced to the same bucket
+        let state = init_teststate();
+        let ds_other = Datastore::new_in_memory(false);
+        let bucket_id = create_bucket(&state.ds_src, 0);
+        create_bucket(&ds_other, 0);
+        let event = create_event("1");
+        let other_event = Event {
+            data: create_event("2").data,
+            ..event.clone()
+        };
+        state
+            .ds_src
+            .insert_events(&bucket_id, std::slice::from_ref(&event))
+            .unwrap();
+        ds_other
+            .insert_events(&bucket_id, &[other_event.clone(), create_event("3")])
+            .unwrap();
+
+        let synced_bucket_id = format!("{bucket_id}-synced-from-device-0");
+        let sync = |ds_from: &Datastore, did: &str, strategy: ConflictStrategy| {
+            let sync_spec = SyncSpec {
+                conflict_strategy: strategy,
+                ..Default::default()
+            };
+            aw_sync::sync_datastores(ds_from, &state.ds_dest, false, Some(did), &sync_spec).unwrap()
+        };
+        let get_synced_events = || {
+            state
+                .ds_dest
+                .get_events(&synced_bucket_id, None, None, None)
+                .unwrap()
+        };
+        let is_tagged = |e: &Event| e.data.contains_key(CONFLICT_KEY);
+
+        assert!(sync(&state.ds_src, "device-a", ConflictStrategy::KeepBoth).is_empty());
+        let conflicts = sync(&ds_other, "device-b", ConflictStrategy::KeepBoth);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].bucket_to, synced_bucket_id);
+        match &conflicts[0].conflicts[..] {
+            [Conflict::OverlappingEvents {
+                source,
+                destination,
+            }] => {
+                assert_eq!(source, &other_event);
+                assert_eq!(destination, &event);
+            }
+            c => panic!("Expected overlapping events, got {c:?}"),
+        }
+
+        // Both are kept and tagged once both devices have been synced, and neither sync deletes
+        // the events of the other device
+        assert_eq!(
+            sync(&state.ds_src, "device-a", ConflictStrategy::KeepBoth).len(),
+            1
+        );
+        let events = get_synced_events();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events.iter().filter(|e| is_tagged(e)).count(), 2);
+
+        // The overlapping event of the other device is deleted when preferring the source
+        sync(&ds_other, "device-b", ConflictStrategy::PreferSource);
+        let events = get_synced_events();
+        assert_eq!(events.len(), 2);
+        assert!(events.contains(&other_event));
+        assert!(!events.iter().any(is_tagged));
+
+        // And the synced event is skipped when preferring the destination
+        sync(
+            &state.ds_src,
+            "device-a",
+            ConflictStrategy::PreferDestination,
+        );
+        assert_eq!(get_synced_events(), events);
+    }
+
+    #[test]
+    fn test_bucket_conflicts() {
+        let state = init_teststate();
+
+        // A bucket with the same ID but another type already exists in the destination
+        let bucket_id = create_bucket(&state.ds_src, 0);
+        let bucket = Bucket {
+            _type: "other".to_string(),
+            ..state.ds_src.get_bucket(&bucket_id).unwrap()
+        };
+        state.ds_dest.create_bucket(&bucket).unwrap();
+        // And a bucket without hostname is synced
+        let bucket_unknown: Bucket = serde_json::from_str(
+            r#"{"id": "bucket-unknown", "type": "test", "hostname": "unknown", "client": "test"}"#,
+        )
+        .unwrap();
+        state.ds_src.create_bucket(&bucket_unknown).unwrap();
+
+        let mut conflicts = aw_sync::sync_datastores(
+            &state.ds_src,
+            &state.ds_dest,
+            true,
+            Some("device-id"),
+            &SyncSpec::default(),
+        )
+        .unwrap();
+        conflicts.sort_by(|a, b| a.bucket_from.cmp(&b.bucket_from));
+        assert_eq!(conflicts.len(), 2);
+        assert_eq!(conflicts[0].bucket_from, bucket_id);
+        assert_eq!(
+            conflicts[0].conflicts,
+            vec![Conflict::BucketMetadata {
+                field: "type".to_string(),
+                source: "test".to_string(),
+                destination: "other".to_string(),
+            }]
+        );
+        assert_eq!(conflicts[1].bucket_from, "bucket-unknown");
+        assert_eq!(
+            conflicts[1].conflicts,
+            vec![Conflict::UnknownHostname {
+                device_id: Some("device-id".to_string())
+            }]
+        );
+
+        // The report is written as JSON
+        let mut report = ConflictReport::new(ConflictStrategy::KeepBoth);
+        report.buckets = conflicts;
+        let path = std::env::temp_dir().join(format!(
+            "aw-sync-test-conflicts-{}.json",
+            std::process::id()
+        ));
+        report.write(&path).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(json["strategy"], "keep-both");
+        assert_eq!(
+            json["buckets"][0]["conflicts"][0]["type"],
+            "bucket_metadata"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
     // TODO: Find a way to reuse this (previously used in an integration test)
     fn setup_test(sync_directory: &Path) -> std::io::Result<Vec<Datastore>> {
         let mut datastores: Vec<Datastore> = Vec::new();
         for n in 0..2 {
             let dspath = sync_directory.join(format!("test-remote-{n}.db"));
-            let ds_ = create_datastore(&dspath);
+            let ds_ = create_datastore(&dspath, None).unwrap();
             let ds = &ds_ as &dyn AccessMethod;
 
             // Create a bucket
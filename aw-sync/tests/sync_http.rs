@@ -0,0 +1,401 @@
+extern crate aw_sync;
+extern crate rocket;
+extern crate tokio_test;
+
+#[cfg(test)]
+mod sync_http_tests {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::thread;
+
+    use aw_client_rust::blocking::AwClient;
+    use chrono::{DateTime, Duration, TimeZone, Utc};
+    use serde_json::json;
+    use tokio_test::block_on;
+
+    use aw_datastore::{Datastore, DatastoreError};
+    use aw_models::{Bucket, Event, EventChanges, OriginChanges};
+    use aw_sync::{
+        sync_changes, sync_http, AccessMethod, Conflict, ConflictStrategy, SyncMode, SyncSpec,
+        SyncTokens, CONFLICT_KEY,
+    };
+
+    // Random ports, but still not guaranteed to not be bound
+    static PORT_A: u16 = 41295;
+    static PORT_B: u16 = 41296;
+
+    fn setup_testserver(port: u16, device_id: &str) -> (AwClient, rocket::Shutdown) {
+        use aw_server::endpoints::AssetResolver;
+        use aw_server::endpoints::ServerState;
+
+        let state = ServerState {
+            datastore: Mutex::new(aw_datastore::Datastore::new_in_memory(false)),
+            asset_resolver: AssetResolver::new(None),
+            device_id: device_id.to_string(),
+        };
+        let aw_config = aw_server::config::AWConfig {
+            port,
+            ..Default::default()
+        };
+        let server = aw_server::endpoints::build_rocket(state, aw_config);
+        let server = block_on(server.ignite()).unwrap();
+        let shutdown_handler = server.shutdown();
+        thread::spawn(move || {
+            let _ = block_on(server.launch()).unwrap();
+        });
+
+        let client = AwClient::new("127.0.0.1", port, "aw-sync-test").unwrap();
+        for i in 0..20 {
+            match client.get_info() {
+                Ok(_) => break,
+                Err(err) if i == 19 => panic!("Timed out starting aw-server: {err:?}"),
+                Err(_) => thread::sleep(std::time::Duration::from_secs(1)),
+            }
+        }
+        (client, shutdown_handler)
+    }
+
+    fn create_bucket(client: &AwClient, bucket_id: &str, hostname: &str) {
+        let bucket: Bucket = serde_json::from_value(json!({
+            "id": bucket_id,
+            "type": "test",
+            "hostname": hostname,
+            "client": "test",
+        }))
+        .unwrap();
+        client.create_bucket(&bucket).unwrap();
+    }
+
+    fn create_events(n: i64) -> Vec<Event> {
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        (0..n)
+            .map(|i| Event {
+                id: None,
+                timestamp: start + Duration::seconds(i),
+                duration: Duration::seconds(1),
+                data: json_map(i),
+                origin: None,
+            })
+            .collect()
+    }
+
+    fn json_map(i: i64) -> serde_json::Map<String, serde_json::Value> {
+        let mut data = serde_json::Map::new();
+        data.insert("test".to_string(), json!(i));
+        data
+    }
+
+    /// A datastore which fails to get the changes after a token
+    #[derive(Debug)]
+    struct FailingChanges(Datastore);
+
+    impl AccessMethod for FailingChanges {
+        fn get_buckets(&self) -> Result<HashMap<String, Bucket>, String> {
+            AccessMethod::get_buckets(&self.0)
+        }
+        fn get_bucket(&self, bucket_id: &str) -> Result<Bucket, DatastoreError> {
+            AccessMethod::get_bucket(&self.0, bucket_id)
+        }
+        fn create_bucket(&self, bucket: &Bucket) -> Result<(), DatastoreError> {
+            AccessMethod::create_bucket(&self.0, bucket)
+        }
+        fn get_events(
+            &self,
+            bucket_id: &str,
+            start: Option<DateTime<Utc>>,
+            end: Option<DateTime<Utc>>,
+            limit: Option<u64>,
+        ) -> Result<Vec<Event>, String> {
+            AccessMethod::get_events(&self.0, bucket_id, start, end, limit)
+        }
+        fn insert_events(&self, bucket_id: &str, events: Vec<Event>) -> Result<(), String> {
+            AccessMethod::insert_events(&self.0, bucket_id, events)
+        }
+        fn get_event_count(&self, bucket_id: &str) -> Result<i64, String> {
+            AccessMethod::get_event_count(&self.0, bucket_id)
+        }
+        fn delete_events(&self, bucket_id: &str, event_ids: Vec<i64>) -> Result<(), String> {
+            AccessMethod::delete_events(&self.0, bucket_id, event_ids)
+        }
+        fn get_event_changes(
+            &self,
+            bucket_id: &str,
+            token: Option<&str>,
+            limit: u64,
+        ) -> Result<EventChanges, DatastoreError> {
+            match token {
+                Some(_) => Err(DatastoreError::InternalError("Disk I/O error".to_string())),
+                None => AccessMethod::get_event_changes(&self.0, bucket_id, token, limit),
+            }
+        }
+        fn apply_event_changes(
+            &self,
+            bucket_id: &str,
+            changes: OriginChanges,
+        ) -> Result<(), String> {
+            AccessMethod::apply_event_changes(&self.0, bucket_id, changes)
+        }
+        fn close(&self) {}
+    }
+
+    fn get_all_events(client: &AwClient, bucket_id: &str) -> Vec<Event> {
+        client.get_events(bucket_id, None, None, None).unwrap()
+    }
+
+    #[test]
+    fn test_sync_http() {
+        let (client_a, shutdown_a) = setup_testserver(PORT_A, "device-a");
+        let (client_b, shutdown_b) = setup_testserver(PORT_B, "device-b");
+
+        let tokens_path =
+            std::env::temp_dir().join(format!("aw-sync-test-tokens-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&tokens_path);
+        let mut tokens = SyncTokens::load(&tokens_path).unwrap();
+        let sync_spec = SyncSpec::default();
+
+        // More events than are transferred at once
+        create_bucket(&client_a, "bucket-a", "host-a");
+        client_a
+            .insert_events("bucket-a", create_events(2500))
+            .unwrap();
+
+        sync_http(
+            &client_b,
+            &client_a,
+            SyncMode::Both,
+            &sync_spec,
+            &mut tokens,
+        )
+        .unwrap();
+        let synced = get_all_events(&client_b, "bucket-a-synced-from-host-a");
+        assert_eq!(synced, get_all_events(&client_a, "bucket-a"));
+        assert!(synced
+            .iter()
+            .all(|e| e.origin.as_deref().unwrap().starts_with("device-a:")));
+        // Synced buckets aren't pushed back
+        assert_eq!(client_a.get_buckets().unwrap().len(), 1);
+
+        // Modifications and deletions are synced, continuing from the stored tokens
+        let events_a = get_all_events(&client_a, "bucket-a");
+        let modified = Event {
+            data: json_map(-1),
+            ..events_a[0].clone()
+        };
+        client_a
+            .update_event("bucket-a", modified.id.unwrap(), &modified)
+            .unwrap();
+        client_a
+            .delete_event("bucket-a", events_a[1].id.unwrap())
+            .unwrap();
+        let mut tokens = SyncTokens::load(&tokens_path).unwrap();
+        assert!(tokens.get("device-a/device-b/bucket-a").is_some());
+        sync_http(
+            &client_b,
+            &client_a,
+            SyncMode::Pull,
+            &sync_spec,
+            &mut tokens,
+        )
+        .unwrap();
+        let synced = get_all_events(&client_b, "bucket-a-synced-from-host-a");
+        assert_eq!(synced.len(), 2499);
+        assert_eq!(synced, get_all_events(&client_a, "bucket-a"));
+        let token = tokens.get("device-a/device-b/bucket-a").unwrap();
+        assert!(client_a
+            .get_event_changes("bucket-a", Some(token), 10)
+            .unwrap()
+            .events
+            .is_empty());
+
+        // Local buckets are pushed
+        create_bucket(&client_b, "bucket-b", "host-b");
+        client_b
+            .insert_events("bucket-b", create_events(3))
+            .unwrap();
+        sync_http(
+            &client_b,
+            &client_a,
+            SyncMode::Push,
+            &sync_spec,
+            &mut tokens,
+        )
+        .unwrap();
+        assert_eq!(
+            get_all_events(&client_a, "bucket-b-synced-from-host-b"),
+            get_all_events(&client_b, "bucket-b")
+        );
+
+        std::fs::remove_file(&tokens_path).unwrap();
+        shutdown_a.notify();
+        shutdown_b.notify();
+    }
+
+    #[test]
+    fn test_sync_changes_token_errors() {
+        let tokens_path = std::env::temp_dir().join(format!(
+            "aw-sync-test-token-errors-{}.json",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&tokens_path);
+        let mut tokens = SyncTokens::load(&tokens_path).unwrap();
+        let sync_spec = SyncSpec::default();
+        let key = "device-a/device-b/bucket-a";
+
+        let ds_from = Datastore::new_in_memory(false);
+        let ds_to = Datastore::new_in_memory(false);
+        let bucket: Bucket = serde_json::from_value(json!({
+            "id": "bucket-a",
+            "type": "test",
+            "hostname": "host-a",
+            "client": "test",
+        }))
+        .unwrap();
+        ds_from.create_bucket(&bucket).unwrap();
+        ds_from
+            .insert_events("bucket-a", &create_events(3))
+            .unwrap();
+        sync_changes(
+            &ds_from,
+            &ds_to,
+            "device-a",
+            "device-b",
+            &sync_spec,
+            &mut tokens,
+        )
+        .unwrap();
+        let token = tokens.get(key).unwrap().to_string();
+
+        // Other errors than a refused token fail the sync, instead of syncing everything again
+        let failing = FailingChanges(ds_from);
+        ds_to
+            .delete_events_by_id("bucket-a-synced-from-host-a", vec![1])
+            .unwrap();
+        assert!(sync_changes(
+            &failing,
+            &ds_to,
+            "device-a",
+            "device-b",
+            &sync_spec,
+            &mut tokens
+        )
+        .is_err());
+        assert_eq!(tokens.get(key), Some(token.as_str()));
+        assert_eq!(
+            ds_to
+                .get_event_count("bucket-a-synced-from-host-a", None, None)
+                .unwrap(),
+            2
+        );
+
+        // A token from another database is refused, and all changes are synced again
+        let ds_from = failing.0;
+        tokens.set(key, "1000").unwrap();
+        sync_changes(
+            &ds_from,
+            &ds_to,
+            "device-a",
+            "device-b",
+            &sync_spec,
+            &mut tokens,
+        )
+        .unwrap();
+        assert_eq!(tokens.get(key), Some(token.as_str()));
+        assert_eq!(
+            ds_to
+                .get_event_count("bucket-a-synced-from-host-a", None, None)
+                .unwrap(),
+            3
+        );
+
+        std::fs::remove_file(&tokens_path).unwrap();
+    }
+
+    #[test]
+    fn test_sync_changes_conflicts() {
+        let tokens_path = std::env::temp_dir().join(format!(
+            "aw-sync-test-conflicts-{}.json",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&tokens_path);
+        let mut tokens = SyncTokens::load(&tokens_path).unwrap();
+        let sync_spec = SyncSpec {
+            conflict_strategy: ConflictStrategy::PreferSource,
+            ..Default::default()
+        };
+
+        let ds_from = Datastore::new_in_memory(false);
+        let ds_to = Datastore::new_in_memory(false);
+        let bucket: Bucket = serde_json::from_value(json!({
+            "id": "bucket-a",
+            "type": "test",
+            "hostname": "host-a",
+            "client": "test",
+        }))
+        .unwrap();
+        ds_from.create_bucket(&bucket).unwrap();
+        ds_from
+            .insert_events("bucket-a", &create_events(2))
+            .unwrap();
+        // An event which wasn't synced from the source overlaps the first one
+        let mut bucket_to = bucket.clone();
+        bucket_to.id = "bucket-a-synced-from-host-a".to_string();
+        ds_to.create_bucket(&bucket_to).unwrap();
+        let other = Event {
+            data: json_map(-1),
+            ..create_events(1)[0].clone()
+        };
+        ds_to
+            .insert_events(&bucket_to.id, std::slice::from_ref(&other))
+            .unwrap();
+
+        let conflicts = sync_changes(
+            &ds_from,
+            &ds_to,
+            "device-a",
+            "device-b",
+            &sync_spec,
+            &mut tokens,
+        )
+        .unwrap();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].bucket_to, bucket_to.id);
+        match &conflicts[0].conflicts[..] {
+            [Conflict::OverlappingEvents { destination, .. }] => {
+                assert_eq!(destination.data, other.data)
+            }
+            conflicts => panic!("Expected one overlapping event, got {conflicts:?}"),
+        }
+        // The event from the source replaced it
+        let events = ds_to.get_events(&bucket_to.id, None, None, None).unwrap();
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|e| e.origin.is_some()));
+
+        // With the default strategy both are kept, and the synced one is tagged
+        ds_to
+            .insert_events(&bucket_to.id, std::slice::from_ref(&other))
+            .unwrap();
+        ds_from
+            .insert_events("bucket-a", &create_events(1))
+            .unwrap();
+        let conflicts = sync_changes(
+            &ds_from,
+            &ds_to,
+            "device-a",
+            "device-b",
+            &SyncSpec::default(),
+            &mut tokens,
+        )
+        .unwrap();
+        assert_eq!(conflicts.len(), 1);
+        let events = ds_to.get_events(&bucket_to.id, None, None, None).unwrap();
+        assert_eq!(events.len(), 4);
+        let tagged: Vec<&Event> = events
+            .iter()
+            .filter(|e| e.data.contains_key(CONFLICT_KEY))
+            .collect();
+        assert_eq!(tagged.len(), 1);
+        assert!(tagged[0].origin.is_some());
+
+        std::fs::remove_file(&tokens_path).unwrap();
+    }
+}
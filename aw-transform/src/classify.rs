@@ -39,6 +39,11 @@ impl RegexRule {
 
         Ok(RegexRule { regex })
     }
+
+    /// Matches a single string, which fails if the regex needs too much backtracking
+    pub fn is_match(&self, text: &str) -> Result<bool, fancy_regex::Error> {
+        self.regex.is_match(text)
+    }
 }
 
 /// This struct defines the rules for classification.
@@ -145,6 +150,13 @@ fn test_rule() {
     assert!(!rule_none.matches(&e_match));
 }
 
+#[test]
+fn test_regex_rule_is_match() {
+    let rule = RegexRule::new("private browsing", true).unwrap();
+    assert!(rule.is_match("Bank - Private Browsing").unwrap());
+    assert!(!rule.is_match("Bank").unwrap());
+}
+
 #[test]
 fn test_rule_lookahead() {
     // Originally requested by a user here, to match aw-server-python: https://canary.discord.com/channels/755040852727955476/755334543891759194/994291987878522961
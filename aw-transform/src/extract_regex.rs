@@ -0,0 +1,120 @@
+use fancy_regex::Regex;
+use serde_json::value::Value;
+
+use aw_models::Event;
+
+/// Copies the named capture groups in target_keys from a regex match on the value for a
+/// specified key into data fields with the same names as the groups.
+/// Events where the value is not a string or doesn't match are kept unchanged, as are fields
+/// for groups which did not take part in the match.
+///
+/// # Example
+/// ```ignore
+/// key: title
+/// regex: "(?P<ticket>[A-Z]+-[0-9]+)"
+/// target_keys: ["ticket"]
+/// input:  [title:"PROJ-12 fix bug"][title:"README.md"]
+/// output: [title:"PROJ-12 fix bug", ticket:"PROJ-12"][title:"README.md"]
+/// ```
+pub fn extract_regex(
+    mut events: Vec<Event>,
+    key: &str,
+    regex: &Regex,
+    target_keys: &[String],
+) -> Vec<Event> {
+    for event in events.iter_mut() {
+        let value = match event.data.get(key).and_then(|v| v.as_str()) {
+            Some(value) => value,
+            None => continue,
+        };
+        let captures = match regex.captures(value) {
+            Ok(Some(captures)) => captures,
+            Ok(None) => continue,
+            Err(err) => {
+                warn!("Failed to run regex: {}", err);
+                continue;
+            }
+        };
+        let extracted: Vec<(String, Value)> = target_keys
+            .iter()
+            .filter_map(|target| {
+                captures
+                    .name(target)
+                    .map(|m| (target.clone(), Value::String(m.as_str().to_string())))
+            })
+            .collect();
+        event.data.extend(extracted);
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chrono::{DateTime, Duration};
+    use fancy_regex::RegexBuilder;
+    use serde_json::json;
+
+    use aw_models::Event;
+
+    use super::extract_regex;
+
+    #[test]
+    fn test_extract_regex() {
+        let e1 = Event {
+            id: None,
+            timestamp: DateTime::from_str("2000-01-01T00:00:00Z").unwrap(),
+            duration: Duration::seconds(1),
+            data: json_map! {"title": json!("PROJ-12 fix bug - main - editor")},
+            origin: None,
+        };
+        let mut e2 = e1.clone();
+        e2.data = json_map! {"title": json!("README.md - editor")};
+        let mut e3 = e1.clone();
+        e3.data = json_map! {"title": json!(1)};
+        let mut e4 = e1.clone();
+        e4.data = json_map! {"title": json!("PROJ-13 - editor")};
+
+        let regex =
+            RegexBuilder::new(r"^(?P<ticket>[A-Z]+-[0-9]+)( \w+)*( - (?P<branch>\w+))? - editor$")
+                .build()
+                .unwrap();
+        let target_keys = vec!["ticket".to_string(), "branch".to_string()];
+        let res = extract_regex(
+            vec![e1.clone(), e2.clone(), e3.clone(), e4.clone()],
+            "title",
+            &regex,
+            &target_keys,
+        );
+
+        let mut e1_expected = e1;
+        e1_expected
+            .data
+            .insert("ticket".to_string(), json!("PROJ-12"));
+        e1_expected.data.insert("branch".to_string(), json!("main"));
+        // Groups not part of the match are not set
+        let mut e4_expected = e4;
+        e4_expected
+            .data
+            .insert("ticket".to_string(), json!("PROJ-13"));
+        assert_eq!(res, vec![e1_expected, e2, e3, e4_expected]);
+    }
+
+    #[test]
+    fn test_extract_regex_only_target_keys() {
+        let e1 = Event {
+            id: None,
+            timestamp: DateTime::from_str("2000-01-01T00:00:00Z").unwrap(),
+            duration: Duration::seconds(1),
+            data: json_map! {"url": json!("https://example.com/path")},
+            origin: None,
+        };
+        let regex = RegexBuilder::new(r"^(?P<scheme>\w+)://(?P<domain>[^/]+)")
+            .build()
+            .unwrap();
+        let res = extract_regex(vec![e1], "url", &regex, &["domain".to_string()]);
+        assert_eq!(res[0].data.get("domain"), Some(&json!("example.com")));
+        assert_eq!(res[0].data.get("scheme"), None);
+    }
+}
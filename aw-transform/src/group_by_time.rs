@@ -0,0 +1,375 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, Duration, LocalResult, NaiveDateTime, TimeZone, Timelike, Utc};
+
+use aw_models::Event;
+
+use crate::merge_events_by_keys;
+use crate::union_no_overlap::split_event;
+
+/// Size of the bins used by split_by_interval and group_by_time
+///
+/// Bins are aligned to the local time of a timezone: minute and hour bins start at multiples
+/// of their size after midnight, day bins at midnight and week bins at midnight on Monday.
+/// Which means that bins around daylight saving time changes can be shorter or longer than
+/// their nominal size, such as a 23 hour day in spring.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeBin {
+    Minutes(u32),
+    Hours(u32),
+    Day,
+    Week,
+}
+
+impl FromStr for TimeBin {
+    type Err = String;
+
+    /// Parses bin sizes such as "15m", "1h", "2h", "1d" and "1w"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (num, unit) = s.split_at(s.len() - s.chars().last().map_or(0, |c| c.len_utf8()));
+        let n: u32 = match num {
+            "" => 1,
+            num => num
+                .parse()
+                .map_err(|_| format!("Invalid time bin '{s}', expected for example 1h or 1d"))?,
+        };
+        let bin = match unit {
+            "m" if n > 0 && 60 % n == 0 => TimeBin::Minutes(n),
+            "h" if n > 0 && 24 % n == 0 => TimeBin::Hours(n),
+            "d" if n == 1 => TimeBin::Day,
+            "w" if n == 1 => TimeBin::Week,
+            "m" | "h" => {
+                return Err(format!(
+                    "Invalid time bin '{s}', minute bins have to divide an hour and hour bins a day"
+                ))
+            }
+            "d" | "w" => {
+                return Err(format!(
+                    "Invalid time bin '{s}', only 1d and 1w are supported"
+                ))
+            }
+            _ => {
+                return Err(format!(
+                    "Invalid time bin '{s}', the unit has to be one of m, h, d or w"
+                ))
+            }
+        };
+        Ok(bin)
+    }
+}
+
+impl TimeBin {
+    fn nominal_duration(&self) -> Duration {
+        match self {
+            TimeBin::Minutes(n) => Duration::minutes(*n as i64),
+            TimeBin::Hours(n) => Duration::hours(*n as i64),
+            TimeBin::Day => Duration::days(1),
+            TimeBin::Week => Duration::weeks(1),
+        }
+    }
+
+    /// Start of the bin containing timestamp
+    pub fn bin_start<Tz: TimeZone>(&self, timestamp: DateTime<Utc>, tz: &Tz) -> DateTime<Utc> {
+        let local = timestamp.with_timezone(tz).naive_local();
+        let midnight = local.date().and_hms_opt(0, 0, 0).unwrap();
+        let start = match self {
+            TimeBin::Minutes(n) => {
+                let minutes = local.hour() * 60 + local.minute();
+                midnight + Duration::minutes((minutes - minutes % n) as i64)
+            }
+            TimeBin::Hours(n) => {
+                midnight + Duration::hours((local.hour() - local.hour() % n) as i64)
+            }
+            TimeBin::Day => midnight,
+            TimeBin::Week => {
+                midnight - Duration::days(local.weekday().num_days_from_monday() as i64)
+            }
+        };
+        local_to_utc(tz, start, timestamp)
+    }
+
+    /// Start of the bin following the bin starting at bin_start
+    pub fn bin_end<Tz: TimeZone>(&self, bin_start: DateTime<Utc>, tz: &Tz) -> DateTime<Utc> {
+        // Going 1.5 bins forward always lands in the next bin, even if the bin or the next
+        // one is made shorter or longer by a daylight saving time change
+        let len = self.nominal_duration();
+        let end = self.bin_start(bin_start + len + len / 2, tz);
+        if end > bin_start {
+            end
+        } else {
+            bin_start + len
+        }
+    }
+}
+
+/// The instant of a local time which is at most not_after, the local time might not exist or
+/// exist twice due to daylight saving time changes.
+fn local_to_utc<Tz: TimeZone>(
+    tz: &Tz,
+    local: NaiveDateTime,
+    not_after: DateTime<Utc>,
+) -> DateTime<Utc> {
+    match tz.from_local_datetime(&local) {
+        LocalResult::Single(dt) => dt.with_timezone(&Utc),
+        LocalResult::Ambiguous(earliest, latest) => {
+            let latest = latest.with_timezone(&Utc);
+            if latest <= not_after {
+                latest
+            } else {
+                earliest.with_timezone(&Utc)
+            }
+        }
+        // Skipped by a daylight saving time change, so the bin starts when the gap ends
+        LocalResult::None => {
+            let mut local = local;
+            loop {
+                local += Duration::minutes(1);
+                if let Some(dt) = tz.from_local_datetime(&local).earliest() {
+                    return dt.with_timezone(&Utc);
+                }
+            }
+        }
+    }
+}
+
+/// Splits events crossing the boundaries of the time bins
+///
+/// # Example
+/// ```ignore
+/// bin: 1h
+/// input:  { timestamp: 00:30, duration: 1.5h }
+/// output: { timestamp: 00:30, duration: 0.5h }
+///         { timestamp: 01:00, duration: 1h }
+/// ```
+pub fn split_by_interval<Tz: TimeZone>(events: Vec<Event>, bin: &TimeBin, tz: &Tz) -> Vec<Event> {
+    let mut split_events = Vec::new();
+    for event in events {
+        let mut event = event;
+        loop {
+            let bin_end = bin.bin_end(bin.bin_start(event.timestamp, tz), tz);
+            match split_event(&event, bin_end) {
+                (first, Some(rest)) => {
+                    let mut first = first;
+                    first.id = event.id;
+                    split_events.push(first);
+                    event = rest;
+                }
+                (event, None) => {
+                    split_events.push(event);
+                    break;
+                }
+            }
+        }
+    }
+    split_events
+}
+
+/// Sums up the duration of events within each time bin, events crossing the boundaries of bins
+/// are split so that every bin only gets the part of the event within it.
+///
+/// With no keys a single event per bin is returned, with keys events are merged by their values
+/// for the keys like merge_events_by_keys does within every bin.
+/// The returned events have the start of their bin as timestamp and are sorted by it, bins
+/// without any events are left out.
+///
+/// # Example
+/// ```ignore
+/// bin: 1h
+/// keys: ["a"]
+/// input:
+///   { timestamp: 00:30, duration: 1h, data: { "a": 1 } }
+///   { timestamp: 01:30, duration: 0.5h, data: { "a": 1 } }
+///   { timestamp: 01:40, duration: 0.1h, data: { "a": 2 } }
+/// output:
+///   { timestamp: 00:00, duration: 0.5h, data: { "a": 1 } }
+///   { timestamp: 01:00, duration: 1h, data: { "a": 1 } }
+///   { timestamp: 01:00, duration: 0.1h, data: { "a": 2 } }
+/// ```
+pub fn group_by_time<Tz: TimeZone>(
+    events: Vec<Event>,
+    bin: &TimeBin,
+    tz: &Tz,
+    keys: &[String],
+) -> Vec<Event> {
+    let mut bins: BTreeMap<DateTime<Utc>, Vec<Event>> = BTreeMap::new();
+    for event in split_by_interval(events, bin, tz) {
+        bins.entry(bin.bin_start(event.timestamp, tz))
+            .or_default()
+            .push(event);
+    }
+
+    let mut grouped_events = Vec::new();
+    for (bin_start, bin_events) in bins {
+        if keys.is_empty() {
+            let duration = bin_events
+                .iter()
+                .fold(Duration::zero(), |sum, e| sum + e.duration);
+            grouped_events.push(Event::new(bin_start, duration, serde_json::Map::new()));
+        } else {
+            for mut event in merge_events_by_keys(bin_events, keys.to_vec()) {
+                event.id = None;
+                event.timestamp = bin_start;
+                grouped_events.push(event);
+            }
+        }
+    }
+    grouped_events
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chrono::{DateTime, Duration, FixedOffset, Utc};
+    use chrono_tz::Europe::Stockholm;
+    use serde_json::json;
+
+    use aw_models::Event;
+
+    use super::{group_by_time, split_by_interval, TimeBin};
+
+    fn ts(s: &str) -> DateTime<Utc> {
+        DateTime::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_parse_time_bin() {
+        assert_eq!(TimeBin::from_str("15m").unwrap(), TimeBin::Minutes(15));
+        assert_eq!(TimeBin::from_str("1h").unwrap(), TimeBin::Hours(1));
+        assert_eq!(TimeBin::from_str("h").unwrap(), TimeBin::Hours(1));
+        assert_eq!(TimeBin::from_str("1d").unwrap(), TimeBin::Day);
+        assert_eq!(TimeBin::from_str("1w").unwrap(), TimeBin::Week);
+        assert!(TimeBin::from_str("7m").is_err());
+        assert!(TimeBin::from_str("2d").is_err());
+        assert!(TimeBin::from_str("1y").is_err());
+        assert!(TimeBin::from_str("xh").is_err());
+        assert!(TimeBin::from_str("").is_err());
+    }
+
+    #[test]
+    fn test_bins() {
+        let tz = FixedOffset::east_opt(2 * 3600).unwrap();
+        let t = ts("2000-01-05T13:37:00Z"); // Wednesday 15:37 local
+        let bin = TimeBin::Minutes(15);
+        assert_eq!(bin.bin_start(t, &tz), ts("2000-01-05T13:30:00Z"));
+        assert_eq!(
+            bin.bin_end(ts("2000-01-05T13:30:00Z"), &tz),
+            ts("2000-01-05T13:45:00Z")
+        );
+        let bin = TimeBin::Hours(6);
+        assert_eq!(bin.bin_start(t, &tz), ts("2000-01-05T10:00:00Z"));
+        let bin = TimeBin::Day;
+        assert_eq!(bin.bin_start(t, &tz), ts("2000-01-04T22:00:00Z"));
+        assert_eq!(
+            bin.bin_end(ts("2000-01-04T22:00:00Z"), &tz),
+            ts("2000-01-05T22:00:00Z")
+        );
+        let bin = TimeBin::Week;
+        assert_eq!(bin.bin_start(t, &tz), ts("2000-01-02T22:00:00Z"));
+        assert_eq!(
+            bin.bin_end(ts("2000-01-02T22:00:00Z"), &tz),
+            ts("2000-01-09T22:00:00Z")
+        );
+    }
+
+    #[test]
+    fn test_bins_dst() {
+        // Clocks in Stockholm went from 02:00 to 03:00 on 2021-03-28 and from 03:00 to 02:00
+        // on 2021-10-31
+        let bin = TimeBin::Day;
+        let start = bin.bin_start(ts("2021-03-28T12:00:00Z"), &Stockholm);
+        assert_eq!(start, ts("2021-03-27T23:00:00Z"));
+        assert_eq!(bin.bin_end(start, &Stockholm), ts("2021-03-28T22:00:00Z"));
+        let start = bin.bin_start(ts("2021-10-31T12:00:00Z"), &Stockholm);
+        assert_eq!(start, ts("2021-10-30T22:00:00Z"));
+        assert_eq!(bin.bin_end(start, &Stockholm), ts("2021-10-31T23:00:00Z"));
+
+        // The hour after the gap starts at 03:00 local time
+        let bin = TimeBin::Hours(1);
+        let start = bin.bin_start(ts("2021-03-28T00:30:00Z"), &Stockholm);
+        assert_eq!(start, ts("2021-03-28T00:00:00Z"));
+        assert_eq!(bin.bin_end(start, &Stockholm), ts("2021-03-28T01:00:00Z"));
+        // 2h bins starting at 02:00 which doesn't exist start when the gap ends instead
+        let bin = TimeBin::Hours(2);
+        assert_eq!(
+            bin.bin_start(ts("2021-03-28T01:30:00Z"), &Stockholm),
+            ts("2021-03-28T01:00:00Z")
+        );
+
+        // 02:00-03:00 happens twice, both are separate bins
+        let bin = TimeBin::Hours(1);
+        let first = bin.bin_start(ts("2021-10-31T00:30:00Z"), &Stockholm);
+        assert_eq!(first, ts("2021-10-31T00:00:00Z"));
+        let second = bin.bin_end(first, &Stockholm);
+        assert_eq!(second, ts("2021-10-31T01:00:00Z"));
+        assert_eq!(
+            bin.bin_start(ts("2021-10-31T01:30:00Z"), &Stockholm),
+            second
+        );
+    }
+
+    #[test]
+    fn test_split_by_interval() {
+        let e = Event {
+            id: Some(1),
+            timestamp: ts("2000-01-01T00:30:00Z"),
+            duration: Duration::minutes(150),
+            data: json_map! {"a": json!(1)},
+            origin: None,
+        };
+        let res = split_by_interval(vec![e.clone()], &TimeBin::Hours(1), &Utc);
+        assert_eq!(res.len(), 3);
+        assert_eq!(res[0].timestamp, ts("2000-01-01T00:30:00Z"));
+        assert_eq!(res[0].duration, Duration::minutes(30));
+        assert_eq!(res[0].id, Some(1));
+        assert_eq!(res[1].timestamp, ts("2000-01-01T01:00:00Z"));
+        assert_eq!(res[1].duration, Duration::minutes(60));
+        assert_eq!(res[2].timestamp, ts("2000-01-01T02:00:00Z"));
+        assert_eq!(res[2].duration, Duration::minutes(60));
+        assert!(res.iter().all(|e| e.data == json_map! {"a": json!(1)}));
+
+        // Events within a single bin are unchanged
+        let res = split_by_interval(vec![e.clone()], &TimeBin::Day, &Utc);
+        assert_eq!(res, vec![e]);
+    }
+
+    #[test]
+    fn test_group_by_time() {
+        let e1 = Event::new(
+            ts("2000-01-01T00:30:00Z"),
+            Duration::minutes(60),
+            json_map! {"a": json!(1)},
+        );
+        let e2 = Event::new(
+            ts("2000-01-01T01:30:00Z"),
+            Duration::minutes(30),
+            json_map! {"a": json!(1)},
+        );
+        let e3 = Event::new(
+            ts("2000-01-01T01:40:00Z"),
+            Duration::minutes(6),
+            json_map! {"a": json!(2)},
+        );
+        let events = vec![e1, e2, e3];
+
+        let res = group_by_time(events.clone(), &TimeBin::Hours(1), &Utc, &[]);
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[0].timestamp, ts("2000-01-01T00:00:00Z"));
+        assert_eq!(res[0].duration, Duration::minutes(30));
+        assert_eq!(res[1].timestamp, ts("2000-01-01T01:00:00Z"));
+        assert_eq!(res[1].duration, Duration::minutes(66));
+
+        let mut res = group_by_time(events, &TimeBin::Hours(1), &Utc, &["a".to_string()]);
+        res.sort_by_key(|e| (e.timestamp, e.data["a"].as_i64()));
+        assert_eq!(res.len(), 3);
+        assert_eq!(res[0].timestamp, ts("2000-01-01T00:00:00Z"));
+        assert_eq!(res[0].duration, Duration::minutes(30));
+        assert_eq!(res[1].timestamp, ts("2000-01-01T01:00:00Z"));
+        assert_eq!(res[1].duration, Duration::minutes(60));
+        assert_eq!(res[1].data, json_map! {"a": json!(1)});
+        assert_eq!(res[2].timestamp, ts("2000-01-01T01:00:00Z"));
+        assert_eq!(res[2].duration, Duration::minutes(6));
+        assert_eq!(res[2].data, json_map! {"a": json!(2)});
+    }
+}
@@ -39,6 +39,9 @@ pub use sort::{sort_by_duration, sort_by_timestamp};
 mod filter_keyvals;
 pub use filter_keyvals::{exclude_keyvals, filter_keyvals, filter_keyvals_regex};
 
+mod extract_regex;
+pub use extract_regex::extract_regex;
+
 mod filter_period;
 pub use filter_period::filter_period_intersect;
 
@@ -50,3 +53,6 @@ pub use period_union::period_union;
 
 mod union_no_overlap;
 pub use union_no_overlap::union_no_overlap;
+
+mod group_by_time;
+pub use group_by_time::{group_by_time, split_by_interval, TimeBin};
@@ -67,6 +67,7 @@ pub fn merge_events_by_keys(events: Vec<Event>, keys: Vec<String>) -> Vec<Event>
                 timestamp: event.timestamp,
                 duration: event.duration,
                 data: event.data.clone(),
+                origin: None,
             };
             merged_events_map.insert(summed_key, merged_event);
         }
@@ -99,24 +100,28 @@ mod tests {
             timestamp: DateTime::from_str("2000-01-01T00:00:00Z").unwrap(),
             duration: Duration::seconds(1),
             data: json_map! {"test": json!(1)},
+            origin: None,
         };
         let e2 = Event {
             id: None,
             timestamp: DateTime::from_str("2000-01-01T00:00:01Z").unwrap(),
             duration: Duration::seconds(3),
             data: json_map! {"test2": json!(3)},
+            origin: None,
         };
         let e3 = Event {
             id: None,
             timestamp: DateTime::from_str("2000-01-01T00:00:02Z").unwrap(),
             duration: Duration::seconds(7),
             data: json_map! {"test": json!(6)},
+            origin: None,
         };
         let e4 = Event {
             id: None,
             timestamp: DateTime::from_str("2000-01-01T00:00:03Z").unwrap(),
             duration: Duration::seconds(9),
             data: json_map! {"test": json!(1)},
+            origin: None,
         };
         let in_events = vec![e1, e2, e3, e4];
         let res1 = merge_events_by_keys(in_events, vec!["test".to_string()]);
@@ -128,12 +133,14 @@ mod tests {
                 timestamp: DateTime::from_str("2000-01-01T00:00:00Z").unwrap(),
                 duration: Duration::seconds(10),
                 data: json_map! {"test": json!(1)},
+                origin: None,
             },
             Event {
                 id: None,
                 timestamp: DateTime::from_str("2000-01-01T00:00:02Z").unwrap(),
                 duration: Duration::seconds(7),
                 data: json_map! {"test": json!(6)},
+                origin: None,
             },
         ];
         assert_eq!(&res2, &expected);
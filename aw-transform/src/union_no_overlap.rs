@@ -60,7 +60,7 @@ pub fn union_no_overlap(events1: Vec<Event>, mut events2: Vec<Event>) -> Vec<Eve
     events_union
 }
 
-fn split_event(e: &Event, timestamp: DateTime<Utc>) -> (Event, Option<Event>) {
+pub(crate) fn split_event(e: &Event, timestamp: DateTime<Utc>) -> (Event, Option<Event>) {
     if e.timestamp < timestamp && timestamp < e.timestamp + e.duration {
         let e1 = Event::new(e.timestamp, timestamp - e.timestamp, e.data.clone());
         let e2 = Event::new(
@@ -89,6 +89,7 @@ mod tests {
             timestamp: now,
             duration: Duration::hours(2),
             data: serde_json::Map::new(),
+            origin: None,
         };
         let (e1, e2_opt) = split_event(&e, now + td1h);
         assert_eq!(e1.timestamp, now);